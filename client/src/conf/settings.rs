@@ -15,8 +15,14 @@ pub struct Settings {
     pub servers: Vec<Server>,
     pub client_id: u8,
     pub api_key: String,
+    /// Key with the admin scope querying the audit logs, `api_key` if not set.
+    #[serde(default)]
+    pub admin_api_key: Option<String>,
     pub shares_to_create: u8,
     pub shares_required: u8,
+    /// Interval between two refreshes of the shares, the servers interval if not set.
+    #[serde(default)]
+    pub refresh_interval_secs: Option<u64>,
 }
 
 impl Settings {
@@ -0,0 +1,156 @@
+//! Errors answered by the servers.
+//!
+//! Servers answer errors with a JSON problem document carrying a stable code, which is parsed
+//! into a `ServerError` instead of matching the error messages.
+use std::fmt;
+use std::time::Duration;
+
+use serde::Deserialize;
+use strum_macros::EnumString;
+
+/// Kind of error answered by a server, from the code of its problem document.
+#[derive(Debug, Clone, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum ErrorKind {
+    NotFound,
+    /// The shares are being refreshed, the request can be retried after `retry_after`.
+    RefreshInProgress,
+    /// Too many requests were sent, the request can be retried after `retry_after`.
+    RateLimited,
+    Unauthorized,
+    Forbidden,
+    InvalidRequest,
+    InvalidPublicKey,
+    /// Any other code, kept as is.
+    #[strum(default)]
+    Other(String),
+}
+
+/// Problem document answered by a server.
+#[derive(Debug, Deserialize)]
+struct Problem {
+    code: String,
+    status: u16,
+    detail: String,
+    retry_after_secs: Option<u64>,
+    request_id: Option<String>,
+}
+
+/// Error answered by a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerError {
+    pub kind: ErrorKind,
+    pub status: u16,
+    pub detail: String,
+    /// Time to wait before retrying the request, if it can be retried as is.
+    pub retry_after: Option<Duration>,
+    /// ID of the request, to look it up in the logs of the server.
+    pub request_id: Option<String>,
+}
+
+impl ServerError {
+    /// Parses the error answered in `response`.
+    ///
+    /// Responses without problem document, such as the ones of a proxy, are told apart by their
+    /// status only.
+    pub async fn from_response(response: reqwest::Response) -> Self {
+        let status = response.status();
+        let request_id = response
+            .headers()
+            .get("x-request-id")
+            .and_then(|id| id.to_str().ok())
+            .map(str::to_string);
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|secs| secs.to_str().ok()?.parse().ok())
+            .map(Duration::from_secs);
+        let body = response.bytes().await.unwrap_or_default();
+        match serde_json::from_slice::<Problem>(&body) {
+            Ok(problem) => {
+                let mut error = Self::from_problem(problem);
+                error.request_id = error.request_id.or(request_id);
+                error
+            }
+            Err(_) => Self {
+                kind: match status {
+                    reqwest::StatusCode::NOT_FOUND => ErrorKind::NotFound,
+                    reqwest::StatusCode::UNAUTHORIZED => ErrorKind::Unauthorized,
+                    reqwest::StatusCode::FORBIDDEN => ErrorKind::Forbidden,
+                    reqwest::StatusCode::TOO_MANY_REQUESTS => ErrorKind::RateLimited,
+                    _ => ErrorKind::Other(status.as_str().to_string()),
+                },
+                status: status.as_u16(),
+                detail: String::from_utf8_lossy(&body).into_owned(),
+                retry_after,
+                request_id,
+            },
+        }
+    }
+
+    /// Returns `true` if the request can be retried as is once `retry_after` has elapsed.
+    pub fn is_retryable(&self) -> bool {
+        self.retry_after.is_some()
+    }
+
+    fn from_problem(problem: Problem) -> Self {
+        Self {
+            kind: problem
+                .code
+                .parse()
+                .unwrap_or(ErrorKind::Other(problem.code)),
+            status: problem.status,
+            detail: problem.detail,
+            retry_after: problem.retry_after_secs.map(Duration::from_secs),
+            request_id: problem.request_id,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.detail, self.status)?;
+        if let Some(request_id) = &self.request_id {
+            write!(f, " [request {}]", request_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_problem() {
+        let problem: Problem = serde_json::from_str(
+            r#"{
+                "code": "refresh_in_progress",
+                "title": "Conflict",
+                "status": 409,
+                "detail": "Refresh in progress",
+                "retry_after_secs": 5,
+                "request_id": "req-1"
+            }"#,
+        )
+        .unwrap();
+        let error = ServerError::from_problem(problem);
+        assert_eq!(error.kind, ErrorKind::RefreshInProgress);
+        assert_eq!(error.retry_after, Some(Duration::from_secs(5)));
+        assert!(error.is_retryable());
+        assert_eq!(
+            error.to_string(),
+            "Refresh in progress (409) [request req-1]"
+        );
+
+        let problem: Problem = serde_json::from_str(
+            r#"{"code": "backend_error", "title": "", "status": 500, "detail": "down"}"#,
+        )
+        .unwrap();
+        let error = ServerError::from_problem(problem);
+        assert_eq!(error.kind, ErrorKind::Other("backend_error".to_string()));
+        assert!(!error.is_retryable());
+    }
+}
@@ -1 +1,2 @@
 pub mod conf;
+pub mod error;
@@ -1,7 +1,9 @@
 use std::collections::HashMap;
 
 use shared_secret_client::conf::settings::Settings;
-use sss_wrap::secret::secret::{Metadata, Share, ShareMeta};
+use shared_secret_client::error::ServerError;
+use sss_wrap::sealed::share::{RecipientKey, SealedShare};
+use sss_wrap::secret::secret::{Metadata, ShareMeta};
 use sss_wrap::wrapped_sharing::reconstruct;
 use sss_wrap::*;
 use structopt::StructOpt;
@@ -14,6 +16,10 @@ enum Command {
     Create,
     #[strum(serialize = "get")]
     Get,
+    #[strum(serialize = "audit")]
+    Audit,
+    #[strum(serialize = "verify-audit")]
+    VerifyAudit,
 }
 
 #[derive(StructOpt)]
@@ -22,6 +28,15 @@ struct Options {
     secret: Option<String>,
     #[structopt(short, long)]
     command: Command,
+    /// Client whose audit entries are listed, every client if not set
+    #[structopt(long)]
+    audit_client_id: Option<u64>,
+    /// Earliest time of the audit entries listed, in RFC 3339
+    #[structopt(long)]
+    from: Option<String>,
+    /// Latest time (exclusive) of the audit entries listed, in RFC 3339
+    #[structopt(long)]
+    to: Option<String>,
 }
 
 async fn send_secret(
@@ -37,11 +52,14 @@ async fn send_secret(
     )
     .unwrap();
 
-    let meta = &Metadata::new(
+    let mut meta = Metadata::new(
         settings.shares_required,
         settings.shares_to_create,
         secret.len(),
     );
+    if let Some(refresh_interval_secs) = settings.refresh_interval_secs {
+        meta = meta.with_refresh_interval_secs(refresh_interval_secs);
+    }
 
     let shares_vec: Vec<ShareMeta> = shares
         .into_iter()
@@ -54,7 +72,7 @@ async fn send_secret(
         .map(|x| (x.id, x.addr.clone()))
         .collect();
 
-    let mut tasks = JoinSet::new();
+    let mut tasks: JoinSet<Result<(), Box<dyn std::error::Error + Send + Sync>>> = JoinSet::new();
     for s in shares_vec {
         let client_id = settings.client_id.clone();
         let api_key = settings.api_key.clone();
@@ -78,8 +96,9 @@ async fn send_secret(
                     Ok(())
                 }
                 _ => {
-                    eprintln!("Error sending share to server {:?}", url);
-                    return Err(Box::new(result.error_for_status().unwrap_err()));
+                    let error = ServerError::from_response(result).await;
+                    eprintln!("Error sending share to server {:?}: {}", url, error);
+                    Err(error.into())
                 }
             }
         });
@@ -105,6 +124,9 @@ async fn get_secret(settings: &Settings) -> Result<(), Box<dyn std::error::Error
 
     let client = reqwest::Client::new();
 
+    // Shares are sealed by the nodes to this key and only opened locally
+    let recipient_key = RecipientKey::generate();
+
     let mut shares = Vec::new();
 
     let mut shares_count = 0;
@@ -112,6 +134,7 @@ async fn get_secret(settings: &Settings) -> Result<(), Box<dyn std::error::Error
         for (_, v) in &map {
             let share = client
                 .get(format!("{}/api/{}/share", v, settings.client_id,))
+                .query(&[("public_key", recipient_key.public_key_hex())])
                 .header("Content-Type", "application/json")
                 .header("Authorization", format!("Bearer {}", settings.api_key))
                 .send()
@@ -123,19 +146,36 @@ async fn get_secret(settings: &Settings) -> Result<(), Box<dyn std::error::Error
                 }
                 Ok(share) => {
                     if share.status() == reqwest::StatusCode::OK {
-                        let share = share.json::<Share>().await;
-                        let share = match share {
+                        let share = share.json::<SealedShare>().await;
+                        let share = match share.map(|sealed| recipient_key.open(&sealed)) {
+                            Ok(Ok(share)) => share,
+                            Ok(Err(_)) => {
+                                eprintln!("Error opening sealed share from server");
+                                break 'outer;
+                            }
                             Err(_) => {
                                 eprintln!("Error getting share from server");
                                 break 'outer;
                             }
-                            Ok(share) => share,
                         };
                         shares.push(share);
                         shares_count += 1;
                         if shares_count == settings.shares_required {
                             break 'outer;
                         }
+                    } else {
+                        let error = ServerError::from_response(share).await;
+                        match error.retry_after {
+                            // The shares are being refreshed, they are retrieved once done
+                            Some(retry_after) => {
+                                eprintln!("Retrying in {:?}: {}", retry_after, error);
+                                tokio::time::sleep(retry_after).await;
+                            }
+                            None => {
+                                eprintln!("Error getting share from server: {}", error);
+                                break 'outer;
+                            }
+                        }
                     }
                 }
             }
@@ -163,6 +203,69 @@ async fn get_secret(settings: &Settings) -> Result<(), Box<dyn std::error::Error
     Ok(())
 }
 
+/// Sends the admin request `path` with `query` to every server and prints their JSON answers.
+async fn query_servers(
+    settings: &Settings,
+    path: &str,
+    query: &[(&str, String)],
+) -> Result<Vec<serde_json::Value>, Box<dyn std::error::Error>> {
+    let client = reqwest::Client::new();
+    let api_key = settings.admin_api_key.as_ref().unwrap_or(&settings.api_key);
+    let mut answers = Vec::new();
+    for server in &settings.servers {
+        let url = format!("{}/admin/{}", server.addr, path);
+        let response = client
+            .get(&url)
+            .query(query)
+            .header("Authorization", format!("Bearer {}", api_key))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let error = ServerError::from_response(response).await;
+            eprintln!("Error querying server {:?}: {}", url, error);
+            continue;
+        }
+        let answer = response.json::<serde_json::Value>().await?;
+        println!(
+            "Server {} ===> {}",
+            server.id,
+            serde_json::to_string_pretty(&answer)?
+        );
+        answers.push(answer);
+    }
+    Ok(answers)
+}
+
+async fn audit(
+    settings: &Settings,
+    client_id: Option<u64>,
+    from: Option<String>,
+    to: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let query = [
+        ("client_id", client_id.map(|id| id.to_string())),
+        ("from", from),
+        ("to", to),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| value.map(|value| (name, value)))
+    .collect::<Vec<_>>();
+    query_servers(settings, "audit", &query).await?;
+    Ok(())
+}
+
+async fn verify_audit(settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    let answers = query_servers(settings, "audit/verify", &[]).await?;
+    let valid = answers.len() == settings.servers.len()
+        && answers.iter().all(|answer| answer["valid"] == true);
+    if valid {
+        println!("Audit logs of every server are intact");
+        Ok(())
+    } else {
+        Err("Audit log of some server is broken or could not be verified".into())
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let options = Options::from_args();
@@ -173,5 +276,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             send_secret(&settings, secret).await
         }
         Command::Get => get_secret(&settings).await,
+        Command::Audit => audit(&settings, options.audit_client_id, options.from, options.to).await,
+        Command::VerifyAudit => verify_audit(&settings).await,
     }
 }
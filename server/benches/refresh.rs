@@ -0,0 +1,91 @@
+//! Refresh latency of a single node cluster against the number of stored secrets.
+//!
+//! Every refresh round is measured with one proposal per client (`batch_size` 1) and with the
+//! default batching, so the cost of the consensus round-trips can be compared.
+
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use shared_secret_server::consensus::backend::riteraft::RiteraftBackend;
+use shared_secret_server::consensus::handler::{ConsensusHandler, DEFAULT_REFRESH_BATCH_SIZE};
+use shared_secret_server::consensus::raft::HashStore;
+use shared_secret_server::consensus::signing::NodeKey;
+use shared_secret_server::domain::model::{ClientId, NodeId};
+use sss_wrap::from_secrets;
+use sss_wrap::secret::secret::{Metadata, ShareMeta};
+use tokio::runtime::Runtime;
+
+const SECRET_LEN: usize = 32;
+
+fn raft_addr() -> String {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    format!("127.0.0.1:{}", port)
+}
+
+/// Starts a single node cluster storing the share of node 1 of `secrets` clients.
+async fn start_node(secrets: u64) -> ConsensusHandler {
+    let raft_addr = raft_addr();
+    let store = HashStore::new(NodeId(1));
+    let (_, backend) = RiteraftBackend::start(
+        &raft_addr,
+        &[],
+        store.clone(),
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .await
+    .unwrap();
+    let mut handler =
+        ConsensusHandler::new(store, Arc::new(backend), Arc::new(NodeKey::generate()));
+    // Wait for the node to win the election
+    while handler
+        .admit(NodeId(1), &raft_addr, handler.public_key())
+        .await
+        .is_err()
+    {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    for id in 0..secrets {
+        let shares = from_secrets(vec![id as u8; SECRET_LEN], 3, 5, None).unwrap();
+        handler
+            .insert(
+                ClientId(id),
+                ShareMeta::new(shares[0].clone().into(), Metadata::new(3, 5, SECRET_LEN)),
+            )
+            .unwrap();
+    }
+    handler
+}
+
+fn refresh(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("refresh_secrets");
+    group.sample_size(10);
+    for secrets in [100, 1_000, 10_000] {
+        let handler = runtime.block_on(start_node(secrets));
+        for batch_size in [1, DEFAULT_REFRESH_BATCH_SIZE] {
+            // Unbatched rounds of 10k secrets take minutes
+            if batch_size == 1 && secrets > 1_000 {
+                continue;
+            }
+            let handler = handler.clone().with_refresh_batch_size(batch_size);
+            group.bench_with_input(
+                BenchmarkId::new(format!("batch_size_{}", batch_size), secrets),
+                &handler,
+                |b, handler| {
+                    b.to_async(&runtime)
+                        .iter(|| async { handler.refresh_secrets().await.unwrap() })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, refresh);
+criterion_main!(benches);
@@ -0,0 +1,606 @@
+//! Tamper-evident audit log of the accesses to the shares of a node.
+//!
+//! Every entry records who acted on which client share, when and with which outcome, and carries
+//! the hash of the previous entry. Hashes are HMAC-SHA256 keyed with a key derived from the node
+//! key, so that entries cannot be rewritten with matching hashes without it. Entries are appended
+//! as JSON lines to the configured file, so changing, removing or reordering an entry breaks the
+//! chain from that entry on.
+//!
+//! The last entry is anchored in a head file next to the log, `<path>.head`, authenticated with
+//! the same key, so that removing the last entries is detected as well. The chain is verified
+//! against its head when the log is opened. Removing both the whole log and its head, or
+//! restoring an older copy of both, cannot be detected by the node itself.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::consensus::signing::NodeKey;
+use crate::domain::error::SecretServerError;
+use crate::domain::model::{ClientId, NodeId};
+use crate::domain::redacted::Redacted;
+use crate::logging;
+
+/// Hash the first entry of the chain links to.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Context the key of the chain is derived from the node key with.
+const AUDIT_KEY_CONTEXT: &[u8] = b"shared-secrets audit log v1";
+
+/// Key of the HMAC-SHA256 hashes of the chain.
+type AuditKey = Redacted<[u8; 32]>;
+
+/// Returns the hex encoded HMAC-SHA256 of `parts` under `key`.
+fn mac(key: &AuditKey, parts: &[&[u8]]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.expose()).expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Action recorded in the audit log.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Create,
+    Read,
+    Delete,
+    Refresh,
+    /// Credentials were missing, unknown or not allowed to perform the request.
+    AuthFailure,
+}
+
+/// Outcome of an audited action.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    /// The caller was not allowed to perform the action.
+    Denied,
+    Failed,
+}
+
+/// Entry of the audit log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// Position of the entry in the log, starting at 1.
+    pub seq: u64,
+    pub at: DateTime<Utc>,
+    pub node_id: NodeId,
+    pub action: AuditAction,
+    /// Client whose share was acted on, `None` for the refresh of every share.
+    pub client_id: Option<ClientId>,
+    /// Fingerprint of the credentials of the caller, `None` for the actions of the node itself.
+    pub caller: Option<String>,
+    pub outcome: AuditOutcome,
+    /// HTTP status of the response to a share request or rejected credentials.
+    pub status: Option<u16>,
+    pub request_id: Option<String>,
+    /// Hash of the previous entry.
+    pub prev_hash: String,
+    /// Keyed hash of this entry without this field, chained to `prev_hash`.
+    pub hash: String,
+}
+
+impl AuditEntry {
+    /// Returns the keyed hash of the entry, computed over every field but `hash`.
+    fn compute_hash(&self, key: &AuditKey) -> String {
+        let unhashed = AuditEntry {
+            hash: String::new(),
+            ..self.clone()
+        };
+        let bytes = serde_json::to_vec(&unhashed).expect("audit entries serialize to JSON");
+        mac(key, &[&bytes])
+    }
+}
+
+/// Last entry of the chain, stored in the head file of the log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct ChainHead {
+    seq: u64,
+    hash: String,
+    /// Keyed hash of `seq` and `hash`, so that the head cannot be rewritten to an earlier entry
+    /// to hide the removal of the last ones.
+    mac: String,
+}
+
+impl ChainHead {
+    fn new(key: &AuditKey, seq: u64, hash: &str) -> Self {
+        Self {
+            seq,
+            hash: hash.to_string(),
+            mac: Self::compute_mac(key, seq, hash),
+        }
+    }
+
+    fn compute_mac(key: &AuditKey, seq: u64, hash: &str) -> String {
+        mac(key, &[b"head", &seq.to_be_bytes(), hash.as_bytes()])
+    }
+
+    fn is_authentic(&self, key: &AuditKey) -> bool {
+        self.mac == Self::compute_mac(key, self.seq, &self.hash)
+    }
+}
+
+/// Identity of an authenticated caller, kept in the extensions of its requests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caller(pub String);
+
+impl Caller {
+    /// Returns the caller presenting `token`, identified by the first 16 hex characters of the
+    /// SHA-256 hash of the token, like the hashes of the configured API keys.
+    pub fn from_token(token: &str) -> Self {
+        let hash = hex::encode(Sha256::digest(token.as_bytes()));
+        Self(format!("key:{}", &hash[..16]))
+    }
+}
+
+/// Action to record, completed with its position in the chain by `AuditLog::record`.
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    pub action: AuditAction,
+    pub client_id: Option<ClientId>,
+    pub caller: Option<String>,
+    pub outcome: AuditOutcome,
+    pub status: Option<u16>,
+}
+
+/// Filter of the entries returned by `AuditLog::query`.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AuditQuery {
+    pub client_id: Option<ClientId>,
+    /// Earliest time of the entries, inclusive.
+    pub from: Option<DateTime<Utc>>,
+    /// Latest time of the entries, exclusive.
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.client_id.is_none_or(|id| entry.client_id == Some(id))
+            && self.from.is_none_or(|from| entry.at >= from)
+            && self.to.is_none_or(|to| entry.at < to)
+    }
+}
+
+/// Result of the verification of the chain.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChainVerification {
+    /// Number of entries verified.
+    pub entries: u64,
+    pub valid: bool,
+    /// Sequence number of the first entry breaking the chain, if any.
+    pub broken_at: Option<u64>,
+    pub reason: Option<String>,
+}
+
+/// Verifies that `entries` form an unbroken chain hashed with `key` from the genesis hash, up to
+/// the entry `head_seq` with the hash `head_hash` at least.
+fn verify_chain(
+    key: &AuditKey,
+    entries: &[AuditEntry],
+    head_seq: u64,
+    head_hash: &str,
+) -> ChainVerification {
+    let broken = |seq: u64, reason: String| ChainVerification {
+        entries: entries.len() as u64,
+        valid: false,
+        broken_at: Some(seq),
+        reason: Some(reason),
+    };
+    let mut prev_hash = GENESIS_HASH;
+    for (index, entry) in entries.iter().enumerate() {
+        let expected_seq = index as u64 + 1;
+        let reason = if entry.seq != expected_seq {
+            Some(format!(
+                "expected entry {} but found {}",
+                expected_seq, entry.seq
+            ))
+        } else if entry.prev_hash != prev_hash {
+            Some("previous hash does not match the previous entry".to_string())
+        } else if entry.hash != entry.compute_hash(key) {
+            Some("hash does not match the content of the entry".to_string())
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return broken(expected_seq, reason);
+        }
+        prev_hash = &entry.hash;
+    }
+    let last_seq = entries.len() as u64;
+    if head_seq > last_seq {
+        return broken(
+            last_seq + 1,
+            format!("entries {} to {} were removed", last_seq + 1, head_seq),
+        );
+    }
+    let anchored = match head_seq {
+        0 => head_hash == GENESIS_HASH,
+        seq => entries[seq as usize - 1].hash == head_hash,
+    };
+    if !anchored {
+        return broken(
+            head_seq,
+            "hash does not match the head of the log".to_string(),
+        );
+    }
+    ChainVerification {
+        entries: entries.len() as u64,
+        valid: true,
+        broken_at: None,
+        reason: None,
+    }
+}
+
+/// Returns the path of the head file of the log at `path`.
+fn head_path(path: &Path) -> PathBuf {
+    let mut head = OsString::from(path.as_os_str());
+    head.push(".head");
+    PathBuf::from(head)
+}
+
+/// Reads the head of the log at `path`, `None` if it does not exist.
+fn read_head(path: &Path, key: &AuditKey) -> Result<Option<ChainHead>, SecretServerError> {
+    let head_path = head_path(path);
+    let content = match fs::read_to_string(&head_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(audit_error(&head_path, e)),
+    };
+    let head: ChainHead = serde_json::from_str(&content).map_err(|e| audit_error(&head_path, e))?;
+    if !head.is_authentic(key) {
+        return Err(audit_error(
+            &head_path,
+            "the head was not written with the key of this node",
+        ));
+    }
+    Ok(Some(head))
+}
+
+/// Replaces the head of the log at `path`, through a temporary file so that it is never left
+/// half written.
+fn write_head(path: &Path, head: &ChainHead) -> Result<(), SecretServerError> {
+    let head_path = head_path(path);
+    let mut tmp_path = OsString::from(head_path.as_os_str());
+    tmp_path.push(".tmp");
+    let content = serde_json::to_vec(head)
+        .map_err(|e| SecretServerError::InvalidStateError(e.to_string()))?;
+    fs::write(&tmp_path, content)
+        .and_then(|_| fs::rename(&tmp_path, &head_path))
+        .map_err(|e| audit_error(&head_path, e))
+}
+
+/// Tail of the chain and where new entries are written.
+///
+/// `last_seq` and `last_hash` anchor the chain: the entries read back from the file must reach
+/// them.
+#[derive(Debug)]
+struct AuditState {
+    last_seq: u64,
+    last_hash: String,
+    file: Option<File>,
+    /// Entries of a log without file.
+    entries: Vec<AuditEntry>,
+}
+
+/// Append-only, hash-chained audit log of a node.
+///
+/// Without a path the entries are only kept in memory.
+#[derive(Debug)]
+pub struct AuditLog {
+    node_id: NodeId,
+    path: Option<PathBuf>,
+    key: AuditKey,
+    state: Mutex<AuditState>,
+}
+
+impl AuditLog {
+    /// Opens the audit log of the node `node_id` at `path`, appending to the entries it holds.
+    /// The chain is keyed with a key derived from `node_key`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidStateError` if the file cannot be opened, holds malformed entries or
+    /// its chain is broken: an entry was changed, removed or written with another key, or the
+    /// log has entries but no head.
+    pub fn open(
+        node_id: NodeId,
+        path: Option<&str>,
+        node_key: &NodeKey,
+    ) -> Result<Self, SecretServerError> {
+        let path = path.map(PathBuf::from);
+        let key = Redacted::new(node_key.derive_key(AUDIT_KEY_CONTEXT));
+        let mut state = AuditState {
+            last_seq: 0,
+            last_hash: GENESIS_HASH.to_string(),
+            file: None,
+            entries: vec![],
+        };
+        if let Some(path) = &path {
+            let entries = read_entries(path)?;
+            let head = match read_head(path, &key)? {
+                Some(head) => head,
+                None if entries.is_empty() => ChainHead::new(&key, 0, GENESIS_HASH),
+                None => {
+                    return Err(audit_error(
+                        path,
+                        "the log has entries but its head file is missing",
+                    ))
+                }
+            };
+            let verification = verify_chain(&key, &entries, head.seq, &head.hash);
+            if !verification.valid {
+                return Err(audit_error(
+                    path,
+                    format!(
+                        "the chain is broken at entry {}: {}",
+                        verification.broken_at.unwrap_or_default(),
+                        verification.reason.unwrap_or_default()
+                    ),
+                ));
+            }
+            if let Some(last) = entries.last() {
+                state.last_seq = last.seq;
+                state.last_hash = last.hash.clone();
+            }
+            // The head may lag behind the log if the node stopped between writing both
+            write_head(
+                path,
+                &ChainHead::new(&key, state.last_seq, &state.last_hash),
+            )?;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| audit_error(path, e))?;
+            state.file = Some(file);
+        }
+        Ok(Self {
+            node_id,
+            path,
+            key,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Appends `record` to the chain, with the ID of the current request.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidStateError` if the entry cannot be written.
+    pub fn record(&self, record: AuditRecord) -> Result<AuditEntry, SecretServerError> {
+        let mut state = self.state.lock()?;
+        let mut entry = AuditEntry {
+            seq: state.last_seq + 1,
+            at: Utc::now(),
+            node_id: self.node_id,
+            action: record.action,
+            client_id: record.client_id,
+            caller: record.caller,
+            outcome: record.outcome,
+            status: record.status,
+            request_id: logging::request_id(),
+            prev_hash: state.last_hash.clone(),
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash(&self.key);
+        match (&mut state.file, &self.path) {
+            (Some(file), Some(path)) => {
+                let mut line = serde_json::to_vec(&entry)
+                    .map_err(|e| SecretServerError::InvalidStateError(e.to_string()))?;
+                line.push(b'\n');
+                file.write_all(&line)
+                    .and_then(|_| file.flush())
+                    .map_err(|e| audit_error(path, e))?;
+                write_head(path, &ChainHead::new(&self.key, entry.seq, &entry.hash))?;
+            }
+            _ => state.entries.push(entry.clone()),
+        }
+        state.last_seq = entry.seq;
+        state.last_hash = entry.hash.clone();
+        Ok(entry)
+    }
+
+    /// Records `record`, logging the failure instead of failing the audited action.
+    pub fn try_record(&self, record: AuditRecord) {
+        if let Err(e) = self.record(record) {
+            warn!("Cannot write audit entry: {}", e);
+        }
+    }
+
+    /// Returns every entry of the log, in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidStateError` if the file cannot be read or holds malformed entries.
+    pub fn entries(&self) -> Result<Vec<AuditEntry>, SecretServerError> {
+        // Holding the state keeps the entry being written out of the file read
+        let state = self.state.lock()?;
+        match &self.path {
+            Some(path) => read_entries(path),
+            None => Ok(state.entries.clone()),
+        }
+    }
+
+    /// Returns the entries matching `query`, in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidStateError` if the entries cannot be read.
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, SecretServerError> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .filter(|entry| query.matches(entry))
+            .collect())
+    }
+
+    /// Verifies the chain of every entry of the log, up to the last entry written.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidStateError` if the entries cannot be read.
+    pub fn verify(&self) -> Result<ChainVerification, SecretServerError> {
+        let entries = self.entries()?;
+        let state = self.state.lock()?;
+        Ok(verify_chain(
+            &self.key,
+            &entries,
+            state.last_seq,
+            &state.last_hash,
+        ))
+    }
+}
+
+fn audit_error(path: &Path, e: impl ToString) -> SecretServerError {
+    SecretServerError::InvalidStateError(format!("audit log {}: {}", path.display(), e.to_string()))
+}
+
+/// Reads the entries of the audit log at `path`, none if it does not exist yet.
+fn read_entries(path: &Path) -> Result<Vec<AuditEntry>, SecretServerError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(audit_error(path, e)),
+    };
+    BufReader::new(file)
+        .lines()
+        .filter(|line| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(|line| {
+            let line = line.map_err(|e| audit_error(path, e))?;
+            serde_json::from_str(&line).map_err(|e| audit_error(path, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(client_id: u64) -> AuditRecord {
+        AuditRecord {
+            action: AuditAction::Read,
+            client_id: Some(ClientId(client_id)),
+            caller: Some("key:0123456789abcdef".to_string()),
+            outcome: AuditOutcome::Success,
+            status: Some(200),
+        }
+    }
+
+    fn log_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(head_path(&path));
+        path
+    }
+
+    #[test]
+    fn test_chain_survives_reopening_and_detects_tampering() {
+        let path = log_path("audit.log");
+        let path_str = path.to_str().unwrap();
+        let node_key = NodeKey::generate();
+
+        let audit = AuditLog::open(NodeId(1), Some(path_str), &node_key).unwrap();
+        audit.record(read(1)).unwrap();
+        audit.record(read(2)).unwrap();
+        drop(audit);
+        let audit = AuditLog::open(NodeId(1), Some(path_str), &node_key).unwrap();
+        let third = audit.record(read(1)).unwrap();
+        assert_eq!(third.seq, 3);
+        assert!(audit.verify().unwrap().valid);
+
+        let query = AuditQuery {
+            client_id: Some(ClientId(1)),
+            ..AuditQuery::default()
+        };
+        assert_eq!(audit.query(&query).unwrap().len(), 2);
+        let query = AuditQuery {
+            from: Some(third.at),
+            ..AuditQuery::default()
+        };
+        assert_eq!(audit.query(&query).unwrap(), vec![third]);
+
+        // Changing the client of the second entry breaks the chain there
+        let tampered = std::fs::read_to_string(&path).unwrap().replacen(
+            r#""client_id":2"#,
+            r#""client_id":1"#,
+            1,
+        );
+        std::fs::write(&path, tampered).unwrap();
+        let verification = audit.verify().unwrap();
+        assert!(!verification.valid);
+        assert_eq!(verification.broken_at, Some(2));
+        drop(audit);
+        assert!(AuditLog::open(NodeId(1), Some(path_str), &node_key).is_err());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(head_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn test_reject_truncated_log_and_other_key() {
+        let path = log_path("audit-truncated.log");
+        let path_str = path.to_str().unwrap();
+        let node_key = NodeKey::generate();
+        let audit = AuditLog::open(NodeId(1), Some(path_str), &node_key).unwrap();
+        for client_id in 1..=3 {
+            audit.record(read(client_id)).unwrap();
+        }
+
+        // The hashes of another key do not match
+        assert!(AuditLog::open(NodeId(1), Some(path_str), &NodeKey::generate()).is_err());
+
+        // Removing the last entry is detected by the running log and on opening
+        let content = std::fs::read_to_string(&path).unwrap();
+        let truncated = content.lines().take(2).collect::<Vec<_>>().join("\n");
+        std::fs::write(&path, truncated).unwrap();
+        let verification = audit.verify().unwrap();
+        assert_eq!(verification.broken_at, Some(3));
+        assert_eq!(
+            verification.reason.as_deref(),
+            Some("entries 3 to 3 were removed")
+        );
+        drop(audit);
+        assert!(AuditLog::open(NodeId(1), Some(path_str), &node_key).is_err());
+
+        // As is removing the head to hide it
+        std::fs::remove_file(head_path(&path)).unwrap();
+        assert!(AuditLog::open(NodeId(1), Some(path_str), &node_key).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_caller_is_a_prefix_of_the_key_hash() {
+        // sha256("api-key-test")
+        let caller = Caller::from_token("api-key-test");
+        assert_eq!(caller.0, "key:47cd528f4164b8ea");
+    }
+
+    #[test]
+    fn test_removed_entries_break_the_chain() {
+        let audit = AuditLog::open(NodeId(1), None, &NodeKey::generate()).unwrap();
+        for client_id in 1..=3 {
+            audit.record(read(client_id)).unwrap();
+        }
+        let mut entries = audit.entries().unwrap();
+        let head = entries.last().unwrap().clone();
+        assert!(verify_chain(&audit.key, &entries, head.seq, &head.hash).valid);
+        entries.remove(1);
+        assert_eq!(
+            verify_chain(&audit.key, &entries, head.seq, &head.hash).broken_at,
+            Some(2)
+        );
+    }
+}
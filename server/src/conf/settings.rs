@@ -1,21 +1,516 @@
 //! Settings based on [`config-rs`] crate which follows 12-factor configuration model.
 //! Configuration file by default is under `config` folder.
 //!
-use config::{Config, ConfigError, File};
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::NaiveTime;
+use config::{Config, ConfigError, File, FileFormat};
+use jsonwebtoken::Algorithm;
 use serde::{Deserialize, Serialize};
 
+use crate::domain::access::{Binding, Namespace, Scope};
+use crate::domain::model::ClientId;
+use crate::domain::redacted::Redacted;
+
+/// Number of clients refreshed in a single consensus entry unless configured otherwise.
+pub const DEFAULT_REFRESH_BATCH_SIZE: usize = 500;
+
+/// API key entry of the key registry.
+///
+/// Only the hex encoded SHA-256 hash of the key is kept in the configuration. Every key is
+/// bound either to a single `client_id` or to a `namespace` of clients.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ApiKeySettings {
+    hash: String,
+    client_id: Option<u64>,
+    namespace: Option<Namespace>,
+    scopes: Vec<Scope>,
+}
+
+impl ApiKeySettings {
+    /// Returns the hex encoded SHA-256 hash of the key.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    /// Returns the clients the key is bound to.
+    pub fn binding(&self) -> Binding {
+        match (self.client_id, self.namespace) {
+            (Some(client_id), _) => Binding::Client(ClientId(client_id)),
+            (None, Some(namespace)) => Binding::Namespace(namespace),
+            (None, None) => unreachable!("api key binding is checked when settings are loaded"),
+        }
+    }
+
+    /// Returns the scopes granted to the key.
+    pub fn scopes(&self) -> &[Scope] {
+        &self.scopes
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.client_id.is_some() == self.namespace.is_some() {
+            return Err(ConfigError::Message(format!(
+                "api key {} must be bound to exactly one of client_id or namespace",
+                self.hash
+            )));
+        }
+        match hex::decode(&self.hash) {
+            Ok(hash) if hash.len() == 32 => Ok(()),
+            _ => Err(ConfigError::Message(format!(
+                "api key hash {} is not a hex encoded SHA-256 digest",
+                self.hash
+            ))),
+        }
+    }
+}
+
+/// Settings for validating JWT bearer tokens.
+///
+/// Tokens are verified with the keys of a local JWKS file, which is reloaded whenever it
+/// changes on disk, and must carry the configured `audience` and `issuer`. They must be signed
+/// with the `alg` of their key, or with `algorithm` for keys without one.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct JwtSettings {
+    jwks_path: String,
+    audience: String,
+    issuer: String,
+    #[serde(default = "JwtSettings::default_client_claim")]
+    client_claim: String,
+    algorithm: Option<Algorithm>,
+    #[serde(default)]
+    allow_admin_scope: bool,
+}
+
+impl JwtSettings {
+    fn default_client_claim() -> String {
+        "client_id".to_string()
+    }
+
+    /// Returns the path of the JWKS file.
+    pub fn jwks_path(&self) -> &str {
+        &self.jwks_path
+    }
+
+    /// Returns the expected `aud` claim.
+    pub fn audience(&self) -> &str {
+        &self.audience
+    }
+
+    /// Returns the expected `iss` claim.
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// Returns the name of the claim holding the client ID the token gives access to.
+    pub fn client_claim(&self) -> &str {
+        &self.client_claim
+    }
+
+    /// Returns the algorithm of the JWKS keys without `alg`, if any.
+    pub fn algorithm(&self) -> Option<Algorithm> {
+        self.algorithm
+    }
+
+    /// Returns `true` if tokens can be granted the `admin` scope.
+    pub fn allow_admin_scope(&self) -> bool {
+        self.allow_admin_scope
+    }
+}
+
+/// Settings for serving the HTTP API over TLS.
+///
+/// When `client_ca_path` is set, client certificates signed by that CA are verified (mTLS) and
+/// a node only releases shares to clients whose certificate common name matches the requested
+/// client ID. With `require_client_cert` clients without certificate are rejected in the
+/// handshake, otherwise they are authenticated by their bearer token alone.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TlsSettings {
+    cert_path: String,
+    key_path: String,
+    client_ca_path: Option<String>,
+    #[serde(default)]
+    require_client_cert: bool,
+}
+
+impl TlsSettings {
+    /// Returns the path of the PEM encoded server certificate chain.
+    pub fn cert_path(&self) -> &str {
+        &self.cert_path
+    }
+
+    /// Returns the path of the PEM encoded server private key.
+    pub fn key_path(&self) -> &str {
+        &self.key_path
+    }
+
+    /// Returns the path of the PEM encoded CA used to verify client certificates, if any.
+    pub fn client_ca_path(&self) -> Option<&str> {
+        self.client_ca_path.as_deref()
+    }
+
+    /// Returns `true` if clients must present a certificate.
+    pub fn require_client_cert(&self) -> bool {
+        self.require_client_cert
+    }
+}
+
+/// Consensus backend the node replicates its state with.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsensusBackendKind {
+    #[default]
+    Riteraft,
+    /// Only available when built with the `openraft` feature.
+    #[cfg(feature = "openraft")]
+    Openraft,
+    /// Only available when built with the `bft` feature.
+    #[cfg(feature = "bft")]
+    Bft,
+}
+
+/// Replica of the BFT consensus group.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BftReplicaSettings {
+    node_id: u8,
+    raft_addr: String,
+    public_key: String,
+}
+
+impl BftReplicaSettings {
+    /// Returns the node ID of the replica.
+    pub fn node_id(&self) -> u8 {
+        self.node_id
+    }
+
+    /// Returns the address the replica receives protocol messages on.
+    pub fn raft_addr(&self) -> &str {
+        &self.raft_addr
+    }
+
+    /// Returns the Ed25519 public key of the replica.
+    pub fn public_key(&self) -> Vec<u8> {
+        hex::decode(&self.public_key).expect("public keys are checked when settings are loaded")
+    }
+}
+
+/// Settings of the BFT consensus backend.
+///
+/// Every node lists the same replicas, including itself, with the public key of its node key.
+/// `3f + 1` replicas tolerate `f` malicious ones.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BftSettings {
+    replicas: Vec<BftReplicaSettings>,
+    #[serde(default = "BftSettings::default_view_change_timeout_ms")]
+    view_change_timeout_ms: u64,
+}
+
+impl BftSettings {
+    fn default_view_change_timeout_ms() -> u64 {
+        2000
+    }
+
+    /// Returns the replicas of the consensus group.
+    pub fn replicas(&self) -> &[BftReplicaSettings] {
+        &self.replicas
+    }
+
+    /// Returns the time a request can stay pending before the primary is replaced.
+    pub fn view_change_timeout(&self) -> Duration {
+        Duration::from_millis(self.view_change_timeout_ms)
+    }
+
+    #[cfg(feature = "bft")]
+    fn validate(&self, node_id: u8) -> Result<(), ConfigError> {
+        if self.replicas.len() < 4 {
+            return Err(ConfigError::Message(
+                "the bft backend needs at least 4 replicas".to_string(),
+            ));
+        }
+        let mut node_ids = self.replicas.iter().map(|r| r.node_id).collect::<Vec<_>>();
+        node_ids.sort_unstable();
+        node_ids.dedup();
+        if node_ids.len() != self.replicas.len() || !node_ids.contains(&node_id) {
+            return Err(ConfigError::Message(
+                "bft replicas must have distinct node IDs including node_id".to_string(),
+            ));
+        }
+        for replica in &self.replicas {
+            match hex::decode(&replica.public_key) {
+                Ok(key) if key.len() == 32 => {}
+                _ => {
+                    return Err(ConfigError::Message(format!(
+                        "public key of bft replica {} is not a hex encoded Ed25519 key",
+                        replica.node_id
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Daily maintenance window, between two `HH:MM` times in UTC, during which no refresh runs.
+///
+/// A window ending before it starts spans midnight.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MaintenanceWindowSettings {
+    start: String,
+    end: String,
+}
+
+impl MaintenanceWindowSettings {
+    /// Returns the start of the window.
+    pub fn start(&self) -> NaiveTime {
+        Self::parse_time(&self.start).expect("windows are checked when settings are loaded")
+    }
+
+    /// Returns the end of the window.
+    pub fn end(&self) -> NaiveTime {
+        Self::parse_time(&self.end).expect("windows are checked when settings are loaded")
+    }
+
+    fn parse_time(time: &str) -> Result<NaiveTime, ConfigError> {
+        NaiveTime::parse_from_str(time, "%H:%M").map_err(|e| {
+            ConfigError::Message(format!("invalid maintenance window time {}: {}", time, e))
+        })
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        Self::parse_time(&self.start)?;
+        Self::parse_time(&self.end)?;
+        Ok(())
+    }
+}
+
+/// Settings of the refresh rounds scheduled by every node.
+///
+/// Rounds run when a secret is due after `interval_refresh_secs` or its own interval, or on the
+/// `cron` expression if configured, delayed by a random jitter of up to `jitter_secs` so the
+/// nodes do not start them at the same time.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RefreshScheduleSettings {
+    cron: Option<String>,
+    #[serde(default)]
+    jitter_secs: u64,
+    #[serde(default)]
+    maintenance_windows: Vec<MaintenanceWindowSettings>,
+}
+
+impl RefreshScheduleSettings {
+    /// Returns the cron expression of the refresh rounds, if any.
+    pub fn cron(&self) -> Option<cron::Schedule> {
+        self.cron.as_deref().map(|cron| {
+            cron::Schedule::from_str(cron).expect("cron is checked when settings are loaded")
+        })
+    }
+
+    /// Returns the maximum random delay added to every scheduled refresh.
+    pub fn jitter(&self) -> Duration {
+        Duration::from_secs(self.jitter_secs)
+    }
+
+    /// Returns the windows during which no refresh runs.
+    pub fn maintenance_windows(&self) -> &[MaintenanceWindowSettings] {
+        &self.maintenance_windows
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(cron) = &self.cron {
+            cron::Schedule::from_str(cron).map_err(|e| {
+                ConfigError::Message(format!("invalid refresh cron expression {}: {}", cron, e))
+            })?;
+        }
+        for window in &self.maintenance_windows {
+            window.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// Settings of the rate limiting of the API.
+///
+/// Every API key and every client IP address can send up to `key_requests_per_minute` and
+/// `ip_requests_per_minute` requests a minute, in bursts of as many requests. An address sending
+/// `max_auth_failures` unknown credentials in a row is locked out for `lockout_secs`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RateLimitSettings {
+    #[serde(default = "RateLimitSettings::default_key_requests_per_minute")]
+    key_requests_per_minute: u32,
+    #[serde(default = "RateLimitSettings::default_ip_requests_per_minute")]
+    ip_requests_per_minute: u32,
+    #[serde(default = "RateLimitSettings::default_max_auth_failures")]
+    max_auth_failures: u32,
+    #[serde(default = "RateLimitSettings::default_lockout_secs")]
+    lockout_secs: u64,
+}
+
+impl RateLimitSettings {
+    fn default_key_requests_per_minute() -> u32 {
+        60
+    }
+
+    fn default_ip_requests_per_minute() -> u32 {
+        120
+    }
+
+    fn default_max_auth_failures() -> u32 {
+        5
+    }
+
+    fn default_lockout_secs() -> u64 {
+        300
+    }
+
+    /// Returns the number of requests an API key can send a minute.
+    pub fn key_requests_per_minute(&self) -> u32 {
+        self.key_requests_per_minute
+    }
+
+    /// Returns the number of requests a client IP address can send a minute.
+    pub fn ip_requests_per_minute(&self) -> u32 {
+        self.ip_requests_per_minute
+    }
+
+    /// Returns the number of unknown credentials in a row locking an address out.
+    pub fn max_auth_failures(&self) -> u32 {
+        self.max_auth_failures
+    }
+
+    /// Returns how long an address is locked out.
+    pub fn lockout(&self) -> Duration {
+        Duration::from_secs(self.lockout_secs)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.key_requests_per_minute == 0
+            || self.ip_requests_per_minute == 0
+            || self.max_auth_failures == 0
+        {
+            return Err(ConfigError::Message(
+                "rate limits and max_auth_failures must be greater than 0".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Format of the log records written by the node.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// One JSON object per record, for log collectors.
+    Json,
+}
+
+/// Settings of the logs written by the node on its standard output.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LogSettings {
+    #[serde(default)]
+    format: LogFormat,
+    #[serde(default = "LogSettings::default_level")]
+    level: String,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::default(),
+            level: Self::default_level(),
+        }
+    }
+}
+
+impl LogSettings {
+    fn default_level() -> String {
+        "info".to_string()
+    }
+
+    /// Returns the format of the log records.
+    pub fn format(&self) -> LogFormat {
+        self.format
+    }
+
+    /// Returns the lowest level of the records written.
+    pub fn level(&self) -> slog::Level {
+        slog::Level::from_str(&self.level).expect("log level is checked when settings are loaded")
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        slog::Level::from_str(&self.level)
+            .map(|_| ())
+            .map_err(|_| ConfigError::Message(format!("invalid log level {}", self.level)))
+    }
+}
+
+/// Seed peer a node joins the cluster through.
+///
+/// `http_addr` is the base URL of the HTTP API of the peer, required to request admission when
+/// a `cluster_token` is configured. Without one, the joining node has the peer relay its own
+/// admission, as the `riteraft` backend does not let it propose it.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct PeerSettings {
+    raft_addr: String,
+    http_addr: Option<String>,
+}
+
+impl PeerSettings {
+    /// Returns the Raft address of the peer.
+    pub fn raft_addr(&self) -> &str {
+        &self.raft_addr
+    }
+
+    /// Returns the base URL of the HTTP API of the peer, if configured.
+    pub fn http_addr(&self) -> Option<&str> {
+        self.http_addr.as_deref()
+    }
+}
+
 /// Struct for storing settings.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Settings {
     raft_addr: String,
+    /// Single seed peer, kept for compatibility and merged into `peers` when loaded.
     peer_addr: Option<String>,
+    peer_http_addr: Option<String>,
+    #[serde(default)]
+    peers: Vec<PeerSettings>,
+    #[serde(default)]
+    bootstrap: bool,
+    bootstrap_marker_path: Option<String>,
+    #[serde(skip_serializing)]
+    cluster_token: Option<Redacted<String>>,
+    node_key_path: Option<String>,
+    audit_log_path: Option<String>,
+    #[serde(default)]
+    consensus_backend: ConsensusBackendKind,
+    bft: Option<BftSettings>,
     http_port: u16,
     node_id: u8,
-    api_key: String,
+    #[serde(default)]
+    api_keys: Vec<ApiKeySettings>,
+    jwt: Option<JwtSettings>,
+    tls: Option<TlsSettings>,
+    rate_limit: Option<RateLimitSettings>,
+    #[serde(default)]
+    require_sealed_shares: bool,
     interval_refresh_secs: u64,
+    #[serde(default = "Settings::default_refresh_batch_size")]
+    refresh_batch_size: usize,
+    #[serde(default)]
+    refresh_schedule: RefreshScheduleSettings,
+    #[serde(default)]
+    log: LogSettings,
 }
 
 impl Settings {
+    fn default_refresh_batch_size() -> usize {
+        DEFAULT_REFRESH_BATCH_SIZE
+    }
+
     /// Creates a new instance of `Settings`.
     ///
     /// # Errors
@@ -27,7 +522,100 @@ impl Settings {
             .add_source(config::Environment::default())
             .build()?;
 
-        let settings: Settings = s.try_deserialize()?;
+        Self::from_config(s)
+    }
+
+    /// Creates a new instance of `Settings` from a TOML document.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the document cannot be parsed or deserialized.
+    pub fn from_toml(source: &str) -> Result<Self, ConfigError> {
+        let s = Config::builder()
+            .add_source(File::from_str(source, FileFormat::Toml))
+            .build()?;
+
+        Self::from_config(s)
+    }
+
+    fn from_config(config: Config) -> Result<Self, ConfigError> {
+        let mut settings: Settings = config.try_deserialize()?;
+
+        if let Some(raft_addr) = settings.peer_addr.take() {
+            let http_addr = settings.peer_http_addr.take();
+            settings.peers.insert(
+                0,
+                PeerSettings {
+                    raft_addr,
+                    http_addr,
+                },
+            );
+        }
+        // Every node can be given the same list of peers
+        let raft_addr = settings.raft_addr.clone();
+        settings.peers.retain(|peer| peer.raft_addr != raft_addr);
+
+        for api_key in &settings.api_keys {
+            api_key.validate()?;
+        }
+
+        settings.refresh_schedule.validate()?;
+        settings.log.validate()?;
+        if let Some(rate_limit) = &settings.rate_limit {
+            rate_limit.validate()?;
+        }
+
+        if settings.refresh_batch_size == 0 {
+            return Err(ConfigError::Message(
+                "refresh_batch_size must be greater than 0".to_string(),
+            ));
+        }
+
+        #[cfg(feature = "bft")]
+        if settings.consensus_backend == ConsensusBackendKind::Bft {
+            settings
+                .bft
+                .as_ref()
+                .ok_or_else(|| {
+                    ConfigError::Message("bft settings are required by the bft backend".to_string())
+                })?
+                .validate(settings.node_id)?;
+        }
+
+        if settings.cluster_token.is_some()
+            && settings.peers.iter().any(|peer| peer.http_addr.is_none())
+        {
+            return Err(ConfigError::Message(
+                "the http_addr of every peer is required to request admission with cluster_token"
+                    .to_string(),
+            ));
+        }
+
+        if settings.audit_log_path.is_some() && settings.node_key_path.is_none() {
+            return Err(ConfigError::Message(
+                "node_key_path is required to verify the audit log after a restart".to_string(),
+            ));
+        }
+
+        // A node without peers would lead a cluster of its own, only the bootstrap node may
+        #[cfg(feature = "bft")]
+        let has_replicas = settings.consensus_backend == ConsensusBackendKind::Bft;
+        #[cfg(not(feature = "bft"))]
+        let has_replicas = false;
+        if settings.peers.is_empty() && !settings.bootstrap && !has_replicas {
+            return Err(ConfigError::Message(
+                "peers are required to join the cluster unless the node sets bootstrap".to_string(),
+            ));
+        }
+
+        if settings.bootstrap
+            && !settings.peers.is_empty()
+            && settings.bootstrap_marker_path.is_none()
+        {
+            return Err(ConfigError::Message(
+                "bootstrap_marker_path is required to bootstrap the cluster only once".to_string(),
+            ));
+        }
 
         Ok(settings)
     }
@@ -37,9 +625,51 @@ impl Settings {
         &self.raft_addr
     }
 
-    /// Returns the peer address, if available.
-    pub fn peer_addr(&self) -> Option<&str> {
-        self.peer_addr.as_deref()
+    /// Returns the seed peers the node joins the cluster through, in the order they are tried.
+    pub fn peers(&self) -> &[PeerSettings] {
+        &self.peers
+    }
+
+    /// Returns `true` if the node bootstraps a new cluster unless its bootstrap marker exists and
+    /// it has peers to rejoin.
+    pub fn bootstrap(&self) -> bool {
+        self.bootstrap
+    }
+
+    /// Returns the file recording that the node has bootstrapped the cluster, if any.
+    pub fn bootstrap_marker_path(&self) -> Option<&str> {
+        self.bootstrap_marker_path.as_deref()
+    }
+
+    /// Returns the pre-shared cluster token nodes use to authenticate their join, if any.
+    pub fn cluster_token(&self) -> Option<&str> {
+        self.cluster_token
+            .as_ref()
+            .map(|token| token.expose().as_str())
+    }
+
+    /// Returns the consensus backend of the node.
+    pub fn consensus_backend(&self) -> ConsensusBackendKind {
+        self.consensus_backend
+    }
+
+    /// Returns the settings of the BFT backend, if configured.
+    pub fn bft(&self) -> Option<&BftSettings> {
+        self.bft.as_ref()
+    }
+
+    /// Returns the file holding the key the node signs its consensus proposals with, if any.
+    ///
+    /// Without it a new key is generated on every start.
+    pub fn node_key_path(&self) -> Option<&str> {
+        self.node_key_path.as_deref()
+    }
+
+    /// Returns the file the audit log of the node is appended to, if any.
+    ///
+    /// Without it the audit log is only kept in memory and lost on restart.
+    pub fn audit_log_path(&self) -> Option<&str> {
+        self.audit_log_path.as_deref()
     }
 
     /// Returns the web server address.
@@ -52,13 +682,189 @@ impl Settings {
         self.node_id
     }
 
-    /// Returns the API key.
-    pub fn api_key(&self) -> &str {
-        &self.api_key
+    /// Returns the API keys registry entries.
+    pub fn api_keys(&self) -> &[ApiKeySettings] {
+        &self.api_keys
+    }
+
+    /// Returns the JWT settings, if JWT authentication is enabled.
+    pub fn jwt(&self) -> Option<&JwtSettings> {
+        self.jwt.as_ref()
+    }
+
+    /// Returns the TLS settings, if the HTTP API is served over TLS.
+    pub fn tls(&self) -> Option<&TlsSettings> {
+        self.tls.as_ref()
+    }
+
+    /// Returns the rate limiting settings, if the API is rate limited.
+    pub fn rate_limit(&self) -> Option<&RateLimitSettings> {
+        self.rate_limit.as_ref()
+    }
+
+    /// Returns `true` if shares are only released sealed to a client public key.
+    pub fn require_sealed_shares(&self) -> bool {
+        self.require_sealed_shares
     }
 
     /// Returns the interval refresh seconds.
     pub fn interval_refresh_secs(&self) -> u64 {
         self.interval_refresh_secs
     }
+
+    /// Returns the maximum number of clients refreshed in a single consensus entry.
+    pub fn refresh_batch_size(&self) -> usize {
+        self.refresh_batch_size
+    }
+
+    /// Returns the schedule settings of the refresh rounds.
+    pub fn refresh_schedule(&self) -> &RefreshScheduleSettings {
+        &self.refresh_schedule
+    }
+
+    /// Returns the settings of the logs.
+    pub fn log(&self) -> &LogSettings {
+        &self.log
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODE: &str = r#"
+        raft_addr = "server-2:7070"
+        http_port = 8080
+        node_id = 2
+        interval_refresh_secs = 10
+    "#;
+
+    const BASE: &str = r#"
+        raft_addr = "server-2:7070"
+        http_port = 8080
+        node_id = 2
+        interval_refresh_secs = 10
+        [[peers]]
+        raft_addr = "server-1:7070"
+        http_addr = "http://server-1:8080"
+    "#;
+
+    #[test]
+    fn test_merge_legacy_peer_and_skip_self() {
+        let settings = Settings::from_toml(&format!(
+            r#"
+            peer_addr = "server-1:7070"
+            peer_http_addr = "http://server-1:8080"
+            {NODE}
+            [[peers]]
+            raft_addr = "server-2:7070"
+            [[peers]]
+            raft_addr = "server-3:7070"
+            "#
+        ))
+        .unwrap();
+        let peers = settings
+            .peers()
+            .iter()
+            .map(|peer| (peer.raft_addr(), peer.http_addr()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            peers,
+            vec![
+                ("server-1:7070", Some("http://server-1:8080")),
+                ("server-3:7070", None)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reject_incomplete_bootstrap_and_admission() {
+        // Only the bootstrap node may have no peers, and it needs a marker once it has some
+        assert!(Settings::from_toml(NODE).is_err());
+        assert!(Settings::from_toml(&format!("bootstrap = true\n{NODE}")).is_ok());
+        assert!(Settings::from_toml(&format!("bootstrap = true\n{BASE}")).is_err());
+        assert!(Settings::from_toml(&format!("audit_log_path = \"audit.log\"\n{BASE}")).is_err());
+        assert!(Settings::from_toml(&format!(
+            r#"
+            cluster_token = "token"
+            {BASE}
+            [[peers]]
+            raft_addr = "server-1:7070"
+            "#
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_refresh_schedule() {
+        let settings = Settings::from_toml(&format!(
+            r#"
+            {BASE}
+            [refresh_schedule]
+            cron = "0 0 3 * * *"
+            jitter_secs = 30
+            [[refresh_schedule.maintenance_windows]]
+            start = "22:00"
+            end = "02:30"
+            "#
+        ))
+        .unwrap();
+        let schedule = settings.refresh_schedule();
+        assert!(schedule.cron().is_some());
+        assert_eq!(schedule.jitter(), Duration::from_secs(30));
+        assert_eq!(
+            schedule.maintenance_windows()[0].end(),
+            NaiveTime::from_hms_opt(2, 30, 0).unwrap()
+        );
+
+        for invalid in [
+            "cron = \"every day\"",
+            "[[refresh_schedule.maintenance_windows]]\nstart = \"25:00\"\nend = \"02:00\"",
+        ] {
+            let toml = format!("{BASE}\n[refresh_schedule]\n{invalid}");
+            assert!(Settings::from_toml(&toml).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_log_settings() {
+        let settings = Settings::from_toml(BASE).unwrap();
+        assert_eq!(settings.log().format(), LogFormat::Text);
+        assert_eq!(settings.log().level(), slog::Level::Info);
+
+        let settings = Settings::from_toml(&format!(
+            r#"
+            cluster_token = "s3cr3t-token"
+            {BASE}
+            [log]
+            format = "json"
+            level = "debug"
+            "#
+        ))
+        .unwrap();
+        assert_eq!(settings.log().format(), LogFormat::Json);
+        assert_eq!(settings.log().level(), slog::Level::Debug);
+        assert_eq!(settings.cluster_token(), Some("s3cr3t-token"));
+        assert!(!format!("{:?}", settings).contains("s3cr3t-token"));
+
+        let toml = format!("{BASE}\n[log]\nlevel = \"verbose\"");
+        assert!(Settings::from_toml(&toml).is_err());
+    }
+
+    #[test]
+    fn test_rate_limit_settings() {
+        let settings = Settings::from_toml(BASE).unwrap();
+        assert!(settings.rate_limit().is_none());
+
+        let toml = format!("{BASE}\n[rate_limit]\nip_requests_per_minute = 30");
+        let settings = Settings::from_toml(&toml).unwrap();
+        let rate_limit = settings.rate_limit().unwrap();
+        assert_eq!(rate_limit.key_requests_per_minute(), 60);
+        assert_eq!(rate_limit.ip_requests_per_minute(), 30);
+        assert_eq!(rate_limit.max_auth_failures(), 5);
+        assert_eq!(rate_limit.lockout(), Duration::from_secs(300));
+
+        let toml = format!("{BASE}\n[rate_limit]\nmax_auth_failures = 0");
+        assert!(Settings::from_toml(&toml).is_err());
+    }
 }
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::domain::error::SecretServerError;
+use crate::domain::model::NodeId;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Maximum difference in seconds between the issue time of a credential and the verifier clock.
+const MAX_CLOCK_SKEW_SECS: u64 = 60;
+
+/// Length in bytes of the random nonce of a credential.
+const NONCE_LEN: usize = 16;
+
+/// Header carrying the hex encoded MAC of an entry relayed through `/cluster/propose`.
+pub const RELAY_MAC_HEADER: &str = "x-cluster-mac";
+
+/// Context of the MACs of the relayed entries, so they are never valid join credentials.
+const RELAY_MAC_CONTEXT: &[u8] = b"shared-secrets relay v1";
+
+/// Credential presented by a node asking to join the cluster.
+///
+/// It is an HMAC-SHA256 with the pre-shared cluster token over the node ID, its Raft address,
+/// the public key it signs its proposals with, the issue time and a random nonce, so it cannot be
+/// forged without the token, and `JoinVerifier` accepts it once and only until it expires.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JoinRequest {
+    pub node_id: NodeId,
+    pub raft_addr: String,
+    #[serde(with = "hex::serde")]
+    pub public_key: Vec<u8>,
+    pub issued_at: u64,
+    #[serde(with = "hex::serde")]
+    nonce: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    mac: Vec<u8>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn mac(
+    token: &str,
+    node_id: NodeId,
+    raft_addr: &str,
+    public_key: &[u8],
+    issued_at: u64,
+    nonce: &[u8],
+) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(token.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(&[*node_id]);
+    mac.update(&(raft_addr.len() as u64).to_be_bytes());
+    mac.update(raft_addr.as_bytes());
+    mac.update(&(public_key.len() as u64).to_be_bytes());
+    mac.update(public_key);
+    mac.update(&issued_at.to_be_bytes());
+    mac.update(nonce);
+    mac
+}
+
+/// Returns the MAC with the cluster `token` of an entry relayed through `/cluster/propose`.
+pub fn relay_mac(token: &str, entry: &[u8]) -> Vec<u8> {
+    relay_hmac(token, entry).finalize().into_bytes().to_vec()
+}
+
+fn relay_hmac(token: &str, entry: &[u8]) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(token.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(RELAY_MAC_CONTEXT);
+    mac.update(entry);
+    mac
+}
+
+impl JoinRequest {
+    /// Creates a new credential for `node_id` listening on `raft_addr` and signing its proposals
+    /// with `public_key`.
+    pub fn new(token: &str, node_id: NodeId, raft_addr: &str, public_key: Vec<u8>) -> Self {
+        let issued_at = now_secs();
+        let mut nonce = vec![0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        Self {
+            node_id,
+            raft_addr: raft_addr.to_string(),
+            issued_at,
+            mac: mac(token, node_id, raft_addr, &public_key, issued_at, &nonce)
+                .finalize()
+                .into_bytes()
+                .to_vec(),
+            nonce,
+            public_key,
+        }
+    }
+
+    /// Verifies the credential against the cluster token.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `AdmissionError` if the credential is too old, issued in the future or its
+    /// MAC does not match.
+    pub fn verify(&self, token: &str) -> Result<(), SecretServerError> {
+        if now_secs().abs_diff(self.issued_at) > MAX_CLOCK_SKEW_SECS {
+            return Err(SecretServerError::AdmissionError(
+                "expired join credential".to_string(),
+            ));
+        }
+        if self.nonce.len() != NONCE_LEN {
+            return Err(SecretServerError::AdmissionError(
+                "invalid join credential nonce".to_string(),
+            ));
+        }
+        mac(
+            token,
+            self.node_id,
+            &self.raft_addr,
+            &self.public_key,
+            self.issued_at,
+            &self.nonce,
+        )
+        .verify_slice(&self.mac)
+        .map_err(|_| SecretServerError::AdmissionError("invalid join credential".to_string()))
+    }
+}
+
+/// Verifier of the join credentials and relayed entries presented to this node, shared by its
+/// HTTP workers.
+///
+/// The nonces of the accepted credentials are kept until the credentials expire, so a credential
+/// is accepted only once by this node. Another member may still accept a replayed credential
+/// before it expires, which only admits the same node with the same key and address again.
+/// Relayed entries carry no nonce, the members reject them once applied, see `HashStore`.
+#[derive(Debug)]
+pub struct JoinVerifier {
+    token: String,
+    /// Nonces of the accepted credentials, with their issue time.
+    seen: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl JoinVerifier {
+    /// Creates a verifier of the credentials issued with the cluster `token`.
+    pub fn new(token: String) -> Self {
+        Self {
+            token,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Verifies the credential and records its nonce.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `AdmissionError` if `JoinRequest::verify` rejects the credential or it was
+    /// already accepted.
+    pub fn verify(&self, request: &JoinRequest) -> Result<(), SecretServerError> {
+        request.verify(&self.token)?;
+        let mut seen = self.seen.lock()?;
+        let now = now_secs();
+        seen.retain(|_, issued_at| now.abs_diff(*issued_at) <= MAX_CLOCK_SKEW_SECS);
+        if seen
+            .insert(request.nonce.clone(), request.issued_at)
+            .is_some()
+        {
+            return Err(SecretServerError::AdmissionError(
+                "replayed join credential".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Verifies the MAC of an entry relayed by a peer.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `AdmissionError` if the MAC was not computed with the cluster token over the
+    /// entry.
+    pub fn verify_relay(&self, entry: &[u8], mac: &[u8]) -> Result<(), SecretServerError> {
+        relay_hmac(&self.token, entry)
+            .verify_slice(mac)
+            .map_err(|_| SecretServerError::AdmissionError("invalid relay MAC".to_string()))
+    }
+}
+
+/// Asks the seed node to admit this node into the cluster.
+///
+/// # Arguments
+///
+/// * `seed_http_addr` - Base URL of the HTTP API of a cluster member.
+/// * `request` - The join credential of this node.
+pub async fn request_admission(
+    seed_http_addr: &str,
+    request: &JoinRequest,
+) -> Result<(), SecretServerError> {
+    let response = reqwest::Client::new()
+        .post(format!("{}/cluster/join", seed_http_addr))
+        .json(request)
+        .send()
+        .await
+        .map_err(|e| SecretServerError::AdmissionError(e.to_string()))?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(SecretServerError::AdmissionError(format!(
+            "join refused by {} with status {}",
+            seed_http_addr,
+            response.status()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_join_request() {
+        let request = JoinRequest::new("cluster-token", NodeId(2), "server-2:7070", vec![2; 32]);
+        assert!(request.verify("cluster-token").is_ok());
+        assert!(request.verify("other-token").is_err());
+    }
+
+    #[test]
+    fn test_verify_relay_mac() {
+        let verifier = JoinVerifier::new("cluster-token".to_string());
+        let mac = relay_mac("cluster-token", b"entry");
+        assert!(verifier.verify_relay(b"entry", &mac).is_ok());
+        assert!(verifier.verify_relay(b"other entry", &mac).is_err());
+        assert!(verifier
+            .verify_relay(b"entry", &relay_mac("other-token", b"entry"))
+            .is_err());
+        assert!(verifier.verify_relay(b"entry", &[]).is_err());
+    }
+
+    #[test]
+    fn test_reject_tampered_or_expired_join_request() {
+        let mut request =
+            JoinRequest::new("cluster-token", NodeId(2), "server-2:7070", vec![2; 32]);
+        request.raft_addr = "attacker:7070".to_string();
+        assert!(request.verify("cluster-token").is_err());
+
+        let mut request =
+            JoinRequest::new("cluster-token", NodeId(2), "server-2:7070", vec![2; 32]);
+        request.public_key = vec![3; 32];
+        assert!(request.verify("cluster-token").is_err());
+
+        let mut request =
+            JoinRequest::new("cluster-token", NodeId(2), "server-2:7070", vec![2; 32]);
+        request.issued_at -= MAX_CLOCK_SKEW_SECS + 1;
+        request.mac = mac(
+            "cluster-token",
+            request.node_id,
+            &request.raft_addr,
+            &request.public_key,
+            request.issued_at,
+            &request.nonce,
+        )
+        .finalize()
+        .into_bytes()
+        .to_vec();
+        assert!(request.verify("cluster-token").is_err());
+    }
+
+    #[test]
+    fn test_reject_replayed_join_request() {
+        let verifier = JoinVerifier::new("cluster-token".to_string());
+        let request = JoinRequest::new("cluster-token", NodeId(2), "server-2:7070", vec![2; 32]);
+        assert!(verifier.verify(&request).is_ok());
+        assert!(verifier.verify(&request).is_err());
+
+        // A new credential of the same node has another nonce
+        let request = JoinRequest::new("cluster-token", NodeId(2), "server-2:7070", vec![2; 32]);
+        assert!(verifier.verify(&request).is_ok());
+
+        let mut request =
+            JoinRequest::new("cluster-token", NodeId(2), "server-2:7070", vec![2; 32]);
+        request.nonce = vec![0; NONCE_LEN];
+        assert!(verifier.verify(&request).is_err());
+    }
+}
@@ -0,0 +1,380 @@
+//! Byzantine fault tolerant consensus backend based on PBFT.
+//!
+//! Unlike the Raft backends, which assume that nodes may crash but never lie, this backend keeps
+//! the replicated log consistent while at most `f` of the `3f + 1` replicas are malicious. The
+//! replicas and their public keys are configured statically, so they are registered as members of
+//! the `HashStore` on start and the group cannot be changed at runtime.
+//!
+//! The protocol itself lives in `protocol`, and the driver task below feeds it the messages
+//! received through a `Transport`, the proposals of this node and clock ticks.
+
+pub mod network;
+pub mod protocol;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::{web, App, HttpServer};
+use async_trait::async_trait;
+use log::warn;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+
+use crate::conf::settings::BftSettings;
+use crate::consensus::raft::{HashStore, Member};
+use crate::consensus::signing::NodeKey;
+use crate::consensus::wire;
+use crate::domain::error::SecretServerError;
+use crate::domain::model::NodeId;
+
+use self::network::{HttpTransport, Transport, INBOX_CAPACITY};
+use self::protocol::{Digest, Output, Replica, Request};
+use super::{ConsensusBackend, ConsensusEvent, ConsensusStatus, ConsensusTask, Role};
+
+/// Time a proposal waits to be executed before failing.
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest protocol message accepted, new view messages carry certificates for many requests.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+type Reply = oneshot::Sender<Result<Vec<u8>, SecretServerError>>;
+
+fn backend_error(e: impl ToString) -> SecretServerError {
+    SecretServerError::BackendError(e.to_string())
+}
+
+/// View of the replica published to the backend handles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ViewState {
+    active: bool,
+    primary: NodeId,
+}
+
+impl ViewState {
+    fn of(replica: &Replica) -> Self {
+        Self {
+            active: replica.is_active(),
+            primary: replica.primary(replica.view()),
+        }
+    }
+
+    fn leader(&self) -> Option<NodeId> {
+        self.active.then_some(self.primary)
+    }
+}
+
+/// Task running the replica of this node.
+struct Driver {
+    replica: Replica,
+    store: HashStore,
+    transport: Arc<dyn Transport>,
+    messages: mpsc::Receiver<Vec<u8>>,
+    proposals: mpsc::UnboundedReceiver<(Vec<u8>, Reply)>,
+    waiters: HashMap<Digest, Reply>,
+    view: watch::Sender<ViewState>,
+    tick: Duration,
+}
+
+impl Driver {
+    async fn run(mut self) -> Result<(), SecretServerError> {
+        let mut ticks = tokio::time::interval(self.tick);
+        loop {
+            let outputs = tokio::select! {
+                message = self.messages.recv() => match message {
+                    Some(message) => self.replica.handle(&message, Instant::now()),
+                    None => return Ok(()),
+                },
+                proposal = self.proposals.recv() => match proposal {
+                    Some((entry, reply)) => self.propose(entry, reply),
+                    None => return Ok(()),
+                },
+                _ = ticks.tick() => {
+                    self.waiters.retain(|_, reply| !reply.is_closed());
+                    self.replica.tick(Instant::now())
+                }
+            };
+            self.dispatch(outputs);
+            self.publish_view();
+        }
+    }
+
+    /// Proposes the entry with its sequence number as nonce, which keeps growing when the node
+    /// restarts, see `signing`.
+    fn propose(&mut self, entry: Vec<u8>, reply: Reply) -> Vec<Output> {
+        let nonce = match wire::decode(&entry) {
+            Ok(decoded) => decoded.sequence,
+            Err(e) => {
+                let _ = reply.send(Err(e));
+                return vec![];
+            }
+        };
+        let request = self.replica.request(nonce, entry);
+        self.waiters.insert(request.digest(), reply);
+        self.replica.propose(request, Instant::now())
+    }
+
+    fn dispatch(&mut self, outputs: Vec<Output>) {
+        for output in outputs {
+            match output {
+                Output::Broadcast(message) => {
+                    let message = message.to_bytes();
+                    for to in self.replica.members() {
+                        if *to != self.store.node_id() {
+                            self.transport.send(*to, message.clone());
+                        }
+                    }
+                }
+                Output::Send(to, message) => self.transport.send(to, message.to_bytes()),
+                Output::Execute(_, request) => self.execute(request),
+                Output::Checkpoint(seq) => match self.store.to_checkpoint() {
+                    Ok(state) => {
+                        let outputs = self.replica.checkpoint(seq, state);
+                        self.dispatch(outputs);
+                    }
+                    Err(e) => warn!("Cannot take checkpoint {}: {}", seq, e),
+                },
+                Output::Restore(seq, state) => self.restore(seq, &state),
+            }
+        }
+    }
+
+    /// Applies the committed request to the store and replies to its proposer, if local.
+    ///
+    /// Requests are signed by their origin, and a replica can only order entries it signed
+    /// itself, so a malicious replica cannot replay the entries of another node.
+    fn execute(&mut self, request: Request) {
+        let result = match wire::decode(&request.entry) {
+            Ok(entry) if entry.origin == request.origin => self.store.apply_entry(&request.entry),
+            _ => {
+                warn!(
+                    "Rejecting entry ordered by {:?} on behalf of another node",
+                    request.origin
+                );
+                Ok(vec![])
+            }
+        };
+        match self.waiters.remove(&request.digest()) {
+            Some(reply) => {
+                let _ = reply.send(result);
+            }
+            None => {
+                if let Err(e) = result {
+                    warn!("Cannot apply entry of {:?}: {}", request.origin, e);
+                }
+            }
+        }
+    }
+
+    /// Restores the replicated state of the store at the stable checkpoint `seq`, fetched from
+    /// another replica as this one fell behind it.
+    ///
+    /// Shares differ on every node and are not transferred, so the shares of this node refreshed
+    /// by the skipped entries are out of date.
+    fn restore(&mut self, seq: u64, state: &[u8]) {
+        match self.store.restore_checkpoint(state) {
+            Ok(()) => warn!(
+                "Skipped to checkpoint {}, shares refreshed by the skipped entries are out of date",
+                seq
+            ),
+            Err(e) => warn!("Cannot restore checkpoint {}: {}", seq, e),
+        }
+    }
+
+    fn publish_view(&self) {
+        let state = ViewState::of(&self.replica);
+        let last = *self.view.borrow();
+        if state != last {
+            if state.leader() != last.leader() {
+                self.store.publish(ConsensusEvent::LeaderChanged {
+                    leader: state.leader(),
+                });
+            }
+            self.view.send_replace(state);
+        }
+    }
+}
+
+/// Consensus backend replicating entries with PBFT between a static group of replicas.
+///
+/// The primary of the current view is reported as the leader, and replicas changing view as
+/// candidates.
+#[derive(Debug, Clone)]
+pub struct BftBackend {
+    store: HashStore,
+    members: Vec<NodeId>,
+    proposals: mpsc::UnboundedSender<(Vec<u8>, Reply)>,
+    view: watch::Receiver<ViewState>,
+}
+
+impl BftBackend {
+    fn new(
+        store: HashStore,
+        key: Arc<NodeKey>,
+        replicas: BTreeMap<NodeId, Member>,
+        view_change_timeout: Duration,
+        transport: Arc<dyn Transport>,
+        messages: mpsc::Receiver<Vec<u8>>,
+    ) -> Result<(Driver, Self), SecretServerError> {
+        let keys = replicas
+            .iter()
+            .map(|(id, member)| (*id, member.public_key.clone()))
+            .collect();
+        let replica = Replica::new(
+            store.node_id(),
+            key,
+            keys,
+            view_change_timeout,
+            Instant::now(),
+        )?;
+        store.seed_members(replicas.into_iter().collect())?;
+        let (proposals, proposals_receiver) = mpsc::unbounded_channel();
+        let (view, view_receiver) = watch::channel(ViewState::of(&replica));
+        let backend = Self {
+            store: store.clone(),
+            members: replica.members().to_vec(),
+            proposals,
+            view: view_receiver,
+        };
+        let driver = Driver {
+            replica,
+            store,
+            transport,
+            messages,
+            proposals: proposals_receiver,
+            waiters: HashMap::new(),
+            view,
+            tick: view_change_timeout / 4,
+        };
+        Ok((driver, backend))
+    }
+
+    /// Starts the replica of this node among `replicas`, exchanging messages through
+    /// `transport` and receiving them from `messages`, and returns the task running it with the
+    /// backend.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `BackendError` if the node is not one of the replicas or `key` is not its key.
+    pub fn start(
+        store: HashStore,
+        key: Arc<NodeKey>,
+        replicas: BTreeMap<NodeId, Member>,
+        view_change_timeout: Duration,
+        transport: Arc<dyn Transport>,
+        messages: mpsc::Receiver<Vec<u8>>,
+    ) -> Result<(ConsensusTask, Self), SecretServerError> {
+        let (driver, backend) = Self::new(
+            store,
+            key,
+            replicas,
+            view_change_timeout,
+            transport,
+            messages,
+        )?;
+        Ok((tokio::spawn(driver.run()), backend))
+    }
+
+    /// Starts the replica of this node among the configured replicas, exchanging messages over
+    /// HTTP on `raft_addr`.
+    pub async fn start_http(
+        settings: &BftSettings,
+        raft_addr: &str,
+        store: HashStore,
+        key: Arc<NodeKey>,
+    ) -> Result<(ConsensusTask, Self), SecretServerError> {
+        let replicas = settings
+            .replicas()
+            .iter()
+            .map(|replica| {
+                let member = Member {
+                    raft_addr: replica.raft_addr().to_string(),
+                    public_key: replica.public_key(),
+                };
+                (NodeId(replica.node_id()), member)
+            })
+            .collect::<BTreeMap<_, _>>();
+        let addrs = replicas
+            .iter()
+            .map(|(id, member)| (*id, member.raft_addr.clone()))
+            .collect();
+        let (inbox, messages) = mpsc::channel(INBOX_CAPACITY);
+        let (driver, backend) = Self::new(
+            store,
+            key,
+            replicas,
+            settings.view_change_timeout(),
+            Arc::new(HttpTransport::new(addrs)),
+            messages,
+        )?;
+
+        let inbox = web::Data::new(inbox);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(inbox.clone())
+                .app_data(web::PayloadConfig::new(MAX_MESSAGE_SIZE))
+                .service(network::receive_message)
+        })
+        .bind(raft_addr)
+        .map_err(backend_error)?
+        .run();
+        let task = tokio::spawn(async move {
+            tokio::select! {
+                result = server => result.map_err(backend_error),
+                result = driver.run() => result,
+            }
+        });
+        Ok((task, backend))
+    }
+}
+
+#[async_trait]
+impl ConsensusBackend for BftBackend {
+    async fn propose(&self, entry: Vec<u8>) -> Result<Vec<u8>, SecretServerError> {
+        let (reply, result) = oneshot::channel();
+        self.proposals
+            .send((entry, reply))
+            .map_err(|_| backend_error("the replica is stopped"))?;
+        tokio::time::timeout(PROPOSE_TIMEOUT, result)
+            .await
+            .map_err(|_| backend_error("the proposal was not executed in time"))?
+            .map_err(|_| backend_error("the replica is stopped"))?
+    }
+
+    async fn leave(&self) -> Result<(), SecretServerError> {
+        Err(SecretServerError::NotSupported(
+            "the replicas of the BFT backend are configured statically, change the `peers` of \
+             every replica to take one out"
+                .to_string(),
+        ))
+    }
+
+    async fn remove(&self, _node_id: NodeId) -> Result<(), SecretServerError> {
+        self.leave().await
+    }
+
+    fn is_reconfigurable(&self) -> bool {
+        false
+    }
+
+    fn status(&self) -> ConsensusStatus {
+        let state = *self.view.borrow();
+        let node_id = self.store.node_id();
+        ConsensusStatus {
+            node_id,
+            role: match state.leader() {
+                Some(leader) if leader == node_id => Role::Leader,
+                Some(_) => Role::Follower,
+                None => Role::Candidate,
+            },
+            leader: state.leader(),
+            voters: self.members.clone(),
+            term: None,
+            last_log_index: None,
+            last_applied: None,
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ConsensusEvent> {
+        self.store.subscribe()
+    }
+}
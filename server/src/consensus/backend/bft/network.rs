@@ -0,0 +1,144 @@
+//! Transports carrying the signed protocol messages between the replicas.
+//!
+//! Delivery is best effort: the protocol tolerates lost, duplicated and reordered messages, so
+//! a transport never retries nor reports failures.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use actix_web::{post, web, HttpResponse};
+use log::debug;
+use tokio::sync::mpsc;
+
+use crate::domain::model::NodeId;
+
+/// Number of received messages queued before new ones are dropped.
+pub const INBOX_CAPACITY: usize = 4096;
+
+/// Sends protocol messages to the other replicas.
+pub trait Transport: Send + Sync {
+    /// Sends the encoded message to the replica `to`.
+    fn send(&self, to: NodeId, message: Vec<u8>);
+}
+
+/// Transport posting the messages to the `/bft/message` endpoint of the replicas.
+#[derive(Debug, Clone)]
+pub struct HttpTransport {
+    client: reqwest::Client,
+    addrs: BTreeMap<NodeId, String>,
+}
+
+impl HttpTransport {
+    /// Creates a transport to the replicas listening on the given addresses.
+    pub fn new(addrs: BTreeMap<NodeId, String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            addrs,
+        }
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send(&self, to: NodeId, message: Vec<u8>) {
+        let Some(addr) = self.addrs.get(&to) else {
+            return;
+        };
+        let request = self
+            .client
+            .post(format!("http://{}/bft/message", addr))
+            .body(message);
+        tokio::spawn(async move {
+            if let Err(e) = request.send().await {
+                debug!("Cannot send BFT message to {:?}: {}", to, e);
+            }
+        });
+    }
+}
+
+/// Queue of the messages received by this replica.
+pub type Inbox = web::Data<mpsc::Sender<Vec<u8>>>;
+
+/// Receives a protocol message from another replica.
+#[post("/bft/message")]
+pub async fn receive_message(inbox: Inbox, body: web::Bytes) -> HttpResponse {
+    match inbox.try_send(body.to_vec()) {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(_) => HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+#[derive(Debug, Default)]
+struct NetworkState {
+    inboxes: HashMap<NodeId, mpsc::Sender<Vec<u8>>>,
+    isolated: HashSet<NodeId>,
+}
+
+/// In-process network connecting replicas of the same test.
+///
+/// Replicas can be isolated to simulate crashes and partitions, and arbitrary messages can be
+/// injected to play a malicious replica.
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl SimulatedNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connects the replica `id`, returning its transport and the receiver of its messages.
+    pub fn connect(&self, id: NodeId) -> (SimulatedTransport, mpsc::Receiver<Vec<u8>>) {
+        let (sender, receiver) = mpsc::channel(INBOX_CAPACITY);
+        self.state.lock().unwrap().inboxes.insert(id, sender);
+        let transport = SimulatedTransport {
+            network: self.clone(),
+            from: id,
+        };
+        (transport, receiver)
+    }
+
+    /// Drops every message sent to or by the replica `id`.
+    pub fn isolate(&self, id: NodeId) {
+        self.state.lock().unwrap().isolated.insert(id);
+    }
+
+    /// Delivers the messages of the replica `id` again.
+    pub fn heal(&self, id: NodeId) {
+        self.state.lock().unwrap().isolated.remove(&id);
+    }
+
+    /// Delivers the message to the replica `to`, unless it is isolated.
+    pub fn inject(&self, to: NodeId, message: Vec<u8>) {
+        let state = self.state.lock().unwrap();
+        if state.isolated.contains(&to) {
+            return;
+        }
+        if let Some(inbox) = state.inboxes.get(&to) {
+            let _ = inbox.try_send(message);
+        }
+    }
+}
+
+/// Transport of a replica connected to a `SimulatedNetwork`.
+#[derive(Debug, Clone)]
+pub struct SimulatedTransport {
+    network: SimulatedNetwork,
+    from: NodeId,
+}
+
+impl Transport for SimulatedTransport {
+    fn send(&self, to: NodeId, message: Vec<u8>) {
+        if self
+            .network
+            .state
+            .lock()
+            .unwrap()
+            .isolated
+            .contains(&self.from)
+        {
+            return;
+        }
+        self.network.inject(to, message);
+    }
+}
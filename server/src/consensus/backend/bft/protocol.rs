@@ -0,0 +1,1563 @@
+//! PBFT replica state machine.
+//!
+//! Implements the normal case operation (pre-prepare, prepare and commit), checkpoints and view
+//! changes of Practical Byzantine Fault Tolerance (Castro and Liskov, 1999) for a static group of
+//! `n = 3f + 1` replicas, so committed requests are executed in the same order by every correct
+//! replica as long as at most `f` of them are faulty or malicious.
+//!
+//! Every protocol message is signed with the node key of the sender, and the certificates carried
+//! in view changes are sets of those signed messages, so they can be checked by any replica.
+//! Requests are also signed by the replica proposing them, together with their nonce, so a
+//! malicious primary can neither forge nor replay the requests of another replica.
+//!
+//! Replicas only keep the requests executed since the last stable checkpoint. A replica behind
+//! it fetches the state of the checkpoint, certified by the `Checkpoint` messages of `2f + 1`
+//! replicas, and the pages of requests executed since.
+//!
+//! The replica does no I/O: it consumes messages, proposals and clock ticks and returns the
+//! messages to send and the requests to execute, which makes it deterministic to test.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bincode::{deserialize, serialize};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::consensus::signing::{verify, NodeKey};
+use crate::domain::error::SecretServerError;
+use crate::domain::model::NodeId;
+
+/// Number of executed sequence numbers between checkpoints.
+pub const CHECKPOINT_INTERVAL: u64 = 64;
+
+/// Number of sequence numbers above the last stable checkpoint a primary can assign.
+const WINDOW: u64 = 4 * CHECKPOINT_INTERVAL;
+
+/// Bytes of entries sent in reply to a `Fetch`, well below the largest message accepted.
+const FETCH_PAGE_SIZE: usize = 4 * 1024 * 1024;
+
+const SIGNING_CONTEXT: &[u8] = b"shared-secrets bft v1";
+
+const REQUEST_SIGNING_CONTEXT: &[u8] = b"shared-secrets bft request v1";
+
+pub type Digest = [u8; 32];
+
+/// Digest of the null request and of the chain before any request is executed.
+const NULL_DIGEST: Digest = [0; 32];
+
+fn hash(parts: &[&[u8]]) -> Digest {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Consensus entry proposed by the replica `origin`, signed by it.
+///
+/// The nonce tells apart proposals of the same entry, and a nonce of an origin is executed once
+/// at most, see `Nonces`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub origin: NodeId,
+    pub nonce: u64,
+    pub entry: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+fn request_signed_bytes(origin: NodeId, nonce: u64, entry: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(REQUEST_SIGNING_CONTEXT.len() + 9 + entry.len());
+    bytes.extend_from_slice(REQUEST_SIGNING_CONTEXT);
+    bytes.push(*origin);
+    bytes.extend_from_slice(&nonce.to_be_bytes());
+    bytes.extend_from_slice(entry);
+    bytes
+}
+
+impl Request {
+    /// Signs the request of `origin` with its key.
+    pub fn new(key: &NodeKey, origin: NodeId, nonce: u64, entry: Vec<u8>) -> Self {
+        let signature = key.sign_bytes(&request_signed_bytes(origin, nonce, &entry));
+        Self {
+            origin,
+            nonce,
+            entry,
+            signature,
+        }
+    }
+
+    pub fn digest(&self) -> Digest {
+        hash(&[&serialize(self).expect("requests are serializable")])
+    }
+
+    fn verify(&self, keys: &BTreeMap<NodeId, Vec<u8>>) -> bool {
+        keys.get(&self.origin).is_some_and(|key| {
+            verify(
+                key,
+                &request_signed_bytes(self.origin, self.nonce, &self.entry),
+                &self.signature,
+            )
+        })
+    }
+}
+
+fn digest_of(request: &Option<Request>) -> Digest {
+    request.as_ref().map_or(NULL_DIGEST, Request::digest)
+}
+
+/// Nonces of the requests executed from an origin.
+///
+/// When a checkpoint is executed, the floor moves to the highest nonce executed up to the
+/// previous one, so a request is skipped if a later request of its origin was executed more than
+/// a checkpoint interval before it. Every correct replica executes the checkpoints after the same
+/// requests, so they all skip the same ones, and the nonces kept are bounded.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Nonces {
+    /// Nonces up to this one are not executed.
+    floor: u64,
+    /// Nonces executed above `floor`, with their sequence number.
+    executed: BTreeMap<u64, u64>,
+}
+
+impl Nonces {
+    fn contains(&self, nonce: u64) -> bool {
+        nonce <= self.floor || self.executed.contains_key(&nonce)
+    }
+
+    /// Records `nonce` executed at `seq` and returns `true` if it was not executed yet.
+    fn record(&mut self, nonce: u64, seq: u64) -> bool {
+        if self.contains(nonce) {
+            return false;
+        }
+        self.executed.insert(nonce, seq);
+        true
+    }
+
+    /// Moves the floor to the highest nonce executed up to `seq`.
+    fn compact(&mut self, seq: u64) {
+        let highest = self
+            .executed
+            .iter()
+            .filter(|(_, executed_at)| **executed_at <= seq)
+            .map(|(nonce, _)| *nonce)
+            .max();
+        if let Some(highest) = highest {
+            self.floor = self.floor.max(highest);
+            self.executed = self.executed.split_off(&(self.floor + 1));
+        }
+    }
+}
+
+/// State of a replica at a checkpoint, transferred to the replicas behind it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct CheckpointState {
+    nonces: BTreeMap<NodeId, Nonces>,
+    /// Replicated state of the application, see `Output::Checkpoint`.
+    app: Vec<u8>,
+}
+
+impl CheckpointState {
+    fn digest(&self) -> Digest {
+        hash(&[&serialize(self).expect("checkpoint states are serializable")])
+    }
+}
+
+/// Proof that a checkpoint is stable: `2f + 1` signed `Checkpoint` messages.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct CheckpointProof {
+    pub seq: u64,
+    /// Digest chaining the digests of every request executed up to `seq`.
+    pub chain: Digest,
+    /// Digest of the `CheckpointState` at `seq`.
+    pub state: Digest,
+    pub checkpoints: Vec<Signed>,
+}
+
+/// Proof that a request prepared: its `PrePrepare` and `2f` matching `Prepare` messages.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PreparedProof {
+    pub pre_prepare: Signed,
+    pub prepares: Vec<Signed>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum BftMessage {
+    Request(Request),
+    PrePrepare {
+        view: u64,
+        seq: u64,
+        /// `None` for the null requests filling the gaps of a view change.
+        request: Option<Request>,
+    },
+    Prepare {
+        view: u64,
+        seq: u64,
+        digest: Digest,
+    },
+    Commit {
+        view: u64,
+        seq: u64,
+        digest: Digest,
+    },
+    Checkpoint {
+        seq: u64,
+        chain: Digest,
+        state: Digest,
+    },
+    ViewChange {
+        view: u64,
+        checkpoint: CheckpointProof,
+        prepared: Vec<PreparedProof>,
+    },
+    NewView {
+        view: u64,
+        view_changes: Vec<Signed>,
+        pre_prepares: Vec<Signed>,
+    },
+    /// Asks for the requests executed after `after`, to catch up with a stable checkpoint.
+    Fetch {
+        after: u64,
+    },
+    /// Page of the requests executed after `after`, in reply to a `Fetch`.
+    Entries {
+        after: u64,
+        requests: Vec<Option<Request>>,
+    },
+    /// State at the stable checkpoint, in reply to a `Fetch` from a replica behind it.
+    State {
+        checkpoint: CheckpointProof,
+        state: CheckpointState,
+    },
+}
+
+/// Protocol message signed by the replica `from`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Signed {
+    pub from: NodeId,
+    payload: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+fn signed_bytes(from: NodeId, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(SIGNING_CONTEXT.len() + 1 + payload.len());
+    bytes.extend_from_slice(SIGNING_CONTEXT);
+    bytes.push(*from);
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+impl Signed {
+    /// Signs the message on behalf of `from`.
+    pub fn new(key: &NodeKey, from: NodeId, message: &BftMessage) -> Self {
+        let payload = serialize(message).expect("protocol messages are serializable");
+        let signature = key.sign_bytes(&signed_bytes(from, &payload));
+        Self {
+            from,
+            payload,
+            signature,
+        }
+    }
+
+    /// Returns the message, if the payload is valid.
+    pub fn message(&self) -> Option<BftMessage> {
+        deserialize(&self.payload).ok()
+    }
+
+    fn verify(&self, keys: &BTreeMap<NodeId, Vec<u8>>) -> bool {
+        keys.get(&self.from).is_some_and(|key| {
+            verify(
+                key,
+                &signed_bytes(self.from, &self.payload),
+                &self.signature,
+            )
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serialize(self).expect("signed messages are serializable")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        deserialize(bytes).ok()
+    }
+}
+
+/// Effect of a replica step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    /// Send the message to every other replica.
+    Broadcast(Signed),
+    /// Send the message to the given replica.
+    Send(NodeId, Signed),
+    /// Execute the committed request with the given sequence number.
+    Execute(u64, Request),
+    /// Pass the replicated state of the application, once the requests up to the given sequence
+    /// number are executed, to `Replica::checkpoint`.
+    Checkpoint(u64),
+    /// Replace the replicated state of the application with the one at the given stable
+    /// checkpoint, the requests up to it are not executed.
+    Restore(u64, Vec<u8>),
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    view: u64,
+    pre_prepare: Option<Signed>,
+    request: Option<Request>,
+    digest: Digest,
+    prepares: BTreeMap<NodeId, (u64, Digest, Signed)>,
+    commits: BTreeMap<NodeId, (u64, Digest)>,
+    /// Latest prepared certificate of the slot, reported in view changes.
+    prepared: Option<PreparedProof>,
+    commit_sent_view: Option<u64>,
+    committed: bool,
+}
+
+/// Replica of the PBFT protocol.
+#[derive(Debug)]
+pub struct Replica {
+    id: NodeId,
+    key: Arc<NodeKey>,
+    keys: BTreeMap<NodeId, Vec<u8>>,
+    members: Vec<NodeId>,
+    f: usize,
+    timeout: Duration,
+    view: u64,
+    active: bool,
+    view_change_at: Instant,
+    new_view_sent: Option<u64>,
+    next_seq: u64,
+    last_executed: u64,
+    chain: Digest,
+    executed: BTreeMap<u64, Option<Request>>,
+    executed_at: HashMap<Digest, u64>,
+    nonces: BTreeMap<NodeId, Nonces>,
+    /// Chain and nonces at the executed checkpoints waiting for the state of the application.
+    executed_checkpoints: BTreeMap<u64, (Digest, BTreeMap<NodeId, Nonces>)>,
+    /// States at the checkpoints from the stable one on.
+    states: BTreeMap<u64, CheckpointState>,
+    /// Pages fetched from each replica to catch up with the stable checkpoint.
+    fetched: BTreeMap<NodeId, Vec<Option<Request>>>,
+    fetch_page_size: usize,
+    stable: CheckpointProof,
+    slots: BTreeMap<u64, Slot>,
+    checkpoints: BTreeMap<(u64, Digest, Digest), BTreeMap<NodeId, Signed>>,
+    pending: HashMap<Digest, (Request, Instant)>,
+    view_changes: BTreeMap<u64, BTreeMap<NodeId, Signed>>,
+    outbox: Vec<Output>,
+}
+
+impl Replica {
+    /// Creates the replica `id` of the group formed by the replicas with the given public keys.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `BackendError` if `id` is not in the group or `key` is not its configured key.
+    pub fn new(
+        id: NodeId,
+        key: Arc<NodeKey>,
+        keys: BTreeMap<NodeId, Vec<u8>>,
+        timeout: Duration,
+        now: Instant,
+    ) -> Result<Self, SecretServerError> {
+        if keys.get(&id) != Some(&key.public_key()) {
+            return Err(SecretServerError::BackendError(format!(
+                "node {:?} is not a replica with the node key",
+                id
+            )));
+        }
+        let members = keys.keys().copied().collect::<Vec<_>>();
+        Ok(Self {
+            id,
+            key,
+            f: (members.len() - 1) / 3,
+            members,
+            keys,
+            timeout,
+            view: 0,
+            active: true,
+            view_change_at: now,
+            new_view_sent: None,
+            next_seq: 1,
+            last_executed: 0,
+            chain: NULL_DIGEST,
+            executed: BTreeMap::new(),
+            executed_at: HashMap::new(),
+            nonces: BTreeMap::new(),
+            executed_checkpoints: BTreeMap::new(),
+            states: BTreeMap::new(),
+            fetched: BTreeMap::new(),
+            fetch_page_size: FETCH_PAGE_SIZE,
+            stable: CheckpointProof::default(),
+            slots: BTreeMap::new(),
+            checkpoints: BTreeMap::new(),
+            pending: HashMap::new(),
+            view_changes: BTreeMap::new(),
+            outbox: vec![],
+        })
+    }
+
+    /// Returns the replicas of the group.
+    pub fn members(&self) -> &[NodeId] {
+        &self.members
+    }
+
+    /// Returns the current view.
+    pub fn view(&self) -> u64 {
+        self.view
+    }
+
+    /// Returns `false` while a view change is in progress.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Returns the primary of the given view.
+    pub fn primary(&self, view: u64) -> NodeId {
+        self.members[(view % self.members.len() as u64) as usize]
+    }
+
+    /// Returns the sequence number of the last executed request.
+    pub fn last_executed(&self) -> u64 {
+        self.last_executed
+    }
+
+    fn quorum(&self) -> usize {
+        2 * self.f + 1
+    }
+
+    fn in_window(&self, seq: u64) -> bool {
+        seq > self.stable.seq && seq <= self.stable.seq + WINDOW
+    }
+
+    fn sign(&self, message: &BftMessage) -> Signed {
+        Signed::new(&self.key, self.id, message)
+    }
+
+    fn broadcast(&mut self, message: &BftMessage) -> Signed {
+        let signed = self.sign(message);
+        self.outbox.push(Output::Broadcast(signed.clone()));
+        signed
+    }
+
+    /// Returns the request of this replica for `entry`, signed with its key.
+    pub fn request(&self, nonce: u64, entry: Vec<u8>) -> Request {
+        Request::new(&self.key, self.id, nonce, entry)
+    }
+
+    /// Proposes a request on behalf of this replica.
+    pub fn propose(&mut self, request: Request, now: Instant) -> Vec<Output> {
+        self.broadcast(&BftMessage::Request(request.clone()));
+        self.on_request(request, now);
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Handles a message received from another replica. Messages with an invalid signature are
+    /// dropped.
+    pub fn handle(&mut self, bytes: &[u8], now: Instant) -> Vec<Output> {
+        let Some(signed) = Signed::from_bytes(bytes).filter(|s| s.verify(&self.keys)) else {
+            warn!("Dropping BFT message with invalid signature");
+            return vec![];
+        };
+        let Some(message) = signed.message() else {
+            warn!("Dropping malformed BFT message from {:?}", signed.from);
+            return vec![];
+        };
+        let from = signed.from;
+        match message {
+            BftMessage::Request(request) if request.origin == from => self.on_request(request, now),
+            BftMessage::Request(_) => {}
+            BftMessage::PrePrepare { view, seq, request } => {
+                if self.active
+                    && view == self.view
+                    && from == self.primary(view)
+                    && self.in_window(seq)
+                {
+                    self.accept_pre_prepare(signed, view, seq, request);
+                }
+            }
+            BftMessage::Prepare { view, seq, digest } => {
+                if from != self.primary(view) && self.in_window(seq) {
+                    let slot = self.slots.entry(seq).or_default();
+                    if slot.prepares.get(&from).is_none_or(|(v, _, _)| *v < view) {
+                        slot.prepares.insert(from, (view, digest, signed));
+                    }
+                    self.check_prepared(seq);
+                }
+            }
+            BftMessage::Commit { view, seq, digest } => {
+                if self.in_window(seq) {
+                    let slot = self.slots.entry(seq).or_default();
+                    if slot.commits.get(&from).is_none_or(|(v, _)| *v < view) {
+                        slot.commits.insert(from, (view, digest));
+                    }
+                    self.check_committed(seq);
+                }
+            }
+            BftMessage::Checkpoint { seq, chain, state } => {
+                if seq > self.stable.seq {
+                    self.record_checkpoint(signed, seq, chain, state);
+                }
+            }
+            BftMessage::ViewChange {
+                view,
+                checkpoint,
+                prepared,
+            } => self.on_view_change(signed, view, &checkpoint, &prepared, now),
+            BftMessage::NewView {
+                view,
+                view_changes,
+                pre_prepares,
+            } => self.on_new_view(from, view, view_changes, pre_prepares, now),
+            BftMessage::Fetch { after } => self.on_fetch(from, after),
+            BftMessage::Entries { after, requests } => self.on_entries(from, after, requests),
+            BftMessage::State { checkpoint, state } => self.on_state(from, checkpoint, state),
+        }
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Starts a view change if a pending request has not been executed within the timeout.
+    pub fn tick(&mut self, now: Instant) -> Vec<Output> {
+        let expired = if self.active {
+            self.pending
+                .values()
+                .any(|(_, since)| now.duration_since(*since) > self.timeout)
+        } else {
+            now.duration_since(self.view_change_at) > 2 * self.timeout
+        };
+        if expired {
+            self.start_view_change(self.view + 1, now);
+        }
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn on_request(&mut self, request: Request, now: Instant) {
+        let digest = request.digest();
+        if self.executed_at.contains_key(&digest)
+            || self
+                .nonces
+                .get(&request.origin)
+                .is_some_and(|nonces| nonces.contains(request.nonce))
+        {
+            return;
+        }
+        if !request.verify(&self.keys) {
+            warn!("Dropping request not signed by {:?}", request.origin);
+            return;
+        }
+        self.pending.entry(digest).or_insert((request, now));
+        self.assign_pending();
+    }
+
+    /// Assigns a sequence number to the pending requests if this replica is the primary.
+    fn assign_pending(&mut self) {
+        if !self.active || self.primary(self.view) != self.id {
+            return;
+        }
+        let mut requests = self
+            .pending
+            .iter()
+            .filter(|(digest, _)| {
+                !self
+                    .slots
+                    .values()
+                    .any(|slot| slot.pre_prepare.is_some() && slot.digest == **digest)
+            })
+            .map(|(_, (request, _))| request.clone())
+            .collect::<Vec<_>>();
+        requests.sort_by_key(|request| (request.origin, request.nonce));
+        for request in requests {
+            let seq = self.next_seq;
+            if !self.in_window(seq) {
+                return;
+            }
+            self.next_seq += 1;
+            let view = self.view;
+            let request = Some(request);
+            let pre_prepare = self.broadcast(&BftMessage::PrePrepare {
+                view,
+                seq,
+                request: request.clone(),
+            });
+            self.accept_pre_prepare(pre_prepare, view, seq, request);
+        }
+    }
+
+    fn accept_pre_prepare(
+        &mut self,
+        pre_prepare: Signed,
+        view: u64,
+        seq: u64,
+        request: Option<Request>,
+    ) {
+        let digest = digest_of(&request);
+        if request
+            .as_ref()
+            .is_some_and(|request| !request.verify(&self.keys))
+        {
+            warn!(
+                "Rejecting request not signed by its origin proposed at {}",
+                seq
+            );
+            return;
+        }
+        if request.is_some() && self.executed_at.get(&digest).is_some_and(|s| *s != seq) {
+            warn!(
+                "Rejecting request executed before proposed again at {}",
+                seq
+            );
+            return;
+        }
+        let slot = self.slots.entry(seq).or_default();
+        if slot.pre_prepare.is_some() && slot.view >= view {
+            if slot.view == view && slot.digest != digest {
+                warn!(
+                    "Primary {:?} proposed different requests for {}",
+                    pre_prepare.from, seq
+                );
+            }
+            return;
+        }
+        if slot.digest != digest {
+            slot.committed = false;
+        }
+        slot.view = view;
+        slot.pre_prepare = Some(pre_prepare);
+        slot.request = request;
+        slot.digest = digest;
+        if self.primary(view) != self.id {
+            let prepare = self.broadcast(&BftMessage::Prepare { view, seq, digest });
+            let slot = self.slots.entry(seq).or_default();
+            slot.prepares.insert(self.id, (view, digest, prepare));
+        }
+        self.check_prepared(seq);
+    }
+
+    fn check_prepared(&mut self, seq: u64) {
+        let f = self.f;
+        let Some(slot) = self.slots.get_mut(&seq) else {
+            return;
+        };
+        let Some(pre_prepare) = slot.pre_prepare.clone() else {
+            return;
+        };
+        if slot.commit_sent_view == Some(slot.view) {
+            return;
+        }
+        let prepares = slot
+            .prepares
+            .values()
+            .filter(|(view, digest, _)| *view == slot.view && *digest == slot.digest)
+            .map(|(_, _, prepare)| prepare.clone())
+            .take(2 * f)
+            .collect::<Vec<_>>();
+        if prepares.len() < 2 * f {
+            return;
+        }
+        let (view, digest) = (slot.view, slot.digest);
+        slot.prepared = Some(PreparedProof {
+            pre_prepare,
+            prepares,
+        });
+        slot.commit_sent_view = Some(view);
+        slot.commits.insert(self.id, (view, digest));
+        self.broadcast(&BftMessage::Commit { view, seq, digest });
+        self.check_committed(seq);
+    }
+
+    fn check_committed(&mut self, seq: u64) {
+        let quorum = self.quorum();
+        let Some(slot) = self.slots.get_mut(&seq) else {
+            return;
+        };
+        if slot.committed || slot.commit_sent_view != Some(slot.view) {
+            return;
+        }
+        let commits = slot
+            .commits
+            .values()
+            .filter(|(view, digest)| *view == slot.view && *digest == slot.digest)
+            .count();
+        if commits >= quorum {
+            slot.committed = true;
+            self.try_execute();
+        }
+    }
+
+    /// Executes the committed requests following the last executed one.
+    fn try_execute(&mut self) {
+        loop {
+            let seq = self.last_executed + 1;
+            match self.slots.get(&seq) {
+                Some(slot) if slot.committed => {
+                    let request = slot.request.clone();
+                    self.execute(seq, request);
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn execute(&mut self, seq: u64, request: Option<Request>) {
+        let digest = digest_of(&request);
+        self.last_executed = seq;
+        self.chain = hash(&[&self.chain, &digest]);
+        self.executed.insert(seq, request.clone());
+        if let Some(request) = request {
+            self.pending.remove(&digest);
+            self.executed_at.insert(digest, seq);
+            // Every correct replica skips the same replayed nonces, as they execute the same
+            // requests in the same order
+            let nonces = self.nonces.entry(request.origin).or_default();
+            if nonces.record(request.nonce, seq) {
+                self.outbox.push(Output::Execute(seq, request));
+            } else {
+                warn!(
+                    "Skipping request of {:?} with nonce {} executed before",
+                    request.origin, request.nonce
+                );
+            }
+        }
+        if seq.is_multiple_of(CHECKPOINT_INTERVAL) {
+            for nonces in self.nonces.values_mut() {
+                nonces.compact(seq - CHECKPOINT_INTERVAL);
+            }
+            self.executed_checkpoints
+                .insert(seq, (self.chain, self.nonces.clone()));
+            self.outbox.push(Output::Checkpoint(seq));
+        }
+    }
+
+    /// Takes the checkpoint at `seq` with the replicated state of the application once the
+    /// requests up to it are executed, in answer to `Output::Checkpoint`.
+    pub fn checkpoint(&mut self, seq: u64, app: Vec<u8>) -> Vec<Output> {
+        let executed = self.executed_checkpoints.remove(&seq);
+        if let Some((chain, nonces)) = executed.filter(|_| seq >= self.stable.seq) {
+            let state = CheckpointState { nonces, app };
+            let digest = state.digest();
+            self.states.insert(seq, state);
+            let checkpoint = self.broadcast(&BftMessage::Checkpoint {
+                seq,
+                chain,
+                state: digest,
+            });
+            self.record_checkpoint(checkpoint, seq, chain, digest);
+        }
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn record_checkpoint(&mut self, checkpoint: Signed, seq: u64, chain: Digest, state: Digest) {
+        let quorum = self.quorum();
+        let signers = self.checkpoints.entry((seq, chain, state)).or_default();
+        signers.insert(checkpoint.from, checkpoint);
+        if signers.len() >= quorum {
+            let checkpoints = signers.values().take(quorum).cloned().collect();
+            self.stabilize(CheckpointProof {
+                seq,
+                chain,
+                state,
+                checkpoints,
+            });
+        }
+    }
+
+    /// Moves the low watermark to the stable checkpoint and discards the older protocol state
+    /// and executed requests.
+    ///
+    /// A replica behind the checkpoint fetches its state or the requests it missed from the
+    /// replicas that signed it.
+    fn stabilize(&mut self, proof: CheckpointProof) {
+        if proof.seq <= self.stable.seq {
+            return;
+        }
+        info!("Checkpoint {} is stable", proof.seq);
+        self.slots.retain(|seq, _| *seq > proof.seq);
+        self.checkpoints.retain(|(seq, _, _), _| *seq > proof.seq);
+        self.executed.retain(|seq, _| *seq > proof.seq);
+        self.executed_at.retain(|_, seq| *seq > proof.seq);
+        self.executed_checkpoints.retain(|seq, _| *seq >= proof.seq);
+        self.states.retain(|seq, _| *seq >= proof.seq);
+        if self.last_executed < proof.seq {
+            for checkpoint in &proof.checkpoints {
+                if checkpoint.from != self.id {
+                    let fetch = self.sign(&BftMessage::Fetch {
+                        after: self.last_executed,
+                    });
+                    self.outbox.push(Output::Send(checkpoint.from, fetch));
+                }
+            }
+        }
+        self.next_seq = self.next_seq.max(proof.seq + 1);
+        self.stable = proof;
+        self.assign_pending();
+    }
+
+    /// Replies to a replica behind with the state at the stable checkpoint, as the requests up
+    /// to it are discarded, or with the next page of requests executed after `after`.
+    fn on_fetch(&mut self, from: NodeId, after: u64) {
+        if after >= self.last_executed {
+            return;
+        }
+        let reply = if after < self.stable.seq {
+            let Some(state) = self.states.get(&self.stable.seq) else {
+                return;
+            };
+            BftMessage::State {
+                checkpoint: self.stable.clone(),
+                state: state.clone(),
+            }
+        } else {
+            // A page holds at least one request, however large
+            let mut budget = self.fetch_page_size;
+            let mut requests = vec![];
+            for request in self.executed.range(after + 1..).map(|(_, request)| request) {
+                let size = request.as_ref().map_or(0, |request| request.entry.len());
+                if !requests.is_empty() && size > budget {
+                    break;
+                }
+                budget = budget.saturating_sub(size);
+                requests.push(request.clone());
+            }
+            BftMessage::Entries { after, requests }
+        };
+        let reply = self.sign(&reply);
+        self.outbox.push(Output::Send(from, reply));
+    }
+
+    /// Collects the pages fetched from a replica, and executes them once they reach the stable
+    /// checkpoint and match its chain.
+    fn on_entries(&mut self, from: NodeId, after: u64, requests: Vec<Option<Request>>) {
+        if self.stable.seq <= self.last_executed || requests.is_empty() {
+            return;
+        }
+        let missing = (self.stable.seq - self.last_executed) as usize;
+        let fetched = self.fetched.entry(from).or_default();
+        if after != self.last_executed + fetched.len() as u64 {
+            return;
+        }
+        fetched.extend(requests);
+        fetched.truncate(missing);
+        if fetched.len() < missing {
+            let after = self.last_executed + fetched.len() as u64;
+            let fetch = self.sign(&BftMessage::Fetch { after });
+            self.outbox.push(Output::Send(from, fetch));
+            return;
+        }
+        let requests = self.fetched.remove(&from).unwrap_or_default();
+        let chain = requests.iter().fold(self.chain, |chain, request| {
+            hash(&[&chain, &digest_of(request)])
+        });
+        if chain != self.stable.chain {
+            warn!(
+                "Dropping entries from {:?} not matching the stable checkpoint",
+                from
+            );
+            return;
+        }
+        self.fetched.clear();
+        for (seq, request) in (self.last_executed + 1..).zip(requests) {
+            self.execute(seq, request);
+        }
+        self.try_execute();
+    }
+
+    /// Skips to the stable checkpoint of another replica, if this one is behind it, once its
+    /// proof and state are checked.
+    fn on_state(&mut self, from: NodeId, checkpoint: CheckpointProof, state: CheckpointState) {
+        if checkpoint.seq <= self.last_executed {
+            return;
+        }
+        if !self.valid_checkpoint(&checkpoint) || state.digest() != checkpoint.state {
+            warn!("Dropping checkpoint state from {:?} without proof", from);
+            return;
+        }
+        let seq = checkpoint.seq;
+        info!("Restoring checkpoint {} from {:?}", seq, from);
+        self.last_executed = seq;
+        self.chain = checkpoint.chain;
+        self.nonces = state.nonces.clone();
+        self.executed.clear();
+        self.executed_at.clear();
+        self.fetched.clear();
+        self.pending.retain(|_, (request, _)| {
+            !self
+                .nonces
+                .get(&request.origin)
+                .is_some_and(|nonces| nonces.contains(request.nonce))
+        });
+        self.outbox.push(Output::Restore(seq, state.app.clone()));
+        self.stabilize(checkpoint);
+        if seq >= self.stable.seq {
+            self.states.insert(seq, state);
+        }
+        if self.last_executed < self.stable.seq {
+            let fetch = self.sign(&BftMessage::Fetch { after: seq });
+            self.outbox.push(Output::Send(from, fetch));
+        }
+        self.try_execute();
+    }
+
+    fn start_view_change(&mut self, view: u64, now: Instant) {
+        if view <= self.view {
+            return;
+        }
+        info!("Starting view change to view {}", view);
+        self.view = view;
+        self.active = false;
+        self.view_change_at = now;
+        let prepared = self
+            .slots
+            .values()
+            .filter_map(|slot| slot.prepared.clone())
+            .collect();
+        let view_change = self.broadcast(&BftMessage::ViewChange {
+            view,
+            checkpoint: self.stable.clone(),
+            prepared,
+        });
+        self.view_changes
+            .entry(view)
+            .or_default()
+            .insert(self.id, view_change);
+        self.maybe_send_new_view(view, now);
+    }
+
+    fn valid_checkpoint(&self, proof: &CheckpointProof) -> bool {
+        if proof.seq == 0 {
+            return proof.chain == NULL_DIGEST && proof.state == NULL_DIGEST;
+        }
+        let mut signers = proof
+            .checkpoints
+            .iter()
+            .filter(|c| c.verify(&self.keys))
+            .filter(|c| {
+                c.message()
+                    == Some(BftMessage::Checkpoint {
+                        seq: proof.seq,
+                        chain: proof.chain,
+                        state: proof.state,
+                    })
+            })
+            .map(|c| c.from)
+            .collect::<Vec<_>>();
+        signers.sort();
+        signers.dedup();
+        signers.len() >= self.quorum()
+    }
+
+    /// Returns the view, sequence number and request of a valid prepared certificate.
+    fn valid_prepared(&self, proof: &PreparedProof) -> Option<(u64, u64, Option<Request>)> {
+        if !proof.pre_prepare.verify(&self.keys) {
+            return None;
+        }
+        let Some(BftMessage::PrePrepare { view, seq, request }) = proof.pre_prepare.message()
+        else {
+            return None;
+        };
+        if proof.pre_prepare.from != self.primary(view) {
+            return None;
+        }
+        let digest = digest_of(&request);
+        let mut signers = proof
+            .prepares
+            .iter()
+            .filter(|p| p.from != proof.pre_prepare.from && p.verify(&self.keys))
+            .filter(|p| p.message() == Some(BftMessage::Prepare { view, seq, digest }))
+            .map(|p| p.from)
+            .collect::<Vec<_>>();
+        signers.sort();
+        signers.dedup();
+        (signers.len() >= 2 * self.f).then_some((view, seq, request))
+    }
+
+    fn valid_view_change(
+        &self,
+        view: u64,
+        checkpoint: &CheckpointProof,
+        prepared: &[PreparedProof],
+    ) -> bool {
+        self.valid_checkpoint(checkpoint)
+            && prepared.iter().all(|proof| {
+                self.valid_prepared(proof)
+                    .is_some_and(|(prepared_view, _, _)| prepared_view < view)
+            })
+    }
+
+    fn on_view_change(
+        &mut self,
+        view_change: Signed,
+        view: u64,
+        checkpoint: &CheckpointProof,
+        prepared: &[PreparedProof],
+        now: Instant,
+    ) {
+        if view < self.view || (view == self.view && self.active) {
+            return;
+        }
+        if !self.valid_view_change(view, checkpoint, prepared) {
+            warn!("Dropping invalid view change from {:?}", view_change.from);
+            return;
+        }
+        self.view_changes
+            .entry(view)
+            .or_default()
+            .insert(view_change.from, view_change);
+
+        // Join the smallest view that f + 1 replicas, so at least a correct one, moved to
+        let mut ahead = self
+            .view_changes
+            .range(self.view + 1..)
+            .flat_map(|(view, senders)| senders.keys().map(move |sender| (*sender, *view)))
+            .collect::<BTreeMap<_, _>>();
+        ahead.remove(&self.id);
+        if ahead.len() > self.f {
+            if let Some(min_view) = ahead.values().min().copied() {
+                self.start_view_change(min_view, now);
+            }
+        }
+        self.maybe_send_new_view(view, now);
+    }
+
+    /// Computes the stable checkpoint and the requests to propose again in the new view from
+    /// `2f + 1` valid view changes.
+    fn new_view_content(
+        &self,
+        view: u64,
+        view_changes: &[Signed],
+    ) -> Option<(CheckpointProof, BTreeMap<u64, Option<Request>>)> {
+        let mut checkpoint = CheckpointProof::default();
+        let mut selected: BTreeMap<u64, (u64, Option<Request>)> = BTreeMap::new();
+        for view_change in view_changes {
+            let Some(BftMessage::ViewChange {
+                view: change_view,
+                checkpoint: change_checkpoint,
+                prepared,
+            }) = view_change.message()
+            else {
+                return None;
+            };
+            if change_view != view {
+                return None;
+            }
+            if change_checkpoint.seq > checkpoint.seq {
+                checkpoint = change_checkpoint;
+            }
+            for proof in &prepared {
+                let (prepared_view, seq, request) = self.valid_prepared(proof)?;
+                if selected
+                    .get(&seq)
+                    .is_none_or(|(selected_view, _)| *selected_view < prepared_view)
+                {
+                    selected.insert(seq, (prepared_view, request));
+                }
+            }
+        }
+        let max_seq = selected
+            .keys()
+            .next_back()
+            .copied()
+            .unwrap_or_default()
+            .max(checkpoint.seq);
+        let requests = (checkpoint.seq + 1..=max_seq)
+            .map(|seq| {
+                let request = selected.get(&seq).and_then(|(_, r)| r.clone());
+                (seq, request)
+            })
+            .collect();
+        Some((checkpoint, requests))
+    }
+
+    fn maybe_send_new_view(&mut self, view: u64, now: Instant) {
+        if self.primary(view) != self.id
+            || view != self.view
+            || self.active
+            || self.new_view_sent >= Some(view)
+        {
+            return;
+        }
+        let view_changes = self
+            .view_changes
+            .get(&view)
+            .map(|senders| senders.values().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        if view_changes.len() < self.quorum() {
+            return;
+        }
+        let view_changes = view_changes[..self.quorum()].to_vec();
+        let Some((checkpoint, requests)) = self.new_view_content(view, &view_changes) else {
+            return;
+        };
+        let pre_prepares = requests
+            .into_iter()
+            .map(|(seq, request)| self.sign(&BftMessage::PrePrepare { view, seq, request }))
+            .collect::<Vec<_>>();
+        self.new_view_sent = Some(view);
+        self.broadcast(&BftMessage::NewView {
+            view,
+            view_changes,
+            pre_prepares: pre_prepares.clone(),
+        });
+        self.enter_view(view, checkpoint, pre_prepares, now);
+    }
+
+    fn on_new_view(
+        &mut self,
+        from: NodeId,
+        view: u64,
+        view_changes: Vec<Signed>,
+        pre_prepares: Vec<Signed>,
+        now: Instant,
+    ) {
+        if from != self.primary(view) || view < self.view || (view == self.view && self.active) {
+            return;
+        }
+        let mut senders = view_changes
+            .iter()
+            .filter(|view_change| view_change.verify(&self.keys))
+            .filter(|view_change| match view_change.message() {
+                Some(BftMessage::ViewChange {
+                    view: change_view,
+                    checkpoint,
+                    prepared,
+                }) => change_view == view && self.valid_view_change(view, &checkpoint, &prepared),
+                _ => false,
+            })
+            .map(|view_change| view_change.from)
+            .collect::<Vec<_>>();
+        senders.sort();
+        senders.dedup();
+        if senders.len() < self.quorum() || senders.len() != view_changes.len() {
+            warn!("Dropping new view {} without valid view changes", view);
+            return;
+        }
+        let Some((checkpoint, expected)) = self.new_view_content(view, &view_changes) else {
+            return;
+        };
+        let proposed = pre_prepares
+            .iter()
+            .filter(|pre_prepare| pre_prepare.from == from && pre_prepare.verify(&self.keys))
+            .filter_map(|pre_prepare| match pre_prepare.message() {
+                Some(BftMessage::PrePrepare {
+                    view: pre_prepare_view,
+                    seq,
+                    request,
+                }) if pre_prepare_view == view => Some((seq, digest_of(&request))),
+                _ => None,
+            })
+            .collect::<BTreeMap<_, _>>();
+        let expected_digests = expected
+            .iter()
+            .map(|(seq, request)| (*seq, digest_of(request)))
+            .collect::<BTreeMap<_, _>>();
+        if proposed != expected_digests || proposed.len() != pre_prepares.len() {
+            warn!("Dropping new view {} with unjustified proposals", view);
+            return;
+        }
+        self.enter_view(view, checkpoint, pre_prepares, now);
+    }
+
+    fn enter_view(
+        &mut self,
+        view: u64,
+        checkpoint: CheckpointProof,
+        pre_prepares: Vec<Signed>,
+        now: Instant,
+    ) {
+        info!(
+            "Entering view {} with primary {:?}",
+            view,
+            self.primary(view)
+        );
+        self.view = view;
+        self.active = true;
+        self.view_change_at = now;
+        self.view_changes
+            .retain(|change_view, _| *change_view > view);
+        for (_, since) in self.pending.values_mut() {
+            *since = now;
+        }
+        self.stabilize(checkpoint);
+        let mut max_seq = self.stable.seq.max(self.last_executed);
+        for pre_prepare in pre_prepares {
+            if let Some(BftMessage::PrePrepare { seq, request, .. }) = pre_prepare.message() {
+                max_seq = max_seq.max(seq);
+                if self.in_window(seq) {
+                    self.accept_pre_prepare(pre_prepare, view, seq, request);
+                }
+            }
+        }
+        self.next_seq = max_seq + 1;
+        self.assign_pending();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    struct Cluster {
+        keys: BTreeMap<NodeId, Arc<NodeKey>>,
+        replicas: BTreeMap<NodeId, Replica>,
+        executed: BTreeMap<NodeId, Vec<(u64, Request)>>,
+        /// Number of requests executed by each replica, standing for the state of the application.
+        apps: BTreeMap<NodeId, u64>,
+        queue: VecDeque<(NodeId, Signed)>,
+        /// Replicas whose messages are neither delivered nor sent.
+        isolated: Vec<NodeId>,
+        now: Instant,
+    }
+
+    impl Cluster {
+        fn new(n: u8) -> Self {
+            let keys = (1..=n)
+                .map(|id| (NodeId(id), Arc::new(NodeKey::generate())))
+                .collect::<BTreeMap<_, _>>();
+            let public_keys = keys
+                .iter()
+                .map(|(id, key)| (*id, key.public_key()))
+                .collect::<BTreeMap<_, _>>();
+            let now = Instant::now();
+            let replicas = keys
+                .iter()
+                .map(|(id, key)| {
+                    let replica = Replica::new(
+                        *id,
+                        key.clone(),
+                        public_keys.clone(),
+                        Duration::from_secs(1),
+                        now,
+                    )
+                    .unwrap();
+                    (*id, replica)
+                })
+                .collect();
+            Self {
+                keys,
+                replicas,
+                executed: BTreeMap::new(),
+                apps: BTreeMap::new(),
+                queue: VecDeque::new(),
+                isolated: vec![],
+                now,
+            }
+        }
+
+        fn route(&mut self, from: NodeId, outputs: Vec<Output>) {
+            for output in outputs {
+                match output {
+                    Output::Broadcast(signed) => {
+                        for to in self.replicas.keys().filter(|to| **to != from) {
+                            self.queue.push_back((*to, signed.clone()));
+                        }
+                    }
+                    Output::Send(to, signed) => self.queue.push_back((to, signed)),
+                    Output::Execute(seq, request) => {
+                        *self.apps.entry(from).or_default() += 1;
+                        self.executed.entry(from).or_default().push((seq, request))
+                    }
+                    Output::Checkpoint(seq) => {
+                        let app = serialize(&self.apps.get(&from).copied().unwrap_or_default());
+                        let replica = self.replicas.get_mut(&from).unwrap();
+                        let outputs = replica.checkpoint(seq, app.unwrap());
+                        self.route(from, outputs);
+                    }
+                    Output::Restore(_, app) => {
+                        self.apps.insert(from, deserialize(&app).unwrap());
+                    }
+                }
+            }
+        }
+
+        fn deliver(&mut self, to: NodeId, signed: &Signed) {
+            if self.isolated.contains(&to) || self.isolated.contains(&signed.from) {
+                return;
+            }
+            let outputs = self
+                .replicas
+                .get_mut(&to)
+                .unwrap()
+                .handle(&signed.to_bytes(), self.now);
+            self.route(to, outputs);
+        }
+
+        fn run(&mut self) {
+            while let Some((to, signed)) = self.queue.pop_front() {
+                self.deliver(to, &signed);
+            }
+        }
+
+        fn propose(&mut self, origin: u8, nonce: u64) -> Request {
+            self.propose_entry(origin, nonce, vec![origin, nonce as u8])
+        }
+
+        fn propose_entry(&mut self, origin: u8, nonce: u64, entry: Vec<u8>) -> Request {
+            let now = self.now;
+            let replica = self.replicas.get_mut(&NodeId(origin)).unwrap();
+            let request = replica.request(nonce, entry);
+            let outputs = replica.propose(request.clone(), now);
+            self.route(NodeId(origin), outputs);
+            self.run();
+            request
+        }
+
+        fn tick(&mut self, elapsed: Duration) {
+            self.now += elapsed;
+            let ids = self
+                .replicas
+                .keys()
+                .copied()
+                .filter(|id| !self.isolated.contains(id))
+                .collect::<Vec<_>>();
+            for id in ids {
+                let now = self.now;
+                let outputs = self.replicas.get_mut(&id).unwrap().tick(now);
+                self.route(id, outputs);
+            }
+            self.run();
+        }
+
+        fn executed(&self, id: u8) -> Vec<(u64, Request)> {
+            self.executed.get(&NodeId(id)).cloned().unwrap_or_default()
+        }
+    }
+
+    #[test]
+    fn test_commit_on_every_replica() {
+        let mut cluster = Cluster::new(4);
+        let request = cluster.propose(2, 1);
+        for id in 1..=4 {
+            assert_eq!(cluster.executed(id), vec![(1, request.clone())]);
+        }
+    }
+
+    #[test]
+    fn test_commit_with_silent_replica() {
+        let mut cluster = Cluster::new(4);
+        cluster.isolated.push(NodeId(4));
+        let first = cluster.propose(2, 1);
+        let second = cluster.propose(3, 1);
+        for id in 1..=3 {
+            assert_eq!(
+                cluster.executed(id),
+                vec![(1, first.clone()), (2, second.clone())]
+            );
+        }
+        assert!(cluster.executed(4).is_empty());
+    }
+
+    #[test]
+    fn test_reject_forged_messages() {
+        let mut cluster = Cluster::new(4);
+        let forged = Signed::new(
+            &NodeKey::generate(),
+            NodeId(1),
+            &BftMessage::PrePrepare {
+                view: 0,
+                seq: 1,
+                request: Some(Request::new(
+                    &cluster.keys[&NodeId(3)],
+                    NodeId(3),
+                    1,
+                    vec![1],
+                )),
+            },
+        );
+        let outputs = cluster
+            .replicas
+            .get_mut(&NodeId(2))
+            .unwrap()
+            .handle(&forged.to_bytes(), cluster.now);
+        assert!(outputs.is_empty());
+    }
+
+    #[test]
+    fn test_reject_forged_and_replayed_requests() {
+        let mut cluster = Cluster::new(4);
+        let request = cluster.propose(2, 1);
+        let pre_prepare = |cluster: &mut Cluster, request: Request| {
+            let signed = Signed::new(
+                &cluster.keys[&NodeId(1)],
+                NodeId(1),
+                &BftMessage::PrePrepare {
+                    view: 0,
+                    seq: 2,
+                    request: Some(request),
+                },
+            );
+            for to in 2..=4 {
+                cluster.deliver(NodeId(to), &signed);
+            }
+            cluster.run();
+        };
+
+        // The primary replays the entry of replica 2 with a fresh nonce, which 2 did not sign
+        let mut replayed = request.clone();
+        replayed.nonce = 2;
+        pre_prepare(&mut cluster, replayed);
+        for id in 2..=4 {
+            assert_eq!(cluster.replicas[&NodeId(id)].last_executed(), 1);
+        }
+
+        // A request reusing an executed nonce is ordered but not executed
+        let reused = cluster.replicas[&NodeId(2)].request(1, vec![0xff]);
+        pre_prepare(&mut cluster, reused);
+        for id in 2..=4 {
+            assert_eq!(cluster.replicas[&NodeId(id)].last_executed(), 2);
+            assert_eq!(cluster.executed(id), vec![(1, request.clone())]);
+        }
+    }
+
+    #[test]
+    fn test_equivocating_primary_is_replaced() {
+        let mut cluster = Cluster::new(4);
+        // The primary of view 0 is malicious and only sends what is crafted below
+        cluster.isolated.push(NodeId(1));
+        let request = cluster.propose(2, 1);
+        let primary_key = cluster.keys[&NodeId(1)].clone();
+        let forged = Request::new(&primary_key, NodeId(1), 1, vec![0xff]);
+        let pre_prepare = |request: &Request| {
+            Signed::new(
+                &primary_key,
+                NodeId(1),
+                &BftMessage::PrePrepare {
+                    view: 0,
+                    seq: 1,
+                    request: Some(request.clone()),
+                },
+            )
+        };
+        let to_2 = pre_prepare(&request);
+        let to_others = pre_prepare(&forged);
+        for (to, signed) in [(2, to_2), (3, to_others.clone()), (4, to_others)] {
+            let outputs = cluster
+                .replicas
+                .get_mut(&NodeId(to))
+                .unwrap()
+                .handle(&signed.to_bytes(), cluster.now);
+            cluster.route(NodeId(to), outputs);
+        }
+        cluster.run();
+        // Neither request gathers a commit quorum
+        for id in 2..=4 {
+            assert!(cluster.executed(id).is_empty());
+        }
+
+        // The pending request times out and replica 2 becomes the primary of view 1
+        cluster.tick(Duration::from_secs(2));
+        let expected = cluster.executed(2);
+        assert!(expected.iter().any(|(_, executed)| *executed == request));
+        for id in 2..=4 {
+            assert_eq!(cluster.replicas[&NodeId(id)].view(), 1);
+            assert_eq!(cluster.executed(id), expected);
+        }
+    }
+
+    #[test]
+    fn test_stable_checkpoint() {
+        let mut cluster = Cluster::new(4);
+        for nonce in 1..=CHECKPOINT_INTERVAL {
+            cluster.propose(2, nonce);
+        }
+        for replica in cluster.replicas.values() {
+            assert_eq!(replica.stable.seq, CHECKPOINT_INTERVAL);
+            assert!(replica.slots.is_empty());
+            assert!(replica.executed.is_empty());
+            assert!(replica.executed_at.is_empty());
+            assert!(replica.valid_checkpoint(&replica.stable));
+        }
+    }
+
+    #[test]
+    fn test_skip_nonces_below_floor() {
+        let mut nonces = Nonces::default();
+        assert!(nonces.record(2, 1));
+        assert!(nonces.record(5, CHECKPOINT_INTERVAL + 1));
+        nonces.compact(CHECKPOINT_INTERVAL);
+        assert!(!nonces.record(1, CHECKPOINT_INTERVAL + 2));
+        assert!(nonces.record(3, CHECKPOINT_INTERVAL + 3));
+        assert!(!nonces.record(5, CHECKPOINT_INTERVAL + 4));
+        assert_eq!(nonces.executed.len(), 2);
+    }
+
+    #[test]
+    fn test_catch_up_with_checkpoint_state() {
+        let mut cluster = Cluster::new(4);
+        cluster.isolated.push(NodeId(4));
+        cluster.propose(2, 1);
+        // Replica 4 cannot execute the next requests, and learns that it is behind from the
+        // checkpoint, when the others discard the request it missed
+        cluster.isolated.clear();
+        for nonce in 2..=CHECKPOINT_INTERVAL {
+            cluster.propose(2, nonce);
+        }
+        let request = cluster.propose(3, 1);
+        let replica = &cluster.replicas[&NodeId(4)];
+        assert_eq!(replica.last_executed(), CHECKPOINT_INTERVAL + 1);
+        assert_eq!(replica.stable, cluster.replicas[&NodeId(1)].stable);
+        assert_eq!(
+            cluster.executed(4),
+            vec![(CHECKPOINT_INTERVAL + 1, request)]
+        );
+        assert_eq!(cluster.apps[&NodeId(4)], cluster.apps[&NodeId(1)]);
+    }
+
+    #[test]
+    fn test_fetch_entries_in_pages() {
+        let mut cluster = Cluster::new(4);
+        for replica in cluster.replicas.values_mut() {
+            replica.fetch_page_size = 8;
+        }
+        cluster.isolated.push(NodeId(4));
+        for nonce in 1..=10 {
+            cluster.propose_entry(2, nonce, vec![nonce as u8; 4]);
+        }
+        cluster.isolated.clear();
+
+        let fetch = Signed::new(
+            &cluster.keys[&NodeId(4)],
+            NodeId(4),
+            &BftMessage::Fetch { after: 0 },
+        );
+        let outputs = cluster
+            .replicas
+            .get_mut(&NodeId(1))
+            .unwrap()
+            .handle(&fetch.to_bytes(), cluster.now);
+        let [Output::Send(_, reply)] = &outputs[..] else {
+            panic!("unexpected reply {:?}", outputs);
+        };
+        assert!(matches!(
+            reply.message(),
+            Some(BftMessage::Entries { after: 0, requests }) if requests.len() == 2
+        ));
+
+        // Replica 4 is shown a checkpoint that the others have not made stable yet, so they
+        // still have the requests up to it
+        let chain = cluster.replicas[&NodeId(1)].chain;
+        let state = CheckpointState::default().digest();
+        let message = BftMessage::Checkpoint {
+            seq: 10,
+            chain,
+            state,
+        };
+        let checkpoints = (1..=3)
+            .map(|id| Signed::new(&cluster.keys[&NodeId(id)], NodeId(id), &message))
+            .collect();
+        let replica = cluster.replicas.get_mut(&NodeId(4)).unwrap();
+        replica.stabilize(CheckpointProof {
+            seq: 10,
+            chain,
+            state,
+            checkpoints,
+        });
+        let outputs = std::mem::take(&mut replica.outbox);
+        cluster.route(NodeId(4), outputs);
+        cluster.run();
+        assert_eq!(cluster.executed(4), cluster.executed(1));
+    }
+}
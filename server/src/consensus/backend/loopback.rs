@@ -0,0 +1,102 @@
+//! In-memory consensus backend for tests.
+//!
+//! Entries are applied directly to the `HashStore` of every node of the group, in the order they
+//! are proposed, so multi-node logic can be exercised deterministically without sockets nor
+//! elections.
+
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use log::warn;
+use tokio::sync::broadcast;
+
+use crate::consensus::raft::HashStore;
+use crate::domain::error::SecretServerError;
+use crate::domain::model::NodeId;
+
+use super::{ConsensusBackend, ConsensusEvent, ConsensusStatus, Role};
+
+/// Consensus backend of a node of an in-memory group.
+///
+/// A proposal is applied to every store of the group before `propose` returns the result of the
+/// store of the proposing node. The first node of the group is reported as the leader.
+#[derive(Debug, Clone)]
+pub struct LoopbackBackend {
+    store: HashStore,
+    group: Arc<Mutex<Vec<HashStore>>>,
+}
+
+impl LoopbackBackend {
+    /// Creates a single node group.
+    pub fn new(store: HashStore) -> Self {
+        Self::group(vec![store]).remove(0)
+    }
+
+    /// Creates a group of the given stores and returns the backend of every node, in order.
+    pub fn group(stores: Vec<HashStore>) -> Vec<Self> {
+        let group = Arc::new(Mutex::new(stores.clone()));
+        stores
+            .into_iter()
+            .map(|store| Self {
+                store,
+                group: group.clone(),
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl ConsensusBackend for LoopbackBackend {
+    async fn propose(&self, entry: Vec<u8>) -> Result<Vec<u8>, SecretServerError> {
+        // The lock orders the proposals of every node
+        let mut group = self.group.lock()?;
+        let mut response = Ok(vec![]);
+        for store in group.iter_mut() {
+            let result = store.apply_entry(&entry);
+            if store.node_id() == self.store.node_id() {
+                response = result;
+            } else if let Err(e) = result {
+                warn!("Cannot apply entry on node {:?}: {}", store.node_id(), e);
+            }
+        }
+        response
+    }
+
+    async fn leave(&self) -> Result<(), SecretServerError> {
+        self.remove(self.store.node_id()).await
+    }
+
+    async fn remove(&self, node_id: NodeId) -> Result<(), SecretServerError> {
+        self.group
+            .lock()?
+            .retain(|store| store.node_id() != node_id);
+        Ok(())
+    }
+
+    fn status(&self) -> ConsensusStatus {
+        let node_id = self.store.node_id();
+        let voters = self
+            .group
+            .lock()
+            .map(|group| group.iter().map(HashStore::node_id).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let leader = voters.first().copied();
+        ConsensusStatus {
+            node_id,
+            role: match leader {
+                Some(leader) if leader == node_id => Role::Leader,
+                _ if voters.contains(&node_id) => Role::Follower,
+                _ => Role::Unknown,
+            },
+            leader,
+            voters,
+            term: None,
+            last_log_index: None,
+            last_applied: None,
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ConsensusEvent> {
+        self.store.subscribe()
+    }
+}
@@ -0,0 +1,153 @@
+//! Consensus backends replicating the entries applied to the `HashStore`.
+//!
+//! Every backend orders the proposed entries in a replicated log and applies them through
+//! `HashStore::apply_entry`, so the state machine and the signature checks are shared. The
+//! `riteraft` backend is always available, the `openraft` one behind the `openraft` feature and
+//! the byzantine fault tolerant `bft` one behind the `bft` feature.
+//! The in-memory `loopback` backend applies entries directly to the stores of a group of nodes
+//! and is meant for tests.
+
+pub mod loopback;
+pub mod riteraft;
+
+#[cfg(feature = "openraft")]
+pub mod openraft;
+
+#[cfg(feature = "bft")]
+pub mod bft;
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+use crate::conf::settings::{ConsensusBackendKind, Settings};
+use crate::domain::error::SecretServerError;
+use crate::domain::model::NodeId;
+
+use super::bootstrap::StartMode;
+use super::raft::HashStore;
+use super::signing::NodeKey;
+
+/// Role of the node in the consensus group.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Leader,
+    Follower,
+    Candidate,
+    Learner,
+    /// The backend does not expose the role of the node.
+    Unknown,
+}
+
+/// Point in time view of the consensus group from this node.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConsensusStatus {
+    pub node_id: NodeId,
+    pub role: Role,
+    pub leader: Option<NodeId>,
+    /// Voting members of the consensus group, empty if the backend does not expose them.
+    pub voters: Vec<NodeId>,
+    /// Current term of the consensus group, if the backend exposes it.
+    pub term: Option<u64>,
+    /// Index of the last entry of the local log, if the backend exposes it.
+    pub last_log_index: Option<u64>,
+    /// Index of the last entry applied to the store, if the backend exposes it.
+    pub last_applied: Option<u64>,
+}
+
+/// Event published by the consensus layer of a node.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ConsensusEvent {
+    /// The node `node_id` has been admitted as a cluster member.
+    MemberAdmitted { node_id: NodeId },
+    /// The node `node_id` has been removed from the cluster members.
+    MemberRemoved { node_id: NodeId },
+    /// The leader of the consensus group has changed.
+    LeaderChanged { leader: Option<NodeId> },
+    /// The voting members of the consensus group have changed.
+    MembershipChanged { voters: Vec<NodeId> },
+    /// The node `node_id` has been admitted again with another key, replacing the former node.
+    MemberReplaced { node_id: NodeId },
+}
+
+/// Replicated log the cluster agrees on.
+#[async_trait]
+pub trait ConsensusBackend: Send + Sync {
+    /// Proposes the encoded entry and returns the result of applying it to the state machine
+    /// once committed.
+    async fn propose(&self, entry: Vec<u8>) -> Result<Vec<u8>, SecretServerError>;
+
+    /// Removes this node from the consensus group.
+    async fn leave(&self) -> Result<(), SecretServerError>;
+
+    /// Removes the node `node_id` from the consensus group through a configuration change.
+    async fn remove(&self, node_id: NodeId) -> Result<(), SecretServerError>;
+
+    /// Returns `false` if the consensus group is configured statically and nodes can neither
+    /// leave it nor be removed from it.
+    fn is_reconfigurable(&self) -> bool {
+        true
+    }
+
+    /// Returns the current role, leader and voting members as seen by this node.
+    fn status(&self) -> ConsensusStatus;
+
+    /// Subscribes to the membership and leadership events of this node.
+    fn subscribe(&self) -> broadcast::Receiver<ConsensusEvent>;
+}
+
+/// Handle of the task running the consensus protocol.
+pub type ConsensusTask = JoinHandle<Result<(), SecretServerError>>;
+
+/// Starts the consensus backend selected in the settings, applying entries to `store`.
+///
+/// With the Raft backends, the node bootstraps a new cluster in the `StartMode::Bootstrap` mode,
+/// otherwise it joins the cluster through the first reachable of its peers. The `bft` backend uses the replicas of its
+/// settings and checks that `node_key` is the key configured for this node, the `openraft`
+/// backend signs its requests to the other nodes with it.
+#[cfg_attr(
+    not(any(feature = "bft", feature = "openraft")),
+    allow(unused_variables)
+)]
+pub async fn init_consensus(
+    settings: &Settings,
+    store: HashStore,
+    node_key: Arc<NodeKey>,
+    logger: Logger,
+) -> Result<(ConsensusTask, Arc<dyn ConsensusBackend>), SecretServerError> {
+    let peers = StartMode::from_settings(settings).peer_raft_addrs();
+    match settings.consensus_backend() {
+        ConsensusBackendKind::Riteraft => {
+            let (task, backend) =
+                self::riteraft::RiteraftBackend::start(settings.raft_addr(), &peers, store, logger)
+                    .await?;
+            Ok((task, Arc::new(backend)))
+        }
+        #[cfg(feature = "openraft")]
+        ConsensusBackendKind::Openraft => {
+            let (task, backend) = self::openraft::OpenraftBackend::start(
+                settings.raft_addr(),
+                &peers,
+                store,
+                node_key,
+            )
+            .await?;
+            Ok((task, Arc::new(backend)))
+        }
+        #[cfg(feature = "bft")]
+        ConsensusBackendKind::Bft => {
+            let bft = settings
+                .bft()
+                .expect("bft settings are checked when settings are loaded");
+            let (task, backend) =
+                self::bft::BftBackend::start_http(bft, settings.raft_addr(), store, node_key)
+                    .await?;
+            Ok((task, Arc::new(backend)))
+        }
+    }
+}
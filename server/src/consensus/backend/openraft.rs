@@ -0,0 +1,848 @@
+//! Consensus backend based on the `openraft` crate.
+//!
+//! The Raft RPCs are served as JSON over HTTP on `raft_addr`, next to the endpoints followers
+//! use to forward proposals and membership changes to the leader. The log is kept in memory as
+//! with `riteraft`, and the state machine is the shared `HashStore`.
+//!
+//! Unlike `riteraft`, joining the consensus group goes through the leader, so when admission is
+//! required only nodes admitted with `Message::Admit` are added to the group.
+//!
+//! Every request to these endpoints is a `SignedRequest`, signed with the key of the sending
+//! node less than `MAX_REQUEST_AGE` ago. Nodes only serve the requests of the members whose key
+//! they know from the replicated membership table, except for the join of a node that is not
+//! admitted yet when admission is not required. A node that has not applied any admission yet,
+//! such as a node joining the group, cannot tell the members apart and serves the requests of
+//! any signer until it does.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Debug;
+use std::io::Cursor;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::{post, web, App, HttpServer};
+use async_trait::async_trait;
+use log::{info, warn};
+use openraft::error::{
+    ClientWriteError, ForwardToLeader, InstallSnapshotError, NetworkError, RPCError, RaftError,
+    RemoteError,
+};
+use openraft::network::{RaftNetwork, RaftNetworkFactory};
+use openraft::raft::{
+    AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotRequest, InstallSnapshotResponse,
+    VoteRequest, VoteResponse,
+};
+use openraft::storage::{Adaptor, LogState, Snapshot};
+use openraft::{
+    AnyError, BasicNode, Config, Entry, EntryPayload, ErrorSubject, ErrorVerb, LogId, Raft,
+    RaftLogReader, RaftSnapshotBuilder, RaftStorage, ServerState, SnapshotMeta, StorageError,
+    StorageIOError, StoredMembership, TokioRuntime, Vote,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, RwLock};
+
+use crate::consensus::bootstrap::retry_peers;
+use crate::consensus::raft::{HashStore, Member};
+use crate::consensus::signing::{verify, NodeKey};
+use crate::domain::error::SecretServerError;
+use crate::domain::model::NodeId;
+
+use super::{ConsensusBackend, ConsensusEvent, ConsensusStatus, ConsensusTask, Role};
+
+/// Entry proposed to the log, encoded as in `wire`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Request(pub Vec<u8>);
+
+/// Result of applying a `Request` to the `HashStore`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Response(pub Vec<u8>);
+
+openraft::declare_raft_types!(
+    pub TypeConfig: D = Request, R = Response, NodeId = u64, Node = BasicNode,
+    Entry = openraft::Entry<TypeConfig>, SnapshotData = Cursor<Vec<u8>>, AsyncRuntime = TokioRuntime
+);
+
+type OpenRaft = Raft<
+    TypeConfig,
+    Network,
+    Adaptor<TypeConfig, Arc<LogStore>>,
+    Adaptor<TypeConfig, Arc<LogStore>>,
+>;
+
+/// Membership change requested to the leader.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum MembershipRequest {
+    Join { node_id: u64, raft_addr: String },
+    Leave { node_id: u64 },
+}
+
+/// Longest time a `SignedRequest` is served after it was signed, or before if the clocks of the
+/// nodes drift apart.
+const MAX_REQUEST_AGE: Duration = Duration::from_secs(30);
+
+const SIGNING_CONTEXT: &[u8] = b"shared-secrets openraft request v1";
+
+/// Request to the endpoint `path` of a node, signed by the node `from`.
+///
+/// The body is the JSON encoded request of the endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SignedRequest {
+    from: u64,
+    /// Time the request was signed, in microseconds since the Unix epoch.
+    sent_at: u64,
+    #[serde(with = "hex::serde")]
+    public_key: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    body: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    signature: Vec<u8>,
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default()
+}
+
+fn request_signed_bytes(path: &str, from: u64, sent_at: u64, body: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(SIGNING_CONTEXT.len() + path.len() + 17 + body.len());
+    bytes.extend_from_slice(SIGNING_CONTEXT);
+    bytes.extend_from_slice(path.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(&from.to_be_bytes());
+    bytes.extend_from_slice(&sent_at.to_be_bytes());
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+impl SignedRequest {
+    /// Signs the request to the endpoint `path` on behalf of `from`.
+    fn new<Req: Serialize>(
+        key: &NodeKey,
+        from: u64,
+        path: &str,
+        request: &Req,
+    ) -> Result<Self, serde_json::Error> {
+        let body = serde_json::to_vec(request)?;
+        let sent_at = now_micros();
+        let signature = key.sign_bytes(&request_signed_bytes(path, from, sent_at, &body));
+        Ok(Self {
+            from,
+            sent_at,
+            public_key: key.public_key(),
+            body,
+            signature,
+        })
+    }
+
+    /// Checks that the request to the endpoint `path` was signed recently by a member of
+    /// `members` and returns its body.
+    ///
+    /// Requests of other nodes are only served if `members` is empty, as the node cannot tell
+    /// the members apart, or if `newcomer` accepts their body.
+    fn verify<Req: DeserializeOwned>(
+        &self,
+        path: &str,
+        members: &HashMap<NodeId, Member>,
+        newcomer: impl FnOnce(&Req) -> bool,
+    ) -> Result<Req, SecretServerError> {
+        let refused = |reason: &str| {
+            SecretServerError::AdmissionError(format!(
+                "request of node {} to /raft/{} refused: {}",
+                self.from, path, reason
+            ))
+        };
+        if self.sent_at.abs_diff(now_micros()) > MAX_REQUEST_AGE.as_micros() as u64 {
+            return Err(refused("expired"));
+        }
+        if !verify(
+            &self.public_key,
+            &request_signed_bytes(path, self.from, self.sent_at, &self.body),
+            &self.signature,
+        ) {
+            return Err(refused("invalid signature"));
+        }
+        let request = serde_json::from_slice(&self.body)
+            .map_err(|e| SecretServerError::InvalidRequest(e.to_string()))?;
+        let accepted = match members.get(&to_node_id(self.from)) {
+            Some(member) => member.public_key == self.public_key,
+            None => members.is_empty() || newcomer(&request),
+        };
+        if !accepted {
+            return Err(refused("not signed by a member"));
+        }
+        Ok(request)
+    }
+}
+
+fn to_node_id(id: u64) -> NodeId {
+    NodeId(id as u8)
+}
+
+fn backend_error(e: impl ToString) -> SecretServerError {
+    SecretServerError::BackendError(e.to_string())
+}
+
+fn storage_error(verb: ErrorVerb, e: &SecretServerError) -> StorageError<u64> {
+    StorageIOError::new(ErrorSubject::StateMachine, verb, AnyError::new(e)).into()
+}
+
+#[derive(Debug, Clone)]
+struct StoredSnapshot {
+    meta: SnapshotMeta<u64, BasicNode>,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct LogStoreState {
+    vote: Option<Vote<u64>>,
+    log: BTreeMap<u64, Entry<TypeConfig>>,
+    last_purged: Option<LogId<u64>>,
+    last_applied: Option<LogId<u64>>,
+    last_membership: StoredMembership<u64, BasicNode>,
+    snapshot: Option<StoredSnapshot>,
+    snapshot_index: u64,
+}
+
+/// In memory Raft log applying the committed entries to the `HashStore`.
+#[derive(Debug)]
+pub struct LogStore {
+    store: HashStore,
+    state: RwLock<LogStoreState>,
+}
+
+impl LogStore {
+    fn new(store: HashStore) -> Self {
+        Self {
+            store,
+            state: RwLock::new(LogStoreState::default()),
+        }
+    }
+}
+
+#[async_trait]
+impl RaftLogReader<TypeConfig> for Arc<LogStore> {
+    async fn get_log_state(&mut self) -> Result<LogState<TypeConfig>, StorageError<u64>> {
+        let state = self.state.read().await;
+        let last_log_id = state
+            .log
+            .values()
+            .next_back()
+            .map(|entry| entry.log_id)
+            .or(state.last_purged);
+        Ok(LogState {
+            last_purged_log_id: state.last_purged,
+            last_log_id,
+        })
+    }
+
+    async fn try_get_log_entries<RB: RangeBounds<u64> + Clone + Debug + Send + Sync>(
+        &mut self,
+        range: RB,
+    ) -> Result<Vec<Entry<TypeConfig>>, StorageError<u64>> {
+        let state = self.state.read().await;
+        Ok(state.log.range(range).map(|(_, e)| e.clone()).collect())
+    }
+}
+
+#[async_trait]
+impl RaftSnapshotBuilder<TypeConfig> for Arc<LogStore> {
+    async fn build_snapshot(&mut self) -> Result<Snapshot<TypeConfig>, StorageError<u64>> {
+        let data = self
+            .store
+            .to_snapshot()
+            .map_err(|e| storage_error(ErrorVerb::Read, &e))?;
+        let mut state = self.state.write().await;
+        state.snapshot_index += 1;
+        let meta = SnapshotMeta {
+            last_log_id: state.last_applied,
+            last_membership: state.last_membership.clone(),
+            snapshot_id: format!(
+                "{}-{}",
+                state.last_applied.map(|id| id.index).unwrap_or_default(),
+                state.snapshot_index
+            ),
+        };
+        state.snapshot = Some(StoredSnapshot {
+            meta: meta.clone(),
+            data: data.clone(),
+        });
+        Ok(Snapshot {
+            meta,
+            snapshot: Box::new(Cursor::new(data)),
+        })
+    }
+}
+
+#[async_trait]
+impl RaftStorage<TypeConfig> for Arc<LogStore> {
+    type LogReader = Self;
+    type SnapshotBuilder = Self;
+
+    async fn save_vote(&mut self, vote: &Vote<u64>) -> Result<(), StorageError<u64>> {
+        self.state.write().await.vote = Some(*vote);
+        Ok(())
+    }
+
+    async fn read_vote(&mut self) -> Result<Option<Vote<u64>>, StorageError<u64>> {
+        Ok(self.state.read().await.vote)
+    }
+
+    async fn get_log_reader(&mut self) -> Self::LogReader {
+        self.clone()
+    }
+
+    async fn append_to_log<I>(&mut self, entries: I) -> Result<(), StorageError<u64>>
+    where
+        I: IntoIterator<Item = Entry<TypeConfig>> + Send,
+    {
+        let mut state = self.state.write().await;
+        for entry in entries {
+            state.log.insert(entry.log_id.index, entry);
+        }
+        Ok(())
+    }
+
+    async fn delete_conflict_logs_since(
+        &mut self,
+        log_id: LogId<u64>,
+    ) -> Result<(), StorageError<u64>> {
+        self.state
+            .write()
+            .await
+            .log
+            .retain(|index, _| *index < log_id.index);
+        Ok(())
+    }
+
+    async fn purge_logs_upto(&mut self, log_id: LogId<u64>) -> Result<(), StorageError<u64>> {
+        let mut state = self.state.write().await;
+        state.last_purged = Some(log_id);
+        state.log = state.log.split_off(&(log_id.index + 1));
+        Ok(())
+    }
+
+    async fn last_applied_state(
+        &mut self,
+    ) -> Result<(Option<LogId<u64>>, StoredMembership<u64, BasicNode>), StorageError<u64>> {
+        let state = self.state.read().await;
+        Ok((state.last_applied, state.last_membership.clone()))
+    }
+
+    /// Applies the committed entries to the `HashStore`.
+    ///
+    /// Entries the store cannot apply are answered with an empty response, as rejected ones,
+    /// instead of stopping the node.
+    async fn apply_to_state_machine(
+        &mut self,
+        entries: &[Entry<TypeConfig>],
+    ) -> Result<Vec<Response>, StorageError<u64>> {
+        let mut state = self.state.write().await;
+        let mut responses = Vec::with_capacity(entries.len());
+        for entry in entries {
+            state.last_applied = Some(entry.log_id);
+            let response = match &entry.payload {
+                EntryPayload::Blank => vec![],
+                EntryPayload::Normal(Request(data)) => {
+                    self.store.clone().apply_entry(data).unwrap_or_else(|e| {
+                        warn!("Cannot apply entry {}: {}", entry.log_id, e);
+                        vec![]
+                    })
+                }
+                EntryPayload::Membership(membership) => {
+                    state.last_membership =
+                        StoredMembership::new(Some(entry.log_id), membership.clone());
+                    vec![]
+                }
+            };
+            responses.push(Response(response));
+        }
+        Ok(responses)
+    }
+
+    async fn get_snapshot_builder(&mut self) -> Self::SnapshotBuilder {
+        self.clone()
+    }
+
+    async fn begin_receiving_snapshot(
+        &mut self,
+    ) -> Result<Box<Cursor<Vec<u8>>>, StorageError<u64>> {
+        Ok(Box::new(Cursor::new(Vec::new())))
+    }
+
+    async fn install_snapshot(
+        &mut self,
+        meta: &SnapshotMeta<u64, BasicNode>,
+        snapshot: Box<Cursor<Vec<u8>>>,
+    ) -> Result<(), StorageError<u64>> {
+        let data = snapshot.into_inner();
+        self.store
+            .clone()
+            .restore_snapshot(&data)
+            .map_err(|e| storage_error(ErrorVerb::Write, &e))?;
+        let mut state = self.state.write().await;
+        state.last_applied = meta.last_log_id;
+        state.last_membership = meta.last_membership.clone();
+        state.snapshot = Some(StoredSnapshot {
+            meta: meta.clone(),
+            data,
+        });
+        Ok(())
+    }
+
+    async fn get_current_snapshot(
+        &mut self,
+    ) -> Result<Option<Snapshot<TypeConfig>>, StorageError<u64>> {
+        let state = self.state.read().await;
+        Ok(state.snapshot.as_ref().map(|snapshot| Snapshot {
+            meta: snapshot.meta.clone(),
+            snapshot: Box::new(Cursor::new(snapshot.data.clone())),
+        }))
+    }
+}
+
+/// Raft RPC client sending JSON requests, signed by the node `node_id`, to the `raft_addr` of
+/// the other nodes.
+#[derive(Debug, Clone)]
+pub struct Network {
+    client: reqwest::Client,
+    node_id: u64,
+    node_key: Arc<NodeKey>,
+}
+
+/// Connection to the node `target` listening on `raft_addr`.
+#[derive(Debug)]
+pub struct Connection {
+    client: reqwest::Client,
+    node_id: u64,
+    node_key: Arc<NodeKey>,
+    target: u64,
+    raft_addr: String,
+}
+
+#[async_trait]
+impl RaftNetworkFactory<TypeConfig> for Network {
+    type Network = Connection;
+
+    async fn new_client(&mut self, target: u64, node: &BasicNode) -> Self::Network {
+        Connection {
+            client: self.client.clone(),
+            node_id: self.node_id,
+            node_key: self.node_key.clone(),
+            target,
+            raft_addr: node.addr.clone(),
+        }
+    }
+}
+
+impl Connection {
+    async fn send<Req, Resp, E>(
+        &self,
+        path: &str,
+        request: &Req,
+    ) -> Result<Resp, RPCError<u64, BasicNode, E>>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+        E: std::error::Error + DeserializeOwned,
+    {
+        let request = SignedRequest::new(&self.node_key, self.node_id, path, request)
+            .map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
+        let result: Result<Resp, E> = self
+            .client
+            .post(format!("http://{}/raft/{}", self.raft_addr, path))
+            .json(&request)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| RPCError::Network(NetworkError::new(&e)))?
+            .json()
+            .await
+            .map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
+        result.map_err(|e| RPCError::RemoteError(RemoteError::new(self.target, e)))
+    }
+}
+
+#[async_trait]
+impl RaftNetwork<TypeConfig> for Connection {
+    async fn send_append_entries(
+        &mut self,
+        request: AppendEntriesRequest<TypeConfig>,
+    ) -> Result<AppendEntriesResponse<u64>, RPCError<u64, BasicNode, RaftError<u64>>> {
+        self.send("append", &request).await
+    }
+
+    async fn send_install_snapshot(
+        &mut self,
+        request: InstallSnapshotRequest<TypeConfig>,
+    ) -> Result<
+        InstallSnapshotResponse<u64>,
+        RPCError<u64, BasicNode, RaftError<u64, InstallSnapshotError>>,
+    > {
+        self.send("snapshot", &request).await
+    }
+
+    async fn send_vote(
+        &mut self,
+        request: VoteRequest<u64>,
+    ) -> Result<VoteResponse<u64>, RPCError<u64, BasicNode, RaftError<u64>>> {
+        self.send("vote", &request).await
+    }
+}
+
+/// Consensus backend based on the `openraft` crate.
+#[derive(Clone)]
+pub struct OpenraftBackend {
+    node_id: u64,
+    raft: OpenRaft,
+    store: HashStore,
+    node_key: Arc<NodeKey>,
+    client: reqwest::Client,
+}
+
+impl Debug for OpenraftBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OpenraftBackend")
+            .field("node_id", &self.node_id)
+            .field("store", &self.store)
+            .finish()
+    }
+}
+
+impl OpenraftBackend {
+    /// Starts a Raft node on `raft_addr`, joining the cluster through `peers` or bootstrapping a
+    /// new one if there are none, and returns the task serving the Raft RPCs with the backend.
+    ///
+    /// The requests to the other nodes are signed with `node_key`.
+    pub async fn start(
+        raft_addr: &str,
+        peers: &[String],
+        store: HashStore,
+        node_key: Arc<NodeKey>,
+    ) -> Result<(ConsensusTask, Self), SecretServerError> {
+        let node_id = u64::from(*store.node_id());
+        let config = Config {
+            cluster_name: "shared-secrets".to_string(),
+            heartbeat_interval: 250,
+            election_timeout_min: 500,
+            election_timeout_max: 1000,
+            ..Default::default()
+        }
+        .validate()
+        .map_err(backend_error)?;
+        let (log_store, state_machine) = Adaptor::new(Arc::new(LogStore::new(store.clone())));
+        let client = reqwest::Client::new();
+        let network = Network {
+            client: client.clone(),
+            node_id,
+            node_key: node_key.clone(),
+        };
+        let raft = Raft::new(node_id, Arc::new(config), network, log_store, state_machine)
+            .await
+            .map_err(backend_error)?;
+        let backend = Self {
+            node_id,
+            raft,
+            store,
+            node_key,
+            client,
+        };
+
+        let data = web::Data::new(backend.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .service(append)
+                .service(vote)
+                .service(snapshot)
+                .service(propose)
+                .service(membership)
+        })
+        .bind(raft_addr)
+        .map_err(backend_error)?
+        .run();
+        let task = tokio::spawn(async move { server.await.map_err(backend_error) });
+
+        tokio::spawn(backend.clone().publish_events());
+        if peers.is_empty() {
+            info!("running in leader mode");
+            let members = BTreeMap::from([(node_id, BasicNode::new(raft_addr))]);
+            if let Err(e) = backend.raft.initialize(members).await {
+                info!("Cluster already initialized: {}", e);
+            }
+        } else {
+            info!("running in follower mode");
+            tokio::spawn(backend.clone().join(peers.to_vec(), raft_addr.to_string()));
+        }
+        Ok((task, backend))
+    }
+
+    /// Asks the cluster to add this node to the consensus group through the peers, retrying
+    /// with a backoff.
+    async fn join(self, peers: Vec<String>, raft_addr: String) {
+        let request = MembershipRequest::Join {
+            node_id: self.node_id,
+            raft_addr,
+        };
+        let joined = retry_peers("join the consensus group", &peers, |peer| {
+            let (backend, request, peer) = (self.clone(), request.clone(), peer.clone());
+            async move {
+                backend
+                    .forward::<_, ()>(&peer, "membership", &request)
+                    .await
+                    .map(|_| peer)
+            }
+        })
+        .await;
+        match joined {
+            Ok(peer) => info!("Joined the consensus group through {}", peer),
+            Err(e) => warn!("Node could not join the consensus group: {}", e),
+        }
+    }
+
+    /// Publishes the leadership and membership changes seen in the Raft metrics.
+    async fn publish_events(self) {
+        let mut metrics = self.raft.metrics();
+        let mut last = self.status();
+        while metrics.changed().await.is_ok() {
+            let status = self.status();
+            if status.leader != last.leader {
+                self.store.publish(ConsensusEvent::LeaderChanged {
+                    leader: status.leader,
+                });
+            }
+            if status.voters != last.voters {
+                self.store.publish(ConsensusEvent::MembershipChanged {
+                    voters: status.voters.clone(),
+                });
+            }
+            last = status;
+        }
+    }
+
+    fn leader_addr(&self) -> Result<Option<String>, SecretServerError> {
+        let metrics = self.raft.metrics().borrow().clone();
+        if metrics.state == ServerState::Leader {
+            return Ok(None);
+        }
+        let leader = metrics
+            .current_leader
+            .ok_or_else(|| backend_error("no leader elected"))?;
+        metrics
+            .membership_config
+            .membership()
+            .get_node(&leader)
+            .map(|node| Some(node.addr.clone()))
+            .ok_or_else(|| backend_error("unknown leader address"))
+    }
+
+    /// Sends the request to the endpoint `path` of the node on `raft_addr`.
+    async fn forward<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        raft_addr: &str,
+        path: &str,
+        request: &Req,
+    ) -> Result<Resp, SecretServerError> {
+        let request = SignedRequest::new(&self.node_key, self.node_id, path, request)
+            .map_err(backend_error)?;
+        let result: Result<Resp, String> = self
+            .client
+            .post(format!("http://{}/raft/{}", raft_addr, path))
+            .json(&request)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(backend_error)?
+            .json()
+            .await
+            .map_err(backend_error)?;
+        result.map_err(SecretServerError::BackendError)
+    }
+
+    /// Applies the membership change on the leader, or forwards it to the leader.
+    async fn change_membership(&self, request: MembershipRequest) -> Result<(), SecretServerError> {
+        if let Some(leader_addr) = self.leader_addr()? {
+            return self.forward(&leader_addr, "membership", &request).await;
+        }
+        let mut voters = self
+            .raft
+            .metrics()
+            .borrow()
+            .membership_config
+            .membership()
+            .voter_ids()
+            .collect::<BTreeSet<_>>();
+        match request {
+            MembershipRequest::Join { node_id, raft_addr } => {
+                if self.store.admission_required()
+                    && !self
+                        .store
+                        .members()?
+                        .get(&to_node_id(node_id))
+                        .is_some_and(|member| member.raft_addr == raft_addr)
+                {
+                    return Err(SecretServerError::AdmissionError(format!(
+                        "node {} on {} has not been admitted",
+                        node_id, raft_addr
+                    )));
+                }
+                self.raft
+                    .add_learner(node_id, BasicNode::new(raft_addr), true)
+                    .await
+                    .map_err(backend_error)?;
+                voters.insert(node_id);
+            }
+            MembershipRequest::Leave { node_id } => {
+                voters.remove(&node_id);
+            }
+        }
+        self.raft
+            .change_membership(voters, false)
+            .await
+            .map_err(backend_error)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ConsensusBackend for OpenraftBackend {
+    async fn propose(&self, entry: Vec<u8>) -> Result<Vec<u8>, SecretServerError> {
+        match self.raft.client_write(Request(entry.clone())).await {
+            Ok(response) => Ok(response.data.0),
+            Err(RaftError::APIError(ClientWriteError::ForwardToLeader(ForwardToLeader {
+                leader_node: Some(leader),
+                ..
+            }))) => self.forward(&leader.addr, "propose", &entry).await,
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn leave(&self) -> Result<(), SecretServerError> {
+        self.change_membership(MembershipRequest::Leave {
+            node_id: self.node_id,
+        })
+        .await
+    }
+
+    async fn remove(&self, node_id: NodeId) -> Result<(), SecretServerError> {
+        self.change_membership(MembershipRequest::Leave {
+            node_id: u64::from(*node_id),
+        })
+        .await
+    }
+
+    fn status(&self) -> ConsensusStatus {
+        let metrics = self.raft.metrics().borrow().clone();
+        ConsensusStatus {
+            node_id: to_node_id(self.node_id),
+            role: match metrics.state {
+                ServerState::Leader => Role::Leader,
+                ServerState::Follower => Role::Follower,
+                ServerState::Candidate => Role::Candidate,
+                ServerState::Learner => Role::Learner,
+                ServerState::Shutdown => Role::Unknown,
+            },
+            leader: metrics.current_leader.map(to_node_id),
+            voters: metrics
+                .membership_config
+                .membership()
+                .voter_ids()
+                .map(to_node_id)
+                .collect(),
+            term: Some(metrics.current_term),
+            last_log_index: metrics.last_log_index,
+            last_applied: metrics.last_applied.map(|log_id| log_id.index),
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ConsensusEvent> {
+        self.store.subscribe()
+    }
+}
+
+type Backend = web::Data<OpenraftBackend>;
+
+impl OpenraftBackend {
+    /// Checks the request to the endpoint `path` and returns its body, see `SignedRequest::verify`.
+    fn authenticate<Req: DeserializeOwned>(
+        &self,
+        path: &str,
+        request: &SignedRequest,
+        newcomer: impl FnOnce(&Req) -> bool,
+    ) -> Result<Req, SecretServerError> {
+        request.verify(path, &self.store.members()?, newcomer)
+    }
+
+    /// Checks a request only members send.
+    fn authenticate_member<Req: DeserializeOwned>(
+        &self,
+        path: &str,
+        request: &SignedRequest,
+    ) -> Result<Req, SecretServerError> {
+        self.authenticate(path, request, |_| false)
+    }
+}
+
+#[post("/raft/append")]
+async fn append(
+    backend: Backend,
+    request: web::Json<SignedRequest>,
+) -> Result<web::Json<Result<AppendEntriesResponse<u64>, RaftError<u64>>>, SecretServerError> {
+    let request = backend.authenticate_member("append", &request)?;
+    Ok(web::Json(backend.raft.append_entries(request).await))
+}
+
+#[post("/raft/vote")]
+async fn vote(
+    backend: Backend,
+    request: web::Json<SignedRequest>,
+) -> Result<web::Json<Result<VoteResponse<u64>, RaftError<u64>>>, SecretServerError> {
+    let request = backend.authenticate_member("vote", &request)?;
+    Ok(web::Json(backend.raft.vote(request).await))
+}
+
+#[post("/raft/snapshot")]
+async fn snapshot(
+    backend: Backend,
+    request: web::Json<SignedRequest>,
+) -> Result<
+    web::Json<Result<InstallSnapshotResponse<u64>, RaftError<u64, InstallSnapshotError>>>,
+    SecretServerError,
+> {
+    let request = backend.authenticate_member("snapshot", &request)?;
+    Ok(web::Json(backend.raft.install_snapshot(request).await))
+}
+
+#[post("/raft/propose")]
+async fn propose(
+    backend: Backend,
+    request: web::Json<SignedRequest>,
+) -> Result<web::Json<Result<Vec<u8>, String>>, SecretServerError> {
+    let entry = backend.authenticate_member("propose", &request)?;
+    Ok(web::Json(
+        backend.propose(entry).await.map_err(|e| e.to_string()),
+    ))
+}
+
+/// Applies or forwards a membership change of a member, or the join of a node on its own behalf
+/// when admission is not required.
+#[post("/raft/membership")]
+async fn membership(
+    backend: Backend,
+    request: web::Json<SignedRequest>,
+) -> Result<web::Json<Result<(), String>>, SecretServerError> {
+    let from = request.from;
+    let open = !backend.store.admission_required();
+    let change = backend.authenticate("membership", &request, |change: &MembershipRequest| {
+        open && matches!(change, MembershipRequest::Join { node_id, .. } if *node_id == from)
+    })?;
+    Ok(web::Json(
+        backend
+            .change_membership(change)
+            .await
+            .map_err(|e| e.to_string()),
+    ))
+}
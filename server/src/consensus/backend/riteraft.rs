@@ -0,0 +1,202 @@
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use log::info;
+use riteraft::{Mailbox, Raft, Result as RiteResult, Store};
+use slog::Logger;
+use tokio::sync::{broadcast, oneshot};
+
+use crate::consensus::bootstrap::retry_peers;
+use crate::consensus::raft::HashStore;
+use crate::domain::error::SecretServerError;
+use crate::domain::model::NodeId;
+
+use super::{ConsensusBackend, ConsensusEvent, ConsensusStatus, ConsensusTask, Role};
+
+#[async_trait]
+impl Store for HashStore {
+    /// Applies the given message to the store and returns the result.
+    async fn apply(&mut self, message: &[u8]) -> RiteResult<Vec<u8>> {
+        Ok(self.apply_entry(message)?)
+    }
+
+    /// Returns a snapshot of the store.
+    async fn snapshot(&self) -> RiteResult<Vec<u8>> {
+        Ok(self.to_snapshot()?)
+    }
+
+    /// Restores the store from the given snapshot.
+    async fn restore(&mut self, snapshot: &[u8]) -> RiteResult<()> {
+        Ok(self.restore_snapshot(snapshot)?)
+    }
+}
+
+/// Consensus backend based on the `riteraft` crate.
+///
+/// `riteraft` does not expose the role of the node, the leader nor the voting members, so
+/// `status` only reports the node ID and the only events published are member admissions and
+/// removals. It can neither remove another node from the Raft group nor let a follower leave
+/// it, both are answered with a `NotSupported` error. Nor can it refuse the `join` of a node that
+/// was not admitted with `Message::Admit`: such a node receives the log, but the members reject
+/// its entries.
+///
+/// A lost node cannot rejoin the cluster: `riteraft` keeps the Raft log in memory and gives a
+/// node joining on the address of a former one its Raft ID back, so the restarted node fails
+/// once told the commit index of the former log. A node joining on another address connects to
+/// every member first, so it cannot join while the lost node is still in the Raft group.
+///
+/// With the `failpoints` feature, the `mailbox-send-<node_id>` failpoint makes the proposals of
+/// the node fail before they reach the Raft log.
+pub struct RiteraftBackend {
+    store: HashStore,
+    /// Mailbox of the Raft node, replaced on every attempt to join the cluster.
+    mailbox: Arc<RwLock<Mailbox>>,
+}
+
+impl std::fmt::Debug for RiteraftBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RiteraftBackend")
+            .field("store", &self.store)
+            .finish()
+    }
+}
+
+impl RiteraftBackend {
+    /// Starts a Raft node on `raft_addr`, joining the cluster through the first of `peers`
+    /// accepting it or leading a new one if there are none, and returns the task running it with
+    /// the backend.
+    ///
+    /// A failed join is retried through the next peer with `retry_peers`, each attempt with a new
+    /// Raft node since `riteraft` consumes it.
+    pub async fn start(
+        raft_addr: &str,
+        peers: &[String],
+        store: HashStore,
+        logger: Logger,
+    ) -> Result<(ConsensusTask, Self), SecretServerError> {
+        let raft = Raft::new(raft_addr.to_owned(), store.clone(), logger.clone());
+        let mailbox = Arc::new(RwLock::new(raft.mailbox()));
+        let task = if peers.is_empty() {
+            info!("running in leader mode");
+            tokio::spawn(run_on_own_runtime(async move { Ok(raft.lead().await?) }))
+        } else {
+            info!("running in follower mode");
+            let (peers, raft_addr) = (peers.to_vec(), raft_addr.to_owned());
+            let (store, mailbox) = (store.clone(), mailbox.clone());
+            let mut raft = Some(raft);
+            tokio::spawn(async move {
+                retry_peers("join the cluster", &peers, |peer| {
+                    let raft = raft.take().unwrap_or_else(|| {
+                        Raft::new(raft_addr.clone(), store.clone(), logger.clone())
+                    });
+                    let replaced = mailbox
+                        .write()
+                        .map(|mut mailbox| *mailbox = raft.mailbox())
+                        .map_err(SecretServerError::from);
+                    let peer = peer.clone();
+                    async move {
+                        replaced?;
+                        info!("joining the cluster through {}", peer);
+                        // The join only returns an error if the node could not join
+                        run_on_own_runtime(async move { Ok(raft.join(peer).await?) }).await
+                    }
+                })
+                .await
+            })
+        };
+        Ok((task, Self { store, mailbox }))
+    }
+}
+
+/// Runs the Raft node on a runtime of its own until it stops.
+///
+/// `riteraft` spawns its server and node loop as detached tasks, which would keep running and
+/// listening on the Raft address once the node is stopped or failed to join. Dropping the
+/// returned future, e.g. by aborting the task running it, shuts the runtime down, which stops
+/// them.
+async fn run_on_own_runtime<F>(run: F) -> Result<(), SecretServerError>
+where
+    F: Future<Output = Result<(), SecretServerError>> + Send + 'static,
+{
+    let (_stop, stopped) = oneshot::channel::<()>();
+    let (done, result) = oneshot::channel();
+    std::thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                let _ = done.send(Err(SecretServerError::BackendError(e.to_string())));
+                return;
+            }
+        };
+        let outcome = runtime.block_on(async move {
+            tokio::select! {
+                outcome = run => outcome,
+                // The future waiting for the node was dropped
+                _ = stopped => Ok(()),
+            }
+        });
+        runtime.shutdown_background();
+        let _ = done.send(outcome);
+    });
+    result.await.unwrap_or_else(|_| {
+        Err(SecretServerError::BackendError(
+            "the Raft node runtime stopped".to_string(),
+        ))
+    })
+}
+
+#[async_trait]
+impl ConsensusBackend for RiteraftBackend {
+    async fn propose(&self, entry: Vec<u8>) -> Result<Vec<u8>, SecretServerError> {
+        fail::fail_point!(
+            &format!("mailbox-send-{}", self.store.node_id().0),
+            |_| Err(SecretServerError::BackendError(
+                "mailbox-send failpoint".to_string()
+            ))
+        );
+        let mailbox = self.mailbox.read()?.clone();
+        Ok(mailbox.send(entry).await?)
+    }
+
+    async fn leave(&self) -> Result<(), SecretServerError> {
+        // riteraft refuses the configuration changes of the followers without telling why
+        let mailbox = self.mailbox.read()?.clone();
+        mailbox.leave().await.map_err(|_| {
+            SecretServerError::NotSupported(
+                "riteraft only lets the leader leave the Raft group, stop the node to take it \
+                 out of the quorum"
+                    .to_string(),
+            )
+        })
+    }
+
+    async fn remove(&self, node_id: NodeId) -> Result<(), SecretServerError> {
+        if node_id == self.store.node_id() {
+            return self.leave().await;
+        }
+        Err(SecretServerError::NotSupported(format!(
+            "riteraft cannot remove node {} from the Raft group, only the node itself can leave it",
+            *node_id
+        )))
+    }
+
+    fn status(&self) -> ConsensusStatus {
+        ConsensusStatus {
+            node_id: self.store.node_id(),
+            role: Role::Unknown,
+            leader: None,
+            voters: vec![],
+            term: None,
+            last_log_index: None,
+            last_applied: None,
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ConsensusEvent> {
+        self.store.subscribe()
+    }
+}
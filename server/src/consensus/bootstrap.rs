@@ -0,0 +1,241 @@
+//! Bootstrap of a new cluster and retries over the seed peers of the nodes joining it.
+//!
+//! Only the node configured with `bootstrap` creates a cluster, the settings refuse a node with
+//! neither peers nor `bootstrap`. It creates the cluster the first time it starts and records it
+//! in its bootstrap marker, so it joins its peers like every other node once restarted. Without
+//! peers it leads a single node cluster, created again at every start as there is no cluster
+//! left to rejoin. Removing the marker or setting `bootstrap` on a second node still starts a
+//! second cluster.
+
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::conf::settings::{PeerSettings, Settings};
+use crate::domain::error::SecretServerError;
+
+/// Delay before the second round of attempts over the peers.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Maximum delay between two rounds of attempts over the peers.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Number of rounds of attempts over the peers before giving up.
+const MAX_ROUNDS: u32 = 12;
+
+/// How a node enters the consensus group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartMode {
+    /// The node creates the cluster and admits itself as its first member.
+    Bootstrap,
+    /// The node joins the cluster through the first reachable peer.
+    Join(Vec<PeerSettings>),
+}
+
+impl StartMode {
+    /// Returns the start mode of the node configured in `settings`.
+    pub fn from_settings(settings: &Settings) -> Self {
+        let bootstrapped = settings
+            .bootstrap_marker_path()
+            .is_some_and(|path| Path::new(path).exists());
+        if settings.bootstrap() && (settings.peers().is_empty() || !bootstrapped) {
+            StartMode::Bootstrap
+        } else {
+            StartMode::Join(settings.peers().to_vec())
+        }
+    }
+
+    /// Returns the Raft addresses of the peers to join, empty when bootstrapping.
+    pub fn peer_raft_addrs(&self) -> Vec<String> {
+        match self {
+            StartMode::Bootstrap => vec![],
+            StartMode::Join(peers) => peers
+                .iter()
+                .map(|peer| peer.raft_addr().to_string())
+                .collect(),
+        }
+    }
+}
+
+/// Records that the node has bootstrapped the cluster, if it is configured to.
+///
+/// # Errors
+///
+/// Returns an `InvalidStateError` if the marker cannot be written.
+pub fn record_bootstrap(settings: &Settings) -> Result<(), SecretServerError> {
+    let Some(path) = settings
+        .bootstrap_marker_path()
+        .filter(|_| settings.bootstrap())
+    else {
+        return Ok(());
+    };
+    if Path::new(path).exists() {
+        return Ok(());
+    }
+    std::fs::write(path, format!("node_id = {}\n", settings.node_id())).map_err(|e| {
+        SecretServerError::InvalidStateError(format!(
+            "cannot write bootstrap marker {}: {}",
+            path, e
+        ))
+    })?;
+    info!("Cluster bootstrapped, recorded in {}", path);
+    Ok(())
+}
+
+/// Exponential backoff between rounds of attempts over the peers.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            delay: INITIAL_BACKOFF,
+        }
+    }
+}
+
+impl Backoff {
+    /// Returns the delay to wait before the next round and doubles it, up to `MAX_BACKOFF`.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(MAX_BACKOFF);
+        delay
+    }
+}
+
+/// Calls `attempt` with every peer in turn until it succeeds, waiting with an exponential
+/// backoff between rounds over the peers.
+///
+/// # Errors
+///
+/// Returns the last error of `attempt` after `MAX_ROUNDS` rounds, or an `InvalidRequest` if
+/// there are no peers.
+pub async fn retry_peers<P, T, F, Fut>(
+    what: &str,
+    peers: &[P],
+    mut attempt: F,
+) -> Result<T, SecretServerError>
+where
+    P: std::fmt::Debug,
+    F: FnMut(&P) -> Fut,
+    Fut: Future<Output = Result<T, SecretServerError>>,
+{
+    let mut backoff = Backoff::default();
+    let mut last_error =
+        SecretServerError::InvalidRequest(format!("no peer configured to {}", what));
+    for round in 1..=MAX_ROUNDS {
+        for peer in peers {
+            match attempt(peer).await {
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    warn!("Cannot {} through {:?}: {}", what, peer, e);
+                    last_error = e;
+                }
+            }
+        }
+        if peers.is_empty() || round == MAX_ROUNDS {
+            break;
+        }
+        tokio::time::sleep(backoff.next_delay()).await;
+    }
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::default();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(500));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.next_delay(), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_retry_peers_until_one_succeeds() {
+        let peers = vec!["down", "up"];
+        let mut attempts = 0;
+        let result = retry_peers("join", &peers, |peer| {
+            attempts += 1;
+            let (peer, ok) = (*peer, *peer == "up" && attempts > 3);
+            async move {
+                if ok {
+                    Ok(peer)
+                } else {
+                    Err(SecretServerError::BackendError("unreachable".to_string()))
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), "up");
+        assert_eq!(attempts, 4);
+
+        let no_peers: Vec<&str> = vec![];
+        let result: Result<(), _> = retry_peers("join", &no_peers, |_| async { Ok(()) }).await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_start_mode() {
+        let dir = std::env::temp_dir().join(format!("bootstrap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let marker = dir.join("bootstrapped");
+        let settings = Settings::from_toml(&format!(
+            r#"
+            raft_addr = "server-1:7070"
+            http_port = 8080
+            node_id = 1
+            interval_refresh_secs = 10
+            bootstrap = true
+            bootstrap_marker_path = "{}"
+            [[peers]]
+            raft_addr = "server-2:7070"
+            "#,
+            marker.display()
+        ))
+        .unwrap();
+        let _ = std::fs::remove_file(&marker);
+
+        assert_eq!(StartMode::from_settings(&settings), StartMode::Bootstrap);
+        record_bootstrap(&settings).unwrap();
+        assert_eq!(
+            StartMode::from_settings(&settings).peer_raft_addrs(),
+            vec!["server-2:7070".to_string()]
+        );
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    fn test_start_mode_without_marker_path() {
+        let settings = |extra: &str| {
+            Settings::from_toml(&format!(
+                r#"
+                raft_addr = "server-2:7070"
+                http_port = 8080
+                node_id = 2
+                interval_refresh_secs = 10
+                {extra}
+                "#
+            ))
+        };
+        let single_node = settings("bootstrap = true").unwrap();
+        assert_eq!(StartMode::from_settings(&single_node), StartMode::Bootstrap);
+        record_bootstrap(&single_node).unwrap();
+        assert_eq!(StartMode::from_settings(&single_node), StartMode::Bootstrap);
+
+        let follower = settings("[[peers]]\nraft_addr = \"server-1:7070\"").unwrap();
+        assert_eq!(
+            StartMode::from_settings(&follower).peer_raft_addrs(),
+            vec!["server-1:7070".to_string()]
+        );
+    }
+}
@@ -1,20 +1,29 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use bincode::serialize;
-use log::info;
-use riteraft::Mailbox;
+use log::{info, warn};
 use sss_wrap::secret::secret::ShareMeta;
 
+use crate::conf::settings::DEFAULT_REFRESH_BATCH_SIZE;
 use crate::domain::error::SecretServerError;
-use crate::domain::model::ClientId;
+use crate::domain::model::{ClientId, NodeId};
 
-use super::messages::Message;
-use super::raft::HashStore;
+use super::admission::{relay_mac, RELAY_MAC_HEADER};
+use super::backend::{ConsensusBackend, ConsensusEvent, ConsensusStatus};
+use super::messages::{Message, ShareRefresh};
+use super::raft::{HashStore, Member};
+use super::signing::NodeKey;
+use super::wire;
 
 #[derive(Clone)]
 pub struct ConsensusHandler {
     storage: HashStore,
-    mailbox: Arc<Mailbox>,
+    backend: Arc<dyn ConsensusBackend>,
+    node_key: Arc<NodeKey>,
+    refresh_batch_size: usize,
+    peer_http_addrs: Vec<String>,
+    cluster_token: Option<String>,
 }
 
 impl std::fmt::Debug for ConsensusHandler {
@@ -24,101 +33,426 @@ impl std::fmt::Debug for ConsensusHandler {
 }
 
 impl ConsensusHandler {
-    pub fn new(storage: HashStore, mailbox: Arc<Mailbox>) -> Self {
-        Self { storage, mailbox }
+    pub fn new(
+        storage: HashStore,
+        backend: Arc<dyn ConsensusBackend>,
+        node_key: Arc<NodeKey>,
+    ) -> Self {
+        Self {
+            storage,
+            backend,
+            node_key,
+            refresh_batch_size: DEFAULT_REFRESH_BATCH_SIZE,
+            peer_http_addrs: vec![],
+            cluster_token: None,
+        }
+    }
+
+    /// Authenticates the proposals relayed through the peers with the cluster token.
+    pub fn with_cluster_token(mut self, cluster_token: Option<String>) -> Self {
+        self.cluster_token = cluster_token;
+        self
+    }
+
+    /// Relays the proposals the backend refuses through the HTTP API of the peers, see `relay`.
+    pub fn with_peer_http_addrs(mut self, peer_http_addrs: Vec<String>) -> Self {
+        self.peer_http_addrs = peer_http_addrs;
+        self
+    }
+
+    /// Refreshes at most `refresh_batch_size` clients in every proposal of a refresh round.
+    pub fn with_refresh_batch_size(mut self, refresh_batch_size: usize) -> Self {
+        self.refresh_batch_size = refresh_batch_size.max(1);
+        self
+    }
+
+    /// Signs the message with the node key and proposes it to the cluster, through the first peer
+    /// accepting it if the backend refuses it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidStateError` if the replicated state machine rejected the signature.
+    async fn propose(&self, message: &Message) -> Result<Vec<u8>, SecretServerError> {
+        let signed = self.node_key.sign(self.storage.node_id(), message);
+        let response = match self.backend.propose(signed).await {
+            Ok(response) => response,
+            // riteraft only accepts proposals on the leader
+            Err(e @ SecretServerError::ConsensusError(_)) if !self.peer_http_addrs.is_empty() => {
+                warn!(
+                    "Cannot propose {:?}, relaying it through the peers: {}",
+                    message, e
+                );
+                let mut relayed = Err(e);
+                for peer in &self.peer_http_addrs {
+                    relayed = self.propose_through(peer, message).await;
+                    if relayed.is_ok() {
+                        break;
+                    }
+                }
+                return relayed;
+            }
+            Err(e) => return Err(e),
+        };
+        if response.is_empty() {
+            warn!("Proposal {:?} was rejected by the cluster", message);
+            return Err(SecretServerError::InvalidStateError(
+                "proposal rejected by the cluster".to_string(),
+            ));
+        }
+        Ok(response)
     }
 
+    /// Signs the message with the node key and has the peer at `peer_http_addr` propose it, see
+    /// `relay`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `BackendError` if the peer cannot propose it, or an `InvalidStateError` if the
+    /// replicated state machine rejected it.
+    async fn propose_through(
+        &self,
+        peer_http_addr: &str,
+        message: &Message,
+    ) -> Result<Vec<u8>, SecretServerError> {
+        let signed = self.node_key.sign(self.storage.node_id(), message);
+        let mut request = reqwest::Client::new()
+            .post(format!("{}/cluster/propose", peer_http_addr))
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream");
+        if let Some(cluster_token) = &self.cluster_token {
+            request = request.header(
+                RELAY_MAC_HEADER,
+                hex::encode(relay_mac(cluster_token, &signed)),
+            );
+        }
+        let response = request
+            .body(signed)
+            .send()
+            .await
+            .map_err(|e| SecretServerError::BackendError(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(SecretServerError::BackendError(format!(
+                "proposal refused by {} with status {}",
+                peer_http_addr,
+                response.status()
+            )));
+        }
+        let response = response
+            .bytes()
+            .await
+            .map_err(|e| SecretServerError::BackendError(e.to_string()))?;
+        if response.is_empty() {
+            warn!("Proposal {:?} was rejected by the cluster", message);
+            return Err(SecretServerError::InvalidStateError(
+                "proposal rejected by the cluster".to_string(),
+            ));
+        }
+        Ok(response.to_vec())
+    }
+
+    /// Returns the public key the node signs its proposals with.
+    pub fn public_key(&self) -> Vec<u8> {
+        self.node_key.public_key()
+    }
+
+    /// Removes the node from the cluster members, then from the consensus group.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the backend if it cannot take the node out of the consensus group,
+    /// e.g. a `NotSupported` for a `riteraft` follower. The node is no longer a member then, so
+    /// it should be stopped.
     pub async fn leave(&self) -> Result<(), SecretServerError> {
-        self.mailbox.leave().await?;
+        if !self.backend.is_reconfigurable() {
+            // Leaving the members would only prevent the node from proposing
+            return self.backend.leave().await;
+        }
+        info!("Leaving the cluster");
+        self.propose(&Message::Remove {
+            node_id: self.storage.node_id(),
+        })
+        .await?;
+        self.backend.leave().await
+    }
+
+    /// Removes the node `node_id` from the consensus group, then from the cluster members so its
+    /// proposals are rejected.
+    ///
+    /// Other nodes can only be removed through the node that bootstrapped the cluster, as the
+    /// members reject the removals proposed by any other node.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidRequest` if the node is not a cluster member or this node did not
+    /// bootstrap the cluster, or the error of the backend if it cannot change the configuration
+    /// of the consensus group.
+    pub async fn remove_member(&self, node_id: NodeId) -> Result<(), SecretServerError> {
+        if node_id == self.storage.node_id() {
+            return self.leave().await;
+        }
+        if !self.members()?.contains_key(&node_id) {
+            return Err(SecretServerError::InvalidRequest(format!(
+                "node {} is not a cluster member",
+                *node_id
+            )));
+        }
+        if self.storage.bootstrap_node()? != Some(self.storage.node_id()) {
+            return Err(SecretServerError::InvalidRequest(
+                "other members can only be removed through the node that bootstrapped the cluster"
+                    .to_string(),
+            ));
+        }
+        info!("Removing node {:?} from the cluster", node_id);
+        // The node stays a member if the backend cannot remove it from the consensus group
+        self.backend.remove(node_id).await?;
+        self.propose(&Message::Remove { node_id }).await?;
         Ok(())
     }
 
+    /// Returns the consensus status of the node.
+    pub fn status(&self) -> ConsensusStatus {
+        self.backend.status()
+    }
+
+    /// Subscribes to the consensus events of the node.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<ConsensusEvent> {
+        self.backend.subscribe()
+    }
+
+    /// Returns the admitted cluster members.
+    pub fn members(&self) -> Result<HashMap<NodeId, Member>, SecretServerError> {
+        self.storage.members()
+    }
+
     pub fn get(&self, id: ClientId) -> Result<Option<ShareMeta>, SecretServerError> {
         self.storage.get(id)
     }
 
+    /// Returns the number of clients with a share stored.
+    pub fn secret_count(&self) -> Result<usize, SecretServerError> {
+        self.storage.secret_count()
+    }
+
+    /// Returns the number of shares the secret of every stored client is spread over.
+    pub fn share_counts(&self) -> Result<Vec<(ClientId, u8)>, SecretServerError> {
+        let storage = self.storage.storage();
+        let storage = storage.read()?;
+        Ok(storage
+            .iter()
+            .map(|(id, share)| (*id, share.meta.shares_to_create))
+            .collect())
+    }
+
     pub fn insert(&mut self, id: ClientId, share: ShareMeta) -> Result<(), SecretServerError> {
         self.storage.insert(id, share)
     }
 
+    pub fn remove(&mut self, id: ClientId) -> Result<Option<ShareMeta>, SecretServerError> {
+        self.storage.remove(id)
+    }
+
     pub fn is_begin_refresh(&self) -> bool {
         self.storage.is_begin_refresh()
     }
 
+    /// Returns the time the refresh round in progress started, if any.
+    pub fn refreshing_since(&self) -> Option<Instant> {
+        self.storage.refreshing_since()
+    }
+
+    /// Returns `true` if the store of the node is usable.
+    pub fn is_store_healthy(&self) -> bool {
+        self.storage.is_healthy()
+    }
+
+    /// Returns when the share of every stored client is due for a refresh, see
+    /// `HashStore::refresh_due_times`.
+    pub fn refresh_due_times(
+        &self,
+        default_interval: Option<Duration>,
+    ) -> Result<Vec<(ClientId, Instant)>, SecretServerError> {
+        self.storage.refresh_due_times(default_interval)
+    }
+
     pub async fn start_refresh(&self) -> Result<(), SecretServerError> {
         info!("Sending start refresh message to the rest of the participants in the network");
-        let message = serialize(&Message::StartRefresh {
+        self.propose(&Message::StartRefresh {
             node_id: self.storage.node_id(),
-        })?;
-        self.mailbox
-            .send(message)
-            .await
-            .map_err(|e| e.into())
-            .map(|_| ())
+        })
+        .await
+        .map(|_| ())
     }
 
-    pub async fn refresh_secrets(&self) -> Result<(), SecretServerError> {
-        let messages = self
-            .storage
-            .storage()
-            .read()?
-            .iter()
-            .map(|(id, share)| {
-                let poly = share.share.renew_poly(&share.meta);
-                let r = (0..share.meta.shares_to_create)
-                    .map(move |i| {
-                        let share = poly.get_share(i + 1, share.share.ys_len());
-                        info!("Refreshing message with share {:?}", share);
-                        Message::Refresh {
-                            client_id: id.clone(),
-                            new_share: share,
-                        }
-                    })
+    pub async fn admit(
+        &self,
+        node_id: NodeId,
+        raft_addr: &str,
+        public_key: Vec<u8>,
+    ) -> Result<(), SecretServerError> {
+        info!(
+            "Admitting node {:?} on {} as cluster member",
+            node_id, raft_addr
+        );
+        self.propose(&Message::Admit {
+            node_id,
+            raft_addr: raft_addr.to_string(),
+            public_key,
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Proposes the admission of this node on `raft_addr` through the peer at `peer_http_addr`,
+    /// for a node that cannot propose before it knows the leader.
+    pub async fn admit_through(
+        &self,
+        peer_http_addr: &str,
+        raft_addr: &str,
+    ) -> Result<(), SecretServerError> {
+        let node_id = self.storage.node_id();
+        self.propose_through(
+            peer_http_addr,
+            &Message::Admit {
+                node_id,
+                raft_addr: raft_addr.to_string(),
+                public_key: self.public_key(),
+            },
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Proposes an entry signed by another node, which could not propose it itself, and returns
+    /// the result of applying it.
+    ///
+    /// The entry must be signed by a member, or be the admission of a node signed by itself when
+    /// the store would apply it, so that strangers cannot fill the log. The members check the
+    /// signature and the sequence number of the entry again when applying it, so relaying it
+    /// grants the origin nothing it could not propose itself.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidRequest` if the entry cannot be decoded, an `AdmissionError` if it is
+    /// not signed by a member, or the error of the backend if this node cannot propose either.
+    pub async fn relay(&self, entry: Vec<u8>) -> Result<Vec<u8>, SecretServerError> {
+        let decoded =
+            wire::decode(&entry).map_err(|e| SecretServerError::InvalidRequest(e.to_string()))?;
+        if !self.storage.is_authentic(&decoded)? {
+            return Err(SecretServerError::AdmissionError(format!(
+                "relayed entry of node {:?} is not signed by a member",
+                decoded.origin
+            )));
+        }
+        info!("Relaying a proposal of node {:?}", decoded.origin);
+        self.backend.propose(entry).await
+    }
+
+    /// Builds the new shares of the `clients`, or of every stored client, grouped in batches of
+    /// at most `refresh_batch_size` clients.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidRequest` if some of the `clients` have no share stored on this node.
+    fn refresh_batches(
+        &self,
+        clients: Option<&[ClientId]>,
+    ) -> Result<Vec<Vec<ShareRefresh>>, SecretServerError> {
+        let storage = self.storage.storage();
+        let storage = storage.read()?;
+        let clients = match clients {
+            Some(ids) => {
+                let unknown = ids
+                    .iter()
+                    .filter(|id| !storage.contains_key(id))
+                    .map(|id| id.0.to_string())
                     .collect::<Vec<_>>();
-                r
+                if !unknown.is_empty() {
+                    return Err(SecretServerError::InvalidRequest(format!(
+                        "no share stored for clients {}",
+                        unknown.join(", ")
+                    )));
+                }
+                storage
+                    .iter()
+                    .filter(|(id, _)| ids.contains(id))
+                    .collect::<Vec<_>>()
+            }
+            None => storage.iter().collect::<Vec<_>>(),
+        };
+        Ok(clients
+            .chunks(self.refresh_batch_size)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .flat_map(|(id, share)| {
+                        let poly = share.share.renew_poly(&share.meta);
+                        (0..share.meta.shares_to_create).map(move |i| ShareRefresh {
+                            client_id: **id,
+                            new_share: poly.get_share(i + 1, share.share.ys_len()),
+                        })
+                    })
+                    .collect()
             })
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>();
+            .collect())
+    }
+
+    /// Proposes new shares for every stored client, batching `refresh_batch_size` clients in
+    /// each consensus entry.
+    pub async fn refresh_secrets(&self) -> Result<(), SecretServerError> {
+        self.refresh_clients(None).await.map(|_| ())
+    }
+
+    /// Proposes new shares for the `clients`, or for every stored client, batching
+    /// `refresh_batch_size` clients in each consensus entry, and returns the number of clients
+    /// refreshed.
+    pub async fn refresh_clients(
+        &self,
+        clients: Option<&[ClientId]>,
+    ) -> Result<usize, SecretServerError> {
+        let batches = self.refresh_batches(clients)?;
         info!(
-            "Sending refresh messages to the rest of the participants in the network {:?}",
-            messages.len()
+            "Sending {} refresh batches to the rest of the participants in the network",
+            batches.len()
         );
-        for message in messages {
-            let message = serialize(&message)?;
-            let _ = self.mailbox.send(message).await?;
+        let mut refreshed = 0;
+        for refreshes in batches {
+            // Batches carry the new share of every node for each of their clients
+            refreshed += refreshes
+                .iter()
+                .map(|refresh| refresh.client_id)
+                .collect::<HashSet<_>>()
+                .len();
+            self.propose(&Message::RefreshBatch { refreshes }).await?;
         }
-        Ok(())
+        Ok(refreshed)
     }
 
     pub async fn finish_refresh(&self) -> Result<(), SecretServerError> {
         info!("Sending finish refresh message to the rest of the participants in the network");
-        let message = serialize(&Message::FinishRefresh {
+        self.propose(&Message::FinishRefresh {
             node_id: self.storage.node_id(),
-        })?;
-        let _ = self.mailbox.send(message).await?;
+        })
+        .await?;
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use riteraft::Raft;
-    use slog::o;
+    use crate::consensus::backend::loopback::LoopbackBackend;
     use sss_wrap::from_secrets;
     use sss_wrap::secret::secret::Metadata;
+    use sss_wrap::wrapped_sharing::reconstruct;
 
     use super::*;
 
     #[tokio::test]
     async fn test_refresh_secrets_no_secrets() -> Result<(), SecretServerError> {
         let storage = HashStore::new(crate::domain::model::NodeId(1)); // Initialize the storage
-        let raft = Raft::new(
-            "localhost:8080".to_string(),
+        let secret_server = ConsensusHandler::new(
             storage.clone(),
-            slog::Logger::root(slog::Discard, o!()),
+            Arc::new(LoopbackBackend::new(storage.clone())),
+            Arc::new(NodeKey::generate()),
         );
-        let secret_server = ConsensusHandler::new(storage.clone(), Arc::new(raft.mailbox()));
         secret_server.refresh_secrets().await?;
         assert_eq!(storage.storage().read()?.len(), 0);
 
@@ -128,14 +462,17 @@ mod tests {
     #[tokio::test]
     async fn test_refresh_secrets_with_secrets() -> Result<(), SecretServerError> {
         let storage = HashStore::new(crate::domain::model::NodeId(1)); // Initialize the storage
-        let raft = Raft::new(
-            "localhost:8080".to_string(),
+
+        // Refresh the 10 secrets in several batches
+        let secret_server = ConsensusHandler::new(
             storage.clone(),
-            slog::Logger::root(slog::Discard, o!()),
-        );
-        let mail = raft.mailbox();
-        tokio::spawn(raft.lead());
-        let secret_server = ConsensusHandler::new(storage.clone(), Arc::new(mail));
+            Arc::new(LoopbackBackend::new(storage.clone())),
+            Arc::new(NodeKey::generate()),
+        )
+        .with_refresh_batch_size(3);
+        secret_server
+            .admit(NodeId(1), "localhost:8080", secret_server.public_key())
+            .await?;
         let secret_vec = "test-secret".to_string().into_bytes();
         let secrets = from_secrets(secret_vec.clone(), 9, 10, None).unwrap();
         for (i, x) in secrets.clone().into_iter().enumerate() {
@@ -155,4 +492,185 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_refresh_selected_clients() -> Result<(), SecretServerError> {
+        let storage = HashStore::new(NodeId(1));
+        let secret_server = ConsensusHandler::new(
+            storage.clone(),
+            Arc::new(LoopbackBackend::new(storage.clone())),
+            Arc::new(NodeKey::generate()),
+        );
+        secret_server
+            .admit(NodeId(1), "localhost:8080", secret_server.public_key())
+            .await?;
+        let secret_vec = "test-secret".to_string().into_bytes();
+        let shares = from_secrets(secret_vec.clone(), 2, 3, None).unwrap();
+        for id in 1..=3 {
+            secret_server.clone().insert(
+                ClientId(id),
+                ShareMeta::new(
+                    shares[0].clone().into(),
+                    Metadata::new(2, 3, secret_vec.len()),
+                ),
+            )?;
+        }
+
+        let refreshed = secret_server
+            .refresh_clients(Some(&[ClientId(1), ClientId(3)]))
+            .await?;
+        assert_eq!(refreshed, 2);
+        for (id, changed) in [(1, true), (2, false), (3, true)] {
+            let share = storage.get(ClientId(id))?.unwrap().share;
+            assert_eq!(share != shares[0].clone().into(), changed);
+        }
+        assert!(secret_server
+            .refresh_clients(Some(&[ClientId(4)]))
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refresh_secrets_across_nodes() -> Result<(), SecretServerError> {
+        let stores = (1..=3)
+            .map(|id| HashStore::new(NodeId(id)))
+            .collect::<Vec<_>>();
+        let backends = LoopbackBackend::group(stores.clone());
+        let handlers = stores
+            .iter()
+            .zip(backends)
+            .map(|(store, backend)| {
+                ConsensusHandler::new(
+                    store.clone(),
+                    Arc::new(backend),
+                    Arc::new(NodeKey::generate()),
+                )
+            })
+            .collect::<Vec<_>>();
+        let secret_vec = "test-secret".to_string().into_bytes();
+        let shares = from_secrets(secret_vec.clone(), 2, 3, None).unwrap();
+        for (id, handler) in (1..=3).zip(handlers.iter()) {
+            handler
+                .admit(NodeId(id), &format!("node-{}", id), handler.public_key())
+                .await?;
+            handler.clone().insert(
+                ClientId(1),
+                ShareMeta::new(
+                    shares[id as usize - 1].clone().into(),
+                    Metadata::new(2, 3, secret_vec.len()),
+                ),
+            )?;
+        }
+
+        handlers[0].start_refresh().await?;
+        assert!(!handlers[0].is_begin_refresh());
+        assert!(handlers[1].is_begin_refresh() && handlers[2].is_begin_refresh());
+        handlers[0].refresh_secrets().await?;
+        handlers[0].finish_refresh().await?;
+        assert!(!handlers[1].is_begin_refresh() && !handlers[2].is_begin_refresh());
+
+        let refreshed = stores
+            .iter()
+            .map(|store| store.get(ClientId(1)).unwrap().unwrap().share)
+            .collect::<Vec<_>>();
+        for (i, share) in refreshed.iter().enumerate() {
+            assert_ne!(*share, shares[i].clone().into());
+        }
+        // Any 2 refreshed shares still reconstruct the secret
+        let raw_shares = refreshed[1..]
+            .iter()
+            .map(|share| share.clone().into())
+            .collect::<Vec<Vec<u8>>>();
+        assert_eq!(reconstruct(raw_shares, false).unwrap(), secret_vec);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_relay_proposal() -> Result<(), SecretServerError> {
+        let stores = vec![HashStore::new(NodeId(1)), HashStore::new(NodeId(2))];
+        let backend = LoopbackBackend::group(stores.clone()).remove(0);
+        let handler = ConsensusHandler::new(
+            stores[0].clone(),
+            Arc::new(backend),
+            Arc::new(NodeKey::generate()),
+        );
+        handler
+            .admit(NodeId(1), "node-1", handler.public_key())
+            .await?;
+
+        let key_2 = NodeKey::generate();
+        let entry = key_2.sign(
+            NodeId(2),
+            &Message::Admit {
+                node_id: NodeId(2),
+                raft_addr: "node-2".to_string(),
+                public_key: key_2.public_key(),
+            },
+        );
+        assert!(!handler.relay(entry.clone()).await?.is_empty());
+        for store in &stores {
+            assert_eq!(store.members()?[&NodeId(2)].public_key, key_2.public_key());
+        }
+        // The members reject it once applied, and anything but an entry is not relayed
+        assert!(handler.relay(entry).await?.is_empty());
+        assert!(handler.relay(vec![1, 2, 3]).await.is_err());
+
+        // Entries of strangers or with a forged signature are not proposed at all
+        let start = Message::StartRefresh { node_id: NodeId(3) };
+        let stranger = NodeKey::generate().sign(NodeId(3), &start);
+        assert!(matches!(
+            handler.relay(stranger).await,
+            Err(SecretServerError::AdmissionError(_))
+        ));
+        let forged =
+            NodeKey::generate().sign(NodeId(2), &Message::StartRefresh { node_id: NodeId(2) });
+        assert!(matches!(
+            handler.relay(forged).await,
+            Err(SecretServerError::AdmissionError(_))
+        ));
+        assert!(!stores[1].is_begin_refresh());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remove_member_and_leave() -> Result<(), SecretServerError> {
+        let stores = (1..=3)
+            .map(|id| HashStore::new(NodeId(id)))
+            .collect::<Vec<_>>();
+        let backends = LoopbackBackend::group(stores.clone());
+        let handlers = stores
+            .iter()
+            .zip(backends)
+            .map(|(store, backend)| {
+                ConsensusHandler::new(
+                    store.clone(),
+                    Arc::new(backend),
+                    Arc::new(NodeKey::generate()),
+                )
+            })
+            .collect::<Vec<_>>();
+        for (id, handler) in (1..=3).zip(handlers.iter()) {
+            handler
+                .admit(NodeId(id), &format!("node-{}", id), handler.public_key())
+                .await?;
+        }
+
+        handlers[0].remove_member(NodeId(3)).await?;
+        assert_eq!(handlers[0].status().voters, vec![NodeId(1), NodeId(2)]);
+        assert!(!handlers[1].members()?.contains_key(&NodeId(3)));
+        assert!(handlers[0].remove_member(NodeId(3)).await.is_err());
+
+        handlers[1].leave().await?;
+        assert_eq!(handlers[0].status().voters, vec![NodeId(1)]);
+        assert_eq!(
+            handlers[0].members()?.keys().collect::<Vec<_>>(),
+            vec![&NodeId(1)]
+        );
+
+        Ok(())
+    }
 }
@@ -3,8 +3,18 @@ use sss_wrap::secret::secret::Share;
 
 use crate::domain::model::{ClientId, NodeId};
 
+/// New share of `client_id` proposed in a refresh round.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShareRefresh {
+    pub client_id: ClientId,
+    pub new_share: Share,
+}
+
 /// Enum representing different types of messages for Raft consensus protocol.
-#[derive(Serialize, Deserialize, Debug)]
+///
+/// Entries are encoded with the protobuf schema of `wire`, serde only encodes the results of
+/// applying them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Message {
     /// Message to start refreshing with the given `node_id`.
     StartRefresh { node_id: NodeId },
@@ -15,4 +25,15 @@ pub enum Message {
     },
     /// Message to finish refreshing with the given `node_id`.
     FinishRefresh { node_id: NodeId },
+    /// Message to admit the node `node_id` listening on `raft_addr` as a cluster member, signing
+    /// its proposals with `public_key`.
+    Admit {
+        node_id: NodeId,
+        raft_addr: String,
+        public_key: Vec<u8>,
+    },
+    /// Message to refresh several clients at once, carrying the new shares of every node.
+    RefreshBatch { refreshes: Vec<ShareRefresh> },
+    /// Message to remove the node `node_id` from the cluster members.
+    Remove { node_id: NodeId },
 }
@@ -1,3 +1,10 @@
+pub mod admission;
+pub mod backend;
+pub mod bootstrap;
 pub mod handler;
 mod messages;
 pub mod raft;
+pub mod signing;
+#[cfg(test)]
+mod simulation;
+pub mod wire;
@@ -1,32 +1,139 @@
-use async_trait::async_trait;
 use bincode::{deserialize, serialize};
-use log::info;
-use riteraft::{Mailbox, Raft, Result as RiteResult, Store};
-use slog::Logger;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sss_wrap::secret::secret::{RenewableShare, ShareMeta};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ops::Deref;
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, RwLock};
-use tokio::task::JoinHandle;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
 
 use crate::domain::error::SecretServerError;
 use crate::domain::model::{ClientId, NodeId};
+use crate::logging;
+use crate::metrics::metrics;
 
-use super::messages::Message;
+use super::backend::ConsensusEvent;
+use super::messages::{Message, ShareRefresh};
+use super::signing::verify;
+use super::wire::{self, Entry};
+
+/// Cluster member admitted through `Message::Admit`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub raft_addr: String,
+    /// Ed25519 public key the member signs its proposals with.
+    #[serde(with = "hex::serde")]
+    pub public_key: Vec<u8>,
+}
+
+/// Replicated state transferred in Raft snapshots.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    shares: HashMap<ClientId, ShareMeta>,
+    members: HashMap<NodeId, Member>,
+    sequences: HashMap<NodeId, SequenceWindow>,
+    bootstrap_node: Option<NodeId>,
+    legacy_digests: HashSet<[u8; 32]>,
+}
+
+impl Snapshot {
+    /// Converts the snapshot of the previous version, holding the shares only.
+    fn from_legacy(shares: HashMap<ClientId, ShareMeta>) -> Self {
+        Self {
+            shares,
+            members: HashMap::new(),
+            sequences: HashMap::new(),
+            bootstrap_node: None,
+            legacy_digests: HashSet::new(),
+        }
+    }
+}
+
+/// Replicated state of the store but the shares, which differ on every node, transferred in BFT
+/// checkpoints. Its maps are ordered, so every replica encodes the same state the same way.
+#[cfg(feature = "bft")]
+#[derive(Serialize, Deserialize)]
+struct ReplicatedState {
+    members: std::collections::BTreeMap<NodeId, Member>,
+    sequences: std::collections::BTreeMap<NodeId, SequenceWindow>,
+    bootstrap_node: Option<NodeId>,
+    legacy_digests: BTreeSet<[u8; 32]>,
+}
+
+/// Capacity of the consensus event channel before slow subscribers miss events.
+const EVENTS_CAPACITY: usize = 64;
+
+/// Sequence numbers lower than the highest one applied from a node by more than this are
+/// rejected. Sequence numbers follow the time in microseconds, so the entries a node signs up to
+/// a minute apart can be committed in any order.
+const SEQUENCE_WINDOW: u64 = 60_000_000;
+
+/// Maximum number of sequence numbers remembered for a node within the window.
+const MAX_SEEN_SEQUENCES: usize = 4096;
+
+/// Sequence numbers recently applied from a node.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct SequenceWindow {
+    /// Sequence numbers up to this one are rejected.
+    floor: u64,
+    /// Sequence numbers applied above `floor`.
+    seen: BTreeSet<u64>,
+}
+
+impl SequenceWindow {
+    /// Records `sequence` and returns `true` if it is within the window and was not applied yet.
+    fn record(&mut self, sequence: u64) -> bool {
+        if sequence <= self.floor || !self.seen.insert(sequence) {
+            return false;
+        }
+        let newest = self.seen.last().copied().unwrap_or(sequence);
+        self.floor = self.floor.max(newest.saturating_sub(SEQUENCE_WINDOW));
+        self.seen = self.seen.split_off(&(self.floor + 1));
+        while self.seen.len() > MAX_SEEN_SEQUENCES {
+            self.floor = self.seen.pop_first().unwrap_or(self.floor);
+        }
+        true
+    }
+}
 
 /// Represents a hash-based store for shares and metadata.
+///
+/// It is the replicated state machine shared by every consensus backend, which apply the
+/// committed entries through `apply_entry`.
+///
+/// The unsigned entries of the previous version, see `wire::decode_legacy`, are still applied
+/// so that a cluster can be upgraded one node at a time, but only once each: their SHA-256 is
+/// recorded, so a captured entry cannot be replayed. A node of the previous version repeating
+/// the same refresh coordination message in a later round is therefore only heard by the nodes
+/// of its version, which does not prevent them from applying the refreshes.
 #[derive(Clone)]
 pub struct HashStore {
     node_id: NodeId,
     storage: Arc<RwLock<HashMap<ClientId, ShareMeta>>>,
+    /// Time the share of every client was stored or last refreshed on this node.
+    refreshed_at: Arc<RwLock<HashMap<ClientId, Instant>>>,
+    members: Arc<RwLock<HashMap<NodeId, Member>>>,
+    /// Sequence numbers recently applied from every node.
+    sequences: Arc<RwLock<HashMap<NodeId, SequenceWindow>>>,
+    /// Node that bootstrapped the cluster by admitting itself first.
+    bootstrap_node: Arc<RwLock<Option<NodeId>>>,
+    /// SHA-256 of the applied entries of the previous version.
+    legacy_digests: Arc<RwLock<HashSet<[u8; 32]>>>,
+    admission_required: bool,
     refreshing: Arc<AtomicBool>,
+    /// Time the refresh round of another node started, while it is in progress.
+    refreshing_since: Arc<RwLock<Option<Instant>>>,
+    events: broadcast::Sender<ConsensusEvent>,
 }
 
 impl std::fmt::Debug for HashStore {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("HashStore")
             .field("node_id", &self.node_id)
+            .field("admission_required", &self.admission_required)
             .field("refreshing", &self.refreshing)
             .finish()
     }
@@ -37,27 +144,203 @@ impl HashStore {
     pub fn new(node_id: NodeId) -> Self {
         Self {
             storage: Arc::new(RwLock::new(HashMap::new())),
+            refreshed_at: Arc::new(RwLock::new(HashMap::new())),
+            members: Arc::new(RwLock::new(HashMap::new())),
+            sequences: Arc::new(RwLock::new(HashMap::new())),
+            bootstrap_node: Arc::new(RwLock::new(None)),
+            legacy_digests: Arc::new(RwLock::new(HashSet::new())),
             node_id,
+            admission_required: false,
             refreshing: Arc::new(AtomicBool::new(false)),
+            refreshing_since: Arc::new(RwLock::new(None)),
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+        }
+    }
+
+    /// Subscribes to the consensus events of this node.
+    pub fn subscribe(&self) -> broadcast::Receiver<ConsensusEvent> {
+        self.events.subscribe()
+    }
+
+    /// Publishes a consensus event to the subscribers, if any.
+    pub(crate) fn publish(&self, event: ConsensusEvent) {
+        if matches!(event, ConsensusEvent::LeaderChanged { .. }) {
+            metrics().leader_changed();
+        }
+        let _ = self.events.send(event);
+    }
+
+    /// Only admits nodes through `Message::Admit` signed by an existing member, so nodes cannot
+    /// admit themselves once the cluster has been bootstrapped.
+    pub fn require_admission(mut self) -> Self {
+        self.admission_required = true;
+        self
+    }
+
+    /// Returns `true` if nodes must be admitted by an existing member.
+    pub fn admission_required(&self) -> bool {
+        self.admission_required
+    }
+
+    /// Returns the admitted members.
+    pub fn members(&self) -> Result<HashMap<NodeId, Member>, SecretServerError> {
+        Ok(self.members.read()?.clone())
+    }
+
+    /// Returns the node that bootstrapped the cluster, if known.
+    pub fn bootstrap_node(&self) -> Result<Option<NodeId>, SecretServerError> {
+        Ok(*self.bootstrap_node.read()?)
+    }
+
+    /// Registers members known from the configuration rather than admitted through the log.
+    #[cfg(feature = "bft")]
+    pub(crate) fn seed_members(
+        &self,
+        members: HashMap<NodeId, Member>,
+    ) -> Result<(), SecretServerError> {
+        self.members.write()?.extend(members);
+        Ok(())
+    }
+
+    /// Checks the signature of the entry against the replicated membership key table.
+    ///
+    /// Entries must be signed by an admitted member, and refresh coordination messages can only
+    /// be sent by a node on its own behalf. A node can admit itself only to bootstrap the cluster
+    /// or, when admission is not required, to join it with a node ID not taken yet. Only the node
+    /// itself or the node that bootstrapped the cluster can change or remove an admitted member.
+    pub(crate) fn is_authentic(&self, entry: &Entry) -> Result<bool, SecretServerError> {
+        let members = self.members.read()?;
+        match &entry.message {
+            Some(Message::StartRefresh { node_id } | Message::FinishRefresh { node_id })
+                if *node_id != entry.origin =>
+            {
+                return Ok(false)
+            }
+            Some(Message::Admit {
+                node_id,
+                raft_addr,
+                public_key,
+            }) if *node_id != entry.origin
+                && members.get(node_id).is_some_and(|member| {
+                    member.raft_addr != *raft_addr || member.public_key != *public_key
+                })
+                && *self.bootstrap_node.read()? != Some(entry.origin) =>
+            {
+                return Ok(false)
+            }
+            Some(Message::Remove { node_id })
+                if *node_id != entry.origin
+                    && members.contains_key(node_id)
+                    && *self.bootstrap_node.read()? != Some(entry.origin) =>
+            {
+                return Ok(false)
+            }
+            _ => {}
+        }
+        if let Some(member) = members.get(&entry.origin) {
+            return Ok(verify(
+                &member.public_key,
+                entry.signed_bytes(),
+                entry.signature(),
+            ));
+        }
+        match &entry.message {
+            Some(Message::Admit {
+                node_id,
+                public_key,
+                ..
+            }) if *node_id == entry.origin && (members.is_empty() || !self.admission_required) => {
+                Ok(verify(public_key, entry.signed_bytes(), entry.signature()))
+            }
+            _ => Ok(false),
         }
     }
 
+    /// Checks that the sequence number of the entry was not applied from its origin yet and is
+    /// within the window of its recent entries, and records it.
+    ///
+    /// The entries a node proposes concurrently, e.g. a scheduled and an admin refresh, can be
+    /// committed in another order than they were signed in, so every sequence number of the
+    /// window is tracked rather than only the highest one.
+    fn is_fresh(&self, entry: &Entry) -> Result<bool, SecretServerError> {
+        Ok(self
+            .sequences
+            .write()?
+            .entry(entry.origin)
+            .or_default()
+            .record(entry.sequence))
+    }
+
     /// Retrieves the share metadata associated with the given client ID.
     pub fn get(&self, id: ClientId) -> Result<Option<ShareMeta>, SecretServerError> {
         Ok(self.storage.read().unwrap().get(&id).cloned())
     }
 
+    /// Returns the number of clients with a share stored.
+    pub fn secret_count(&self) -> Result<usize, SecretServerError> {
+        Ok(self.storage.read()?.len())
+    }
+
     /// Inserts a new share metadata associated with the given client ID.
     pub fn insert(&mut self, id: ClientId, share: ShareMeta) -> Result<(), SecretServerError> {
         self.storage.write().unwrap().insert(id, share);
+        self.refreshed_at.write()?.insert(id, Instant::now());
         Ok(())
     }
 
+    /// Removes the share metadata associated with the given client ID, returning it if present.
+    pub fn remove(&mut self, id: ClientId) -> Result<Option<ShareMeta>, SecretServerError> {
+        self.refreshed_at.write()?.remove(&id);
+        Ok(self.storage.write()?.remove(&id))
+    }
+
+    /// Returns when the share of every stored client is due for a refresh, once its own refresh
+    /// interval or `default_interval` has elapsed since it was stored or last refreshed.
+    ///
+    /// Clients without their own interval are left out if there is no `default_interval`.
+    pub fn refresh_due_times(
+        &self,
+        default_interval: Option<Duration>,
+    ) -> Result<Vec<(ClientId, Instant)>, SecretServerError> {
+        let storage = self.storage.read()?;
+        let refreshed_at = self.refreshed_at.read()?;
+        let now = Instant::now();
+        Ok(storage
+            .iter()
+            .filter_map(|(id, share)| {
+                let interval = share
+                    .meta
+                    .refresh_interval_secs
+                    .map(Duration::from_secs)
+                    .or(default_interval)?;
+                let since = refreshed_at.get(id).copied().unwrap_or(now);
+                Some((*id, since + interval))
+            })
+            .collect())
+    }
+
     /// Checks if the store is currently in the process of refreshing.
     pub fn is_begin_refresh(&self) -> bool {
         self.refreshing.load(std::sync::atomic::Ordering::Acquire)
     }
 
+    /// Returns the time the refresh round in progress started, if any.
+    pub fn refreshing_since(&self) -> Option<Instant> {
+        self.refreshing_since.read().ok().and_then(|since| *since)
+    }
+
+    /// Returns `true` if no thread panicked while holding the store, which would leave it
+    /// unusable.
+    pub fn is_healthy(&self) -> bool {
+        !self.storage.is_poisoned()
+            && !self.refreshed_at.is_poisoned()
+            && !self.members.is_poisoned()
+            && !self.sequences.is_poisoned()
+            && !self.bootstrap_node.is_poisoned()
+            && !self.legacy_digests.is_poisoned()
+            && !self.refreshing_since.is_poisoned()
+    }
+
     /// Returns the node ID associated with the store.
     pub fn node_id(&self) -> NodeId {
         self.node_id
@@ -67,19 +350,88 @@ impl HashStore {
     pub fn storage(&self) -> Arc<RwLock<HashMap<ClientId, ShareMeta>>> {
         self.storage.clone()
     }
-}
 
-#[async_trait]
-impl Store for HashStore {
-    /// Applies the given message to the store and returns the result.
-    async fn apply(&mut self, message: &[u8]) -> RiteResult<Vec<u8>> {
-        let message: Message = deserialize(message)?;
+    /// Applies the given committed entry to the store and returns the result.
+    ///
+    /// Unsigned or forged entries, and entries of a kind unknown to this version, are not
+    /// applied and an empty result is returned.
+    ///
+    /// With the `failpoints` feature, the `apply-entry-<node_id>` failpoint can pause or crash
+    /// the node before the entry is applied.
+    pub fn apply_entry(&mut self, message: &[u8]) -> Result<Vec<u8>, SecretServerError> {
+        fail::fail_point!(&format!("apply-entry-{}", self.node_id.0));
+        let _timer = metrics().apply_timer();
+        if wire::is_legacy(message) {
+            return self.apply_legacy(message);
+        }
+        let entry = match wire::decode(message) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Rejecting consensus entry: {}", e);
+                return Ok(vec![]);
+            }
+        };
+        // Records of the entry are logged with the ID of the request that proposed it
+        logging::in_request(entry.request_id.clone(), || self.apply_decoded(entry))
+    }
+
+    /// Applies an entry of the previous version to the store unless it was already applied, see
+    /// `apply_entry`.
+    fn apply_legacy(&mut self, bytes: &[u8]) -> Result<Vec<u8>, SecretServerError> {
+        let message = match wire::decode_legacy(bytes) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Rejecting consensus entry: {}", e);
+                return Ok(vec![]);
+            }
+        };
+        let digest: [u8; 32] = Sha256::digest(bytes).into();
+        if !self.legacy_digests.write()?.insert(digest) {
+            warn!("Rejecting replayed unsigned consensus entry {:?}", message);
+            return Ok(vec![]);
+        }
+        self.apply_message(None, message)
+    }
+
+    /// Applies the decoded entry to the store, see `apply_entry`.
+    fn apply_decoded(&mut self, entry: Entry) -> Result<Vec<u8>, SecretServerError> {
+        if !self.is_authentic(&entry)? {
+            warn!(
+                "Rejecting consensus entry with invalid signature from node {:?}",
+                entry.origin
+            );
+            return Ok(vec![]);
+        }
+        if !self.is_fresh(&entry)? {
+            warn!(
+                "Rejecting replayed consensus entry {} from node {:?}",
+                entry.sequence, entry.origin
+            );
+            return Ok(vec![]);
+        }
+        let Some(message) = entry.message else {
+            warn!(
+                "Ignoring consensus entry of unknown kind from node {:?}",
+                entry.origin
+            );
+            return Ok(vec![]);
+        };
+        self.apply_message(Some(entry.origin), message)
+    }
+
+    /// Applies the message proposed by `origin`, unknown for the entries of the previous version.
+    fn apply_message(
+        &mut self,
+        origin: Option<NodeId>,
+        message: Message,
+    ) -> Result<Vec<u8>, SecretServerError> {
         let message: Vec<u8> = match message {
             Message::StartRefresh { node_id } => {
                 info!("Start refresh from node {:?}", node_id);
                 if node_id != self.node_id {
                     self.refreshing
                         .store(true, std::sync::atomic::Ordering::Release);
+                    *self.refreshing_since.write()? = Some(Instant::now());
                 }
                 serialize(&Message::StartRefresh { node_id })?
             }
@@ -99,6 +451,7 @@ impl Store for HashStore {
                     }
                     let new_share_to_store =
                         RenewableShare::renew_with_share(&new_share, &old_share.share);
+                    self.refreshed_at.write()?.insert(client_id, Instant::now());
                     self.storage
                         .write()
                         .map_err(|e| -> SecretServerError { e.into() })
@@ -113,58 +466,562 @@ impl Store for HashStore {
                     new_share: new_share.clone(),
                 })?
             }
+            Message::RefreshBatch { refreshes } => {
+                info!("Refresh batch of {} shares", refreshes.len());
+                let mut storage = self
+                    .storage
+                    .write()
+                    .map_err(|e| -> SecretServerError { e.into() })?;
+                let mut refreshed_at = self.refreshed_at.write()?;
+                let mut refreshed = 0u64;
+                for ShareRefresh {
+                    client_id,
+                    new_share,
+                } in refreshes
+                    .iter()
+                    .filter(|r| r.new_share.id() == *self.node_id.deref())
+                {
+                    // The client may have been deleted since the batch was proposed
+                    let Some(old_share) = storage.get_mut(client_id) else {
+                        warn!("Skipping refresh of unknown client {:?}", client_id);
+                        continue;
+                    };
+                    old_share.share = RenewableShare::renew_with_share(new_share, &old_share.share);
+                    refreshed_at.insert(*client_id, Instant::now());
+                    refreshed += 1;
+                }
+                serialize(&refreshed)?
+            }
             Message::FinishRefresh { node_id } => {
                 info!("Finish refresh from node {:?}", node_id);
                 if node_id != self.node_id {
                     self.refreshing
                         .store(false, std::sync::atomic::Ordering::Release);
+                    *self.refreshing_since.write()? = None;
                 }
                 serialize(&Message::FinishRefresh { node_id })?
             }
+            Message::Admit {
+                node_id,
+                raft_addr,
+                public_key,
+            } => {
+                info!(
+                    "Admitting node {:?} on {} proposed by {:?}",
+                    node_id, raft_addr, origin
+                );
+                let former = {
+                    let mut members = self
+                        .members
+                        .write()
+                        .map_err(|e| -> SecretServerError { e.into() })?;
+                    if members.is_empty() && origin == Some(node_id) {
+                        *self.bootstrap_node.write()? = Some(node_id);
+                    }
+                    members.insert(
+                        node_id,
+                        Member {
+                            raft_addr: raft_addr.clone(),
+                            public_key: public_key.clone(),
+                        },
+                    )
+                };
+                match former {
+                    Some(former) if former.public_key != public_key => {
+                        self.publish(ConsensusEvent::MemberReplaced { node_id })
+                    }
+                    _ => self.publish(ConsensusEvent::MemberAdmitted { node_id }),
+                }
+                serialize(&Message::Admit {
+                    node_id,
+                    raft_addr,
+                    public_key,
+                })?
+            }
+            Message::Remove { node_id } => {
+                info!("Removing node {:?} proposed by {:?}", node_id, origin);
+                let removed = self
+                    .members
+                    .write()
+                    .map_err(|e| -> SecretServerError { e.into() })?
+                    .remove(&node_id)
+                    .is_some();
+                if removed {
+                    self.publish(ConsensusEvent::MemberRemoved { node_id });
+                }
+                serialize(&Message::Remove { node_id })?
+            }
         };
         Ok(message)
     }
 
     /// Returns a snapshot of the store.
-    async fn snapshot(&self) -> RiteResult<Vec<u8>> {
-        Ok(serialize(
-            &self
-                .storage
-                .read()
-                .map_err(|e| -> SecretServerError { e.into() })?
-                .clone(),
-        )?)
+    pub fn to_snapshot(&self) -> Result<Vec<u8>, SecretServerError> {
+        Ok(serialize(&Snapshot {
+            shares: self.storage.read()?.clone(),
+            members: self.members()?,
+            sequences: self.sequences.read()?.clone(),
+            bootstrap_node: self.bootstrap_node()?,
+            legacy_digests: self.legacy_digests.read()?.clone(),
+        })?)
+    }
+
+    /// Returns the replicated state of the store without the shares, see `ReplicatedState`.
+    #[cfg(feature = "bft")]
+    pub(crate) fn to_checkpoint(&self) -> Result<Vec<u8>, SecretServerError> {
+        Ok(serialize(&ReplicatedState {
+            members: self.members()?.into_iter().collect(),
+            sequences: self.sequences.read()?.clone().into_iter().collect(),
+            bootstrap_node: self.bootstrap_node()?,
+            legacy_digests: self.legacy_digests.read()?.iter().copied().collect(),
+        })?)
+    }
+
+    /// Restores the replicated state of the store from a checkpoint, keeping the shares.
+    #[cfg(feature = "bft")]
+    pub(crate) fn restore_checkpoint(
+        &mut self,
+        checkpoint: &[u8],
+    ) -> Result<(), SecretServerError> {
+        let state = deserialize::<ReplicatedState>(checkpoint)?;
+        *self.members.write()? = state.members.into_iter().collect();
+        *self.sequences.write()? = state.sequences.into_iter().collect();
+        *self.bootstrap_node.write()? = state.bootstrap_node;
+        *self.legacy_digests.write()? = state.legacy_digests.into_iter().collect();
+        Ok(())
     }
 
     /// Restores the store from the given snapshot.
-    async fn restore(&mut self, snapshot: &[u8]) -> RiteResult<()> {
-        let new: HashMap<ClientId, ShareMeta> = deserialize(snapshot)?;
-        let mut db = self
-            .storage
-            .write()
-            .map_err(|e| -> SecretServerError { e.into() })?;
-        let _ = std::mem::replace(&mut *db, new);
+    pub fn restore_snapshot(&mut self, snapshot: &[u8]) -> Result<(), SecretServerError> {
+        let new = deserialize::<Snapshot>(snapshot).or_else(|_| {
+            deserialize::<HashMap<ClientId, ShareMeta>>(snapshot).map(Snapshot::from_legacy)
+        })?;
+        let mut db = self.storage.write()?;
+        // Refresh times are local, restored shares are due a full interval from now
+        let now = Instant::now();
+        *self.refreshed_at.write()? = new.shares.keys().map(|id| (*id, now)).collect();
+        let _ = std::mem::replace(&mut *db, new.shares);
+        let mut members = self.members.write()?;
+        let _ = std::mem::replace(&mut *members, new.members);
+        *self.sequences.write()? = new.sequences;
+        *self.bootstrap_node.write()? = new.bootstrap_node;
+        *self.legacy_digests.write()? = new.legacy_digests;
         Ok(())
     }
 }
 
-/// Initializes the consensus algorithm with the given parameters and returns the Raft handle and mailbox.
-pub async fn init_consensus(
-    raft_addr: &str,
-    peer_addr: Option<&str>,
-    store: HashStore,
-    logger: Logger,
-) -> Result<(JoinHandle<Result<(), riteraft::Error>>, Arc<Mailbox>), SecretServerError> {
-    let raft = Raft::new(raft_addr.to_owned(), store.clone(), logger.clone());
-    let mailbox = Arc::new(raft.mailbox());
-    let (raft_handle, mailbox) = if let Some(addr) = peer_addr {
-        info!("running in follower mode");
-        let handle = tokio::spawn(raft.join(addr.to_owned()));
-        (handle, mailbox)
-    } else {
-        info!("running in leader mode");
-        let handle = tokio::spawn(raft.lead());
-        (handle, mailbox)
-    };
-    Ok((raft_handle, mailbox))
+#[cfg(test)]
+mod tests {
+    use super::super::signing::NodeKey;
+    use super::*;
+
+    /// Admits `node_id` with `key` through a proposal of node 1 signed with `signer`.
+    fn admit(store: &mut HashStore, signer: &NodeKey, node_id: NodeId, key: &NodeKey) {
+        let admit = Message::Admit {
+            node_id,
+            raft_addr: format!("server-{}:7070", *node_id),
+            public_key: key.public_key(),
+        };
+        let signed = signer.sign(NodeId(1), &admit);
+        assert!(!store.apply_entry(&signed).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reject_unsigned_and_forged_entries() {
+        let mut store = HashStore::new(NodeId(1)).require_admission();
+        let key_1 = NodeKey::generate();
+        admit(&mut store, &key_1, NodeId(1), &key_1);
+
+        // Only the refresh messages of the previous version are applied unsigned
+        let remove = Message::Remove { node_id: NodeId(1) };
+        let unsigned = serialize(&remove).unwrap();
+        assert!(store.apply_entry(&unsigned).unwrap().is_empty());
+        assert_eq!(store.members().unwrap().len(), 1);
+
+        let start = Message::StartRefresh { node_id: NodeId(1) };
+        let forged = NodeKey::generate().sign(NodeId(1), &start);
+        assert!(store.apply_entry(&forged).unwrap().is_empty());
+
+        let signed = key_1.sign(NodeId(1), &start);
+        assert!(!store.apply_entry(&signed).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reject_refresh_on_behalf_of_other_node() {
+        let mut store = HashStore::new(NodeId(1)).require_admission();
+        let key_1 = NodeKey::generate();
+        let key_2 = NodeKey::generate();
+        admit(&mut store, &key_1, NodeId(1), &key_1);
+
+        // Once bootstrapped, nodes cannot admit themselves
+        let self_admit = key_2.sign(
+            NodeId(2),
+            &Message::Admit {
+                node_id: NodeId(2),
+                raft_addr: "server-2:7070".to_string(),
+                public_key: key_2.public_key(),
+            },
+        );
+        assert!(store.apply_entry(&self_admit).unwrap().is_empty());
+        assert_eq!(store.members().unwrap().len(), 1);
+
+        admit(&mut store, &key_1, NodeId(2), &key_2);
+        let start = key_2.sign(NodeId(2), &Message::StartRefresh { node_id: NodeId(3) });
+        assert!(store.apply_entry(&start).unwrap().is_empty());
+        assert!(!store.is_begin_refresh());
+    }
+
+    #[test]
+    fn test_remove_member() {
+        let mut store = HashStore::new(NodeId(1)).require_admission();
+        let key_1 = NodeKey::generate();
+        let key_2 = NodeKey::generate();
+        admit(&mut store, &key_1, NodeId(1), &key_1);
+        admit(&mut store, &key_1, NodeId(2), &key_2);
+        let mut events = store.subscribe();
+
+        let remove = key_1.sign(NodeId(1), &Message::Remove { node_id: NodeId(2) });
+        assert!(!store.apply_entry(&remove).unwrap().is_empty());
+        assert!(!store.members().unwrap().contains_key(&NodeId(2)));
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ConsensusEvent::MemberRemoved { node_id: NodeId(2) }
+        );
+
+        // The removed node cannot propose anymore
+        let start = key_2.sign(NodeId(2), &Message::StartRefresh { node_id: NodeId(2) });
+        assert!(store.apply_entry(&start).unwrap().is_empty());
+        assert!(!store.is_begin_refresh());
+
+        // Admitting the node again with another key replaces it
+        admit(&mut store, &key_1, NodeId(2), &key_2);
+        admit(&mut store, &key_1, NodeId(2), &NodeKey::generate());
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ConsensusEvent::MemberAdmitted { node_id: NodeId(2) }
+        );
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ConsensusEvent::MemberReplaced { node_id: NodeId(2) }
+        );
+    }
+
+    #[test]
+    fn test_only_node_or_bootstrap_node_changes_member() {
+        let mut store = HashStore::new(NodeId(1));
+        let keys = (0..4).map(|_| NodeKey::generate()).collect::<Vec<_>>();
+        for id in 1..=3 {
+            admit(&mut store, &keys[0], NodeId(id), &keys[id as usize - 1]);
+        }
+        assert_eq!(store.bootstrap_node().unwrap(), Some(NodeId(1)));
+
+        // Node 2 cannot take over the key of node 3 nor remove it
+        let take_over = keys[1].sign(
+            NodeId(2),
+            &Message::Admit {
+                node_id: NodeId(3),
+                raft_addr: "server-3:7070".to_string(),
+                public_key: keys[1].public_key(),
+            },
+        );
+        assert!(store.apply_entry(&take_over).unwrap().is_empty());
+        let remove = keys[1].sign(NodeId(2), &Message::Remove { node_id: NodeId(3) });
+        assert!(store.apply_entry(&remove).unwrap().is_empty());
+        assert_eq!(
+            store.members().unwrap()[&NodeId(3)].public_key,
+            keys[2].public_key()
+        );
+
+        // Node 3 rotates its own key
+        let rotate = keys[2].sign(
+            NodeId(3),
+            &Message::Admit {
+                node_id: NodeId(3),
+                raft_addr: "server-3:7070".to_string(),
+                public_key: keys[3].public_key(),
+            },
+        );
+        assert!(!store.apply_entry(&rotate).unwrap().is_empty());
+        assert_eq!(
+            store.members().unwrap()[&NodeId(3)].public_key,
+            keys[3].public_key()
+        );
+    }
+
+    #[test]
+    fn test_reject_replayed_entries() {
+        let mut store = HashStore::new(NodeId(1));
+        let key_1 = NodeKey::generate();
+        let key_2 = NodeKey::generate();
+        admit(&mut store, &key_1, NodeId(1), &key_1);
+        admit(&mut store, &key_1, NodeId(2), &key_2);
+
+        let start = key_2.sign(NodeId(2), &Message::StartRefresh { node_id: NodeId(2) });
+        let finish = key_2.sign(NodeId(2), &Message::FinishRefresh { node_id: NodeId(2) });
+        assert!(!store.apply_entry(&start).unwrap().is_empty());
+        assert!(!store.apply_entry(&finish).unwrap().is_empty());
+        // Neither the last entry nor an older one can be applied again
+        assert!(store.apply_entry(&finish).unwrap().is_empty());
+        assert!(store.apply_entry(&start).unwrap().is_empty());
+        assert!(!store.is_begin_refresh());
+
+        // Nor once the store is restored from a snapshot
+        let mut restored = HashStore::new(NodeId(1));
+        restored
+            .restore_snapshot(&store.to_snapshot().unwrap())
+            .unwrap();
+        assert!(restored.apply_entry(&start).unwrap().is_empty());
+        assert_eq!(restored.bootstrap_node().unwrap(), Some(NodeId(1)));
+    }
+
+    #[test]
+    fn test_apply_concurrent_entries_in_any_order() {
+        let mut store = HashStore::new(NodeId(1));
+        let key_1 = NodeKey::generate();
+        let key_2 = NodeKey::generate();
+        admit(&mut store, &key_1, NodeId(1), &key_1);
+        admit(&mut store, &key_1, NodeId(2), &key_2);
+
+        // Node 2 signs two proposals concurrently, committed in the reverse order
+        let first = key_2.sign(NodeId(2), &Message::StartRefresh { node_id: NodeId(2) });
+        let second = key_2.sign(NodeId(2), &Message::FinishRefresh { node_id: NodeId(2) });
+        assert!(!store.apply_entry(&second).unwrap().is_empty());
+        assert!(!store.apply_entry(&first).unwrap().is_empty());
+        assert!(store.apply_entry(&first).unwrap().is_empty());
+        assert!(store.apply_entry(&second).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sequence_window() {
+        let mut window = SequenceWindow::default();
+        assert!(window.record(SEQUENCE_WINDOW * 2));
+        assert!(window.record(SEQUENCE_WINDOW * 2 - 1));
+        assert!(!window.record(SEQUENCE_WINDOW * 2 - 1));
+        // Sequence numbers falling out of the window are rejected and forgotten
+        assert!(window.record(SEQUENCE_WINDOW * 3));
+        assert!(!window.record(SEQUENCE_WINDOW * 2 - 2));
+        assert_eq!(window.seen.len(), 1);
+
+        for sequence in 1..=MAX_SEEN_SEQUENCES as u64 + 1 {
+            assert!(window.record(SEQUENCE_WINDOW * 3 + sequence));
+        }
+        assert_eq!(window.seen.len(), MAX_SEEN_SEQUENCES);
+        assert!(!window.record(SEQUENCE_WINDOW * 3 + 1));
+    }
+
+    #[test]
+    fn test_apply_legacy_entries_once() {
+        use sss_wrap::secret::secret::{Metadata, Share};
+
+        let mut store = HashStore::new(NodeId(2));
+        let key_1 = NodeKey::generate();
+        admit(&mut store, &key_1, NodeId(1), &key_1);
+        let old_share = Share::new(2, vec![1, 2, 3]);
+        store
+            .insert(
+                ClientId(1),
+                ShareMeta::new(old_share.clone(), Metadata::new(2, 3, 3)),
+            )
+            .unwrap();
+
+        // Entries of the previous version are plain bincode messages, applied along with the
+        // signed entries during a rolling upgrade
+        let legacy = |message: &wire::LegacyMessage| serialize(message).unwrap();
+        let start = legacy(&wire::LegacyMessage::StartRefresh { node_id: NodeId(1) });
+        assert!(!store.apply_entry(&start).unwrap().is_empty());
+        assert!(store.is_begin_refresh());
+        let delta = Share::new(2, vec![4, 5, 6]);
+        let refresh = legacy(&wire::LegacyMessage::Refresh {
+            client_id: ClientId(1),
+            new_share: delta.clone(),
+        });
+        assert!(!store.apply_entry(&refresh).unwrap().is_empty());
+        let refreshed = RenewableShare::renew_with_share(&delta, &old_share);
+        assert_eq!(store.get(ClientId(1)).unwrap().unwrap().share, refreshed);
+        let finish = key_1.sign(NodeId(1), &Message::FinishRefresh { node_id: NodeId(1) });
+        assert!(!store.apply_entry(&finish).unwrap().is_empty());
+        assert!(!store.is_begin_refresh());
+
+        // A replayed entry is not applied twice, nor once the store is restored from a snapshot
+        assert!(store.apply_entry(&refresh).unwrap().is_empty());
+        let mut restored = HashStore::new(NodeId(2));
+        restored
+            .restore_snapshot(&store.to_snapshot().unwrap())
+            .unwrap();
+        assert!(restored.apply_entry(&refresh).unwrap().is_empty());
+        assert_eq!(restored.get(ClientId(1)).unwrap().unwrap().share, refreshed);
+        assert!(store.is_healthy());
+    }
+
+    #[cfg(feature = "bft")]
+    #[test]
+    fn test_restore_checkpoint_keeps_shares() {
+        use sss_wrap::secret::secret::{Metadata, Share};
+
+        let keys = [NodeKey::generate(), NodeKey::generate()];
+        let mut entries = (1..)
+            .zip(&keys)
+            .map(|(id, key)| {
+                let admit = Message::Admit {
+                    node_id: NodeId(id),
+                    raft_addr: format!("server-{}:7070", id),
+                    public_key: key.public_key(),
+                };
+                keys[0].sign(NodeId(1), &admit)
+            })
+            .collect::<Vec<_>>();
+        let start = keys[1].sign(NodeId(2), &Message::StartRefresh { node_id: NodeId(2) });
+        entries.push(start.clone());
+        let mut stores = [HashStore::new(NodeId(1)), HashStore::new(NodeId(2))];
+        for store in &mut stores {
+            for entry in &entries {
+                assert!(!store.apply_entry(entry).unwrap().is_empty());
+            }
+        }
+        let share = ShareMeta::new(Share::new(2, vec![1, 2, 3]), Metadata::new(2, 3, 3));
+        stores[1].insert(ClientId(1), share.clone()).unwrap();
+        // Both replicas applied the same entries, whatever their shares
+        let checkpoint = stores[0].to_checkpoint().unwrap();
+        assert_eq!(stores[1].to_checkpoint().unwrap(), checkpoint);
+
+        let mut behind = HashStore::new(NodeId(2));
+        behind.insert(ClientId(1), share.clone()).unwrap();
+        behind.restore_checkpoint(&checkpoint).unwrap();
+        assert_eq!(behind.members().unwrap(), stores[0].members().unwrap());
+        assert_eq!(behind.get(ClientId(1)).unwrap(), Some(share));
+        assert!(behind.apply_entry(&start).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_restore_legacy_snapshot() {
+        use sss_wrap::secret::secret::{Metadata, Share};
+
+        // The previous version only snapshots the shares
+        let shares = HashMap::from([(
+            ClientId(1),
+            ShareMeta::new(Share::new(1, vec![1, 2, 3]), Metadata::new(2, 3, 3)),
+        )]);
+        let mut store = HashStore::new(NodeId(1));
+        store
+            .restore_snapshot(&serialize(&shares).unwrap())
+            .unwrap();
+        assert_eq!(store.secret_count().unwrap(), 1);
+        assert!(store.members().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ignore_entries_of_unknown_kind() {
+        use prost::Message as _;
+
+        let mut store = HashStore::new(NodeId(1));
+        let key_1 = NodeKey::generate();
+        admit(&mut store, &key_1, NodeId(1), &key_1);
+
+        // Message kind 15 added by a newer version
+        let mut body = vec![];
+        prost::encoding::bytes::encode(15, &vec![1, 2, 3], &mut body);
+        let envelope = wire::Envelope {
+            version: wire::WIRE_VERSION + 1,
+            origin: 1,
+            signature: key_1.sign_bytes(&wire::envelope_signed_bytes(
+                wire::WIRE_VERSION + 1,
+                NodeId(1),
+                &body,
+            )),
+            body,
+            request_id: String::new(),
+        };
+        let mut entry = wire::MAGIC.to_vec();
+        envelope.encode(&mut entry).unwrap();
+        assert!(store.apply_entry(&entry).unwrap().is_empty());
+        assert_eq!(store.members().unwrap().len(), 1);
+
+        // The store keeps applying the entries it knows
+        let start = key_1.sign(NodeId(1), &Message::StartRefresh { node_id: NodeId(1) });
+        assert!(!store.apply_entry(&start).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_apply_refresh_batch() {
+        use sss_wrap::secret::secret::{Metadata, Share};
+
+        let mut store = HashStore::new(NodeId(1));
+        let key_1 = NodeKey::generate();
+        admit(&mut store, &key_1, NodeId(1), &key_1);
+        let old_share = Share::new(1, vec![1, 2, 3]);
+        store
+            .insert(
+                ClientId(1),
+                ShareMeta::new(old_share.clone(), Metadata::new(2, 3, 3)),
+            )
+            .unwrap();
+
+        let refreshes = [ClientId(1), ClientId(2)]
+            .into_iter()
+            .flat_map(|client_id| {
+                (1..=3).map(move |x| ShareRefresh {
+                    client_id,
+                    new_share: Share::new(x, vec![x; 3]),
+                })
+            })
+            .collect();
+        let batch = key_1.sign(NodeId(1), &Message::RefreshBatch { refreshes });
+        let refreshed: u64 = deserialize(&store.apply_entry(&batch).unwrap()).unwrap();
+
+        // Only the share of this node for the stored client is refreshed
+        assert_eq!(refreshed, 1);
+        assert_eq!(
+            store.get(ClientId(1)).unwrap().unwrap().share,
+            RenewableShare::renew_with_share(&Share::new(1, vec![1; 3]), &old_share)
+        );
+        assert!(store.get(ClientId(2)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_refresh_due_times() {
+        use sss_wrap::secret::secret::{Metadata, Share};
+
+        let mut store = HashStore::new(NodeId(1));
+        let key_1 = NodeKey::generate();
+        admit(&mut store, &key_1, NodeId(1), &key_1);
+        let meta = Metadata::new(2, 3, 3);
+        for (client_id, meta) in [
+            (ClientId(1), meta.clone()),
+            (ClientId(2), meta.with_refresh_interval_secs(60)),
+        ] {
+            store
+                .insert(
+                    client_id,
+                    ShareMeta::new(Share::new(1, vec![1, 2, 3]), meta),
+                )
+                .unwrap();
+        }
+        let due_times = |store: &HashStore| -> HashMap<ClientId, Instant> {
+            store
+                .refresh_due_times(Some(Duration::from_secs(3600)))
+                .unwrap()
+                .into_iter()
+                .collect()
+        };
+
+        // Secrets with their own interval are due before the others
+        let before = due_times(&store);
+        assert!(before[&ClientId(2)] + Duration::from_secs(3000) < before[&ClientId(1)]);
+
+        // A refreshed share is due a full interval after its refresh
+        std::thread::sleep(Duration::from_millis(10));
+        let refreshes = vec![ShareRefresh {
+            client_id: ClientId(2),
+            new_share: Share::new(1, vec![1; 3]),
+        }];
+        let batch = key_1.sign(NodeId(1), &Message::RefreshBatch { refreshes });
+        store.apply_entry(&batch).unwrap();
+        let after = due_times(&store);
+        assert_eq!(after[&ClientId(1)], before[&ClientId(1)]);
+        assert!(after[&ClientId(2)] > before[&ClientId(2)]);
+
+        // Without a default interval only secrets with their own interval are due
+        let own = store.refresh_due_times(None).unwrap();
+        assert_eq!(own, vec![(ClientId(2), after[&ClientId(2)])]);
+    }
 }
@@ -0,0 +1,248 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use rand::rngs::OsRng;
+use sha2::Sha256;
+
+use crate::domain::error::SecretServerError;
+use crate::domain::model::NodeId;
+
+use super::messages::Message;
+use super::wire;
+
+/// Returns `true` if `signature` over `bytes` was made by the given Ed25519 public key.
+pub fn verify(public_key: &[u8], bytes: &[u8], signature: &[u8]) -> bool {
+    let Ok(public_key) = <[u8; 32]>::try_from(public_key) else {
+        return false;
+    };
+    let Ok(verifying_key) = VerifyingKey::from_bytes(&public_key) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return false;
+    };
+    verifying_key.verify(bytes, &signature).is_ok()
+}
+
+/// Number of sequence numbers reserved at once in the sequence file of a node key, a minute of
+/// sequence numbers following the time in microseconds.
+const SEQUENCE_RESERVATION: u64 = 60_000_000;
+
+/// Last sequence number signed with a node key and the highest one reserved in its file.
+#[derive(Debug, Default)]
+struct Sequence {
+    last: u64,
+    reserved: u64,
+}
+
+/// Ed25519 key every node signs its consensus proposals with.
+///
+/// Entries are signed with increasing sequence numbers following the current time in
+/// microseconds. A key stored in a file reserves its sequence numbers ahead in a
+/// `<path>.sequence` file, so they keep increasing when the node restarts with the same key even
+/// if its clock was set back.
+pub struct NodeKey {
+    signing_key: SigningKey,
+    sequence: Mutex<Sequence>,
+    sequence_path: Option<PathBuf>,
+}
+
+impl std::fmt::Debug for NodeKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NodeKey")
+            .field("public_key", &hex::encode(self.public_key()))
+            .finish()
+    }
+}
+
+impl NodeKey {
+    /// Generates a new random node key.
+    pub fn generate() -> Self {
+        Self::from_signing_key(SigningKey::generate(&mut OsRng))
+    }
+
+    fn from_signing_key(signing_key: SigningKey) -> Self {
+        Self {
+            signing_key,
+            sequence: Mutex::new(Sequence::default()),
+            sequence_path: None,
+        }
+    }
+
+    /// Reserves the sequence numbers of the key in `path`, starting after the ones it reserved
+    /// before.
+    fn with_sequence_file(mut self, path: PathBuf) -> Result<Self, SecretServerError> {
+        let invalid =
+            |e: String| SecretServerError::InvalidStateError(format!("{}: {}", path.display(), e));
+        if path.exists() {
+            let reserved = fs::read_to_string(&path)
+                .map_err(|e| invalid(e.to_string()))?
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| invalid(e.to_string()))?;
+            self.sequence = Mutex::new(Sequence {
+                last: reserved,
+                reserved,
+            });
+        }
+        self.sequence_path = Some(path);
+        Ok(self)
+    }
+
+    /// Loads the hex encoded node key stored in `path`, or generates and stores a new one if the
+    /// file does not exist. Without `path` the key only lives in memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or written or does not hold a valid key.
+    pub fn load_or_generate(path: Option<&str>) -> Result<Self, SecretServerError> {
+        let Some(path) = path else {
+            return Ok(Self::generate());
+        };
+        let invalid = |e: String| SecretServerError::InvalidStateError(format!("{}: {}", path, e));
+        let sequence_path = PathBuf::from(format!("{}.sequence", path));
+        if Path::new(path).exists() {
+            let seed: [u8; 32] = hex::decode(
+                fs::read_to_string(path)
+                    .map_err(|e| invalid(e.to_string()))?
+                    .trim(),
+            )
+            .map_err(|e| invalid(e.to_string()))?
+            .try_into()
+            .map_err(|_| invalid("node key must be 32 bytes".to_string()))?;
+            return Self::from_signing_key(SigningKey::from_bytes(&seed))
+                .with_sequence_file(sequence_path);
+        }
+        let key = Self::generate();
+        fs::write(path, hex::encode(key.signing_key.to_bytes()))
+            .map_err(|e| invalid(e.to_string()))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .map_err(|e| invalid(e.to_string()))?;
+        info!("Generated new node key in {}", path);
+        key.with_sequence_file(sequence_path)
+    }
+
+    /// Returns the Ed25519 public key of the node.
+    pub fn public_key(&self) -> Vec<u8> {
+        self.signing_key.verifying_key().to_bytes().to_vec()
+    }
+
+    /// Signs the given bytes.
+    pub fn sign_bytes(&self, bytes: &[u8]) -> Vec<u8> {
+        self.signing_key.sign(bytes).to_bytes().to_vec()
+    }
+
+    /// Derives a secret for `context` from the node key, so that other keys of the node, such as
+    /// the key of its audit log, need not be stored.
+    pub fn derive_key(&self, context: &[u8]) -> [u8; 32] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.signing_key.to_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(context);
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Signs the message on behalf of `origin` with the next sequence number and returns the
+    /// encoded consensus entry.
+    pub fn sign(&self, origin: NodeId, message: &Message) -> Vec<u8> {
+        wire::encode(origin, self.next_sequence(), message, |bytes| {
+            self.sign_bytes(bytes)
+        })
+    }
+
+    /// Returns the next sequence number, reserving more in the sequence file when needed.
+    ///
+    /// If the reservation cannot be written the sequence number is still used, the node only
+    /// risks having its proposals rejected after a restart with its clock set back.
+    fn next_sequence(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or_default();
+        let mut sequence = self.sequence.lock().unwrap_or_else(|e| e.into_inner());
+        sequence.last = now.max(sequence.last + 1);
+        if let Some(path) = self
+            .sequence_path
+            .as_ref()
+            .filter(|_| sequence.last > sequence.reserved)
+        {
+            let reserved = sequence.last + SEQUENCE_RESERVATION;
+            let tmp = path.with_extension("sequence.tmp");
+            match fs::write(&tmp, reserved.to_string()).and_then(|()| fs::rename(&tmp, path)) {
+                Ok(()) => sequence.reserved = reserved,
+                Err(e) => warn!(
+                    "Cannot reserve sequence numbers in {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        sequence.last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = NodeKey::generate();
+        let entry =
+            wire::decode(&key.sign(NodeId(1), &Message::StartRefresh { node_id: NodeId(1) }))
+                .unwrap();
+        assert!(verify(
+            &key.public_key(),
+            entry.signed_bytes(),
+            entry.signature()
+        ));
+        assert!(!verify(
+            &NodeKey::generate().public_key(),
+            entry.signed_bytes(),
+            entry.signature()
+        ));
+    }
+
+    #[test]
+    fn test_sequence_survives_restart() {
+        let path = std::env::temp_dir().join(format!("node-key-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let key = NodeKey::load_or_generate(Some(path)).unwrap();
+        let first = key.next_sequence();
+        assert!(key.next_sequence() > first);
+
+        // The restarted key starts after the reserved numbers, wherever the clock is
+        let restarted = NodeKey::load_or_generate(Some(path)).unwrap();
+        assert_eq!(restarted.public_key(), key.public_key());
+        assert!(restarted.next_sequence() > first + SEQUENCE_RESERVATION);
+        fs::remove_file(path).unwrap();
+        fs::remove_file(format!("{}.sequence", path)).unwrap();
+    }
+
+    #[test]
+    fn test_derive_key() {
+        let key = NodeKey::generate();
+        assert_eq!(key.derive_key(b"audit"), key.derive_key(b"audit"));
+        assert_ne!(key.derive_key(b"audit"), key.derive_key(b"other"));
+        assert_ne!(
+            key.derive_key(b"audit"),
+            NodeKey::generate().derive_key(b"audit")
+        );
+    }
+
+    #[test]
+    fn test_reject_forged_origin() {
+        let key = NodeKey::generate();
+        let signature = key.sign_bytes(&wire::envelope_signed_bytes(1, NodeId(1), b"body"));
+        assert!(!verify(
+            &key.public_key(),
+            &wire::envelope_signed_bytes(1, NodeId(2), b"body"),
+            &signature
+        ));
+    }
+}
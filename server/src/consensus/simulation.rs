@@ -0,0 +1,514 @@
+//! Deterministic simulation of the refresh protocol.
+//!
+//! A seeded scheduler drives the `HashStore`s of several nodes through a simulated replicated log.
+//! A proposal is committed when its node reaches a majority, and the committed entries reach the
+//! other nodes through messages that are dropped, reordered and cut by partitions. Nodes crash in
+//! the middle of refresh rounds and recover from their last snapshot, and proposals whose
+//! response is lost are retried, so their entry is duplicated in the log.
+//!
+//! After every step, every set of `THRESHOLD` shares of a client that the nodes have applied in
+//! the same epoch must reconstruct its secret. The epoch of a share is the number of refreshes of
+//! it applied by the node.
+//!
+//! The default test runs a few seeds in seconds, `test_shares_reconstruct_under_faults_long` runs
+//! many more with `cargo test -- --ignored`.
+
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use sss_wrap::from_secrets;
+use sss_wrap::secret::secret::{Metadata, Share, ShareMeta};
+use sss_wrap::wrapped_sharing::reconstruct;
+use tokio::sync::broadcast;
+
+use crate::domain::error::SecretServerError;
+use crate::domain::model::{ClientId, NodeId};
+
+use super::backend::{ConsensusBackend, ConsensusEvent, ConsensusStatus, Role};
+use super::handler::ConsensusHandler;
+use super::messages::Message;
+use super::raft::HashStore;
+use super::signing::NodeKey;
+use super::wire;
+
+const NODES: usize = 5;
+const THRESHOLD: u8 = 3;
+const CLIENTS: u64 = 3;
+const SECRET_LEN: usize = 16;
+const STEPS: usize = 400;
+const SEEDS: u64 = 3;
+const LONG_STEPS: usize = 1000;
+const LONG_SEEDS: u64 = 32;
+
+struct SimNode {
+    store: HashStore,
+    /// Number of log entries applied to the store.
+    applied: usize,
+    up: bool,
+    /// Epoch of the share of every client in the store.
+    epochs: Vec<usize>,
+    /// Last snapshot of the store, the number of log entries it contains and its epochs.
+    snapshot: (Vec<u8>, usize, Vec<usize>),
+}
+
+impl SimNode {
+    fn share(&self, client: usize) -> Option<Share> {
+        let meta = self.store.get(ClientId(client as u64)).unwrap();
+        meta.map(|meta| meta.share)
+    }
+
+    fn take_snapshot(&mut self) {
+        self.snapshot = (
+            self.store.to_snapshot().unwrap(),
+            self.applied,
+            self.epochs.clone(),
+        );
+    }
+}
+
+/// State shared by the scheduler and the consensus backends of the nodes.
+struct World {
+    rng: StdRng,
+    faults: bool,
+    log: Vec<Vec<u8>>,
+    nodes: Vec<SimNode>,
+    /// Minority of nodes cut from the others.
+    partitioned: BTreeSet<usize>,
+    /// Deliveries of the log entry at the given index to the given node.
+    in_flight: Vec<(usize, usize)>,
+}
+
+impl World {
+    fn has_quorum(&self, node: usize) -> bool {
+        let side = self.partitioned.contains(&node);
+        let reachable = (0..NODES)
+            .filter(|n| self.nodes[*n].up && self.partitioned.contains(n) == side)
+            .count();
+        self.nodes[node].up && reachable > NODES / 2
+    }
+
+    /// Applies the next log entry on `node`, moving the shares it refreshes to their next epoch,
+    /// and returns the result.
+    fn apply_next(&mut self, node: usize) -> Result<Vec<u8>, SecretServerError> {
+        let node = &mut self.nodes[node];
+        let entry = &self.log[node.applied];
+        let result = node.store.apply_entry(entry);
+        node.applied += 1;
+        // Rejected entries are answered with an empty result
+        if result.as_ref().is_ok_and(|result| !result.is_empty()) {
+            for client in refreshed_clients(entry) {
+                node.epochs[client] += 1;
+            }
+        }
+        result
+    }
+
+    /// Stops the node, which loses every entry applied after its last snapshot.
+    fn crash(&mut self, node: usize) {
+        let sim_node = &mut self.nodes[node];
+        sim_node.up = false;
+        sim_node
+            .store
+            .restore_snapshot(&sim_node.snapshot.0)
+            .unwrap();
+        sim_node.applied = sim_node.snapshot.1;
+        sim_node.epochs = sim_node.snapshot.2.clone();
+        self.in_flight.retain(|(to, _)| *to != node);
+    }
+}
+
+/// Backend committing the proposals of a node in the simulated log.
+///
+/// Like a Raft leader, the node applies the log up to its proposal before answering.
+struct SimBackend {
+    node: usize,
+    world: Arc<Mutex<World>>,
+}
+
+#[async_trait]
+impl ConsensusBackend for SimBackend {
+    async fn propose(&self, entry: Vec<u8>) -> Result<Vec<u8>, SecretServerError> {
+        let mut state = self.world.lock()?;
+        let world = &mut *state;
+        if !world.has_quorum(self.node) {
+            return Err(SecretServerError::BackendError("no quorum".to_string()));
+        }
+        let index = world.log.len();
+        world.log.push(entry.clone());
+        // The response was lost and the client proposed the entry again
+        if world.faults && world.rng.gen_bool(0.05) {
+            world.log.push(entry);
+        }
+        let mut response = Ok(vec![]);
+        while world.nodes[self.node].applied < world.log.len() {
+            let applied = world.nodes[self.node].applied;
+            let result = world.apply_next(self.node);
+            if applied == index {
+                response = result;
+            }
+        }
+        if world.faults && world.rng.gen_bool(0.02) {
+            world.crash(self.node);
+            return Err(SecretServerError::BackendError(
+                "node crashed before answering".to_string(),
+            ));
+        }
+        response
+    }
+
+    async fn leave(&self) -> Result<(), SecretServerError> {
+        Ok(())
+    }
+
+    async fn remove(&self, _node_id: NodeId) -> Result<(), SecretServerError> {
+        Ok(())
+    }
+
+    fn status(&self) -> ConsensusStatus {
+        ConsensusStatus {
+            node_id: NodeId(self.node as u8 + 1),
+            role: Role::Unknown,
+            leader: None,
+            voters: vec![],
+            term: None,
+            last_log_index: None,
+            last_applied: None,
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ConsensusEvent> {
+        broadcast::channel(1).1
+    }
+}
+
+/// Returns the clients whose shares are refreshed by the log entry.
+///
+/// A refresh can leave the share of a node unchanged, so the epochs are not told by comparing
+/// the shares.
+fn refreshed_clients(entry: &[u8]) -> BTreeSet<usize> {
+    match wire::decode(entry).ok().and_then(|entry| entry.message) {
+        Some(Message::Refresh { client_id, .. }) => BTreeSet::from([client_id.0 as usize]),
+        Some(Message::RefreshBatch { refreshes }) => refreshes
+            .iter()
+            .map(|refresh| refresh.client_id.0 as usize)
+            .collect(),
+        _ => BTreeSet::new(),
+    }
+}
+
+/// Phase of the refresh round a node is driving.
+///
+/// Unlike the refresher task, which gives up on a failed round, the node retries the failed phase
+/// at its next turn, also once recovered from a crash. A round whose node gives up is never
+/// finished and the other nodes never start another one.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    Started,
+    Refreshed,
+}
+
+/// Returns every subset of `k` indexes among `0..n`.
+fn subsets(n: usize, k: usize) -> Vec<Vec<usize>> {
+    if k == 0 {
+        return vec![vec![]];
+    }
+    (k - 1..n)
+        .flat_map(|last| {
+            subsets(last, k - 1).into_iter().map(move |mut subset| {
+                subset.push(last);
+                subset
+            })
+        })
+        .collect()
+}
+
+struct Simulation {
+    seed: u64,
+    step: usize,
+    world: Arc<Mutex<World>>,
+    handlers: Vec<ConsensusHandler>,
+    phases: Vec<Phase>,
+    secrets: Vec<Vec<u8>>,
+    rounds: usize,
+}
+
+impl Simulation {
+    /// Starts the nodes, admits them and stores the shares of every client without faults.
+    async fn new(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let secrets = (0..CLIENTS)
+            .map(|_| (0..SECRET_LEN).map(|_| rng.gen()).collect::<Vec<u8>>())
+            .collect::<Vec<_>>();
+        let stores = (1..=NODES)
+            .map(|id| HashStore::new(NodeId(id as u8)))
+            .collect::<Vec<_>>();
+        let world = Arc::new(Mutex::new(World {
+            rng,
+            faults: false,
+            log: vec![],
+            nodes: stores
+                .iter()
+                .map(|store| SimNode {
+                    store: store.clone(),
+                    applied: 0,
+                    up: true,
+                    epochs: vec![0; CLIENTS as usize],
+                    snapshot: (vec![], 0, vec![]),
+                })
+                .collect(),
+            partitioned: BTreeSet::new(),
+            in_flight: vec![],
+        }));
+        let mut handlers = stores
+            .into_iter()
+            .enumerate()
+            .map(|(node, store)| {
+                let backend = SimBackend {
+                    node,
+                    world: world.clone(),
+                };
+                ConsensusHandler::new(store, Arc::new(backend), Arc::new(NodeKey::generate()))
+                    .with_refresh_batch_size(2)
+            })
+            .collect::<Vec<_>>();
+
+        for (node, handler) in handlers.iter().enumerate() {
+            let node_id = NodeId(node as u8 + 1);
+            handler
+                .admit(
+                    node_id,
+                    &format!("node-{}", node_id.0),
+                    handler.public_key(),
+                )
+                .await
+                .unwrap();
+        }
+        for (client, secret) in secrets.iter().enumerate() {
+            let shares = from_secrets(secret, THRESHOLD, NODES as u8, None).unwrap();
+            for (handler, share) in handlers.iter_mut().zip(shares) {
+                handler
+                    .insert(
+                        ClientId(client as u64),
+                        ShareMeta::new(
+                            share.into(),
+                            Metadata::new(THRESHOLD, NODES as u8, SECRET_LEN),
+                        ),
+                    )
+                    .unwrap();
+            }
+        }
+        {
+            let mut state = world.lock().unwrap();
+            let world = &mut *state;
+            for node in 0..NODES {
+                while world.nodes[node].applied < world.log.len() {
+                    world.apply_next(node).unwrap();
+                }
+                world.nodes[node].take_snapshot();
+            }
+            world.faults = true;
+        }
+
+        Self {
+            seed,
+            step: 0,
+            world,
+            handlers,
+            phases: vec![Phase::Idle; NODES],
+            secrets,
+            rounds: 0,
+        }
+    }
+
+    /// Advances the refresh round driven by `node` by one phase.
+    async fn refresh(&mut self, node: usize) {
+        let handler = &self.handlers[node];
+        let (result, next) = match self.phases[node] {
+            Phase::Idle if handler.is_begin_refresh() => return,
+            Phase::Idle => (handler.start_refresh().await, Phase::Started),
+            Phase::Started => (handler.refresh_secrets().await, Phase::Refreshed),
+            Phase::Refreshed => (handler.finish_refresh().await, Phase::Idle),
+        };
+        if result.is_ok() && next == Phase::Idle {
+            self.rounds += 1;
+        }
+        if result.is_ok() {
+            self.phases[node] = next;
+        }
+    }
+
+    /// Performs a step chosen by the seeded scheduler.
+    async fn step(&mut self) {
+        self.step += 1;
+        let (action, node) = {
+            let mut world = self.world.lock().unwrap();
+            (world.rng.gen_range(0..100), world.rng.gen_range(0..NODES))
+        };
+        if (60..=79).contains(&action) {
+            self.refresh(node).await;
+            return;
+        }
+        let mut world = self.world.lock().unwrap();
+        let world = &mut *world;
+        match action {
+            // Deliver a message, picked at random so messages are reordered
+            0..=39 if !world.in_flight.is_empty() => {
+                let message = world.rng.gen_range(0..world.in_flight.len());
+                let (to, index) = world.in_flight.swap_remove(message);
+                if world.nodes[to].up
+                    && !world.partitioned.contains(&to)
+                    && world.nodes[to].applied == index
+                {
+                    let _ = world.apply_next(to);
+                }
+            }
+            40..=49 if !world.in_flight.is_empty() => {
+                let message = world.rng.gen_range(0..world.in_flight.len());
+                world.in_flight.swap_remove(message);
+            }
+            // The leader sends the next entry to the lagging nodes it reaches
+            50..=59 => {
+                for to in 0..NODES {
+                    let applied = world.nodes[to].applied;
+                    if world.nodes[to].up
+                        && !world.partitioned.contains(&to)
+                        && applied < world.log.len()
+                    {
+                        world.in_flight.push((to, applied));
+                    }
+                }
+            }
+            // Nodes recover more often than they crash, so a majority is usually up
+            80..=81 => world.crash(node),
+            82..=87 => world.nodes[node].up = true,
+            88..=91 => {
+                let size = world.rng.gen_range(1..=(NODES - 1) / 2);
+                let rng = &mut world.rng;
+                world.partitioned = (0..size).map(|_| rng.gen_range(0..NODES)).collect();
+            }
+            92..=95 => world.partitioned.clear(),
+            96..=99 if world.nodes[node].up => world.nodes[node].take_snapshot(),
+            _ => {}
+        }
+    }
+
+    /// Checks that every `THRESHOLD` shares of every client applied by the nodes in the same
+    /// epoch reconstruct its secret.
+    fn check(&self) {
+        let world = self.world.lock().unwrap();
+        for (client, secret) in self.secrets.iter().enumerate() {
+            let mut epochs = world
+                .nodes
+                .iter()
+                .map(|node| node.epochs[client])
+                .collect::<Vec<_>>();
+            epochs.sort_unstable();
+            epochs.dedup();
+            for epoch in epochs {
+                let shares = world
+                    .nodes
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, node)| node.epochs[client] == epoch)
+                    .map(|(i, node)| (i, node.share(client).unwrap()))
+                    .collect::<Vec<_>>();
+                for subset in subsets(shares.len(), THRESHOLD as usize) {
+                    let raw_shares = subset
+                        .iter()
+                        .map(|i| shares[*i].1.clone().into())
+                        .collect::<Vec<Vec<u8>>>();
+                    assert_eq!(
+                        reconstruct(raw_shares, false).ok().as_ref(),
+                        Some(secret),
+                        "shares of nodes {:?} of client {} in epoch {} do not reconstruct the \
+                         secret (seed {}, step {})",
+                        subset.iter().map(|i| shares[*i].0).collect::<Vec<_>>(),
+                        client,
+                        epoch,
+                        self.seed,
+                        self.step
+                    );
+                }
+            }
+        }
+    }
+
+    /// Heals the network, recovers every node and delivers the whole log, which brings every
+    /// share to the same epoch.
+    fn settle(&mut self) {
+        let mut state = self.world.lock().unwrap();
+        let world = &mut *state;
+        world.partitioned.clear();
+        world.in_flight.clear();
+        for node in 0..NODES {
+            world.nodes[node].up = true;
+            while world.nodes[node].applied < world.log.len() {
+                let _ = world.apply_next(node);
+            }
+        }
+        for client in 0..CLIENTS as usize {
+            let epochs = world
+                .nodes
+                .iter()
+                .map(|node| node.epochs[client])
+                .collect::<BTreeSet<_>>();
+            assert_eq!(
+                epochs.len(),
+                1,
+                "shares of client {} are in several epochs once settled (seed {})",
+                client,
+                self.seed
+            );
+        }
+    }
+
+    /// Runs `steps` steps and returns the number of refresh rounds completed.
+    async fn run(mut self, steps: usize) -> usize {
+        for _ in 0..steps {
+            self.step().await;
+            self.check();
+        }
+        self.settle();
+        self.check();
+        self.rounds
+    }
+}
+
+/// Simulates every seed of `seeds` for `steps` steps.
+async fn simulate(seeds: u64, steps: usize) {
+    for seed in 0..seeds {
+        let rounds = Simulation::new(seed).await.run(steps).await;
+        // The faults must not prevent every refresh round from completing
+        assert!(rounds > 0, "no refresh round completed (seed {})", seed);
+    }
+}
+
+#[test]
+fn test_subsets() {
+    assert_eq!(
+        subsets(4, 2),
+        vec![
+            vec![0, 1],
+            vec![0, 2],
+            vec![1, 2],
+            vec![0, 3],
+            vec![1, 3],
+            vec![2, 3]
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_shares_reconstruct_under_faults() {
+    simulate(SEEDS, STEPS).await;
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_shares_reconstruct_under_faults_long() {
+    simulate(LONG_SEEDS, LONG_STEPS).await;
+}
@@ -0,0 +1,485 @@
+//! Wire format of the entries proposed to the consensus log.
+//!
+//! Entries are encoded as a protobuf `Envelope` prefixed with `MAGIC`. The envelope carries the
+//! wire version, the proposing node and the signature over the encoded `Body`, so nodes verify
+//! the exact bytes that were signed even if they do not know all of its fields. It also carries
+//! the ID of the request the entry was proposed for, to correlate the logs of the nodes. That ID
+//! is not signed, so it is untrusted: it is only logged, once checked to be a valid request ID,
+//! and never used to decide how an entry is applied. Protobuf skips unknown fields, and a `Body`
+//! holding a message kind added by a newer version is decoded without message, so nodes can be
+//! upgraded one at a time.
+//!
+//! The `Body` also carries the sequence number of the entry among the ones signed by its origin,
+//! which grows with every entry, so a captured entry cannot be replayed once applied.
+//!
+//! Fields and message kinds must only be added, with new tags, never renumbered or removed.
+//!
+//! Entries without `MAGIC` were written by the previous version as a plain bincode
+//! `LegacyMessage`, neither signed nor numbered, see `decode_legacy`.
+
+use bincode::deserialize;
+use prost::Message as _;
+use serde::{Deserialize, Serialize};
+use sss_wrap::secret::secret::Share;
+
+use crate::domain::error::SecretServerError;
+use crate::domain::model::{ClientId, NodeId};
+use crate::logging;
+
+use super::messages::{Message, ShareRefresh};
+
+/// Prefix of the entries encoded as `Envelope`.
+pub const MAGIC: &[u8; 4] = b"SSE\0";
+
+/// Version of the wire format written by this node.
+pub const WIRE_VERSION: u32 = 1;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Envelope {
+    #[prost(uint32, tag = "1")]
+    pub version: u32,
+    #[prost(uint32, tag = "2")]
+    pub origin: u32,
+    /// Encoded `Body`, kept as bytes so the signature is checked over what was signed.
+    #[prost(bytes = "vec", tag = "3")]
+    pub body: Vec<u8>,
+    #[prost(bytes = "vec", tag = "4")]
+    pub signature: Vec<u8>,
+    /// ID of the request the entry was proposed for. It is not covered by the signature, so it is
+    /// untrusted and only used to correlate logs.
+    #[prost(string, tag = "5")]
+    pub request_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Body {
+    #[prost(oneof = "body::Kind", tags = "1, 2, 3, 4, 5, 6")]
+    pub kind: Option<body::Kind>,
+    /// Sequence number of the entry among the ones signed by its origin, from 1.
+    #[prost(uint64, tag = "16")]
+    pub sequence: u64,
+}
+
+pub mod body {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Kind {
+        #[prost(message, tag = "1")]
+        StartRefresh(super::StartRefresh),
+        #[prost(message, tag = "2")]
+        Refresh(super::Refresh),
+        #[prost(message, tag = "3")]
+        FinishRefresh(super::FinishRefresh),
+        #[prost(message, tag = "4")]
+        Admit(super::Admit),
+        #[prost(message, tag = "5")]
+        RefreshBatch(super::RefreshBatch),
+        #[prost(message, tag = "6")]
+        Remove(super::Remove),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StartRefresh {
+    #[prost(uint32, tag = "1")]
+    pub node_id: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Refresh {
+    #[prost(uint64, tag = "1")]
+    pub client_id: u64,
+    /// Share encoded as its `x` followed by its `ys`.
+    #[prost(bytes = "vec", tag = "2")]
+    pub new_share: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FinishRefresh {
+    #[prost(uint32, tag = "1")]
+    pub node_id: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Admit {
+    #[prost(uint32, tag = "1")]
+    pub node_id: u32,
+    #[prost(string, tag = "2")]
+    pub raft_addr: String,
+    #[prost(bytes = "vec", tag = "3")]
+    pub public_key: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RefreshBatch {
+    #[prost(message, repeated, tag = "1")]
+    pub refreshes: Vec<Refresh>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Remove {
+    #[prost(uint32, tag = "1")]
+    pub node_id: u32,
+}
+
+/// Message of the previous version, proposed as is in bincode before the `Envelope` was
+/// introduced. It only knows the refresh messages, so an unsigned entry can never change the
+/// members.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LegacyMessage {
+    StartRefresh {
+        node_id: NodeId,
+    },
+    Refresh {
+        client_id: ClientId,
+        new_share: Share,
+    },
+    FinishRefresh {
+        node_id: NodeId,
+    },
+}
+
+impl From<LegacyMessage> for Message {
+    fn from(legacy: LegacyMessage) -> Self {
+        match legacy {
+            LegacyMessage::StartRefresh { node_id } => Message::StartRefresh { node_id },
+            LegacyMessage::Refresh {
+                client_id,
+                new_share,
+            } => Message::Refresh {
+                client_id,
+                new_share,
+            },
+            LegacyMessage::FinishRefresh { node_id } => Message::FinishRefresh { node_id },
+        }
+    }
+}
+
+/// Signed consensus entry decoded from any supported `Envelope` version.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub origin: NodeId,
+    /// The proposed message, or `None` if it is of a kind unknown to this version.
+    pub message: Option<Message>,
+    /// Sequence number of the entry among the ones signed by its origin.
+    pub sequence: u64,
+    /// ID of the request the entry was proposed for, if any. Untrusted, as it is not signed.
+    pub request_id: Option<String>,
+    signed_bytes: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl Entry {
+    /// Returns the bytes covered by the signature.
+    pub fn signed_bytes(&self) -> &[u8] {
+        &self.signed_bytes
+    }
+
+    /// Returns the signature of the proposing node.
+    pub fn signature(&self) -> &[u8] {
+        &self.signature
+    }
+}
+
+fn invalid(e: impl ToString) -> SecretServerError {
+    SecretServerError::InvalidStateError(format!("malformed consensus entry: {}", e.to_string()))
+}
+
+fn node_id(value: u32) -> Result<NodeId, SecretServerError> {
+    u8::try_from(value).map(NodeId).map_err(invalid)
+}
+
+/// Bytes covered by the signature of an `Envelope`, binding the body to its version and origin.
+pub fn envelope_signed_bytes(version: u32, origin: NodeId, body: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(MAGIC.len() + 5 + body.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&version.to_be_bytes());
+    bytes.push(*origin);
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+fn refresh(client_id: ClientId, new_share: &Share) -> Refresh {
+    Refresh {
+        client_id: client_id.0,
+        new_share: new_share.clone().into(),
+    }
+}
+
+impl TryFrom<Refresh> for ShareRefresh {
+    type Error = SecretServerError;
+
+    fn try_from(refresh: Refresh) -> Result<Self, Self::Error> {
+        let (x, ys) = refresh
+            .new_share
+            .split_first()
+            .ok_or_else(|| invalid("empty share"))?;
+        Ok(ShareRefresh {
+            client_id: ClientId(refresh.client_id),
+            new_share: Share::new(*x, ys.to_vec()),
+        })
+    }
+}
+
+impl Body {
+    fn new(message: &Message, sequence: u64) -> Self {
+        let kind = match message {
+            Message::StartRefresh { node_id } => body::Kind::StartRefresh(StartRefresh {
+                node_id: u32::from(**node_id),
+            }),
+            Message::Refresh {
+                client_id,
+                new_share,
+            } => body::Kind::Refresh(refresh(*client_id, new_share)),
+            Message::FinishRefresh { node_id } => body::Kind::FinishRefresh(FinishRefresh {
+                node_id: u32::from(**node_id),
+            }),
+            Message::Admit {
+                node_id,
+                raft_addr,
+                public_key,
+            } => body::Kind::Admit(Admit {
+                node_id: u32::from(**node_id),
+                raft_addr: raft_addr.clone(),
+                public_key: public_key.clone(),
+            }),
+            Message::RefreshBatch { refreshes } => body::Kind::RefreshBatch(RefreshBatch {
+                refreshes: refreshes
+                    .iter()
+                    .map(|r| refresh(r.client_id, &r.new_share))
+                    .collect(),
+            }),
+            Message::Remove { node_id } => body::Kind::Remove(Remove {
+                node_id: u32::from(**node_id),
+            }),
+        };
+        Self {
+            kind: Some(kind),
+            sequence,
+        }
+    }
+}
+
+impl TryFrom<body::Kind> for Message {
+    type Error = SecretServerError;
+
+    fn try_from(kind: body::Kind) -> Result<Self, Self::Error> {
+        Ok(match kind {
+            body::Kind::StartRefresh(m) => Message::StartRefresh {
+                node_id: node_id(m.node_id)?,
+            },
+            body::Kind::Refresh(m) => {
+                let ShareRefresh {
+                    client_id,
+                    new_share,
+                } = m.try_into()?;
+                Message::Refresh {
+                    client_id,
+                    new_share,
+                }
+            }
+            body::Kind::FinishRefresh(m) => Message::FinishRefresh {
+                node_id: node_id(m.node_id)?,
+            },
+            body::Kind::Admit(m) => Message::Admit {
+                node_id: node_id(m.node_id)?,
+                raft_addr: m.raft_addr,
+                public_key: m.public_key,
+            },
+            body::Kind::RefreshBatch(m) => Message::RefreshBatch {
+                refreshes: m
+                    .refreshes
+                    .into_iter()
+                    .map(ShareRefresh::try_from)
+                    .collect::<Result<_, _>>()?,
+            },
+            body::Kind::Remove(m) => Message::Remove {
+                node_id: node_id(m.node_id)?,
+            },
+        })
+    }
+}
+
+/// Encodes the message signed by `origin` as the current wire version, with the ID of the
+/// request the current task is working for.
+///
+/// # Arguments
+///
+/// * `origin` - The node proposing the message.
+/// * `sequence` - The sequence number of the entry among the ones signed by `origin`.
+/// * `message` - The message to propose.
+/// * `sign` - Signs the given bytes with the key of `origin`.
+pub fn encode(
+    origin: NodeId,
+    sequence: u64,
+    message: &Message,
+    sign: impl FnOnce(&[u8]) -> Vec<u8>,
+) -> Vec<u8> {
+    let body = Body::new(message, sequence).encode_to_vec();
+    let signature = sign(&envelope_signed_bytes(WIRE_VERSION, origin, &body));
+    let envelope = Envelope {
+        version: WIRE_VERSION,
+        origin: u32::from(*origin),
+        body,
+        signature,
+        request_id: logging::request_id().unwrap_or_default(),
+    };
+    let mut bytes = Vec::with_capacity(MAGIC.len() + envelope.encoded_len());
+    bytes.extend_from_slice(MAGIC);
+    envelope
+        .encode(&mut bytes)
+        .expect("a Vec grows to fit the envelope");
+    bytes
+}
+
+/// Returns `true` if the entry was written by the previous version, see `decode_legacy`.
+pub fn is_legacy(bytes: &[u8]) -> bool {
+    !bytes.starts_with(MAGIC)
+}
+
+/// Decodes a signed entry of any supported `Envelope` version.
+///
+/// # Errors
+///
+/// Returns an `InvalidStateError` if the entry is not a valid envelope.
+pub fn decode(bytes: &[u8]) -> Result<Entry, SecretServerError> {
+    let envelope = bytes
+        .strip_prefix(MAGIC.as_slice())
+        .ok_or_else(|| invalid("not a signed envelope"))?;
+    let envelope = Envelope::decode(envelope).map_err(invalid)?;
+    if envelope.version == 0 {
+        return Err(invalid("missing wire version"));
+    }
+    let origin = node_id(envelope.origin)?;
+    let body = Body::decode(envelope.body.as_slice()).map_err(invalid)?;
+    let message = body.kind.map(Message::try_from).transpose()?;
+    Ok(Entry {
+        origin,
+        message,
+        sequence: body.sequence,
+        request_id: Some(envelope.request_id).filter(|id| logging::is_valid_request_id(id)),
+        signed_bytes: envelope_signed_bytes(envelope.version, origin, &envelope.body),
+        signature: envelope.signature,
+    })
+}
+
+/// Decodes an entry written by the previous version, a plain bincode `LegacyMessage`.
+///
+/// Such entries carry neither origin nor signature, so they are only applied to let a cluster be
+/// upgraded one node at a time, and at most once each, see `HashStore`.
+///
+/// # Errors
+///
+/// Returns a `SerializeError` if the entry is not a `LegacyMessage`.
+pub fn decode_legacy(bytes: &[u8]) -> Result<Message, SecretServerError> {
+    Ok(deserialize::<LegacyMessage>(bytes)?.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_and_decode_envelope() {
+        let refresh = ShareRefresh {
+            client_id: ClientId(7),
+            new_share: Share::new(2, vec![1, 2, 3]),
+        };
+        let messages = [
+            Message::Refresh {
+                client_id: refresh.client_id,
+                new_share: refresh.new_share.clone(),
+            },
+            Message::RefreshBatch {
+                refreshes: vec![refresh.clone(), refresh],
+            },
+            Message::Remove { node_id: NodeId(3) },
+        ];
+        for message in messages {
+            let bytes = encode(NodeId(2), 5, &message, |bytes| bytes.to_vec());
+            assert!(bytes.starts_with(MAGIC));
+
+            let entry = decode(&bytes).unwrap();
+            assert_eq!(entry.origin, NodeId(2));
+            assert_eq!(entry.sequence, 5);
+            assert_eq!(entry.message, Some(message));
+            assert_eq!(entry.signature(), entry.signed_bytes());
+        }
+    }
+
+    #[test]
+    fn test_decode_unknown_fields_and_kinds() {
+        // A newer version adding field 15 to the envelope and message kind 15 to the body
+        let mut body = vec![];
+        prost::encoding::bytes::encode(15, &vec![1, 2, 3], &mut body);
+        let envelope = Envelope {
+            version: WIRE_VERSION + 1,
+            origin: 3,
+            body: body.clone(),
+            signature: vec![9; 64],
+            request_id: String::new(),
+        };
+        let mut bytes = MAGIC.to_vec();
+        envelope.encode(&mut bytes).unwrap();
+        prost::encoding::string::encode(15, &"new field".to_string(), &mut bytes);
+
+        let entry = decode(&bytes).unwrap();
+        assert_eq!(entry.origin, NodeId(3));
+        assert_eq!(entry.message, None);
+        assert_eq!(entry.request_id, None);
+        assert_eq!(
+            entry.signed_bytes(),
+            envelope_signed_bytes(WIRE_VERSION + 1, NodeId(3), &body)
+        );
+    }
+
+    #[test]
+    fn test_request_id_is_not_signed() {
+        let message = Message::Remove { node_id: NodeId(3) };
+        let propose = || encode(NodeId(2), 1, &message, |bytes| bytes.to_vec());
+        let in_request = decode(&logging::in_request(Some("req-1".to_string()), propose)).unwrap();
+        assert_eq!(in_request.request_id.as_deref(), Some("req-1"));
+
+        let outside = decode(&propose()).unwrap();
+        assert_eq!(outside.request_id, None);
+        assert_eq!(outside.signed_bytes(), in_request.signed_bytes());
+    }
+
+    #[test]
+    fn test_decode_legacy_entries() {
+        // Entries of the previous version are plain bincode messages
+        let refresh = LegacyMessage::Refresh {
+            client_id: ClientId(7),
+            new_share: Share::new(2, vec![1, 2, 3]),
+        };
+        let bytes = bincode::serialize(&refresh).unwrap();
+        assert!(is_legacy(&bytes));
+        assert!(decode(&bytes).is_err());
+        assert_eq!(
+            decode_legacy(&bytes).unwrap(),
+            Message::Refresh {
+                client_id: ClientId(7),
+                new_share: Share::new(2, vec![1, 2, 3]),
+            }
+        );
+
+        // Messages added since cannot be proposed unsigned
+        let remove = bincode::serialize(&Message::Remove { node_id: NodeId(1) }).unwrap();
+        assert!(decode_legacy(&remove).is_err());
+    }
+
+    #[test]
+    fn test_reject_malformed_entries() {
+        assert!(decode(&[]).is_err());
+        assert!(decode(b"SSE\0not protobuf").is_err());
+        let envelope = Envelope {
+            version: WIRE_VERSION,
+            origin: 256,
+            body: vec![],
+            signature: vec![],
+            request_id: String::new(),
+        };
+        let mut bytes = MAGIC.to_vec();
+        envelope.encode(&mut bytes).unwrap();
+        assert!(decode(&bytes).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+
+use super::model::ClientId;
+
+/// Operation a caller is allowed to perform over a client share, or over the cluster.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Debug, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Store a new share for the client.
+    Create,
+    /// Retrieve the share of the client.
+    Read,
+    /// Remove the share of the client.
+    Delete,
+    /// Administer the cluster, whatever the clients the credential is bound to.
+    Admin,
+}
+
+/// Inclusive range of client IDs a credential is bound to.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Debug, Copy)]
+pub struct Namespace {
+    pub from: u64,
+    pub to: u64,
+}
+
+/// Set of clients a credential can act on.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Copy)]
+pub enum Binding {
+    /// A single client.
+    Client(ClientId),
+    /// Every client inside the namespace.
+    Namespace(Namespace),
+}
+
+impl Binding {
+    /// Returns `true` if the given client falls inside this binding.
+    pub fn allows(&self, id: ClientId) -> bool {
+        match self {
+            Binding::Client(client_id) => *client_id == id,
+            Binding::Namespace(namespace) => namespace.from <= *id && *id <= namespace.to,
+        }
+    }
+}
+
+/// Authenticated caller with the clients and operations it has been granted.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Principal {
+    binding: Binding,
+    scopes: Vec<Scope>,
+}
+
+impl Principal {
+    /// Creates a new `Principal` bound to `binding` with the given `scopes`.
+    pub fn new(binding: Binding, scopes: Vec<Scope>) -> Self {
+        Self { binding, scopes }
+    }
+
+    /// Returns `true` if the principal can perform `scope` over the client `id`.
+    pub fn can(&self, scope: Scope, id: ClientId) -> bool {
+        self.scopes.contains(&scope) && self.binding.allows(id)
+    }
+
+    /// Returns `true` if the principal can administer the cluster.
+    pub fn is_admin(&self) -> bool {
+        self.scopes.contains(&Scope::Admin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_principal_bound_to_client() {
+        let principal = Principal::new(Binding::Client(ClientId(1)), vec![Scope::Read]);
+        assert!(principal.can(Scope::Read, ClientId(1)));
+        assert!(!principal.can(Scope::Read, ClientId(2)));
+        assert!(!principal.can(Scope::Create, ClientId(1)));
+    }
+
+    #[test]
+    fn test_principal_bound_to_namespace() {
+        let principal = Principal::new(
+            Binding::Namespace(Namespace { from: 10, to: 19 }),
+            vec![Scope::Create, Scope::Delete],
+        );
+        assert!(principal.can(Scope::Create, ClientId(10)));
+        assert!(principal.can(Scope::Delete, ClientId(19)));
+        assert!(!principal.can(Scope::Create, ClientId(20)));
+        assert!(!principal.can(Scope::Read, ClientId(15)));
+        assert!(!principal.is_admin());
+    }
+
+    #[test]
+    fn test_admin_principal() {
+        let principal = Principal::new(Binding::Client(ClientId(1)), vec![Scope::Admin]);
+        assert!(principal.is_admin());
+        assert!(!principal.can(Scope::Read, ClientId(1)));
+    }
+}
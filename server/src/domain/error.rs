@@ -3,11 +3,42 @@ use std::sync::PoisonError;
 
 use actix_web::{
     error,
-    http::{header::ContentType, StatusCode},
+    http::{
+        header::{CONTENT_TYPE, RETRY_AFTER},
+        StatusCode,
+    },
     HttpResponse,
 };
+use serde::{Deserialize, Serialize};
+use sss_wrap::sealed::share::SealError;
 use thiserror::Error;
 
+use crate::logging;
+
+/// Media type of the error responses.
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// Seconds a client is advised to wait before retrying a request rejected by a refresh round.
+pub const REFRESH_RETRY_AFTER_SECS: u64 = 5;
+
+/// Problem document (RFC 7807) the API answers errors with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// Stable, machine readable code of the error, e.g. `refresh_in_progress`.
+    pub code: String,
+    /// Reason phrase of the status.
+    pub title: String,
+    pub status: u16,
+    /// Description of this occurrence of the error.
+    pub detail: String,
+    /// Seconds to wait before retrying, if the request can be retried as is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+    /// ID of the request, also returned in the `X-Request-Id` header.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
 /// Error type for the processing based on thiserror crate
 #[derive(Error, Debug)]
 pub enum SecretServerError {
@@ -23,6 +54,75 @@ pub enum SecretServerError {
     RefreshError,
     #[error("Refresh in progress")]
     RefreshInProgress,
+    #[error("Invalid authentication configuration [{0}]")]
+    AuthConfigError(String),
+    #[error("Invalid request [{0}]")]
+    InvalidRequest(String),
+    #[error("Cannot seal share [{0}]")]
+    SealError(#[from] SealError),
+    #[error("Cluster admission refused [{0}]")]
+    AdmissionError(String),
+    #[error("Error in consensus backend [{0}]")]
+    BackendError(String),
+    #[error("Cannot render metrics [{0}]")]
+    MetricsError(#[from] prometheus::Error),
+    /// Too many requests, or too many unknown credentials, were sent. Holds the number of
+    /// seconds to wait before retrying.
+    #[error("Too many requests, retry in {0} seconds")]
+    RateLimited(u64),
+    #[error("Unknown credentials")]
+    Unauthorized,
+    #[error("Credentials not allowed to perform the request")]
+    Forbidden,
+    #[error("Not supported [{0}]")]
+    NotSupported(String),
+}
+
+impl SecretServerError {
+    /// Returns the stable code of the error, which clients can match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidStateError(_) => "invalid_state",
+            Self::ConsensusError(_) => "consensus_error",
+            Self::NotFound => "not_found",
+            Self::SerializeError(_) => "serialization_error",
+            Self::RefreshError => "refresh_failed",
+            Self::RefreshInProgress => "refresh_in_progress",
+            Self::AuthConfigError(_) => "auth_config_error",
+            Self::InvalidRequest(_) => "invalid_request",
+            Self::SealError(SealError::InvalidPublicKey) => "invalid_public_key",
+            Self::SealError(_) => "seal_error",
+            Self::AdmissionError(_) => "admission_refused",
+            Self::BackendError(_) => "backend_error",
+            Self::MetricsError(_) => "metrics_error",
+            Self::RateLimited(_) => "rate_limited",
+            Self::Unauthorized => "unauthorized",
+            Self::Forbidden => "forbidden",
+            Self::NotSupported(_) => "not_supported",
+        }
+    }
+
+    /// Returns the seconds to wait before retrying the request, if it can be retried as is.
+    pub fn retry_after_secs(&self) -> Option<u64> {
+        match self {
+            Self::RefreshInProgress => Some(REFRESH_RETRY_AFTER_SECS),
+            Self::RateLimited(retry_after_secs) => Some(*retry_after_secs),
+            _ => None,
+        }
+    }
+
+    /// Returns the problem document describing the error, with the ID of the current request.
+    pub fn problem(&self) -> Problem {
+        let status = error::ResponseError::status_code(self);
+        Problem {
+            code: self.code().to_string(),
+            title: status.canonical_reason().unwrap_or_default().to_string(),
+            status: status.as_u16(),
+            detail: self.to_string(),
+            retry_after_secs: self.retry_after_secs(),
+            request_id: logging::request_id(),
+        }
+    }
 }
 
 impl<T> From<PoisonError<T>> for SecretServerError {
@@ -39,9 +139,14 @@ impl From<SecretServerError> for riteraft::Error {
 
 impl error::ResponseError for SecretServerError {
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::html())
-            .body(self.to_string())
+        let problem = self.problem();
+        let mut response = HttpResponse::build(self.status_code());
+        if let Some(retry_after_secs) = problem.retry_after_secs {
+            response.insert_header((RETRY_AFTER, retry_after_secs.to_string()));
+        }
+        response
+            .insert_header((CONTENT_TYPE, PROBLEM_CONTENT_TYPE))
+            .json(problem)
     }
 
     fn status_code(&self) -> StatusCode {
@@ -52,6 +157,61 @@ impl error::ResponseError for SecretServerError {
             Self::SerializeError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::RefreshError => StatusCode::INTERNAL_SERVER_ERROR,
             Self::RefreshInProgress => StatusCode::CONFLICT,
+            Self::AuthConfigError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            Self::SealError(SealError::InvalidPublicKey) => StatusCode::BAD_REQUEST,
+            Self::SealError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::AdmissionError(_) => StatusCode::FORBIDDEN,
+            Self::BackendError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::MetricsError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::NotSupported(_) => StatusCode::NOT_IMPLEMENTED,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+    use actix_web::ResponseError;
+
+    #[tokio::test]
+    async fn test_problem_document() {
+        let response = logging::with_request_id("req-1".to_string(), async {
+            SecretServerError::RefreshInProgress.error_response()
+        })
+        .await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            PROBLEM_CONTENT_TYPE
+        );
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "5");
+        let body = response.into_body().try_into_bytes().unwrap();
+        let problem: Problem = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            problem,
+            Problem {
+                code: "refresh_in_progress".to_string(),
+                title: "Conflict".to_string(),
+                status: 409,
+                detail: "Refresh in progress".to_string(),
+                retry_after_secs: Some(REFRESH_RETRY_AFTER_SECS),
+                request_id: Some("req-1".to_string()),
+            }
+        );
+
+        let problem = SecretServerError::NotFound.problem();
+        assert_eq!((problem.code.as_str(), problem.status), ("not_found", 404));
+        assert_eq!(problem.retry_after_secs, None);
+
+        let problem = SecretServerError::NotSupported("remove".to_string()).problem();
+        assert_eq!(
+            (problem.code.as_str(), problem.status),
+            ("not_supported", 501)
+        );
+    }
+}
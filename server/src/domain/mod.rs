@@ -1,2 +1,4 @@
+pub mod access;
 pub mod error;
 pub mod model;
+pub mod redacted;
@@ -13,7 +13,7 @@ impl Deref for ClientId {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Debug, Copy)]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug, Copy)]
 pub struct NodeId(pub u8);
 
 impl Deref for NodeId {
@@ -0,0 +1,83 @@
+use std::fmt::{self, Debug, Display, Formatter};
+
+use serde::{Deserialize, Deserializer};
+
+/// Value that must never be written to the logs, such as a share or a credential.
+///
+/// `Debug` and `Display` only print the type of the value, so a `Redacted` value can be logged or
+/// kept in a struct deriving `Debug` without leaking it. The value is read with `expose`.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct Redacted<T>(T);
+
+impl<T> Redacted<T> {
+    /// Wraps `value` so it is never printed.
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    /// Returns the wrapped value.
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    /// Returns the wrapped value, consuming the wrapper.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Debug for Redacted<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Redacted<{}>", short_type_name::<T>())
+    }
+}
+
+impl<T> Display for Redacted<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(self, f)
+    }
+}
+
+impl<T> From<T> for Redacted<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+/// Redacted values are read from the settings like the value they wrap. They are deliberately not
+/// `Serialize`, so they cannot leak through a serialized structure either.
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Redacted<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Self)
+    }
+}
+
+/// Returns the name of `T` without its module path, e.g. `ShareMeta`.
+fn short_type_name<T>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    match name.rfind("::") {
+        // Generic types keep their full name, cutting them would be misleading
+        Some(index) if !name.contains('<') => &name[index + 2..],
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted_is_never_printed() {
+        let token = Redacted::new("s3cr3t-token".to_string());
+        assert_eq!(format!("{:?}", token), "Redacted<String>");
+        assert_eq!(format!("{}", token), "Redacted<String>");
+        assert_eq!(
+            format!("{:#?}", Some(&token)),
+            "Some(\n    Redacted<String>,\n)"
+        );
+        assert_eq!(token.expose(), "s3cr3t-token");
+
+        let token: Redacted<String> = serde_json::from_str("\"s3cr3t-token\"").unwrap();
+        assert_eq!(token.into_inner(), "s3cr3t-token");
+    }
+}
@@ -1,6 +1,10 @@
 #![warn(rust_2018_idioms, missing_debug_implementations)]
+pub mod audit;
 pub mod conf;
 pub mod consensus;
 pub mod domain;
+pub mod logging;
+pub mod metrics;
+pub mod node;
 pub mod refresher;
 pub mod routes;
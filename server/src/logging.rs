@@ -0,0 +1,236 @@
+//! Logs of the node.
+//!
+//! Records are written as text or JSON lines by a `slog` drain. The records of the `log` crate,
+//! used by the server, are forwarded to it tagged with the ID of the HTTP request they belong to:
+//! the ID is kept in a task local while the request is handled and travels with the consensus
+//! entries it proposes, so the records of every node applying them carry it too.
+//!
+//! Shares and credentials must never be logged as is, see `Redacted`.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::io;
+
+use slog::{Drain, Logger, Never};
+use slog_async::AsyncGuard;
+
+use crate::conf::settings::{LogFormat, LogSettings};
+
+/// Header carrying the ID of a request, set on every response.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest request ID accepted from a client.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+tokio::task_local! {
+    /// ID of the HTTP request handled by the current task.
+    static REQUEST_ID: String;
+}
+
+thread_local! {
+    /// ID of the request of the consensus entry applied by the current thread.
+    static APPLIED_REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Returns the ID of the request the current task or thread is working for, if any.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID
+        .try_with(Clone::clone)
+        .ok()
+        .or_else(|| APPLIED_REQUEST_ID.with(|id| id.borrow().clone()))
+}
+
+/// Runs `future` on behalf of the request `request_id`.
+pub async fn with_request_id<F: Future>(request_id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(request_id, future).await
+}
+
+/// Runs `f` on behalf of the request `request_id`, if any, within the current thread.
+pub fn in_request<R>(request_id: Option<String>, f: impl FnOnce() -> R) -> R {
+    let previous = APPLIED_REQUEST_ID.with(|id| id.replace(request_id));
+    let result = f();
+    APPLIED_REQUEST_ID.with(|id| *id.borrow_mut() = previous);
+    result
+}
+
+/// Returns `true` if `request_id` can be logged as is: short and made of letters, digits, `-`
+/// and `_` only, so that a client cannot forge log records with it.
+pub fn is_valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= MAX_REQUEST_ID_LEN
+        && request_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Returns the request ID sent by the client if it is valid, otherwise a new random one.
+pub fn request_id_or_new(sent: Option<&str>) -> String {
+    match sent.filter(|sent| is_valid_request_id(sent)) {
+        Some(sent) => sent.to_string(),
+        None => hex::encode(rand::random::<[u8; 8]>()),
+    }
+}
+
+/// Returns a logger writing the records from the configured level to `writer`, and the guard
+/// flushing the pending records when dropped.
+pub fn logger<W>(settings: &LogSettings, writer: W) -> (Logger, AsyncGuard)
+where
+    W: io::Write + Send + 'static,
+{
+    let drain: Box<dyn Drain<Ok = (), Err = Never> + Send> = match settings.format() {
+        LogFormat::Json => Box::new(
+            slog_json::Json::new(writer)
+                .add_default_keys()
+                .build()
+                .fuse(),
+        ),
+        LogFormat::Text => {
+            let decorator = slog_term::PlainSyncDecorator::new(writer);
+            Box::new(slog_term::FullFormat::new(decorator).build().fuse())
+        }
+    };
+    let drain = slog::LevelFilter::new(drain, settings.level()).fuse();
+    let (drain, guard) = slog_async::Async::new(drain)
+        .chan_size(4096)
+        .overflow_strategy(slog_async::OverflowStrategy::Block)
+        .build_with_guard();
+    (Logger::root(drain.ignore_res(), slog::o!()), guard)
+}
+
+/// Logger of the node, installed as the global logger of the `log` crate and of `slog_scope`.
+///
+/// Dropping it flushes the pending records, records logged afterwards are lost.
+pub struct LogGuard {
+    logger: Logger,
+    _scope: slog_scope::GlobalLoggerGuard,
+    _flush: AsyncGuard,
+}
+
+impl fmt::Debug for LogGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogGuard").finish_non_exhaustive()
+    }
+}
+
+impl LogGuard {
+    /// Returns the logger of the node.
+    pub fn logger(&self) -> &Logger {
+        &self.logger
+    }
+}
+
+/// Installs the logger writing to `writer` as configured in `settings`.
+///
+/// # Errors
+///
+/// Returns a `SetLoggerError` if a logger of the `log` crate is already installed.
+pub fn init<W>(settings: &LogSettings, writer: W) -> Result<LogGuard, log::SetLoggerError>
+where
+    W: io::Write + Send + 'static,
+{
+    let (logger, flush) = logger(settings, writer);
+    let level = log_level(settings.level());
+    log::set_logger(Box::leak(Box::new(LogBridge {
+        logger: logger.clone(),
+        level,
+    })))?;
+    log::set_max_level(level);
+    Ok(LogGuard {
+        _scope: slog_scope::set_global_logger(logger.clone()),
+        logger,
+        _flush: flush,
+    })
+}
+
+/// Returns the filter of the `log` crate matching the `slog` level.
+fn log_level(level: slog::Level) -> log::LevelFilter {
+    match level {
+        slog::Level::Critical | slog::Level::Error => log::LevelFilter::Error,
+        slog::Level::Warning => log::LevelFilter::Warn,
+        slog::Level::Info => log::LevelFilter::Info,
+        slog::Level::Debug => log::LevelFilter::Debug,
+        slog::Level::Trace => log::LevelFilter::Trace,
+    }
+}
+
+/// Forwards the records of the `log` crate to the logger, with the ID of their request.
+struct LogBridge {
+    logger: Logger,
+    level: log::LevelFilter,
+}
+
+impl log::Log for LogBridge {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let location = slog::RecordLocation {
+            file: record.file_static().unwrap_or("<unknown>"),
+            line: record.line().unwrap_or_default(),
+            column: 0,
+            function: "",
+            module: record.module_path_static().unwrap_or("<unknown>"),
+        };
+        let level = match record.level() {
+            log::Level::Error => slog::Level::Error,
+            log::Level::Warn => slog::Level::Warning,
+            log::Level::Info => slog::Level::Info,
+            log::Level::Debug => slog::Level::Debug,
+            log::Level::Trace => slog::Level::Trace,
+        };
+        let record_static = slog::RecordStatic {
+            location: &location,
+            tag: record.target(),
+            level,
+        };
+        match request_id() {
+            Some(request_id) => self.logger.log(&slog::Record::new(
+                &record_static,
+                record.args(),
+                slog::b!("request_id" => request_id),
+            )),
+            None => self.logger.log(&slog::Record::new(
+                &record_static,
+                record.args(),
+                slog::b!(),
+            )),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_ids() {
+        assert_eq!(request_id_or_new(Some("req-42_a")), "req-42_a");
+        for forged in ["", "id\nforged record", &"a".repeat(MAX_REQUEST_ID_LEN + 1)] {
+            let request_id = request_id_or_new(Some(forged));
+            assert_ne!(request_id, forged);
+            assert!(is_valid_request_id(&request_id));
+        }
+
+        assert_eq!(request_id(), None);
+        let inner = in_request(Some("req-1".to_string()), request_id);
+        assert_eq!(inner.as_deref(), Some("req-1"));
+        assert_eq!(request_id(), None);
+    }
+
+    #[tokio::test]
+    async fn test_request_id_of_task() {
+        let request_id = with_request_id("req-2".to_string(), async {
+            tokio::task::yield_now().await;
+            request_id()
+        })
+        .await;
+        assert_eq!(request_id.as_deref(), Some("req-2"));
+    }
+}
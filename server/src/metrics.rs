@@ -0,0 +1,214 @@
+//! Prometheus metrics of the node, served on `/metrics`.
+//!
+//! The metrics are process wide, so they are kept in a registry shared by every part of the
+//! server instead of being threaded through the consensus handler.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramTimer, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+
+/// Buckets of the refresh duration histogram, in seconds.
+const REFRESH_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// Buckets of the apply latency histogram, in seconds.
+const APPLY_BUCKETS: &[f64] = &[
+    0.000_05, 0.000_1, 0.000_25, 0.000_5, 0.001, 0.002_5, 0.005, 0.01, 0.025, 0.05, 0.1,
+];
+
+/// Metrics only exported when the backend exposes the term and leader of the consensus group.
+const CONSENSUS_METRICS: &[&str] = &[
+    "secret_server_raft_term",
+    "secret_server_leader_changes_total",
+];
+
+/// Metrics of the node.
+pub struct Metrics {
+    registry: Registry,
+    share_requests: IntCounterVec,
+    auth_failures: IntCounterVec,
+    refresh_rounds: IntCounterVec,
+    refresh_duration: Histogram,
+    stored_secrets: IntGauge,
+    raft_term: IntGauge,
+    leader_changes: IntCounter,
+    apply_duration: Histogram,
+}
+
+impl std::fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("secret_server".to_string()), None)?;
+        let metrics = Self {
+            share_requests: IntCounterVec::new(
+                Opts::new(
+                    "share_requests_total",
+                    "Share requests by operation and status",
+                ),
+                &["operation", "status"],
+            )?,
+            auth_failures: IntCounterVec::new(
+                Opts::new("auth_failures_total", "Rejected credentials by reason"),
+                &["reason"],
+            )?,
+            refresh_rounds: IntCounterVec::new(
+                Opts::new("refresh_rounds_total", "Refresh rounds by outcome"),
+                &["outcome"],
+            )?,
+            refresh_duration: Histogram::with_opts(
+                HistogramOpts::new(
+                    "refresh_duration_seconds",
+                    "Duration of the completed refresh rounds",
+                )
+                .buckets(REFRESH_BUCKETS.to_vec()),
+            )?,
+            stored_secrets: IntGauge::new("stored_secrets", "Secrets with a share on the node")?,
+            raft_term: IntGauge::new("raft_term", "Current term of the consensus group")?,
+            leader_changes: IntCounter::new("leader_changes_total", "Leader changes seen")?,
+            apply_duration: Histogram::with_opts(
+                HistogramOpts::new(
+                    "apply_duration_seconds",
+                    "Latency of applying a committed entry to the store",
+                )
+                .buckets(APPLY_BUCKETS.to_vec()),
+            )?,
+            registry,
+        };
+        metrics
+            .registry
+            .register(Box::new(metrics.share_requests.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.auth_failures.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.refresh_rounds.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.refresh_duration.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.stored_secrets.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.raft_term.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.leader_changes.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.apply_duration.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Counts a share request of `operation` answered with `status`.
+    pub fn share_request(&self, operation: &str, status: StatusCode) {
+        self.share_requests
+            .with_label_values(&[operation, status.as_str()])
+            .inc();
+    }
+
+    /// Counts credentials rejected for `reason`.
+    pub fn auth_failure(&self, reason: &str) {
+        self.auth_failures.with_label_values(&[reason]).inc();
+    }
+
+    /// Counts a refresh round started by the node.
+    pub fn refresh_started(&self) {
+        self.refresh_rounds.with_label_values(&["started"]).inc();
+    }
+
+    /// Counts a refresh round completed by the node in `duration`.
+    pub fn refresh_completed(&self, duration: Duration) {
+        self.refresh_rounds.with_label_values(&["completed"]).inc();
+        self.refresh_duration.observe(duration.as_secs_f64());
+    }
+
+    /// Counts a refresh round the node failed to start or complete.
+    pub fn refresh_failed(&self) {
+        self.refresh_rounds.with_label_values(&["failed"]).inc();
+    }
+
+    /// Counts a change of leader of the consensus group.
+    pub fn leader_changed(&self) {
+        self.leader_changes.inc();
+    }
+
+    /// Starts timing the application of a committed entry, observed when the timer is dropped.
+    pub fn apply_timer(&self) -> HistogramTimer {
+        self.apply_duration.start_timer()
+    }
+
+    /// Renders every metric in the Prometheus text format, with the gauges sampled at scrape
+    /// time.
+    ///
+    /// Without `raft_term` the backend does not expose the leader either, so `raft_term` and
+    /// `leader_changes_total` are left out instead of being reported as zero.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `prometheus::Error` if the metrics cannot be encoded.
+    pub fn render(
+        &self,
+        stored_secrets: usize,
+        raft_term: Option<u64>,
+    ) -> prometheus::Result<String> {
+        self.stored_secrets.set(stored_secrets as i64);
+        if let Some(term) = raft_term {
+            self.raft_term.set(term as i64);
+        }
+        let mut families = self.registry.gather();
+        if raft_term.is_none() {
+            families.retain(|family| !CONSENSUS_METRICS.contains(&family.get_name()));
+        }
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&families, &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+}
+
+/// Returns the metrics of the node.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("metrics are registered once"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_metrics() {
+        let metrics = metrics();
+        metrics.share_request("read", StatusCode::OK);
+        metrics.auth_failure("unauthorized");
+        metrics.refresh_started();
+        metrics.refresh_completed(Duration::from_millis(300));
+        drop(metrics.apply_timer());
+
+        let text = metrics.render(3, Some(7)).unwrap();
+        assert!(
+            text.contains(r#"secret_server_share_requests_total{operation="read",status="200"}"#)
+        );
+        assert!(text.contains(r#"secret_server_auth_failures_total{reason="unauthorized"}"#));
+        assert!(text.contains(r#"secret_server_refresh_rounds_total{outcome="completed"}"#));
+        assert!(text.contains("secret_server_refresh_duration_seconds_bucket"));
+        assert!(text.contains("secret_server_apply_duration_seconds_count"));
+        assert!(text.contains("secret_server_stored_secrets 3"));
+        assert!(text.contains("secret_server_raft_term 7"));
+        assert!(text.contains("secret_server_leader_changes_total"));
+
+        let text = metrics.render(3, None).unwrap();
+        assert!(!text.contains("secret_server_raft_term"));
+        assert!(!text.contains("secret_server_leader_changes_total"));
+    }
+}
@@ -0,0 +1,227 @@
+//! Startup of a server node: the consensus backend, the HTTP API and the secret refresher.
+//!
+//! The server binary runs a single node, tests can run several of them in the same process.
+
+use std::error::Error;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::dev::ServerHandle;
+use log::warn;
+use slog::Logger;
+use tokio::task::{AbortHandle, JoinHandle};
+
+use crate::audit::AuditLog;
+use crate::conf::settings::Settings;
+use crate::consensus::admission::{request_admission, JoinRequest};
+use crate::consensus::backend::{init_consensus, ConsensusTask};
+use crate::consensus::bootstrap::{record_bootstrap, retry_peers, StartMode};
+use crate::consensus::handler::ConsensusHandler;
+use crate::consensus::raft::HashStore;
+use crate::consensus::signing::NodeKey;
+use crate::domain::model::NodeId;
+use crate::refresher::schedule::RefreshSchedule;
+use crate::refresher::secret;
+use crate::routes::http;
+
+/// Registers the node and its public key in the replicated membership table.
+///
+/// The proposal is retried because the cluster needs to elect a leader before it can commit it.
+/// A joining node cannot propose before the join has completed and it knows the leader, which
+/// `riteraft` never reports, so it has the first of its peers accepting it propose its
+/// admission, or proposes it once it knows the leader if no peer has an `http_addr`.
+async fn admit_self(
+    consensus_handler: ConsensusHandler,
+    node_id: NodeId,
+    mode: StartMode,
+    peer_http_addrs: Vec<String>,
+    raft_addr: String,
+) {
+    if mode != StartMode::Bootstrap && !peer_http_addrs.is_empty() {
+        let admitted = retry_peers("be admitted to the cluster", &peer_http_addrs, |peer| {
+            let (consensus_handler, peer, raft_addr) =
+                (consensus_handler.clone(), peer.clone(), raft_addr.clone());
+            async move { consensus_handler.admit_through(&peer, &raft_addr).await }
+        })
+        .await;
+        if let Err(e) = admitted {
+            warn!("Node could not be admitted as cluster member: {}", e);
+        }
+        return;
+    }
+    for _ in 0..30 {
+        if mode == StartMode::Bootstrap || consensus_handler.status().leader.is_some() {
+            match consensus_handler
+                .admit(node_id, &raft_addr, consensus_handler.public_key())
+                .await
+            {
+                Ok(_) => return,
+                Err(e) => warn!("Cannot admit node yet: {}", e),
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    warn!("Node could not be admitted as cluster member");
+}
+
+/// Handle stopping a running node.
+#[derive(Clone)]
+pub struct NodeHandle {
+    server: ServerHandle,
+    consensus: AbortHandle,
+    refresher: AbortHandle,
+}
+
+impl std::fmt::Debug for NodeHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NodeHandle").finish()
+    }
+}
+
+impl NodeHandle {
+    /// Stops the HTTP server gracefully, then the consensus and refresher tasks.
+    pub async fn stop(&self) {
+        warn!("Shutting down http server....");
+        self.server.stop(true).await;
+        warn!("Shutting down Consensus Module....");
+        self.consensus.abort();
+        warn!("Shutting down Cron Module....");
+        self.refresher.abort();
+    }
+}
+
+/// Server node running the consensus backend, the HTTP API and the secret refresher.
+pub struct Node {
+    consensus_handler: ConsensusHandler,
+    handle: NodeHandle,
+    consensus: ConsensusTask,
+    http_server: JoinHandle<io::Result<()>>,
+    refresher: JoinHandle<()>,
+}
+
+impl std::fmt::Debug for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Node")
+            .field("consensus_handler", &self.consensus_handler)
+            .finish()
+    }
+}
+
+impl Node {
+    /// Starts the node configured in `settings`.
+    ///
+    /// When a `cluster_token` is configured the node is admitted through the first of its peers
+    /// accepting it before joining the consensus group, otherwise it registers itself through its
+    /// peers once the group can commit entries. The bootstrapping node records it in its
+    /// bootstrap marker.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the node key cannot be loaded, the admission is refused or the
+    /// consensus backend or HTTP server cannot be started.
+    pub async fn start(settings: &Settings, logger: Logger) -> Result<Self, Box<dyn Error>> {
+        let node_id = NodeId(settings.node_id());
+        let node_key = Arc::new(NodeKey::load_or_generate(settings.node_key_path())?);
+        let store = match settings.cluster_token() {
+            Some(_) => HashStore::new(node_id).require_admission(),
+            None => HashStore::new(node_id),
+        };
+
+        let mode = StartMode::from_settings(settings);
+        let peer_http_addrs = settings
+            .peers()
+            .iter()
+            .filter_map(|peer| peer.http_addr().map(str::to_string))
+            .collect::<Vec<_>>();
+        if let (Some(cluster_token), StartMode::Join(_)) = (settings.cluster_token(), &mode) {
+            // Every attempt presents a new credential, as a credential is accepted once and
+            // expires during the backoff
+            retry_peers("be admitted to the cluster", &peer_http_addrs, |peer| {
+                let request = JoinRequest::new(
+                    cluster_token,
+                    node_id,
+                    settings.raft_addr(),
+                    node_key.public_key(),
+                );
+                let peer = peer.clone();
+                async move { request_admission(&peer, &request).await }
+            })
+            .await?;
+        }
+
+        let (consensus, backend) =
+            init_consensus(settings, store.clone(), node_key.clone(), logger).await?;
+
+        let consensus_handler = ConsensusHandler::new(store, backend, node_key.clone())
+            .with_refresh_batch_size(settings.refresh_batch_size())
+            .with_peer_http_addrs(peer_http_addrs.clone())
+            .with_cluster_token(settings.cluster_token().map(str::to_string));
+
+        // The bootstrapping node creates the membership table. Without a cluster token the other
+        // nodes register themselves, otherwise a peer admits them after verifying their join
+        // credential.
+        if mode == StartMode::Bootstrap {
+            record_bootstrap(settings)?;
+        }
+        if mode == StartMode::Bootstrap || settings.cluster_token().is_none() {
+            tokio::spawn(admit_self(
+                consensus_handler.clone(),
+                node_id,
+                mode,
+                peer_http_addrs,
+                settings.raft_addr().to_string(),
+            ));
+        }
+
+        let audit_log = Arc::new(AuditLog::open(
+            node_id,
+            settings.audit_log_path(),
+            &node_key,
+        )?);
+        let server = http::run(settings, consensus_handler.clone(), audit_log.clone()).await?;
+        let server_handle = server.handle();
+        let http_server = tokio::spawn(server);
+
+        let refresher = tokio::spawn(secret::run(
+            RefreshSchedule::from_settings(settings),
+            consensus_handler.clone(),
+            audit_log,
+        ));
+
+        Ok(Self {
+            consensus_handler,
+            handle: NodeHandle {
+                server: server_handle,
+                consensus: consensus.abort_handle(),
+                refresher: refresher.abort_handle(),
+            },
+            consensus,
+            http_server,
+            refresher,
+        })
+    }
+
+    /// Returns the consensus handler of the node.
+    pub fn consensus_handler(&self) -> &ConsensusHandler {
+        &self.consensus_handler
+    }
+
+    /// Returns a handle stopping the node.
+    pub fn handle(&self) -> NodeHandle {
+        self.handle.clone()
+    }
+
+    /// Waits until the consensus backend, the HTTP server or the refresher stops.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the first task failing or being stopped.
+    pub async fn wait(self) -> Result<(), Box<dyn Error>> {
+        let (consensus, http_server, _) =
+            tokio::try_join!(self.consensus, self.http_server, self.refresher)?;
+        consensus?;
+        http_server?;
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+//! Refreshes triggered by the departure of a node, so that the shares it held stop being useful.
+//!
+//! Every node follows the consensus events, but only the coordinator runs the refresh.
+//!
+//! The refresh keeps the number of shares of every secret. Resharing a secret over the new
+//! number of members is not supported, the secrets whose share count differs from it are only
+//! reported by `unreshared` and have to be created again.
+
+use std::collections::{BTreeSet, HashSet};
+
+use log::info;
+
+use crate::consensus::backend::ConsensusEvent;
+use crate::consensus::handler::ConsensusHandler;
+use crate::domain::model::{ClientId, NodeId};
+
+/// Nodes that left the cluster or were replaced since the last refresh of their shares.
+#[derive(Debug)]
+pub struct Departures {
+    node_id: NodeId,
+    voters: Vec<NodeId>,
+    pending: BTreeSet<NodeId>,
+    handled: HashSet<NodeId>,
+}
+
+impl Departures {
+    /// Follows the departures seen by the node `node_id`, whose consensus group has `voters`.
+    pub fn new(node_id: NodeId, voters: Vec<NodeId>) -> Self {
+        Self {
+            node_id,
+            voters,
+            pending: BTreeSet::new(),
+            handled: HashSet::new(),
+        }
+    }
+
+    /// Records the departures of the consensus event.
+    ///
+    /// A node removed from the members and then from the voters is only refreshed away once,
+    /// until it is admitted again.
+    pub fn observe(&mut self, event: &ConsensusEvent) {
+        match event {
+            ConsensusEvent::MemberRemoved { node_id } => self.depart(*node_id),
+            ConsensusEvent::MemberReplaced { node_id } => {
+                self.handled.remove(node_id);
+                self.depart(*node_id);
+            }
+            ConsensusEvent::MembershipChanged { voters } => {
+                let departed = self
+                    .voters
+                    .iter()
+                    .filter(|voter| !voters.contains(voter))
+                    .copied()
+                    .collect::<Vec<_>>();
+                for node_id in departed {
+                    self.depart(node_id);
+                }
+                self.voters = voters.clone();
+            }
+            ConsensusEvent::MemberAdmitted { node_id } => {
+                self.handled.remove(node_id);
+            }
+            ConsensusEvent::LeaderChanged { .. } => {}
+        }
+    }
+
+    /// Records that the events of the node may have been missed, so any node may have left.
+    pub fn missed_events(&mut self) {
+        info!("Consensus events were missed, refreshing in case a node has left");
+        self.pending.insert(self.node_id);
+    }
+
+    fn depart(&mut self, node_id: NodeId) {
+        if node_id != self.node_id && !self.handled.contains(&node_id) {
+            info!("Node {:?} has left, its shares must be refreshed", node_id);
+            self.pending.insert(node_id);
+        }
+    }
+
+    /// Returns `true` if a refresh is pending for departed nodes.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Records that the shares of the departed nodes have been refreshed.
+    pub fn refreshed(&mut self) {
+        let node_id = self.node_id;
+        self.handled
+            .extend(self.pending.iter().filter(|departed| **departed != node_id));
+        self.pending.clear();
+    }
+}
+
+/// Returns `true` if the node runs the refreshes triggered by departures: the leader, or the
+/// member with the lowest node ID if the backend does not expose the leader or the leader has
+/// left.
+pub fn is_coordinator(consensus_handler: &ConsensusHandler) -> bool {
+    let status = consensus_handler.status();
+    let members = consensus_handler.members().unwrap_or_default();
+    match status.leader.filter(|leader| members.contains_key(leader)) {
+        Some(leader) => leader == status.node_id,
+        None => members.keys().min() == Some(&status.node_id),
+    }
+}
+
+/// Returns the clients, in order, whose secret is spread over another number of shares than
+/// there are `members`, among the `share_counts` of the stored secrets.
+pub fn unreshared(share_counts: Vec<(ClientId, u8)>, members: usize) -> Vec<ClientId> {
+    let mut clients = share_counts
+        .into_iter()
+        .filter(|(_, count)| usize::from(*count) != members)
+        .map(|(client_id, _)| client_id)
+        .collect::<Vec<_>>();
+    clients.sort_by_key(|client_id| client_id.0);
+    clients
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_departures() {
+        let mut departures = Departures::new(NodeId(1), vec![NodeId(1), NodeId(2), NodeId(3)]);
+        departures.observe(&ConsensusEvent::LeaderChanged {
+            leader: Some(NodeId(2)),
+        });
+        assert!(!departures.is_pending());
+
+        // The removal of node 3 from the members then from the voters is refreshed once
+        departures.observe(&ConsensusEvent::MemberRemoved { node_id: NodeId(3) });
+        assert!(departures.is_pending());
+        departures.refreshed();
+        departures.observe(&ConsensusEvent::MembershipChanged {
+            voters: vec![NodeId(1), NodeId(2)],
+        });
+        assert!(!departures.is_pending());
+
+        // A replaced node is refreshed away even if its former departure was
+        departures.observe(&ConsensusEvent::MemberReplaced { node_id: NodeId(3) });
+        assert!(departures.is_pending());
+        departures.refreshed();
+
+        // The departure of the node itself is left to the others
+        departures.observe(&ConsensusEvent::MemberRemoved { node_id: NodeId(1) });
+        assert!(!departures.is_pending());
+    }
+
+    #[test]
+    fn test_unreshared() {
+        let share_counts = vec![(ClientId(3), 2), (ClientId(1), 3), (ClientId(2), 4)];
+        assert_eq!(
+            unreshared(share_counts.clone(), 3),
+            vec![ClientId(2), ClientId(3)]
+        );
+        assert_eq!(unreshared(share_counts, 4), vec![ClientId(1), ClientId(3)]);
+    }
+}
@@ -1 +1,3 @@
+pub mod membership;
+pub mod schedule;
 pub mod secret;
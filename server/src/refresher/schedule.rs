@@ -0,0 +1,195 @@
+//! Schedule of the refresh rounds: secrets are due after a fixed interval or rounds follow a cron
+//! expression, with a random jitter and maintenance windows during which no refresh runs.
+
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, NaiveTime, Utc};
+use rand::Rng;
+
+use crate::conf::settings::Settings;
+
+/// Schedule of the refresh rounds of a node.
+#[derive(Debug, Clone)]
+pub struct RefreshSchedule {
+    interval: Duration,
+    cron: Option<cron::Schedule>,
+    jitter: Duration,
+    windows: Vec<(NaiveTime, NaiveTime)>,
+}
+
+impl RefreshSchedule {
+    /// Creates the schedule configured in `settings`.
+    pub fn from_settings(settings: &Settings) -> Self {
+        let schedule = settings.refresh_schedule();
+        Self {
+            interval: Duration::from_secs(settings.interval_refresh_secs()),
+            cron: schedule.cron(),
+            jitter: schedule.jitter(),
+            windows: schedule
+                .maintenance_windows()
+                .iter()
+                .map(|window| (window.start(), window.end()))
+                .collect(),
+        }
+    }
+
+    /// Returns the interval after which a secret without its own interval is due, or `None` if
+    /// these secrets are refreshed by the rounds of the cron expression.
+    pub fn default_interval(&self) -> Option<Duration> {
+        match self.cron {
+            Some(_) => None,
+            None => Some(self.interval),
+        }
+    }
+
+    /// Returns the first round of the cron expression after `after`, if one is configured.
+    pub fn next_round(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.cron
+            .as_ref()
+            .and_then(|cron| cron.after(&after).next())
+    }
+
+    /// Returns the maximum random delay added to every scheduled refresh.
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// Returns a random delay between zero and the jitter.
+    pub fn random_jitter(&self) -> Duration {
+        let millis = self.jitter.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+    }
+
+    /// Returns the end of the maintenance window `at` falls in, if any.
+    pub fn maintenance_end(&self, at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let time = at.time();
+        let today = at.date_naive();
+        self.windows
+            .iter()
+            .filter_map(|(start, end)| {
+                let end_day = if start <= end {
+                    (*start <= time && time < *end).then_some(today)
+                } else if time >= *start {
+                    today.succ_opt()
+                } else {
+                    (time < *end).then_some(today)
+                };
+                end_day.map(|day| day.and_time(*end).and_utc())
+            })
+            .max()
+    }
+
+    /// Returns `at`, or the end of the maintenance windows it falls in.
+    pub fn defer(&self, mut at: DateTime<Utc>) -> DateTime<Utc> {
+        // Windows can overlap, each step leaves one of them
+        for _ in 0..=self.windows.len() {
+            match self.maintenance_end(at) {
+                Some(end) => at = end,
+                None => break,
+            }
+        }
+        at
+    }
+
+    /// Returns when a secret due at `due` is refreshed next, without the jitter, if `now` is
+    /// the current time.
+    pub fn next_refresh(
+        &self,
+        due: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let next = match (due.map(|due| due.max(now)), self.next_round(now)) {
+            (Some(due), Some(round)) => due.min(round),
+            (due, round) => due.or(round)?,
+        };
+        Some(self.defer(next))
+    }
+}
+
+/// Returns the wall clock time of the instant `at`.
+pub fn wall_clock(at: Instant) -> DateTime<Utc> {
+    let now = Instant::now();
+    let delta =
+        |d: Duration| chrono::Duration::from_std(d).unwrap_or_else(|_| chrono::Duration::zero());
+    if at >= now {
+        Utc::now() + delta(at - now)
+    } else {
+        Utc::now() - delta(now - at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(cron: Option<&str>, windows: &[(&str, &str)]) -> RefreshSchedule {
+        let mut toml = String::from(
+            r#"
+            raft_addr = "server-1:7070"
+            http_port = 8080
+            node_id = 1
+            interval_refresh_secs = 3600
+            bootstrap = true
+            [refresh_schedule]
+            jitter_secs = 30
+            "#,
+        );
+        if let Some(cron) = cron {
+            toml.push_str(&format!("cron = \"{}\"\n", cron));
+        }
+        for (start, end) in windows {
+            toml.push_str(&format!(
+                "[[refresh_schedule.maintenance_windows]]\nstart = \"{}\"\nend = \"{}\"\n",
+                start, end
+            ));
+        }
+        RefreshSchedule::from_settings(&Settings::from_toml(&toml).unwrap())
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().into()
+    }
+
+    #[test]
+    fn test_maintenance_windows() {
+        let schedule = schedule(None, &[("22:00", "02:00"), ("01:30", "03:00")]);
+        assert_eq!(schedule.maintenance_end(at("2024-05-01T12:00:00Z")), None);
+        assert_eq!(
+            schedule.maintenance_end(at("2024-05-01T23:00:00Z")),
+            Some(at("2024-05-02T02:00:00Z"))
+        );
+        assert_eq!(
+            schedule.maintenance_end(at("2024-05-02T01:45:00Z")),
+            Some(at("2024-05-02T03:00:00Z"))
+        );
+        // Overlapping windows are left one after the other
+        assert_eq!(
+            schedule.defer(at("2024-05-01T23:00:00Z")),
+            at("2024-05-02T03:00:00Z")
+        );
+    }
+
+    #[test]
+    fn test_next_refresh() {
+        let now = at("2024-05-01T12:00:00Z");
+        let interval = schedule(None, &[("13:00", "14:00")]);
+        assert_eq!(interval.default_interval(), Some(Duration::from_secs(3600)));
+        assert_eq!(interval.next_refresh(None, now), None);
+        assert_eq!(
+            interval.next_refresh(Some(at("2024-05-01T13:30:00Z")), now),
+            Some(at("2024-05-01T14:00:00Z"))
+        );
+
+        let cron = schedule(Some("0 0 */6 * * *"), &[]);
+        assert_eq!(cron.default_interval(), None);
+        assert_eq!(
+            cron.next_refresh(None, now),
+            Some(at("2024-05-01T18:00:00Z"))
+        );
+        assert_eq!(
+            cron.next_refresh(Some(at("2024-05-01T11:00:00Z")), now),
+            Some(now)
+        );
+        assert!(cron.random_jitter() <= cron.jitter());
+    }
+}
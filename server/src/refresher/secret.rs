@@ -1,77 +1,265 @@
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use log::info;
-use tokio::time::Instant;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use tokio::sync::broadcast::error::RecvError;
 
+use crate::audit::{AuditAction, AuditLog, AuditOutcome, AuditRecord};
 use crate::consensus::handler::ConsensusHandler;
 use crate::domain::error::SecretServerError;
+use crate::domain::model::ClientId;
+use crate::metrics::metrics;
+
+use super::membership::{is_coordinator, unreshared, Departures};
+use super::schedule::RefreshSchedule;
+
+/// Shortest delay between two wake-ups of the refresher, so that a round skipped because another
+/// one is in progress is not retried in a busy loop.
+const MIN_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between two wake-ups of the refresher, so that secrets stored in the meantime
+/// with a short refresh interval are not refreshed late.
+const MAX_DELAY: Duration = Duration::from_secs(60);
 
 /// Asynchronously refreshes secrets.
 ///
-/// The function takes a `ConsensusHandler` as an argument and attempts to refresh the secrets.
-/// If `is_begin_refresh` returns `true`, the function skips the refresh process and returns early.
-/// Otherwise, it sends a start refresh message and waits for the response.
+/// The function takes a `ConsensusHandler` as an argument and attempts to refresh the secrets
+/// of the given `clients`, or of every stored client.
+/// If `is_begin_refresh` returns `true`, another node is refreshing the secrets and the function
+/// returns early. Otherwise, it sends a start refresh message and waits for the response.
 /// If the response is successful, it proceeds to refresh the secrets and then finishes the refresh process.
+/// The outcome of a started round is recorded in the audit log, for each of the `clients` or
+/// once for every stored client.
 ///
 /// # Arguments
 ///
 /// * `consensus_handler` - The consensus handler used for secret refreshing.
+/// * `clients` - The clients to refresh, every stored client if `None`.
+/// * `audit_log` - The audit log the round is recorded in.
+/// * `caller` - The caller requesting the round, `None` for the scheduled rounds.
 ///
 /// # Returns
 ///
-/// Returns `Ok(())` if the secrets are refreshed successfully, otherwise returns a `SecretServerError`.
-async fn refresh_secret(consensus_handler: ConsensusHandler) -> Result<(), SecretServerError> {
+/// Returns the number of clients refreshed, `RefreshInProgress` if another refresh is in progress,
+/// `InvalidRequest` if some of the `clients` have no share stored, otherwise a `SecretServerError`.
+pub async fn refresh_secret(
+    consensus_handler: &ConsensusHandler,
+    clients: Option<&[ClientId]>,
+    audit_log: &AuditLog,
+    caller: Option<&str>,
+) -> Result<usize, SecretServerError> {
     if consensus_handler.is_begin_refresh() {
-        info!("Secrets are being refreshed, skipping this refresh");
-        return Ok(());
+        return Err(SecretServerError::RefreshInProgress);
     }
-    info!("Refreshing all secrets share with new random polynomial coefficients");
+    // Unknown clients are rejected before the round starts, so it is never left unfinished
+    for client_id in clients.unwrap_or_default() {
+        if consensus_handler.get(*client_id)?.is_none() {
+            return Err(SecretServerError::InvalidRequest(format!(
+                "no share stored for client {}",
+                client_id.0
+            )));
+        }
+    }
+    info!("Refreshing secrets share with new random polynomial coefficients");
+    let audit = |outcome| match clients {
+        Some(clients) => clients
+            .iter()
+            .for_each(|client_id| audit_refresh(audit_log, Some(*client_id), caller, outcome)),
+        None => audit_refresh(audit_log, None, caller, outcome),
+    };
     let start = consensus_handler.start_refresh().await;
-    if let Ok(_) = start {
+    if start.is_ok() {
         info!("Start refresh message sent successfully");
-        consensus_handler.refresh_secrets().await?;
-        consensus_handler.finish_refresh().await
+        metrics().refresh_started();
+        let started_at = Instant::now();
+        let refreshed = match consensus_handler.refresh_clients(clients).await {
+            Ok(refreshed) => consensus_handler.finish_refresh().await.map(|_| refreshed),
+            Err(e) => Err(e),
+        };
+        match refreshed {
+            Ok(_) => {
+                metrics().refresh_completed(started_at.elapsed());
+                audit(AuditOutcome::Success);
+            }
+            Err(_) => {
+                metrics().refresh_failed();
+                audit(AuditOutcome::Failed);
+            }
+        }
+        refreshed
     } else {
+        metrics().refresh_failed();
+        audit(AuditOutcome::Failed);
         Err(SecretServerError::RefreshError)
     }
 }
 
-/// Runs the secret refresher task with the specified interval.
+/// Records the refresh of the share of `client_id`, or of every share, in the audit log.
+fn audit_refresh(
+    audit_log: &AuditLog,
+    client_id: Option<ClientId>,
+    caller: Option<&str>,
+    outcome: AuditOutcome,
+) {
+    audit_log.try_record(AuditRecord {
+        action: AuditAction::Refresh,
+        client_id,
+        caller: caller.map(str::to_string),
+        outcome,
+        status: None,
+    });
+}
+
+/// Splits the clients whose refresh is due at `now` from the others, and returns them with the
+/// time the next client is due, if any.
+fn due_clients(
+    due_times: Vec<(ClientId, Instant)>,
+    now: Instant,
+) -> (Vec<ClientId>, Option<Instant>) {
+    let mut next = None;
+    let mut due = vec![];
+    for (client_id, due_time) in due_times {
+        if due_time <= now {
+            due.push(client_id);
+        } else {
+            next = Some(next.map_or(due_time, |next: Instant| next.min(due_time)));
+        }
+    }
+    (due, next)
+}
+
+/// Warns about the secrets spread over another number of shares than there are members, which
+/// a refresh does not reshare.
+fn warn_unreshared(consensus_handler: &ConsensusHandler) {
+    let (Ok(share_counts), Ok(members)) = (
+        consensus_handler.share_counts(),
+        consensus_handler.members(),
+    ) else {
+        return;
+    };
+    let clients = unreshared(share_counts, members.len());
+    if !clients.is_empty() {
+        warn!(
+            "The secrets of clients {:?} have another number of shares than the {} members, \
+             resharing is not supported: create them again to spread them over the members",
+            clients
+                .iter()
+                .map(|client_id| client_id.0)
+                .collect::<Vec<_>>(),
+            members.len()
+        );
+    }
+}
+
+/// Refreshes every secret if the node coordinates the refreshes triggered by departures, and
+/// returns `true` once the shares of the departed nodes no longer need to be refreshed.
+async fn refresh_departed(consensus_handler: &ConsensusHandler, audit_log: &AuditLog) -> bool {
+    if !is_coordinator(consensus_handler) {
+        return true;
+    }
+    info!("Refreshing secrets after the departure of a node");
+    match refresh_secret(consensus_handler, None, audit_log, None).await {
+        Ok(refreshed) => {
+            info!("{} secrets refreshed after the departure", refreshed);
+            warn_unreshared(consensus_handler);
+            true
+        }
+        Err(e) => {
+            info!("Error refreshing secrets after the departure: {}", e);
+            false
+        }
+    }
+}
+
+/// Returns the delay until the next secret or round is due, waking up regularly for new secrets.
+fn next_delay(next_round: Option<DateTime<Utc>>, next_due: Option<Instant>) -> Duration {
+    let until_round = next_round.and_then(|round| (round - Utc::now()).to_std().ok());
+    let until_due = next_due.map(|due| due.saturating_duration_since(Instant::now()));
+    [until_round, until_due]
+        .into_iter()
+        .flatten()
+        .fold(MAX_DELAY, Duration::min)
+        .max(MIN_DELAY)
+}
+
+/// Runs the secret refresher task with the specified schedule.
 ///
-/// The function initializes a timer to keep track of the start time.
-/// It then enters an infinite loop where it calculates the next execution time based on the interval.
-/// It sleeps until the next execution time is reached, and then calls the `refresh_secret` function.
-/// If the function succeeds, it logs a success message, otherwise it logs an error message.
-/// Finally, it updates the start time for the next iteration.
+/// The function enters an infinite loop where it first refreshes every secret if a node has left
+/// the cluster or was replaced, so that the shares it held stop being useful. Otherwise it waits
+/// for the end of the maintenance windows, then refreshes every secret if a round of the cron
+/// expression is due, or the secrets due after their own `refresh_interval_secs` or the
+/// default interval.
+/// It sleeps until the next secret or round is due, plus a random jitter, or until a consensus
+/// event is received.
+/// If the refresh succeeds, it logs a success message, otherwise it logs an error message.
 ///
 /// # Arguments
 ///
-/// * `interval_secs` - The interval in seconds between each secret refresh task.
+/// * `schedule` - The schedule of the refresh rounds.
 /// * `consensus_handler` - The consensus handler used for secret refreshing.
-pub async fn run(interval_secs: u64, consensus_handler: ConsensusHandler) {
-    let mut start_time =
-        Instant::now() + Duration::from_secs(interval_secs) + Duration::from_secs(20);
-
+/// * `audit_log` - The audit log the refreshes are recorded in.
+pub async fn run(
+    schedule: RefreshSchedule,
+    consensus_handler: ConsensusHandler,
+    audit_log: Arc<AuditLog>,
+) {
     info!(
-        "Starting secret refresher task with interval {} seconds",
-        interval_secs
+        "Starting secret refresher task with schedule {:?}",
+        schedule
     );
+    let mut events = consensus_handler.subscribe();
+    let status = consensus_handler.status();
+    let mut departures = Departures::new(status.node_id, status.voters);
+    let mut next_round = schedule.next_round(Utc::now());
 
     loop {
-        // Calculate the next time the task should be executed based on the interval
-        let next_execution_time = start_time + Duration::from_secs(interval_secs);
+        let now = Utc::now();
+        let delay = if departures.is_pending() {
+            // Shares of departed nodes are made useless right away, even in a maintenance window
+            if refresh_departed(&consensus_handler, &audit_log).await {
+                departures.refreshed();
+            }
+            MIN_DELAY
+        } else if let Some(end) = schedule.maintenance_end(now) {
+            info!("Refreshes are paused by a maintenance window until {}", end);
+            (end - now).to_std().unwrap_or_default()
+        } else {
+            let round_due = next_round.is_some_and(|round| round <= now);
+            if round_due {
+                next_round = schedule.next_round(now);
+            }
+            let (due, next_due) =
+                match consensus_handler.refresh_due_times(schedule.default_interval()) {
+                    Ok(due_times) => due_clients(due_times, Instant::now()),
+                    Err(e) => {
+                        info!("Error scheduling secrets refresh: {}", e);
+                        (vec![], None)
+                    }
+                };
 
-        // Sleep until the next execution time
-        tokio::time::sleep_until(next_execution_time).await;
+            if round_due || !due.is_empty() {
+                // A round of the cron expression refreshes every secret
+                let clients = (!round_due).then_some(due.as_slice());
+                match refresh_secret(&consensus_handler, clients, &audit_log, None).await {
+                    Ok(refreshed) => info!("{} secrets refreshed successfully", refreshed),
+                    Err(SecretServerError::RefreshInProgress) => {
+                        info!("Secrets are being refreshed, skipping this refresh")
+                    }
+                    Err(e) => info!("Error refreshing secrets: {}", e),
+                }
+            }
+            next_delay(next_round, next_due)
+        };
 
-        // Perform your task here
-        let result = refresh_secret(consensus_handler.clone()).await;
-        match result {
-            Ok(_) => info!("Secrets refreshed successfully"),
-            Err(e) => info!("Error refreshing secrets: {}", e),
+        tokio::select! {
+            _ = tokio::time::sleep(delay + schedule.random_jitter()) => {}
+            event = events.recv() => match event {
+                Ok(event) => departures.observe(&event),
+                Err(RecvError::Lagged(_)) => departures.missed_events(),
+                // The store publishing the events lives as long as the handler
+                Err(RecvError::Closed) => return,
+            },
         }
-
-        // Update the start time for the next iteration
-        start_time = next_execution_time;
     }
 }
@@ -0,0 +1,160 @@
+//! Cluster administration routes, served under `/admin` to keys with the `admin` scope.
+
+use std::time::Duration;
+
+use actix_web::{delete, get, post, web, HttpMessage, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+
+use crate::audit::{AuditQuery, Caller};
+use crate::consensus::backend::Role;
+use crate::domain::error::SecretServerError;
+use crate::domain::model::{ClientId, NodeId};
+use crate::refresher::secret::refresh_secret;
+
+use super::context::AppContext;
+
+/// Time after which a member not accepting connections on its Raft address is reported down.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Cluster member as seen by the node answering the request.
+#[derive(Serialize, Debug)]
+struct ClusterMember {
+    node_id: NodeId,
+    raft_addr: String,
+    role: Role,
+    /// Whether the member accepts connections on its Raft address.
+    alive: bool,
+}
+
+/// Cluster as seen by the node answering the request.
+#[derive(Serialize, Debug)]
+struct ClusterView {
+    node_id: NodeId,
+    leader: Option<NodeId>,
+    members: Vec<ClusterMember>,
+}
+
+/// Secrets to refresh, every stored secret if no client is given.
+#[derive(Deserialize, Debug, Default)]
+struct RefreshRequest {
+    #[serde(default)]
+    client_ids: Option<Vec<ClientId>>,
+}
+
+/// Result of a refresh round.
+#[derive(Serialize, Debug)]
+struct RefreshResponse {
+    /// Number of clients whose shares were refreshed.
+    refreshed: usize,
+}
+
+/// Returns `true` if a connection to `addr` is accepted within `PROBE_TIMEOUT`.
+async fn is_reachable(addr: &str) -> bool {
+    matches!(
+        tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect(addr)).await,
+        Ok(Ok(_))
+    )
+}
+
+/// Lists the cluster members. Their role is `unknown` when the backend does not expose it, as
+/// with `riteraft`.
+#[get("/cluster")]
+async fn cluster(data: web::Data<AppContext>) -> Result<HttpResponse, SecretServerError> {
+    let handler = data.consensus_handler();
+    let status = handler.status();
+    let mut members = handler.members()?.into_iter().collect::<Vec<_>>();
+    members.sort_by_key(|(node_id, _)| *node_id);
+    let mut view = Vec::with_capacity(members.len());
+    for (node_id, member) in members {
+        let role = if node_id == status.node_id {
+            status.role
+        } else if status.leader == Some(node_id) {
+            Role::Leader
+        } else if status.voters.contains(&node_id) {
+            Role::Follower
+        } else {
+            Role::Unknown
+        };
+        let alive = node_id == status.node_id || is_reachable(&member.raft_addr).await;
+        view.push(ClusterMember {
+            node_id,
+            raft_addr: member.raft_addr,
+            role,
+            alive,
+        });
+    }
+    Ok(HttpResponse::Ok().json(ClusterView {
+        node_id: status.node_id,
+        leader: status.leader,
+        members: view,
+    }))
+}
+
+/// Removes a node from the consensus group, then from the cluster members. Answered with
+/// `501 Not Implemented` when the backend cannot remove another node, as with `riteraft`.
+#[delete("/cluster/members/{node_id}")]
+async fn remove_member(
+    data: web::Data<AppContext>,
+    path: web::Path<u8>,
+) -> Result<HttpResponse, SecretServerError> {
+    data.consensus_handler()
+        .remove_member(NodeId(path.into_inner()))
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Removes the node from the cluster members, then from the consensus group. Answered with
+/// `501 Not Implemented` once the node has left the members if the backend cannot take it out
+/// of the consensus group, as with a `riteraft` follower.
+#[post("/cluster/leave")]
+async fn leave(data: web::Data<AppContext>) -> Result<HttpResponse, SecretServerError> {
+    data.consensus_handler().leave().await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/refresh")]
+async fn refresh(
+    req: HttpRequest,
+    data: web::Data<AppContext>,
+    request: web::Json<RefreshRequest>,
+) -> Result<HttpResponse, SecretServerError> {
+    let clients = request.into_inner().client_ids;
+    let caller = req
+        .extensions()
+        .get::<Caller>()
+        .map(|caller| caller.0.clone());
+    let refreshed = refresh_secret(
+        &data.consensus_handler(),
+        clients.as_deref(),
+        data.audit_log(),
+        caller.as_deref(),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(RefreshResponse { refreshed }))
+}
+
+/// Entries of the audit log of the node, filtered by client and time range.
+#[get("/audit")]
+async fn audit(
+    data: web::Data<AppContext>,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, SecretServerError> {
+    Ok(HttpResponse::Ok().json(data.audit_log().query(&query)?))
+}
+
+/// Verifies the hash chain of the audit log of the node.
+#[get("/audit/verify")]
+async fn verify_audit(data: web::Data<AppContext>) -> Result<HttpResponse, SecretServerError> {
+    Ok(HttpResponse::Ok().json(data.audit_log().verify()?))
+}
+
+/// Registers the admin routes.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(cluster)
+        .service(remove_member)
+        .service(leave)
+        .service(refresh)
+        .service(audit)
+        .service(verify_audit);
+}
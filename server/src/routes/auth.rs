@@ -1,15 +1,77 @@
 use actix_web::dev::ServiceRequest;
-use actix_web::error::{ErrorInternalServerError, ErrorUnauthorized};
-use actix_web::{web, Error};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, Error, HttpMessage};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 
+use crate::audit::{AuditAction, AuditOutcome, AuditRecord, Caller};
+use crate::domain::access::Scope;
+use crate::domain::error::SecretServerError;
+use crate::domain::model::ClientId;
+use crate::metrics::metrics;
+
 use super::context::AppContext;
+use super::tls::ClientCertificate;
+
+/// Returns the scope required by the request based on its HTTP method.
+fn required_scope(method: &Method) -> Option<Scope> {
+    match *method {
+        Method::POST => Some(Scope::Create),
+        Method::GET => Some(Scope::Read),
+        Method::DELETE => Some(Scope::Delete),
+        _ => None,
+    }
+}
+
+/// Returns the `{client_id}` path segment of the request, if present and valid.
+fn requested_client(req: &ServiceRequest) -> Option<ClientId> {
+    req.match_info()
+        .get("client_id")
+        .and_then(|id| id.parse::<u64>().ok())
+        .map(ClientId)
+}
+
+/// Returns `true` if the client certificate of the connection matches the requested client, or
+/// if the connection has no certificate and `required` is `false`.
+fn certificate_allows(req: &ServiceRequest, client_id: ClientId, required: bool) -> bool {
+    match req.conn_data::<ClientCertificate>() {
+        Some(cert) => cert.client_id() == Some(client_id),
+        None => !required,
+    }
+}
+
+/// Returns the error of a request reaching a validator without application context.
+fn missing_context() -> Error {
+    SecretServerError::InvalidStateError("application context not found".to_string()).into()
+}
+
+/// Counts and audits the credentials of `req` rejected with `status`.
+fn reject(config: &AppContext, req: &ServiceRequest, token: &str, status: StatusCode) {
+    let reason = if status == StatusCode::FORBIDDEN {
+        "forbidden"
+    } else {
+        "unauthorized"
+    };
+    metrics().auth_failure(reason);
+    config.audit_log().try_record(AuditRecord {
+        action: AuditAction::AuthFailure,
+        client_id: requested_client(req),
+        caller: Some(Caller::from_token(token).0),
+        outcome: AuditOutcome::Denied,
+        status: Some(status.as_u16()),
+    });
+}
 
 /// Validator function for validating bearer token.
 ///
 /// This function is used as a middleware to validate the bearer token
-/// provided in the request. It checks if the token is valid by calling
-/// the `validate_key` method on the `AppContext` configuration.
+/// provided in the request. It looks up the token in the key registry of the
+/// `AppContext` and checks that the key is bound to the `{client_id}` path
+/// segment and carries the scope required by the HTTP method. When client
+/// certificates are verified (mTLS), a certificate presented by the connection
+/// must also match the `{client_id}` path segment, and connections without
+/// certificate are only accepted if `require_client_cert` is not set. Rejected credentials are
+/// recorded in the audit log, the `Caller` of accepted ones is kept in the
+/// extensions of the request.
 ///
 /// # Arguments
 ///
@@ -19,15 +81,17 @@ use super::context::AppContext;
 /// # Returns
 ///
 /// Returns a `Result` containing `ServiceRequest` on success, or an `Error`
-/// along with the `ServiceRequest` on failure. The error can be either an
-/// unauthorized error if the token is invalid, or an internal server error
-/// if the application configuration is not found.
+/// along with the `ServiceRequest` on failure. The error can be an
+/// unauthorized error if the token is unknown, a forbidden error if the key is
+/// not allowed to perform the operation over the requested client or the
+/// client certificate does not match it, or an internal server error if the
+/// application configuration is not found.
 ///
 /// # Examples
 ///
 /// ```
 /// use actix_web::dev::ServiceRequest;
-/// use actix_web::error::{ErrorInternalServerError, ErrorUnauthorized};
+/// use actix_web::error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized};
 /// use actix_web::{web, Error};
 /// use actix_web_httpauth::extractors::bearer::BearerAuth;
 ///
@@ -39,13 +103,11 @@ use super::context::AppContext;
 /// ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
 ///     let config = req.app_data::<web::Data<AppContext>>();
 ///     match config {
-///         Some(config) => {
-///             if config.validate_key(credentials.token()) {
-///                 Ok(req)
-///             } else {
-///                 Err((ErrorUnauthorized("Unauthorized"), req))
-///             }
-///         }
+///         Some(config) => match config.validate_key(credentials.token()) {
+///             Some(principal) if principal.can(Scope::Read, ClientId(1)) => Ok(req),
+///             Some(_) => Err((ErrorForbidden("Forbidden"), req)),
+///             None => Err((ErrorUnauthorized("Unauthorized"), req)),
+///         },
 ///         None => Err((ErrorInternalServerError("Internal Error"), req)),
 ///     }
 /// }
@@ -57,12 +119,58 @@ pub async fn validator(
     let config = req.app_data::<web::Data<AppContext>>();
     match config {
         Some(config) => {
-            if config.validate_key(credentials.token()) {
+            let principal = match config.validate_key(credentials.token()) {
+                Some(principal) => principal,
+                None => {
+                    reject(config, &req, credentials.token(), StatusCode::UNAUTHORIZED);
+                    return Err((SecretServerError::Unauthorized.into(), req));
+                }
+            };
+            let client_cert_required = config.client_cert_required();
+            let allowed = required_scope(req.method())
+                .zip(requested_client(&req))
+                .map(|(scope, client_id)| {
+                    principal.can(scope, client_id)
+                        && certificate_allows(&req, client_id, client_cert_required)
+                })
+                .unwrap_or(false);
+            if allowed {
+                req.extensions_mut()
+                    .insert(Caller::from_token(credentials.token()));
                 Ok(req)
             } else {
-                Err((ErrorUnauthorized("Unauthorized"), req))
+                reject(config, &req, credentials.token(), StatusCode::FORBIDDEN);
+                Err((SecretServerError::Forbidden.into(), req))
             }
         }
-        None => Err((ErrorInternalServerError("Internal Error"), req)),
+        None => Err((missing_context(), req)),
+    }
+}
+
+/// Validator function for the bearer token of the admin routes.
+///
+/// The token is validated like in `validator`, but the key must carry the `admin` scope instead
+/// of being bound to a client.
+pub async fn admin_validator(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+) -> Result<ServiceRequest, (Error, ServiceRequest)> {
+    let Some(config) = req.app_data::<web::Data<AppContext>>() else {
+        return Err((missing_context(), req));
+    };
+    match config.validate_key(credentials.token()) {
+        Some(principal) if principal.is_admin() => {
+            req.extensions_mut()
+                .insert(Caller::from_token(credentials.token()));
+            Ok(req)
+        }
+        Some(_) => {
+            reject(config, &req, credentials.token(), StatusCode::FORBIDDEN);
+            Err((SecretServerError::Forbidden.into(), req))
+        }
+        None => {
+            reject(config, &req, credentials.token(), StatusCode::UNAUTHORIZED);
+            Err((SecretServerError::Unauthorized.into(), req))
+        }
     }
 }
@@ -1,9 +1,26 @@
+use std::sync::Arc;
+
+use crate::audit::AuditLog;
+use crate::consensus::admission::JoinVerifier;
 use crate::consensus::handler::ConsensusHandler;
+use crate::domain::access::Principal;
+use crate::refresher::schedule::RefreshSchedule;
+
+use super::jwt::JwtValidator;
+use super::keys::KeyRegistry;
+use super::rate_limit::RateLimiter;
 
 /// The application context.
 pub struct AppContext {
     consensus_handler: ConsensusHandler,
-    api_key: String,
+    key_registry: Arc<KeyRegistry>,
+    jwt_validator: Option<Arc<JwtValidator>>,
+    client_cert_required: bool,
+    require_sealed_shares: bool,
+    join_verifier: Option<Arc<JoinVerifier>>,
+    refresh_schedule: Arc<RefreshSchedule>,
+    audit_log: Arc<AuditLog>,
+    rate_limiter: Option<Arc<RateLimiter>>,
 }
 
 impl AppContext {
@@ -12,15 +29,41 @@ impl AppContext {
     /// # Arguments
     ///
     /// * `consensus_handler` - The consensus handler.
-    /// * `api_key` - The API key as a string.
+    /// * `key_registry` - The registry of hashed API keys.
+    /// * `jwt_validator` - The JWT validator, if JWT authentication is enabled.
+    /// * `client_cert_required` - Whether API requests must present a client certificate.
+    /// * `require_sealed_shares` - Whether shares are only released sealed to a client key.
+    /// * `join_verifier` - The verifier of the credentials of joining nodes, if cluster admission
+    ///   is enabled.
+    /// * `refresh_schedule` - The schedule of the refresh rounds of the node.
+    /// * `audit_log` - The audit log of the accesses to the shares.
+    /// * `rate_limiter` - The rate limiter of the API, if rate limiting is enabled.
     ///
     /// # Returns
     ///
     /// A new `AppContext` instance.
-    pub fn new(consensus_handler: ConsensusHandler, api_key: &str) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        consensus_handler: ConsensusHandler,
+        key_registry: Arc<KeyRegistry>,
+        jwt_validator: Option<Arc<JwtValidator>>,
+        client_cert_required: bool,
+        require_sealed_shares: bool,
+        join_verifier: Option<Arc<JoinVerifier>>,
+        refresh_schedule: Arc<RefreshSchedule>,
+        audit_log: Arc<AuditLog>,
+        rate_limiter: Option<Arc<RateLimiter>>,
+    ) -> Self {
         Self {
             consensus_handler,
-            api_key: api_key.to_string(),
+            key_registry,
+            jwt_validator,
+            client_cert_required,
+            require_sealed_shares,
+            join_verifier,
+            refresh_schedule,
+            audit_log,
+            rate_limiter,
         }
     }
 
@@ -33,7 +76,41 @@ impl AppContext {
         self.consensus_handler.clone()
     }
 
-    /// Validates the provided key against the stored API key.
+    /// Returns `true` if API requests must present a client certificate (mTLS with
+    /// `require_client_cert`), otherwise requests without certificate are authenticated by their
+    /// bearer token alone.
+    pub fn client_cert_required(&self) -> bool {
+        self.client_cert_required
+    }
+
+    /// Returns `true` if shares can only be released sealed to a client public key.
+    pub fn require_sealed_shares(&self) -> bool {
+        self.require_sealed_shares
+    }
+
+    /// Returns the verifier of the credentials of joining nodes and of the relayed entries, if
+    /// cluster admission is enabled.
+    pub fn join_verifier(&self) -> Option<&JoinVerifier> {
+        self.join_verifier.as_deref()
+    }
+
+    /// Returns the schedule of the refresh rounds of the node.
+    pub fn refresh_schedule(&self) -> &RefreshSchedule {
+        &self.refresh_schedule
+    }
+
+    /// Returns the audit log of the accesses to the shares.
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit_log
+    }
+
+    /// Returns the rate limiter of the API, if rate limiting is enabled.
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_deref()
+    }
+
+    /// Validates the provided key as a JWT, when JWT authentication is enabled, or against the
+    /// key registry.
     ///
     /// # Arguments
     ///
@@ -41,8 +118,14 @@ impl AppContext {
     ///
     /// # Returns
     ///
-    /// `true` if the provided key is valid, otherwise `false`.
-    pub fn validate_key(&self, key: &str) -> bool {
-        self.api_key == key
+    /// The `Principal` bound to the key if it is valid, otherwise `None`.
+    pub fn validate_key(&self, key: &str) -> Option<Principal> {
+        let is_jwt = key.split('.').count() == 3;
+        match &self.jwt_validator {
+            Some(jwt_validator) if is_jwt => jwt_validator
+                .authenticate(key)
+                .or_else(|| self.key_registry.authenticate(key)),
+            _ => self.key_registry.authenticate(key),
+        }
     }
 }
@@ -0,0 +1,198 @@
+//! Liveness and readiness routes, served without authentication to the probes of the platform.
+
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+
+use crate::consensus::backend::Role;
+use crate::domain::model::NodeId;
+
+use super::context::AppContext;
+
+/// Number of entries of the local log not applied yet above which the store is too far behind
+/// to serve the shares of the cluster.
+const MAX_APPLY_LAG: u64 = 100;
+
+/// Health of the node as seen by itself.
+#[derive(Serialize, Debug)]
+struct HealthReport {
+    node_id: NodeId,
+    role: Role,
+    leader: Option<NodeId>,
+    /// Whether the node is an admitted cluster member.
+    member: bool,
+    last_log_index: Option<u64>,
+    last_applied: Option<u64>,
+    /// Entries of the local log not applied to the store yet, if the backend exposes them.
+    apply_lag: Option<u64>,
+    refreshing: bool,
+    /// Time since the refresh round in progress started.
+    refreshing_for_secs: Option<u64>,
+    storage_healthy: bool,
+    /// Whether shares are only released sealed to a client key.
+    require_sealed_shares: bool,
+    /// Reasons the node cannot serve a consistent share, empty if it is ready.
+    failures: Vec<String>,
+    /// Readiness checks skipped as the backend does not expose the state they need.
+    unchecked: Vec<String>,
+}
+
+impl HealthReport {
+    /// Builds the report of the node serving `data`.
+    fn new(data: &AppContext) -> Self {
+        let handler = data.consensus_handler();
+        let status = handler.status();
+        let member = handler
+            .members()
+            .is_ok_and(|members| members.contains_key(&status.node_id));
+        let apply_lag = status
+            .last_log_index
+            .zip(status.last_applied)
+            .map(|(last_log_index, last_applied)| last_log_index.saturating_sub(last_applied));
+        let mut report = Self {
+            node_id: status.node_id,
+            role: status.role,
+            leader: status.leader,
+            member,
+            last_log_index: status.last_log_index,
+            last_applied: status.last_applied,
+            apply_lag,
+            refreshing: handler.is_begin_refresh(),
+            refreshing_for_secs: handler
+                .refreshing_since()
+                .map(|since| since.elapsed().as_secs()),
+            storage_healthy: handler.is_store_healthy(),
+            require_sealed_shares: data.require_sealed_shares(),
+            failures: vec![],
+            unchecked: vec![],
+        };
+        report.failures = report.readiness_failures();
+        report.unchecked = report.unchecked_readiness();
+        report
+    }
+
+    /// Returns the reasons the node cannot serve a consistent share.
+    fn readiness_failures(&self) -> Vec<String> {
+        let mut failures = vec![];
+        if !self.storage_healthy {
+            failures.push("the store is unusable".to_string());
+        }
+        if !self.member {
+            failures.push("the node is not an admitted cluster member".to_string());
+        }
+        // Backends which do not expose the role do not expose the leader either
+        if self.role != Role::Unknown && self.leader.is_none() {
+            failures.push("no leader is known".to_string());
+        }
+        if let Some(lag) = self.apply_lag.filter(|lag| *lag > MAX_APPLY_LAG) {
+            failures.push(format!("{} entries are not applied yet", lag));
+        }
+        if self.refreshing {
+            failures.push(format!(
+                "a refresh round is in progress for {} seconds",
+                self.refreshing_for_secs.unwrap_or_default()
+            ));
+        }
+        failures
+    }
+
+    /// Returns the readiness checks the backend does not expose the state for.
+    fn unchecked_readiness(&self) -> Vec<String> {
+        let mut unchecked = vec![];
+        if self.role == Role::Unknown {
+            unchecked.push("leader".to_string());
+        }
+        if self.apply_lag.is_none() {
+            unchecked.push("apply_lag".to_string());
+        }
+        unchecked
+    }
+}
+
+/// Reports whether the process is alive and its store usable, otherwise it must be restarted.
+#[get("/livez")]
+async fn livez(data: web::Data<AppContext>) -> HttpResponse {
+    let report = HealthReport::new(&data);
+    if report.storage_healthy {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+/// Reports whether the node can serve a consistent share, otherwise requests must be routed to
+/// other nodes.
+///
+/// The checks needing a state the backend does not expose are listed in `unchecked` rather than
+/// failed: with `riteraft` a node without leader or behind its log is still reported ready.
+#[get("/readyz")]
+async fn readyz(data: web::Data<AppContext>) -> HttpResponse {
+    let report = HealthReport::new(&data);
+    if report.failures.is_empty() {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+/// Registers the health routes.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(livez).service(readyz);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ready_report() -> HealthReport {
+        HealthReport {
+            node_id: NodeId(1),
+            role: Role::Follower,
+            leader: Some(NodeId(2)),
+            member: true,
+            last_log_index: Some(120),
+            last_applied: Some(110),
+            apply_lag: Some(10),
+            refreshing: false,
+            refreshing_for_secs: None,
+            storage_healthy: true,
+            require_sealed_shares: false,
+            failures: vec![],
+            unchecked: vec![],
+        }
+    }
+
+    #[test]
+    fn test_readiness_failures() {
+        assert!(ready_report().readiness_failures().is_empty());
+        let unknown_role = HealthReport {
+            role: Role::Unknown,
+            leader: None,
+            ..ready_report()
+        };
+        assert!(unknown_role.readiness_failures().is_empty());
+        assert!(ready_report().unchecked_readiness().is_empty());
+        let riteraft = HealthReport {
+            apply_lag: None,
+            ..unknown_role
+        };
+        assert_eq!(riteraft.unchecked_readiness(), vec!["leader", "apply_lag"]);
+
+        let failing = HealthReport {
+            leader: None,
+            apply_lag: Some(MAX_APPLY_LAG + 1),
+            refreshing: true,
+            refreshing_for_secs: Some(42),
+            storage_healthy: false,
+            ..ready_report()
+        };
+        assert_eq!(
+            failing.readiness_failures(),
+            vec![
+                "the store is unusable",
+                "no leader is known",
+                "101 entries are not applied yet",
+                "a refresh round is in progress for 42 seconds",
+            ]
+        );
+    }
+}
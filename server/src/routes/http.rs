@@ -1,19 +1,46 @@
-use super::auth::validator;
+use super::admin;
+use super::auth::{admin_validator, validator};
 use super::context::AppContext;
+use super::health;
+use super::jwt::JwtValidator;
+use super::keys::KeyRegistry;
+use super::rate_limit::RateLimiter;
+use super::tls;
+use crate::audit::{AuditAction, AuditLog, AuditOutcome, AuditRecord, Caller};
 use crate::conf::settings::Settings;
+use crate::consensus::admission::{JoinRequest, JoinVerifier, RELAY_MAC_HEADER};
 use crate::consensus::handler::ConsensusHandler;
 use crate::domain::model::ClientId;
-use actix_web::dev::Server;
+use crate::domain::redacted::Redacted;
+use crate::logging::{self, REQUEST_ID_HEADER};
+use crate::metrics::metrics;
+use crate::refresher::schedule::{wall_clock, RefreshSchedule};
+use actix_web::dev::{Server, Service, ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use actix_web::http::{Method, StatusCode};
 use actix_web_httpauth::middleware::HttpAuthentication;
+use chrono::{DateTime, Utc};
 use log::info;
+use serde::{Deserialize, Serialize};
+use sss_wrap::sealed::share::{parse_public_key, SealedShare};
 use sss_wrap::secret::secret::ShareMeta;
 
 use crate::domain::error::SecretServerError;
-use actix_web::{get, post, web, App, HttpServer, Responder, Result};
+use actix_web::{
+    delete, get, post, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder,
+    Result,
+};
+use std::future::Future;
 use std::io;
 use std::ops::Deref;
+use std::sync::Arc;
 
-#[post("/{client_id}/secret")]
+/// Format of the access log, the default one with the ID of the request.
+const ACCESS_LOG_FORMAT: &str =
+    r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T request_id=%{x-request-id}o"#;
+
+#[post("/secret")]
 async fn create_share(
     data: web::Data<AppContext>,
     path: web::Path<ClientId>,
@@ -21,7 +48,8 @@ async fn create_share(
 ) -> Result<impl Responder, SecretServerError> {
     info!(
         "Creating new share from client {:?} with value {:?}",
-        path, share
+        path,
+        Redacted::new(&share)
     );
     let client_id = path.into_inner();
     data.consensus_handler()
@@ -29,17 +57,128 @@ async fn create_share(
     Ok(web::Json(share))
 }
 
-#[get("/{id}/share")]
-async fn get_share(
+#[delete("/secret")]
+async fn delete_share(
     data: web::Data<AppContext>,
     path: web::Path<ClientId>,
 ) -> Result<impl Responder, SecretServerError> {
+    let client_id = path.into_inner();
+    info!("Deleting share from client {:?}", client_id);
+    if data.consensus_handler().is_begin_refresh() {
+        return Err(SecretServerError::RefreshInProgress);
+    }
+    data.consensus_handler()
+        .remove(client_id)?
+        .ok_or(SecretServerError::NotFound)?;
+    Ok(HttpResponse::NoContent())
+}
+
+/// Query of the share retrieval.
+#[derive(Deserialize, Debug)]
+struct ShareQuery {
+    /// Hex encoded X25519 public key of the client to seal the share to.
+    public_key: Option<String>,
+}
+
+#[get("/share")]
+async fn get_share(
+    data: web::Data<AppContext>,
+    path: web::Path<ClientId>,
+    query: web::Query<ShareQuery>,
+) -> Result<HttpResponse, SecretServerError> {
     let id = path.into_inner();
+    info!("Retrieving share of client {:?}", id);
     if data.consensus_handler().is_begin_refresh() {
         return Err(SecretServerError::RefreshInProgress);
     }
     let result = data.consensus_handler().get(id)?;
-    Ok(web::Json(result.map(|share| share.share.clone())))
+    match &query.public_key {
+        Some(public_key) => {
+            let public_key = parse_public_key(public_key)?;
+            let sealed = result
+                .map(|share| SealedShare::seal(&share.share, &public_key))
+                .transpose()?;
+            Ok(HttpResponse::Ok().json(sealed))
+        }
+        None if data.require_sealed_shares() => Err(SecretServerError::InvalidRequest(
+            "a public_key is required to seal the share".to_string(),
+        )),
+        None => Ok(HttpResponse::Ok().json(result.map(|share| share.share))),
+    }
+}
+
+/// Next refresh of the share of a client.
+#[derive(Serialize, Debug)]
+struct NextRefresh {
+    /// Time the refresh is scheduled at, if any.
+    next_refresh_at: Option<DateTime<Utc>>,
+    /// Maximum random delay after `next_refresh_at` before the refresh starts.
+    jitter_secs: u64,
+}
+
+#[get("/refresh")]
+async fn next_refresh(
+    data: web::Data<AppContext>,
+    path: web::Path<ClientId>,
+) -> Result<HttpResponse, SecretServerError> {
+    let id = path.into_inner();
+    let handler = data.consensus_handler();
+    handler.get(id)?.ok_or(SecretServerError::NotFound)?;
+    let schedule = data.refresh_schedule();
+    let due = handler
+        .refresh_due_times(schedule.default_interval())?
+        .into_iter()
+        .find(|(client_id, _)| *client_id == id)
+        .map(|(_, due)| wall_clock(due));
+    Ok(HttpResponse::Ok().json(NextRefresh {
+        next_refresh_at: schedule.next_refresh(due, Utc::now()),
+        jitter_secs: schedule.jitter().as_secs(),
+    }))
+}
+
+#[post("/join")]
+async fn join_cluster(
+    data: web::Data<AppContext>,
+    request: web::Json<JoinRequest>,
+) -> Result<HttpResponse, SecretServerError> {
+    let Some(join_verifier) = data.join_verifier() else {
+        return Err(SecretServerError::AdmissionError(
+            "cluster admission is not enabled".to_string(),
+        ));
+    };
+    join_verifier.verify(&request)?;
+    data.consensus_handler()
+        .admit(
+            request.node_id,
+            &request.raft_addr,
+            request.public_key.clone(),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Proposes an entry signed by a peer whose backend refused it, see `ConsensusHandler::relay`,
+/// and answers the result of applying it, empty if the members rejected it.
+///
+/// With a cluster token, the peer must send the MAC of the entry in the `x-cluster-mac` header.
+#[post("/propose")]
+async fn relay_proposal(
+    req: HttpRequest,
+    data: web::Data<AppContext>,
+    entry: web::Bytes,
+) -> Result<HttpResponse, SecretServerError> {
+    if let Some(join_verifier) = data.join_verifier() {
+        let mac = req
+            .headers()
+            .get(RELAY_MAC_HEADER)
+            .and_then(|mac| hex::decode(mac.as_bytes()).ok())
+            .unwrap_or_default();
+        join_verifier.verify_relay(&entry, &mac)?;
+    }
+    let response = data.consensus_handler().relay(entry.to_vec()).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .body(response))
 }
 
 #[get("/healthz")]
@@ -47,23 +186,240 @@ async fn healthz() -> impl Responder {
     "OK".to_string()
 }
 
-pub async fn run(settings: &Settings, consensus_handler: ConsensusHandler) -> io::Result<Server> {
-    let api_key = settings.api_key().to_string();
+/// Serves the metrics of the node in the Prometheus text format.
+#[get("/metrics")]
+async fn prometheus_metrics(
+    data: web::Data<AppContext>,
+) -> Result<HttpResponse, SecretServerError> {
+    let handler = data.consensus_handler();
+    let text = metrics().render(handler.secret_count()?, handler.status().term)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(text))
+}
+
+/// Returns the error answering a request whose path, query or body cannot be parsed.
+fn invalid_request(e: impl std::fmt::Display) -> actix_web::Error {
+    SecretServerError::InvalidRequest(e.to_string()).into()
+}
+
+/// Returns the share operation of a request to the `/api/{client_id}` routes.
+fn share_operation(method: &Method, path: &str) -> &'static str {
+    match (method, path.rsplit('/').next()) {
+        (&Method::POST, Some("secret")) => "create",
+        (&Method::DELETE, Some("secret")) => "delete",
+        (&Method::GET, Some("share")) => "read",
+        (&Method::GET, Some("refresh")) => "next_refresh",
+        _ => "unknown",
+    }
+}
+
+/// Records a share operation answered with `status` in the audit log.
+///
+/// Requests rejected by the authentication are recorded by the validator. The ones rejected by
+/// the rate limiter never reach the share, no more than the next refresh of a share does, so
+/// they are not recorded.
+fn audit_share_operation(request: &HttpRequest, operation: &str, status: StatusCode) {
+    let action = match operation {
+        "create" => AuditAction::Create,
+        "read" => AuditAction::Read,
+        "delete" => AuditAction::Delete,
+        _ => return,
+    };
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        return;
+    }
+    let Some(data) = request.app_data::<web::Data<AppContext>>() else {
+        return;
+    };
+    data.audit_log().try_record(AuditRecord {
+        action,
+        client_id: request
+            .match_info()
+            .get("client_id")
+            .and_then(|id| id.parse().ok())
+            .map(ClientId),
+        caller: request
+            .extensions()
+            .get::<Caller>()
+            .map(|caller| caller.0.clone()),
+        outcome: if status.is_success() {
+            AuditOutcome::Success
+        } else {
+            AuditOutcome::Failed
+        },
+        status: Some(status.as_u16()),
+    });
+}
+
+/// Rate limits the requests authenticated by a bearer token, per key and per client address,
+/// and locks out the addresses sending too many unknown credentials, when rate limiting is
+/// enabled.
+fn rate_limited<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let data = req.app_data::<web::Data<AppContext>>().cloned();
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    // Keys are told apart by their fingerprint, so the limiter never holds a credential
+    let key = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| Caller::from_token(token).0);
+    let admitted = match data.as_ref().and_then(|data| data.rate_limiter()).zip(ip) {
+        Some((limiter, ip)) => limiter.admit(ip, key.as_deref()),
+        None => Ok(()),
+    };
+    let response = admitted.map(|_| srv.call(req));
+    async move {
+        let response = match response {
+            Ok(response) => response.await,
+            Err(e) => return Err(actix_web::Error::from(e)),
+        };
+        if let Some((limiter, ip)) = data.as_ref().and_then(|data| data.rate_limiter()).zip(ip) {
+            let status = match &response {
+                Ok(response) => response.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            limiter.record_auth(ip, status != StatusCode::UNAUTHORIZED);
+        }
+        response
+    }
+}
+
+/// Answers the ID of the request in the `x-request-id` header.
+fn set_request_id(headers: &mut HeaderMap, request_id: &str) {
+    // Request IDs are made of valid header value characters only
+    if let Ok(request_id) = HeaderValue::from_str(request_id) {
+        headers.insert(HeaderName::from_static(REQUEST_ID_HEADER), request_id);
+    }
+}
+
+pub async fn run(
+    settings: &Settings,
+    consensus_handler: ConsensusHandler,
+    audit_log: Arc<AuditLog>,
+) -> io::Result<Server> {
+    let key_registry = Arc::new(KeyRegistry::new(settings.api_keys()));
+    let jwt_validator = settings
+        .jwt()
+        .map(JwtValidator::new)
+        .transpose()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?
+        .map(Arc::new);
+    let tls_config = settings.tls().map(tls::server_config).transpose()?;
+    let client_cert_required = settings
+        .tls()
+        .is_some_and(|tls| tls.client_ca_path().is_some() && tls.require_client_cert());
+    let require_sealed_shares = settings.require_sealed_shares();
+    let join_verifier = settings
+        .cluster_token()
+        .map(|token| Arc::new(JoinVerifier::new(token.to_string())));
+    let refresh_schedule = Arc::new(RefreshSchedule::from_settings(settings));
+    let rate_limiter = settings.rate_limit().map(RateLimiter::new).map(Arc::new);
     let http_port = settings.http_port();
-    Ok(HttpServer::new(move || {
-        let app_context = AppContext::new(consensus_handler.clone(), &api_key);
+    let server = HttpServer::new(move || {
+        let app_context = AppContext::new(
+            consensus_handler.clone(),
+            key_registry.clone(),
+            jwt_validator.clone(),
+            client_cert_required,
+            require_sealed_shares,
+            join_verifier.clone(),
+            refresh_schedule.clone(),
+            audit_log.clone(),
+            rate_limiter.clone(),
+        );
         let auth_middleware = HttpAuthentication::bearer(validator);
         App::new()
             .app_data(web::Data::new(app_context))
-            .wrap(actix_web::middleware::Logger::default())
+            // Malformed requests are answered with a problem document like the other errors
+            .app_data(web::JsonConfig::default().error_handler(|e, _| invalid_request(e)))
+            .app_data(web::PathConfig::default().error_handler(|e, _| invalid_request(e)))
+            .app_data(web::QueryConfig::default().error_handler(|e, _| invalid_request(e)))
+            .wrap_fn(|req, srv| {
+                let request_id = logging::request_id_or_new(
+                    req.headers()
+                        .get(REQUEST_ID_HEADER)
+                        .and_then(|id| id.to_str().ok()),
+                );
+                let response = srv.call(req);
+                // Errors are rendered within the request, so their problem document has its ID
+                logging::with_request_id(request_id.clone(), async move {
+                    match response.await {
+                        Ok(mut response) => {
+                            set_request_id(response.headers_mut(), &request_id);
+                            Ok(response)
+                        }
+                        // The request is owned by the service that failed, so the response is
+                        // built from the error alone
+                        Err(e) => {
+                            let mut response = e.error_response();
+                            set_request_id(response.headers_mut(), &request_id);
+                            Err(InternalError::from_response(e, response).into())
+                        }
+                    }
+                })
+            })
+            // Outside of the request ID middleware, so the access log has the ID of the request
+            .wrap(actix_web::middleware::Logger::new(ACCESS_LOG_FORMAT))
             .service(healthz)
+            .service(prometheus_metrics)
+            .configure(health::configure)
+            .service(
+                web::scope("/cluster")
+                    .wrap_fn(rate_limited)
+                    .service(join_cluster)
+                    .service(relay_proposal),
+            )
             .service(
-                web::scope("/api")
+                web::scope("/api/{client_id}")
                     .wrap(auth_middleware)
+                    .wrap_fn(rate_limited)
+                    // Counts the requests rejected by the authentication as well
+                    .wrap_fn(|req, srv| {
+                        let operation = share_operation(req.method(), req.path());
+                        let response = srv.call(req);
+                        async move {
+                            let response = response.await;
+                            match &response {
+                                Ok(response) => {
+                                    metrics().share_request(operation, response.status());
+                                    audit_share_operation(
+                                        response.request(),
+                                        operation,
+                                        response.status(),
+                                    );
+                                }
+                                // Rejected before reaching the handler, by the authentication
+                                // which records it, or the rate limiter
+                                Err(e) => metrics()
+                                    .share_request(operation, e.as_response_error().status_code()),
+                            }
+                            response
+                        }
+                    })
                     .service(create_share)
-                    .service(get_share),
+                    .service(delete_share)
+                    .service(get_share)
+                    .service(next_refresh),
+            )
+            .service(
+                web::scope("/admin")
+                    .wrap(HttpAuthentication::bearer(admin_validator))
+                    .wrap_fn(rate_limited)
+                    .configure(admin::configure),
             )
     })
-    .bind(("0.0.0.0", http_port))?
-    .run())
+    .on_connect(tls::on_connect);
+    let server = match tls_config {
+        Some(tls_config) => server.bind_rustls_021(("0.0.0.0", http_port), tls_config)?,
+        None => server.bind(("0.0.0.0", http_port))?,
+    };
+    Ok(server.run())
 }
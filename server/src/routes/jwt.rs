@@ -0,0 +1,317 @@
+use std::fs;
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::{info, warn};
+use serde_json::{Map, Value};
+
+use crate::conf::settings::JwtSettings;
+use crate::domain::access::{Binding, Principal, Scope};
+use crate::domain::error::SecretServerError;
+use crate::domain::model::ClientId;
+
+/// Minimum time between two checks of the JWKS file modification time.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Keys loaded from the JWKS file along with the file modification time.
+struct CachedJwks {
+    modified: Option<SystemTime>,
+    checked_at: Instant,
+    keys: JwkSet,
+}
+
+/// Validator of JWT bearer tokens signed with the keys of a local JWKS file.
+///
+/// The JWKS file is reloaded when its modification time changes, so signing keys can be
+/// rotated without restarting the node.
+pub struct JwtValidator {
+    settings: JwtSettings,
+    jwks: RwLock<CachedJwks>,
+}
+
+impl std::fmt::Debug for JwtValidator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtValidator")
+            .field("settings", &self.settings)
+            .finish()
+    }
+}
+
+/// Loads the JWKS file returning its modification time and keys.
+fn load_jwks(path: &str) -> Result<(Option<SystemTime>, JwkSet), SecretServerError> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| SecretServerError::AuthConfigError(format!("{}: {}", path, e)))?;
+    let keys: JwkSet = serde_json::from_str(&content)
+        .map_err(|e| SecretServerError::AuthConfigError(format!("{}: {}", path, e)))?;
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+    Ok((modified, keys))
+}
+
+impl JwtValidator {
+    /// Creates a new `JwtValidator` loading the JWKS file of the settings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the JWKS file cannot be read or parsed.
+    pub fn new(settings: &JwtSettings) -> Result<Self, SecretServerError> {
+        let (modified, keys) = load_jwks(settings.jwks_path())?;
+        Ok(Self {
+            settings: settings.clone(),
+            jwks: RwLock::new(CachedJwks {
+                modified,
+                checked_at: Instant::now(),
+                keys,
+            }),
+        })
+    }
+
+    /// Reloads the JWKS file if it changed since the last time it was loaded.
+    ///
+    /// If the new file cannot be loaded the previous keys are kept.
+    fn reload_if_changed(&self) -> Result<(), SecretServerError> {
+        if self.jwks.read()?.checked_at.elapsed() < RELOAD_CHECK_INTERVAL {
+            return Ok(());
+        }
+        let path = self.settings.jwks_path();
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        let mut cached = self.jwks.write()?;
+        cached.checked_at = Instant::now();
+        if modified != cached.modified {
+            match load_jwks(path) {
+                Ok((modified, keys)) => {
+                    info!("Reloaded JWKS file {}", path);
+                    cached.modified = modified;
+                    cached.keys = keys;
+                }
+                Err(e) => warn!("Keeping previous JWKS keys: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the algorithm tokens signed with `jwk` must use: the `alg` of the key, or the
+    /// configured `algorithm` for keys without one. The `alg` of the token header is never
+    /// trusted, so a token cannot pick the algorithm its signature is checked with.
+    fn key_algorithm(&self, jwk: &Jwk) -> Option<Algorithm> {
+        match jwk.common.key_algorithm {
+            Some(alg) => alg.to_string().parse().ok(),
+            None => self.settings.algorithm(),
+        }
+    }
+
+    /// Returns the principal of the given token if it is a valid JWT.
+    ///
+    /// The token must be signed by a key of the JWKS file with the algorithm of the key, not be
+    /// expired and carry the configured `aud` and `iss`. The client claim is mapped to the client
+    /// the token gives access to and the space separated `scope` claim to the granted scopes. The
+    /// `admin` scope is dropped unless `allow_admin_scope` is set.
+    pub fn authenticate(&self, token: &str) -> Option<Principal> {
+        if let Err(e) = self.reload_if_changed() {
+            warn!("Cannot reload JWKS file: {}", e);
+        }
+        let header = decode_header(token).ok()?;
+        let (key, algorithm) = {
+            let cached = self.jwks.read().ok()?;
+            let jwk = match &header.kid {
+                Some(kid) => cached.keys.find(kid)?,
+                None if cached.keys.keys.len() == 1 => cached.keys.keys.first()?,
+                None => return None,
+            };
+            let Some(algorithm) = self.key_algorithm(jwk) else {
+                warn!("Rejected JWT: its key has no signing algorithm");
+                return None;
+            };
+            (DecodingKey::from_jwk(jwk).ok()?, algorithm)
+        };
+
+        let mut validation = Validation::new(algorithm);
+        validation.set_audience(&[self.settings.audience()]);
+        validation.set_issuer(&[self.settings.issuer()]);
+        validation.set_required_spec_claims(&["exp", "aud", "iss"]);
+
+        let claims = match decode::<Map<String, Value>>(token, &key, &validation) {
+            Ok(data) => data.claims,
+            Err(e) => {
+                warn!("Rejected JWT: {}", e);
+                return None;
+            }
+        };
+
+        let client_id = match claims.get(self.settings.client_claim())? {
+            Value::Number(id) => id.as_u64()?,
+            Value::String(id) => id.parse::<u64>().ok()?,
+            _ => return None,
+        };
+        let scopes = claims
+            .get("scope")
+            .and_then(Value::as_str)
+            .map(|scopes| {
+                scopes
+                    .split_whitespace()
+                    .filter_map(|scope| {
+                        serde_json::from_value::<Scope>(Value::String(scope.to_string())).ok()
+                    })
+                    .filter(|scope| *scope != Scope::Admin || self.settings.allow_admin_scope())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        Some(Principal::new(Binding::Client(ClientId(client_id)), scopes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::time::UNIX_EPOCH;
+
+    use config::{Config, File, FileFormat};
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"shared-secrets-jwt-test-signing-k";
+    // base64 of SECRET, same in the standard and url safe alphabets
+    const SECRET_B64: &str = "c2hhcmVkLXNlY3JldHMtand0LXRlc3Qtc2lnbmluZy1r";
+
+    fn jwks_file(name: &str, kid: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.json", name, std::process::id()));
+        let jwks = json!({
+            "keys": [{ "kty": "oct", "kid": kid, "alg": "HS256", "k": SECRET_B64 }]
+        });
+        fs::write(&path, jwks.to_string()).unwrap();
+        path
+    }
+
+    fn validator(path: &Path) -> JwtValidator {
+        validator_with(path, "")
+    }
+
+    fn validator_with(path: &Path, extra_settings: &str) -> JwtValidator {
+        let settings: JwtSettings = Config::builder()
+            .add_source(File::from_str(
+                &format!(
+                    "jwks_path = {:?}\naudience = \"shared-secrets\"\nissuer = \"platform\"\n{}",
+                    path.to_str().unwrap(),
+                    extra_settings
+                ),
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        JwtValidator::new(&settings).unwrap()
+    }
+
+    fn token(kid: &str, exp_offset: i64, aud: &str) -> String {
+        token_with(Algorithm::HS256, kid, exp_offset, aud)
+    }
+
+    fn token_with(alg: Algorithm, kid: &str, exp_offset: i64, aud: &str) -> String {
+        scoped_token(alg, kid, exp_offset, aud, "read create")
+    }
+
+    fn scoped_token(alg: Algorithm, kid: &str, exp_offset: i64, aud: &str, scope: &str) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let mut header = Header::new(alg);
+        header.kid = Some(kid.to_string());
+        let claims = json!({
+            "exp": now + exp_offset,
+            "aud": aud,
+            "iss": "platform",
+            "client_id": "7",
+            "scope": scope,
+        });
+        encode(&header, &claims, &EncodingKey::from_secret(SECRET)).unwrap()
+    }
+
+    #[test]
+    fn test_authenticate_valid_token() {
+        let path = jwks_file("jwks-valid", "k1");
+        let validator = validator(&path);
+        let principal = validator
+            .authenticate(&token("k1", 60, "shared-secrets"))
+            .unwrap();
+        assert!(principal.can(Scope::Read, ClientId(7)));
+        assert!(principal.can(Scope::Create, ClientId(7)));
+        assert!(!principal.can(Scope::Delete, ClientId(7)));
+        assert!(!principal.can(Scope::Read, ClientId(8)));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_reject_expired_wrong_audience_and_unknown_key() {
+        let path = jwks_file("jwks-reject", "k1");
+        let validator = validator(&path);
+        assert!(validator
+            .authenticate(&token("k1", -120, "shared-secrets"))
+            .is_none());
+        assert!(validator.authenticate(&token("k1", 60, "other")).is_none());
+        assert!(validator
+            .authenticate(&token("k2", 60, "shared-secrets"))
+            .is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_pin_algorithm_of_key() {
+        let path = jwks_file("jwks-alg", "k1");
+        assert!(validator(&path)
+            .authenticate(&token_with(Algorithm::HS384, "k1", 60, "shared-secrets"))
+            .is_none());
+
+        // Keys without `alg` take the configured algorithm, and are unusable without one
+        let jwks = json!({ "keys": [{ "kty": "oct", "kid": "k1", "k": SECRET_B64 }] });
+        fs::write(&path, jwks.to_string()).unwrap();
+        assert!(validator(&path)
+            .authenticate(&token("k1", 60, "shared-secrets"))
+            .is_none());
+        let validator = validator_with(&path, "algorithm = \"HS256\"");
+        assert!(validator
+            .authenticate(&token("k1", 60, "shared-secrets"))
+            .is_some());
+        assert!(validator
+            .authenticate(&token_with(Algorithm::HS512, "k1", 60, "shared-secrets"))
+            .is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_admin_scope_requires_setting() {
+        let path = jwks_file("jwks-admin", "k1");
+        let token = scoped_token(Algorithm::HS256, "k1", 60, "shared-secrets", "read admin");
+        let principal = validator(&path).authenticate(&token).unwrap();
+        assert!(principal.can(Scope::Read, ClientId(7)));
+        assert!(!principal.is_admin());
+        let principal = validator_with(&path, "allow_admin_scope = true")
+            .authenticate(&token)
+            .unwrap();
+        assert!(principal.is_admin());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_rotated_keys() {
+        let path = jwks_file("jwks-rotate", "k1");
+        let validator = validator(&path);
+        jwks_file("jwks-rotate", "k2");
+        {
+            let mut cached = validator.jwks.write().unwrap();
+            cached.modified = None;
+            cached.checked_at = Instant::now() - RELOAD_CHECK_INTERVAL;
+        }
+        assert!(validator
+            .authenticate(&token("k2", 60, "shared-secrets"))
+            .is_some());
+        assert!(validator
+            .authenticate(&token("k1", 60, "shared-secrets"))
+            .is_none());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::conf::settings::ApiKeySettings;
+use crate::domain::access::Principal;
+
+/// Registry entry holding the hash of an API key and what it grants.
+struct KeyEntry {
+    hash: Vec<u8>,
+    principal: Principal,
+}
+
+/// Registry of hashed API keys.
+///
+/// Keys are never stored in clear text. Presented tokens are hashed with SHA-256 and compared in
+/// constant time against every registered hash.
+pub struct KeyRegistry {
+    entries: Vec<KeyEntry>,
+}
+
+impl std::fmt::Debug for KeyRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyRegistry")
+            .field("entries", &self.entries.len())
+            .finish()
+    }
+}
+
+impl KeyRegistry {
+    /// Creates a new `KeyRegistry` from the API keys settings.
+    ///
+    /// # Arguments
+    ///
+    /// * `api_keys` - The API keys entries, already validated when settings were loaded.
+    pub fn new(api_keys: &[ApiKeySettings]) -> Self {
+        let entries = api_keys
+            .iter()
+            .map(|api_key| KeyEntry {
+                hash: hex::decode(api_key.hash()).unwrap_or_default(),
+                principal: Principal::new(api_key.binding(), api_key.scopes().to_vec()),
+            })
+            .collect();
+        Self { entries }
+    }
+
+    /// Returns the principal of the given token, if the token is registered.
+    ///
+    /// Every entry is compared so the time spent does not depend on which key matched.
+    pub fn authenticate(&self, token: &str) -> Option<Principal> {
+        let digest = Sha256::digest(token.as_bytes());
+        let mut principal = None;
+        for entry in &self.entries {
+            if bool::from(entry.hash.as_slice().ct_eq(digest.as_slice())) {
+                principal = Some(entry.principal.clone());
+            }
+        }
+        principal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, File, FileFormat};
+
+    use crate::domain::access::Scope;
+    use crate::domain::model::ClientId;
+
+    use super::*;
+
+    fn registry(source: &str) -> KeyRegistry {
+        let api_keys: Vec<ApiKeySettings> = Config::builder()
+            .add_source(File::from_str(source, FileFormat::Toml))
+            .build()
+            .unwrap()
+            .get("api_keys")
+            .unwrap();
+        KeyRegistry::new(&api_keys)
+    }
+
+    #[test]
+    fn test_authenticate_known_and_unknown_keys() {
+        // sha256("api-key-test")
+        let registry = registry(
+            r#"
+            [[api_keys]]
+            hash = "47cd528f4164b8ea10cb964d47ba75a5e9671564a625056c0b3d43ca8b66c64e"
+            client_id = 1
+            scopes = ["read"]
+            "#,
+        );
+        let principal = registry.authenticate("api-key-test").unwrap();
+        assert!(principal.can(Scope::Read, ClientId(1)));
+        assert!(!principal.can(Scope::Read, ClientId(2)));
+        assert!(registry.authenticate("api-key-test ").is_none());
+        assert!(registry.authenticate("").is_none());
+    }
+}
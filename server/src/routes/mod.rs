@@ -1,3 +1,9 @@
+mod admin;
 mod auth;
 mod context;
+mod health;
 pub mod http;
+mod jwt;
+mod keys;
+mod rate_limit;
+mod tls;
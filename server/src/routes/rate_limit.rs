@@ -0,0 +1,337 @@
+//! Rate limiting of the API per API key and per client IP address.
+//!
+//! Keys and addresses get a token bucket refilled at their rate a minute, and an address sending
+//! too many unknown credentials in a row is locked out for a while, so that API keys cannot be
+//! brute forced.
+//!
+//! Every map of the limiter holds at most `MAX_TRACKED` entries, as keys and addresses are
+//! chosen by the clients: once full, the idle entries are forgotten, then the least recently
+//! seen ones.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::conf::settings::RateLimitSettings;
+use crate::domain::error::SecretServerError;
+
+/// Number of keys, addresses or failing addresses tracked by the limiter from which the idle
+/// ones are forgotten.
+const MAX_TRACKED: usize = 10_000;
+
+/// Token bucket of a key or an address.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Capacity and refill rate, per second, of a token bucket.
+#[derive(Debug, Clone, Copy)]
+struct Rate {
+    capacity: f64,
+    per_sec: f64,
+}
+
+impl Rate {
+    fn per_minute(requests: u32) -> Self {
+        Self {
+            capacity: f64::from(requests),
+            per_sec: f64::from(requests) / 60.0,
+        }
+    }
+}
+
+impl Bucket {
+    fn tokens_at(&self, rate: Rate, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * rate.per_sec).min(rate.capacity)
+    }
+
+    /// Returns the time until a token is available, `None` if one is.
+    fn wait(&self, rate: Rate, now: Instant) -> Option<Duration> {
+        let tokens = self.tokens_at(rate, now);
+        (tokens < 1.0).then(|| Duration::from_secs_f64((1.0 - tokens) / rate.per_sec))
+    }
+
+    fn take(&mut self, rate: Rate, now: Instant) {
+        self.tokens = self.tokens_at(rate, now) - 1.0;
+        self.updated = now;
+    }
+}
+
+/// Unknown credentials sent in a row by an address.
+#[derive(Debug)]
+struct AuthFailures {
+    count: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl AuthFailures {
+    /// Returns the last time the entry mattered, in the future while the address is locked out.
+    fn last_seen(&self) -> Instant {
+        self.locked_until.unwrap_or(self.last_failure)
+    }
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    keys: HashMap<String, Bucket>,
+    ips: HashMap<IpAddr, Bucket>,
+    failures: HashMap<IpAddr, AuthFailures>,
+}
+
+/// Returns the bucket of `id`, created full if unknown.
+fn bucket<K: Eq + Hash>(
+    buckets: &mut HashMap<K, Bucket>,
+    id: K,
+    rate: Rate,
+    now: Instant,
+) -> &mut Bucket {
+    buckets.entry(id).or_insert(Bucket {
+        tokens: rate.capacity,
+        updated: now,
+    })
+}
+
+/// Makes room for an entry in `map` if it is full, forgetting the entries `idle` accepts and
+/// then, if too many are left, all but the half seen the most recently according to `last_seen`.
+fn prune<K: Eq + Hash, V>(
+    map: &mut HashMap<K, V>,
+    idle: impl Fn(&V) -> bool,
+    last_seen: impl Fn(&V) -> Instant,
+) {
+    if map.len() < MAX_TRACKED {
+        return;
+    }
+    map.retain(|_, value| !idle(value));
+    if map.len() < MAX_TRACKED {
+        return;
+    }
+    let keep = MAX_TRACKED / 2;
+    let mut seen = map.values().map(&last_seen).collect::<Vec<_>>();
+    let (_, oldest_kept, _) = seen.select_nth_unstable_by(keep, |a, b| b.cmp(a));
+    let oldest_kept = *oldest_kept;
+    map.retain(|_, value| last_seen(value) > oldest_kept);
+}
+
+/// Returns the error asking to retry after `delay`, in whole seconds and at least one.
+fn retry_after(delay: Duration) -> SecretServerError {
+    SecretServerError::RateLimited(delay.as_secs_f64().ceil().max(1.0) as u64)
+}
+
+/// Rate limiter of the API, shared by the workers of the HTTP server.
+#[derive(Debug)]
+pub struct RateLimiter {
+    key_rate: Rate,
+    ip_rate: Rate,
+    max_auth_failures: u32,
+    lockout: Duration,
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter with the configured rates and lockout.
+    pub fn new(settings: &RateLimitSettings) -> Self {
+        Self {
+            key_rate: Rate::per_minute(settings.key_requests_per_minute()),
+            ip_rate: Rate::per_minute(settings.ip_requests_per_minute()),
+            max_auth_failures: settings.max_auth_failures(),
+            lockout: settings.lockout(),
+            state: Mutex::new(LimiterState::default()),
+        }
+    }
+
+    /// Admits a request from `ip` presenting the credentials identified by `key`, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns `RateLimited` with the seconds to wait if the address is locked out, or the
+    /// address or the key has no request left.
+    pub fn admit(&self, ip: IpAddr, key: Option<&str>) -> Result<(), SecretServerError> {
+        self.admit_at(ip, key, Instant::now())
+    }
+
+    fn admit_at(
+        &self,
+        ip: IpAddr,
+        key: Option<&str>,
+        now: Instant,
+    ) -> Result<(), SecretServerError> {
+        let mut state = self.state.lock()?;
+        if let Some(locked_until) = state.failures.get(&ip).and_then(|f| f.locked_until) {
+            if locked_until > now {
+                return Err(retry_after(locked_until - now));
+            }
+            state.failures.remove(&ip);
+        }
+        let (key_rate, ip_rate) = (self.key_rate, self.ip_rate);
+        prune(
+            &mut state.keys,
+            |bucket| bucket.tokens_at(key_rate, now) >= key_rate.capacity,
+            |bucket| bucket.updated,
+        );
+        prune(
+            &mut state.ips,
+            |bucket| bucket.tokens_at(ip_rate, now) >= ip_rate.capacity,
+            |bucket| bucket.updated,
+        );
+        // Nothing is taken from a bucket unless the request is admitted
+        let ip_bucket = *bucket(&mut state.ips, ip, self.ip_rate, now);
+        let key_bucket =
+            key.map(|key| *bucket(&mut state.keys, key.to_string(), self.key_rate, now));
+        let wait = ip_bucket
+            .wait(self.ip_rate, now)
+            .max(key_bucket.and_then(|bucket| bucket.wait(self.key_rate, now)));
+        if let Some(wait) = wait {
+            return Err(retry_after(wait));
+        }
+        bucket(&mut state.ips, ip, self.ip_rate, now).take(self.ip_rate, now);
+        if let Some(key) = key {
+            bucket(&mut state.keys, key.to_string(), self.key_rate, now).take(self.key_rate, now);
+        }
+        Ok(())
+    }
+
+    /// Records whether the credentials sent by `ip` were known, locking the address out after
+    /// too many unknown ones in a row.
+    ///
+    /// The failures of an address are forgotten once it has not sent unknown credentials for
+    /// the lockout duration.
+    pub fn record_auth(&self, ip: IpAddr, authenticated: bool) {
+        self.record_auth_at(ip, authenticated, Instant::now())
+    }
+
+    fn record_auth_at(&self, ip: IpAddr, authenticated: bool, now: Instant) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        if authenticated {
+            state.failures.remove(&ip);
+            return;
+        }
+        let lockout = self.lockout;
+        prune(
+            &mut state.failures,
+            |failures| now.saturating_duration_since(failures.last_seen()) >= lockout,
+            AuthFailures::last_seen,
+        );
+        let failures = state.failures.entry(ip).or_insert(AuthFailures {
+            count: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        if now.saturating_duration_since(failures.last_seen()) >= lockout {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last_failure = now;
+        if failures.count >= self.max_auth_failures {
+            failures.count = 0;
+            failures.locked_until = Some(now + self.lockout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::settings::Settings;
+
+    fn limiter(rate_limit: &str) -> RateLimiter {
+        let settings = Settings::from_toml(&format!(
+            r#"
+            raft_addr = "127.0.0.1:7070"
+            http_port = 8080
+            node_id = 1
+            interval_refresh_secs = 3600
+            bootstrap = true
+            [rate_limit]
+            {rate_limit}
+            "#
+        ))
+        .unwrap();
+        RateLimiter::new(settings.rate_limit().unwrap())
+    }
+
+    fn retry_secs(result: Result<(), SecretServerError>) -> u64 {
+        match result {
+            Err(SecretServerError::RateLimited(secs)) => secs,
+            other => panic!("request not rate limited: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_per_key_and_ip() {
+        let limiter = limiter("key_requests_per_minute = 2\nip_requests_per_minute = 3");
+        let (ip, other_ip) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let now = Instant::now();
+
+        limiter.admit_at(ip, Some("key-1"), now).unwrap();
+        limiter.admit_at(ip, Some("key-1"), now).unwrap();
+        assert_eq!(retry_secs(limiter.admit_at(ip, Some("key-1"), now)), 30);
+        // The address has a request left for another key, the key none from another address
+        limiter.admit_at(ip, Some("key-2"), now).unwrap();
+        assert_eq!(retry_secs(limiter.admit_at(ip, Some("key-2"), now)), 20);
+        assert!(limiter.admit_at(other_ip, Some("key-1"), now).is_err());
+
+        // Buckets refill at their rate
+        let later = now + Duration::from_secs(30);
+        limiter.admit_at(other_ip, Some("key-1"), later).unwrap();
+    }
+
+    #[test]
+    fn test_lockout_after_auth_failures() {
+        let limiter = limiter("max_auth_failures = 3\nlockout_secs = 60");
+        let ip = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        // A known key resets the count of failures
+        limiter.record_auth_at(ip, false, now);
+        limiter.record_auth_at(ip, false, now);
+        limiter.record_auth_at(ip, true, now);
+        limiter.record_auth_at(ip, false, now);
+        limiter.record_auth_at(ip, false, now);
+        limiter.admit_at(ip, None, now).unwrap();
+
+        limiter.record_auth_at(ip, false, now);
+        let later = now + Duration::from_secs(15);
+        assert_eq!(retry_secs(limiter.admit_at(ip, Some("key"), later)), 45);
+        limiter
+            .admit_at(ip, Some("key"), now + Duration::from_secs(60))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_forget_least_recently_seen() {
+        let limiter = limiter("key_requests_per_minute = 1\nmax_auth_failures = 2");
+        let now = Instant::now();
+        let ip = |i: usize| IpAddr::from((i as u32).to_be_bytes());
+        let at = |i: usize| now + Duration::from_micros(i as u64);
+
+        // Every key and address stays busy, as are the addresses failing once
+        for i in 0..=MAX_TRACKED {
+            let key = format!("key-{}", i);
+            limiter.admit_at(ip(i), Some(&key), at(i)).unwrap();
+            limiter.record_auth_at(ip(i), false, at(i));
+        }
+        {
+            let state = limiter.state.lock().unwrap();
+            assert!(state.keys.len() <= MAX_TRACKED);
+            assert!(state.ips.len() <= MAX_TRACKED);
+            assert!(state.failures.len() <= MAX_TRACKED);
+        }
+        // The most recent ones are kept
+        let last = format!("key-{}", MAX_TRACKED);
+        assert!(limiter
+            .admit_at(ip(MAX_TRACKED), Some(&last), at(MAX_TRACKED))
+            .is_err());
+        limiter.record_auth_at(ip(MAX_TRACKED), false, at(MAX_TRACKED));
+        assert!(limiter
+            .admit_at(ip(MAX_TRACKED), None, at(MAX_TRACKED))
+            .is_err());
+    }
+}
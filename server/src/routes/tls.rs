@@ -0,0 +1,101 @@
+use std::any::Any;
+use std::fs::File;
+use std::io::{self, BufReader};
+
+use actix_tls::accept::rustls_0_21::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::conf::settings::TlsSettings;
+use crate::domain::model::ClientId;
+
+/// Client certificate presented in the TLS handshake of a connection.
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    client_id: Option<ClientId>,
+}
+
+impl ClientCertificate {
+    /// Returns the client ID of the certificate common name, if it is a valid client ID.
+    pub fn client_id(&self) -> Option<ClientId> {
+        self.client_id
+    }
+}
+
+fn invalid_input<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect())
+}
+
+fn load_private_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::read_all(&mut reader)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid_input(format!("no private key found in {}", path)))
+}
+
+/// Builds the rustls server configuration from the TLS settings.
+///
+/// # Errors
+///
+/// Returns an error if any of the certificates or the key cannot be loaded.
+pub fn server_config(settings: &TlsSettings) -> io::Result<ServerConfig> {
+    let certs = load_certs(settings.cert_path())?;
+    let key = load_private_key(settings.key_path())?;
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match settings.client_ca_path() {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_path)? {
+                roots.add(&cert).map_err(invalid_input)?;
+            }
+            let verifier = if settings.require_client_cert() {
+                AllowAnyAuthenticatedClient::new(roots).boxed()
+            } else {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    builder.with_single_cert(certs, key).map_err(invalid_input)
+}
+
+/// Stores the client certificate of a TLS connection in the connection data.
+///
+/// The certificate has already been verified against the client CA during the handshake, so only
+/// its common name is kept and later matched against the requested client ID.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    let (_, session) = stream.get_ref();
+    if let Some(cert) = session.peer_certificates().and_then(|certs| certs.first()) {
+        let client_id = X509Certificate::from_der(&cert.0)
+            .ok()
+            .and_then(|(_, cert)| {
+                cert.subject()
+                    .iter_common_name()
+                    .next()
+                    .and_then(|cn| cn.as_str().ok())
+                    .and_then(|cn| cn.parse::<u64>().ok())
+                    .map(ClientId)
+            });
+        data.insert(ClientCertificate { client_id });
+    }
+}
@@ -0,0 +1,136 @@
+//! Replication through the BFT backend between four replicas on a simulated network.
+#![cfg(feature = "bft")]
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use shared_secret_server::consensus::backend::bft::network::SimulatedNetwork;
+use shared_secret_server::consensus::backend::bft::BftBackend;
+use shared_secret_server::consensus::backend::Role;
+use shared_secret_server::consensus::handler::ConsensusHandler;
+use shared_secret_server::consensus::raft::{HashStore, Member};
+use shared_secret_server::consensus::signing::NodeKey;
+use shared_secret_server::domain::model::NodeId;
+
+const VIEW_CHANGE_TIMEOUT: Duration = Duration::from_millis(200);
+
+struct Replica {
+    handler: ConsensusHandler,
+    store: HashStore,
+}
+
+/// Starts four replicas connected to `network` and returns them by node ID.
+fn start_cluster(network: &SimulatedNetwork) -> BTreeMap<NodeId, Replica> {
+    let keys = (1..=4)
+        .map(|id| (NodeId(id), Arc::new(NodeKey::generate())))
+        .collect::<BTreeMap<_, _>>();
+    let replicas = keys
+        .iter()
+        .map(|(id, key)| {
+            let member = Member {
+                raft_addr: format!("replica-{}", **id),
+                public_key: key.public_key(),
+            };
+            (*id, member)
+        })
+        .collect::<BTreeMap<_, _>>();
+    keys.into_iter()
+        .map(|(id, key)| {
+            let store = HashStore::new(id);
+            let (transport, messages) = network.connect(id);
+            let (_, backend) = BftBackend::start(
+                store.clone(),
+                key.clone(),
+                replicas.clone(),
+                VIEW_CHANGE_TIMEOUT,
+                Arc::new(transport),
+                messages,
+            )
+            .unwrap();
+            let handler = ConsensusHandler::new(store.clone(), Arc::new(backend), key);
+            (id, Replica { handler, store })
+        })
+        .collect()
+}
+
+/// Waits until the node `admitted` is a member on every given replica.
+async fn wait_admitted(replicas: &[&Replica], admitted: NodeId) {
+    for _ in 0..50 {
+        let admitted_everywhere = replicas
+            .iter()
+            .all(|replica| replica.store.members().unwrap().contains_key(&admitted));
+        if admitted_everywhere {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("node {:?} was not admitted on every replica", admitted);
+}
+
+#[tokio::test]
+async fn test_replicate_to_every_replica() {
+    let network = SimulatedNetwork::new();
+    let replicas = start_cluster(&network);
+    let new_key = NodeKey::generate();
+
+    replicas[&NodeId(2)]
+        .handler
+        .admit(NodeId(5), "replica-5", new_key.public_key())
+        .await
+        .unwrap();
+
+    wait_admitted(&replicas.values().collect::<Vec<_>>(), NodeId(5)).await;
+    assert_eq!(replicas[&NodeId(1)].handler.status().role, Role::Leader);
+    assert_eq!(
+        replicas[&NodeId(3)].handler.status().leader,
+        Some(NodeId(1))
+    );
+}
+
+#[tokio::test]
+async fn test_replace_crashed_primary() {
+    let network = SimulatedNetwork::new();
+    let replicas = start_cluster(&network);
+    // Node 1 is the primary of the first view
+    network.isolate(NodeId(1));
+    let new_key = NodeKey::generate();
+
+    replicas[&NodeId(3)]
+        .handler
+        .admit(NodeId(5), "replica-5", new_key.public_key())
+        .await
+        .unwrap();
+
+    let correct = [2, 3, 4].map(|id| &replicas[&NodeId(id)]);
+    wait_admitted(&correct, NodeId(5)).await;
+    assert!(!replicas[&NodeId(1)]
+        .store
+        .members()
+        .unwrap()
+        .contains_key(&NodeId(5)));
+    for replica in correct {
+        assert_eq!(replica.handler.status().leader, Some(NodeId(2)));
+    }
+}
+
+#[tokio::test]
+async fn test_commit_without_silent_replica() {
+    let network = SimulatedNetwork::new();
+    let replicas = start_cluster(&network);
+    network.isolate(NodeId(4));
+    let new_key = NodeKey::generate();
+
+    replicas[&NodeId(1)]
+        .handler
+        .admit(NodeId(5), "replica-5", new_key.public_key())
+        .await
+        .unwrap();
+
+    let correct = [1, 2, 3].map(|id| &replicas[&NodeId(id)]);
+    wait_admitted(&correct, NodeId(5)).await;
+    assert_eq!(
+        replicas[&NodeId(2)].handler.status().leader,
+        Some(NodeId(1))
+    );
+}
@@ -0,0 +1,182 @@
+//! Chaos tests of the refresh protocol with real nodes, run with
+//! `cargo test --features failpoints --test chaos_test`.
+//!
+//! Failpoints are global to the process, so their names carry the ID of the node they target and
+//! `FailScenario` runs the tests one at a time.
+#![cfg(feature = "failpoints")]
+
+mod common;
+
+use std::future::Future;
+use std::time::Duration;
+
+use common::{create_secret, get_share, get_share_status, wait_until, TestCluster};
+use fail::FailScenario;
+use reqwest::StatusCode;
+use shared_secret_server::domain::error::SecretServerError;
+use shared_secret_server::domain::model::ClientId;
+use sss_wrap::secret::secret::Share;
+use sss_wrap::wrapped_sharing::reconstruct;
+
+const CLIENT_IDS: [u64; 2] = [1, 2];
+const SECRET: &[u8] = b"my-secret-test";
+
+/// Retries `propose` until it succeeds, as proposals hang or fail until a new leader is elected.
+async fn retry<F, Fut>(what: &str, mut propose: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), SecretServerError>>,
+{
+    wait_until(what, Duration::from_secs(60), || {
+        let proposal = propose();
+        async move {
+            matches!(
+                tokio::time::timeout(Duration::from_secs(5), proposal).await,
+                Ok(Ok(()))
+            )
+        }
+    })
+    .await;
+}
+
+/// Refreshes every share stored on the node `id`, retrying each phase of the round.
+async fn refresh(cluster: &TestCluster, id: u8) {
+    let handler = cluster.node(id).consensus_handler();
+    retry("the start of the refresh", || handler.start_refresh()).await;
+    retry("the new shares", || handler.refresh_secrets()).await;
+    retry("the end of the refresh", || handler.finish_refresh()).await;
+}
+
+/// Waits until the node `id` answers the retrieval of a share of `client_id` with `status`.
+async fn wait_status(cluster: &TestCluster, id: u8, client_id: u64, status: StatusCode) {
+    wait_until(
+        &format!("node {} to answer {} for client {}", id, status, client_id),
+        Duration::from_secs(10),
+        || async move { get_share_status(cluster, id, client_id).await == status },
+    )
+    .await;
+}
+
+/// Waits until the node `id` serves a share of `client_id` different from `old` and returns it.
+async fn wait_refreshed(cluster: &TestCluster, id: u8, client_id: u64, old: &Share) -> Share {
+    wait_until(
+        &format!("node {} to refresh its share", id),
+        Duration::from_secs(30),
+        || async move {
+            get_share(cluster, id, client_id)
+                .await
+                .is_some_and(|share| share != *old)
+        },
+    )
+    .await;
+    get_share(cluster, id, client_id).await.unwrap()
+}
+
+/// Checks whether the shares reconstruct `SECRET`.
+fn recovers_secret(shares: Vec<Share>) -> bool {
+    reconstruct(
+        shares.into_iter().map(Vec::<u8>::from).collect::<Vec<_>>(),
+        false,
+    )
+    .is_ok_and(|s| s == SECRET)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_stop_leader_during_refresh() {
+    let scenario = FailScenario::setup();
+    let mut cluster = TestCluster::start("chaos-stop", 3).await;
+    for client_id in CLIENT_IDS {
+        create_secret(&cluster, client_id, SECRET, 2, 3).await;
+    }
+
+    // Node 1 stops after committing the new shares of a single client. riteraft cannot take it
+    // back, see `RiteraftBackend`
+    let handler = cluster
+        .node(1)
+        .consensus_handler()
+        .clone()
+        .with_refresh_batch_size(1);
+    handler.start_refresh().await.unwrap();
+    fail::cfg("mailbox-send-1", "1*off->return").unwrap();
+    assert!(handler.refresh_secrets().await.is_err());
+    cluster.stop_node(1).await;
+    fail::remove("mailbox-send-1");
+
+    // The other nodes refuse to serve shares of a round that never finishes. Once they agree on
+    // the log left by node 1, the shares they store recover every secret, refreshed or not
+    for client_id in CLIENT_IDS {
+        for id in [2, 3] {
+            wait_status(&cluster, id, client_id, StatusCode::CONFLICT).await;
+        }
+        let stored = || {
+            [2, 3]
+                .iter()
+                .map(|id| {
+                    let handler = cluster.node(*id).consensus_handler();
+                    handler.get(ClientId(client_id)).unwrap().unwrap().share
+                })
+                .collect::<Vec<_>>()
+        };
+        wait_until(
+            "the remaining nodes to agree on the shares",
+            Duration::from_secs(30),
+            || async { recovers_secret(stored()) },
+        )
+        .await;
+    }
+    cluster.stop().await;
+    scenario.teardown();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_partitioned_follower() {
+    let scenario = FailScenario::setup();
+    let cluster = TestCluster::start("chaos-partition", 3).await;
+    let client_id = CLIENT_IDS[0];
+    let old = create_secret(&cluster, client_id, SECRET, 2, 3).await;
+
+    // Node 3 stops at the next committed entry, so the other nodes refresh their shares without
+    // it
+    fail::cfg("apply-entry-3", "pause").unwrap();
+    refresh(&cluster, 1).await;
+    let refreshed = vec![
+        wait_refreshed(&cluster, 1, client_id, &old[0]).await,
+        wait_refreshed(&cluster, 2, client_id, &old[1]).await,
+    ];
+    assert!(recovers_secret(refreshed.clone()));
+
+    // Node 3 catches up with the log once the partition heals
+    fail::remove("apply-entry-3");
+    let share = wait_refreshed(&cluster, 3, client_id, &old[2]).await;
+    assert!(recovers_secret(vec![share, refreshed[0].clone()]));
+    cluster.stop().await;
+    scenario.teardown();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_crashed_follower_keeps_stale_share() {
+    let scenario = FailScenario::setup();
+    let cluster = TestCluster::start("chaos-crash", 3).await;
+    let client_id = CLIENT_IDS[0];
+    let old = create_secret(&cluster, client_id, SECRET, 2, 3).await;
+
+    // The Raft node of node 3 crashes while applying the start of the refresh and misses the
+    // round, its HTTP API still serves the share it had before
+    fail::cfg("apply-entry-3", "panic").unwrap();
+    refresh(&cluster, 1).await;
+    fail::remove("apply-entry-3");
+
+    // The refreshed nodes still recover the secret, the stale share is useless with them
+    let refreshed = vec![
+        wait_refreshed(&cluster, 1, client_id, &old[0]).await,
+        wait_refreshed(&cluster, 2, client_id, &old[1]).await,
+    ];
+    let stale = get_share(&cluster, 3, client_id).await.unwrap();
+    assert_eq!(stale, old[2]);
+    assert!(recovers_secret(refreshed.clone()));
+    for share in refreshed {
+        assert!(!recovers_secret(vec![share, stale.clone()]));
+    }
+    cluster.stop().await;
+    scenario.teardown();
+}
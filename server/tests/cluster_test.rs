@@ -0,0 +1,455 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{create_secret, get_share, reconstruct_secret, wait_until, TestCluster, API_KEY};
+use sss_wrap::secret::secret::Share;
+use sss_wrap::wrapped_sharing::reconstruct;
+
+const CLIENT_ID: u64 = 1;
+const SECRET: &[u8] = b"my-secret-test";
+
+/// Refreshes every share through the node `id`, as the refresher task does.
+async fn refresh(cluster: &TestCluster, id: u8) {
+    let handler = cluster.node(id).consensus_handler();
+    handler.start_refresh().await.unwrap();
+    handler.refresh_secrets().await.unwrap();
+    handler.finish_refresh().await.unwrap();
+}
+
+/// Waits until the nodes serve a share of `CLIENT_ID` different from `old` and returns them.
+async fn wait_refreshed(cluster: &TestCluster, ids: &[u8], old: &[Share]) -> Vec<Share> {
+    let mut refreshed = vec![];
+    for id in ids {
+        let old = &old[*id as usize - 1];
+        wait_until(
+            &format!("node {} to refresh its share", id),
+            Duration::from_secs(10),
+            || async move {
+                get_share(cluster, *id, CLIENT_ID)
+                    .await
+                    .is_some_and(|s| s != *old)
+            },
+        )
+        .await;
+        refreshed.push(get_share(cluster, *id, CLIENT_ID).await.unwrap());
+    }
+    refreshed
+}
+
+#[tokio::test]
+async fn test_create_refresh_and_retrieve() {
+    let cluster = TestCluster::start("cluster-refresh", 3).await;
+    let shares = create_secret(&cluster, CLIENT_ID, SECRET, 2, 3).await;
+
+    refresh(&cluster, 1).await;
+
+    let refreshed = wait_refreshed(&cluster, &[1, 2, 3], &shares).await;
+    assert_eq!(reconstruct_secret(refreshed[..2].to_vec()), SECRET);
+    assert_eq!(reconstruct_secret(refreshed[1..].to_vec()), SECRET);
+
+    // The next refresh is due a full interval after this one
+    let next: serde_json::Value = reqwest::Client::new()
+        .get(format!(
+            "http://{}/api/{}/refresh",
+            cluster.http_addr(1),
+            CLIENT_ID
+        ))
+        .bearer_auth(API_KEY)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let next_refresh_at =
+        chrono::DateTime::parse_from_rfc3339(next["next_refresh_at"].as_str().unwrap()).unwrap();
+    let in_secs = (next_refresh_at.timestamp() - chrono::Utc::now().timestamp()) as u64;
+    assert!((3500..=3600).contains(&in_secs), "{}", in_secs);
+    cluster.stop().await;
+}
+
+#[tokio::test]
+async fn test_node_loss() {
+    let mut cluster = TestCluster::start("cluster-loss", 3).await;
+    let shares = create_secret(&cluster, CLIENT_ID, SECRET, 2, 3).await;
+
+    // The remaining nodes still form a quorum and release their shares. riteraft cannot take
+    // the lost node back, see `RiteraftBackend`
+    cluster.stop_node(3).await;
+    let remaining = vec![
+        get_share(&cluster, 1, CLIENT_ID).await.unwrap(),
+        get_share(&cluster, 2, CLIENT_ID).await.unwrap(),
+    ];
+    assert_eq!(reconstruct_secret(remaining), SECRET);
+    refresh(&cluster, 1).await;
+    let refreshed = wait_refreshed(&cluster, &[1, 2], &shares).await;
+    assert_eq!(reconstruct_secret(refreshed), SECRET);
+    cluster.stop().await;
+}
+
+/// Lists the cluster members through the admin API of the node `id`.
+async fn list_members(cluster: &TestCluster, id: u8) -> Vec<serde_json::Value> {
+    let view: serde_json::Value = reqwest::Client::new()
+        .get(format!("http://{}/admin/cluster", cluster.http_addr(id)))
+        .bearer_auth(API_KEY)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    view["members"].as_array().unwrap().clone()
+}
+
+#[tokio::test]
+async fn test_admin_cluster_members_and_leave() {
+    let cluster = TestCluster::start("cluster-admin", 3).await;
+
+    let members = list_members(&cluster, 1).await;
+    assert_eq!(members.len(), 3);
+    for (member, id) in members.iter().zip(1..=3) {
+        assert_eq!(member["node_id"], id);
+        assert_eq!(member["alive"], true);
+    }
+
+    // riteraft does not let a follower leave the Raft group, only the cluster members
+    let response = reqwest::Client::new()
+        .post(format!(
+            "http://{}/admin/cluster/leave",
+            cluster.http_addr(3)
+        ))
+        .bearer_auth(API_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_IMPLEMENTED);
+    {
+        let cluster = &cluster;
+        wait_until(
+            "node 1 to remove node 3 from the members",
+            Duration::from_secs(10),
+            || async move { list_members(cluster, 1).await.len() == 2 },
+        )
+        .await;
+    }
+    cluster.stop().await;
+}
+
+#[tokio::test]
+async fn test_refresh_after_node_leaves() {
+    let cluster = TestCluster::start("cluster-departure", 3).await;
+    let shares = create_secret(&cluster, CLIENT_ID, SECRET, 2, 3).await;
+
+    // The node leaves the cluster members, but riteraft keeps the follower in the Raft group
+    let response = reqwest::Client::new()
+        .post(format!(
+            "http://{}/admin/cluster/leave",
+            cluster.http_addr(3)
+        ))
+        .bearer_auth(API_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_IMPLEMENTED);
+
+    // The remaining nodes refresh their shares without waiting for the schedule, so the share
+    // kept by the departed node is useless with theirs
+    let refreshed = wait_refreshed(&cluster, &[1, 2], &shares).await;
+    assert_eq!(reconstruct_secret(refreshed.clone()), SECRET);
+    for share in refreshed {
+        let raw_shares: Vec<Vec<u8>> = vec![share.into(), shares[2].clone().into()];
+        assert!(!reconstruct(raw_shares, false).is_ok_and(|secret| secret == SECRET));
+    }
+    cluster.stop().await;
+}
+
+/// Starts a refresh round of the `body` selection through the admin API of the node `id`.
+async fn admin_refresh(
+    cluster: &TestCluster,
+    id: u8,
+    body: serde_json::Value,
+) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("http://{}/admin/refresh", cluster.http_addr(id)))
+        .bearer_auth(API_KEY)
+        .json(&body)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_relay_with_cluster_token() {
+    let cluster = TestCluster::new("cluster-token", 3)
+        .with_cluster_token("cluster-token-test")
+        .start_all()
+        .await;
+    let shares = create_secret(&cluster, CLIENT_ID, SECRET, 2, 3).await;
+
+    // The followers relay their proposals through the leader with the MAC of the cluster token
+    for id in 1..=3 {
+        refresh(&cluster, id).await;
+    }
+    let refreshed = wait_refreshed(&cluster, &[1, 2, 3], &shares).await;
+    assert_eq!(reconstruct_secret(refreshed[..2].to_vec()), SECRET);
+
+    // Entries without the MAC are refused
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/cluster/propose", cluster.http_addr(1)))
+        .body(vec![1, 2, 3])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+    cluster.stop().await;
+}
+
+#[tokio::test]
+async fn test_admin_refresh_selected_secrets() {
+    let cluster = TestCluster::start("cluster-admin-refresh", 3).await;
+    let kept = create_secret(&cluster, CLIENT_ID, SECRET, 2, 3).await;
+    let old = create_secret(&cluster, CLIENT_ID + 1, SECRET, 2, 3).await;
+
+    let response = admin_refresh(
+        &cluster,
+        2,
+        serde_json::json!({ "client_ids": [CLIENT_ID + 1] }),
+    )
+    .await;
+    assert!(response.status().is_success());
+    let result: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(result["refreshed"], 1);
+
+    // Only the selected secret is refreshed
+    let mut refreshed = vec![];
+    for id in [1, 2] {
+        let old = &old[id as usize - 1];
+        let cluster = &cluster;
+        wait_until(
+            &format!("node {} to refresh its share", id),
+            Duration::from_secs(10),
+            || async move {
+                get_share(cluster, id, CLIENT_ID + 1)
+                    .await
+                    .is_some_and(|share| share != *old)
+            },
+        )
+        .await;
+        refreshed.push(get_share(cluster, id, CLIENT_ID + 1).await.unwrap());
+        assert_eq!(
+            get_share(cluster, id, CLIENT_ID).await.unwrap(),
+            kept[id as usize - 1]
+        );
+    }
+    assert_eq!(reconstruct_secret(refreshed), SECRET);
+
+    let response = admin_refresh(&cluster, 2, serde_json::json!({ "client_ids": [42] })).await;
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+    cluster.stop().await;
+}
+
+#[tokio::test]
+async fn test_health_endpoints() {
+    let cluster = TestCluster::start("cluster-health", 3).await;
+    let status = |id: u8, path: &'static str| {
+        let cluster = &cluster;
+        async move {
+            reqwest::Client::new()
+                .get(format!("http://{}/{}", cluster.http_addr(id), path))
+                .send()
+                .await
+                .unwrap()
+                .status()
+        }
+    };
+    for id in 1..=3 {
+        wait_until(
+            &format!("node {} to be ready", id),
+            Duration::from_secs(10),
+            || async move { status(id, "readyz").await.is_success() },
+        )
+        .await;
+        assert!(status(id, "livez").await.is_success());
+    }
+
+    let report: serde_json::Value = reqwest::Client::new()
+        .get(format!("http://{}/readyz", cluster.http_addr(2)))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(report["node_id"], 2);
+    assert_eq!(report["member"], true);
+    assert_eq!(report["storage_healthy"], true);
+    assert_eq!(report["refreshing"], false);
+    assert_eq!(report["failures"], serde_json::json!([]));
+    cluster.stop().await;
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    let cluster = TestCluster::start("cluster-metrics", 3).await;
+    create_secret(&cluster, CLIENT_ID, SECRET, 2, 3).await;
+    assert!(get_share(&cluster, 1, CLIENT_ID).await.is_some());
+    let response = reqwest::Client::new()
+        .get(format!(
+            "http://{}/api/{}/share",
+            cluster.http_addr(1),
+            CLIENT_ID
+        ))
+        .bearer_auth("unknown-key")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    let metrics = reqwest::Client::new()
+        .get(format!("http://{}/metrics", cluster.http_addr(1)))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    for metric in [
+        r#"secret_server_share_requests_total{operation="create",status="200"}"#,
+        r#"secret_server_share_requests_total{operation="read",status="200"}"#,
+        r#"secret_server_share_requests_total{operation="read",status="401"}"#,
+        r#"secret_server_auth_failures_total{reason="unauthorized"}"#,
+        "secret_server_stored_secrets 1",
+        "secret_server_apply_duration_seconds_count",
+    ] {
+        assert!(
+            metrics.contains(metric),
+            "{} missing from {}",
+            metric,
+            metrics
+        );
+    }
+    cluster.stop().await;
+}
+
+/// Sends an admin `GET` request for `path` with `query` to the node `id`.
+async fn admin_get(
+    cluster: &TestCluster,
+    id: u8,
+    path: &str,
+    query: &[(&str, &str)],
+) -> serde_json::Value {
+    reqwest::Client::new()
+        .get(format!("http://{}/admin/{}", cluster.http_addr(id), path))
+        .query(query)
+        .bearer_auth(API_KEY)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_audit_log() {
+    let cluster = TestCluster::start("cluster-audit", 3).await;
+    create_secret(&cluster, CLIENT_ID, SECRET, 2, 3).await;
+    assert!(get_share(&cluster, 1, CLIENT_ID).await.is_some());
+    let response = reqwest::Client::new()
+        .get(format!(
+            "http://{}/api/{}/share",
+            cluster.http_addr(1),
+            CLIENT_ID + 1
+        ))
+        .bearer_auth("unknown-key")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+    let response = admin_refresh(
+        &cluster,
+        1,
+        serde_json::json!({ "client_ids": [CLIENT_ID] }),
+    )
+    .await;
+    assert!(response.status().is_success());
+
+    let entries = admin_get(&cluster, 1, "audit", &[]).await;
+    let actions = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["action"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(actions, ["create", "read", "auth_failure", "refresh"]);
+    let read = &entries[1];
+    assert_eq!(read["client_id"], CLIENT_ID);
+    assert_eq!(read["outcome"], "success");
+    assert_eq!(read["status"], 200);
+    assert!(read["caller"].as_str().unwrap().starts_with("key:"));
+    assert_eq!(entries[2]["outcome"], "denied");
+    assert_eq!(entries[2]["status"], 401);
+    assert_eq!(entries[1]["hash"], entries[2]["prev_hash"]);
+
+    // Entries are filtered by client and time range
+    let entries = admin_get(&cluster, 1, "audit", &[("client_id", "2")]).await;
+    assert_eq!(entries.as_array().unwrap().len(), 1);
+    let entries = admin_get(&cluster, 1, "audit", &[("to", "2000-01-01T00:00:00Z")]).await;
+    assert_eq!(entries, serde_json::json!([]));
+
+    for id in 1..=3 {
+        let verification = admin_get(&cluster, id, "audit/verify", &[]).await;
+        assert_eq!(verification["valid"], true);
+    }
+    cluster.stop().await;
+}
+
+#[tokio::test]
+async fn test_problem_documents() {
+    let cluster = TestCluster::start("cluster-problems", 3).await;
+    let client = reqwest::Client::new();
+    let response = client
+        .delete(format!("http://{}/api/42/secret", cluster.http_addr(1)))
+        .bearer_auth(API_KEY)
+        .header("x-request-id", "test-problem-1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/problem+json"
+    );
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["code"], "not_found");
+    assert_eq!(problem["status"], 404);
+    assert_eq!(problem["request_id"], "test-problem-1");
+
+    let response = client
+        .get(format!("http://{}/api/42/share", cluster.http_addr(1)))
+        .bearer_auth("unknown-key")
+        .send()
+        .await
+        .unwrap();
+    let request_id = response.headers()["x-request-id"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["code"], "unauthorized");
+    assert_eq!(problem["status"], 401);
+    assert_eq!(problem["request_id"], request_id);
+
+    let response = client
+        .post(format!("http://{}/api/42/secret", cluster.http_addr(1)))
+        .bearer_auth(API_KEY)
+        .header("content-type", "application/json")
+        .body("not a share")
+        .send()
+        .await
+        .unwrap();
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["code"], "invalid_request");
+    cluster.stop().await;
+}
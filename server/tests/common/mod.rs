@@ -0,0 +1,304 @@
+//! Cluster of full server nodes running in the test process on free ports.
+//!
+//! Node 1 bootstraps the cluster and the other nodes join it through the first running node.
+//! Node keys are kept in a temporary directory, so a restarted node keeps its identity.
+#![allow(dead_code)]
+
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use shared_secret_server::conf::settings::Settings;
+use shared_secret_server::domain::model::NodeId;
+use shared_secret_server::node::Node;
+use sss_wrap::from_secrets;
+use sss_wrap::secret::secret::{Metadata, Share, ShareMeta};
+use sss_wrap::wrapped_sharing::reconstruct;
+
+// sha256("api-key-test")
+pub const API_KEY: &str = "api-key-test";
+const API_KEY_HASH: &str = "47cd528f4164b8ea10cb964d47ba75a5e9671564a625056c0b3d43ca8b66c64e";
+
+/// First port handed out by `free_port`, below the ephemeral range of Linux so outgoing
+/// connections cannot take a port between its allocation and the bind of the node.
+const FIRST_PORT: u16 = 20000;
+
+/// Returns a port free on the loopback interface, never the same twice in a test process.
+pub fn free_port() -> u16 {
+    static NEXT: OnceLock<AtomicU16> = OnceLock::new();
+    let next = NEXT.get_or_init(|| {
+        // Test binaries running side by side start from different ports
+        AtomicU16::new(FIRST_PORT + (std::process::id() % 100) as u16 * 100)
+    });
+    loop {
+        let port = next.fetch_add(1, Ordering::Relaxed);
+        if TcpListener::bind(("127.0.0.1", port)).is_ok() {
+            return port;
+        }
+    }
+}
+
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Polls `condition` every 100ms until it holds, panicking with `what` after `timeout`.
+pub async fn wait_until<F, Fut>(what: &str, timeout: Duration, mut condition: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+    while !condition().await {
+        if tokio::time::Instant::now() > deadline {
+            panic!("timed out waiting for {}", what);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Ports of a node of a `TestCluster`.
+#[derive(Debug, Clone, Copy)]
+pub struct NodePorts {
+    pub http: u16,
+    pub raft: u16,
+}
+
+/// Cluster of server nodes running in the test process.
+#[derive(Debug)]
+pub struct TestCluster {
+    dir: PathBuf,
+    ports: BTreeMap<u8, NodePorts>,
+    nodes: BTreeMap<u8, Node>,
+    cluster_token: Option<String>,
+}
+
+impl TestCluster {
+    /// Starts `size` nodes and waits until every one of them is an admitted member.
+    pub async fn start(name: &str, size: u8) -> Self {
+        Self::new(name, size).start_all().await
+    }
+
+    /// Starts every node of the cluster and waits until every one of them is an admitted member.
+    pub async fn start_all(mut self) -> Self {
+        let ids = self.ports.keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            self.start_node(id).await;
+        }
+        self
+    }
+
+    /// Admits the nodes with the cluster `token` rather than letting them admit themselves.
+    pub fn with_cluster_token(mut self, token: &str) -> Self {
+        self.cluster_token = Some(token.to_string());
+        self
+    }
+
+    /// Allocates the ports of `size` nodes without starting any.
+    pub fn new(name: &str, size: u8) -> Self {
+        let ports = (1..=size)
+            .map(|id| {
+                let ports = NodePorts {
+                    http: free_port(),
+                    raft: free_port(),
+                };
+                (id, ports)
+            })
+            .collect();
+        Self {
+            dir: temp_dir(name),
+            ports,
+            nodes: BTreeMap::new(),
+            cluster_token: None,
+        }
+    }
+
+    /// Returns the settings of the node `id`, listing `first_seed` before its peers if given.
+    fn settings(&self, id: u8, first_seed: Option<&str>) -> Settings {
+        let ports = self.ports[&id];
+        let mut peers = first_seed
+            .map(|raft_addr| format!("[[peers]]\nraft_addr = \"{}\"\n", raft_addr))
+            .unwrap_or_default();
+        for (_, ports) in self.ports.iter().filter(|(peer, _)| **peer != id) {
+            peers += &format!(
+                "[[peers]]\nraft_addr = \"127.0.0.1:{}\"\nhttp_addr = \"http://127.0.0.1:{}\"\n",
+                ports.raft, ports.http
+            );
+        }
+        Settings::from_toml(&format!(
+            r#"
+            raft_addr = "127.0.0.1:{raft_port}"
+            http_port = {http_port}
+            node_id = {id}
+            node_key_path = "{key_path}"
+            interval_refresh_secs = 3600
+            bootstrap = {bootstrap}
+            bootstrap_marker_path = "{marker_path}"
+            {cluster_token}
+
+            {peers}
+
+            [[api_keys]]
+            hash = "{API_KEY_HASH}"
+            namespace = {{ from = 0, to = 1000 }}
+            scopes = ["create", "read", "delete", "admin"]
+            "#,
+            raft_port = ports.raft,
+            http_port = ports.http,
+            key_path = self.dir.join(format!("node-{}.key", id)).display(),
+            bootstrap = id == 1,
+            marker_path = self.dir.join(format!("node-{}.bootstrapped", id)).display(),
+            cluster_token = self
+                .cluster_token
+                .as_ref()
+                .map(|token| format!("cluster_token = \"{}\"", token))
+                .unwrap_or_default(),
+        ))
+        .unwrap()
+    }
+
+    /// Starts the node `id`, joining the running nodes if any, and waits until it is admitted.
+    pub async fn start_node(&mut self, id: u8) {
+        self.start_node_with_first_seed(id, None).await
+    }
+
+    /// Starts the node `id` like `start_node`, trying to join through `first_seed` before the
+    /// other nodes.
+    pub async fn start_node_with_first_seed(&mut self, id: u8, first_seed: Option<&str>) {
+        let settings = self.settings(id, first_seed);
+        let node = Node::start(&settings, slog::Logger::root(slog::Discard, slog::o!()))
+            .await
+            .unwrap();
+        let handler = node.consensus_handler().clone();
+        self.nodes.insert(id, node);
+        wait_until(
+            &format!("node {} to be admitted", id),
+            Duration::from_secs(30),
+            || {
+                let handler = handler.clone();
+                async move {
+                    handler
+                        .members()
+                        .is_ok_and(|members| members.contains_key(&NodeId(id)))
+                }
+            },
+        )
+        .await;
+    }
+
+    /// Stops the node `id`: its HTTP server gracefully, its Raft server and refresher at once.
+    pub async fn stop_node(&mut self, id: u8) {
+        if let Some(node) = self.nodes.remove(&id) {
+            node.handle().stop().await;
+        }
+    }
+
+    /// Returns the running node `id`.
+    pub fn node(&self, id: u8) -> &Node {
+        &self.nodes[&id]
+    }
+
+    /// Returns the HTTP address of the node `id`.
+    pub fn http_addr(&self, id: u8) -> String {
+        format!("127.0.0.1:{}", self.ports[&id].http)
+    }
+
+    /// Returns the HTTP addresses of the running nodes by node ID.
+    pub fn http_addrs(&self) -> HashMap<u8, String> {
+        self.nodes
+            .keys()
+            .map(|id| (*id, self.http_addr(*id)))
+            .collect()
+    }
+
+    /// Stops every running node.
+    pub async fn stop(mut self) {
+        let ids = self.nodes.keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            self.stop_node(id).await;
+        }
+    }
+}
+
+/// Splits `secret` in `shares_to_create` shares and stores the share `i` on the node `i`.
+pub async fn create_secret(
+    cluster: &TestCluster,
+    client_id: u64,
+    secret: &[u8],
+    shares_required: u8,
+    shares_to_create: u8,
+) -> Vec<Share> {
+    let shares = from_secrets(secret, shares_required, shares_to_create, None).unwrap();
+    let meta = Metadata::new(shares_required, shares_to_create, secret.len());
+    let client = reqwest::Client::new();
+    let mut created = vec![];
+    for share in shares {
+        let share: Share = share.into();
+        let response = client
+            .post(format!(
+                "http://{}/api/{}/secret",
+                cluster.http_addr(share.id()),
+                client_id
+            ))
+            .bearer_auth(API_KEY)
+            .json(&ShareMeta::new(share.clone(), meta.clone()))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        created.push(share);
+    }
+    created
+}
+
+/// Retrieves the share of `client_id` stored on the node `id`.
+pub async fn get_share(cluster: &TestCluster, id: u8, client_id: u64) -> Option<Share> {
+    let response = reqwest::Client::new()
+        .get(format!(
+            "http://{}/api/{}/share",
+            cluster.http_addr(id),
+            client_id
+        ))
+        .bearer_auth(API_KEY)
+        .send()
+        .await
+        .ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    response.json::<Option<Share>>().await.ok()?
+}
+
+/// Returns the HTTP status of the retrieval of the share of `client_id` stored on the node `id`.
+pub async fn get_share_status(
+    cluster: &TestCluster,
+    id: u8,
+    client_id: u64,
+) -> reqwest::StatusCode {
+    reqwest::Client::new()
+        .get(format!(
+            "http://{}/api/{}/share",
+            cluster.http_addr(id),
+            client_id
+        ))
+        .bearer_auth(API_KEY)
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+/// Reconstructs the secret from the shares.
+pub fn reconstruct_secret(shares: Vec<Share>) -> Vec<u8> {
+    reconstruct(
+        shares.into_iter().map(Vec::<u8>::from).collect::<Vec<_>>(),
+        false,
+    )
+    .unwrap()
+}
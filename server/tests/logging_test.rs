@@ -0,0 +1,149 @@
+//! Logs written while secrets are created, retrieved and refreshed.
+//!
+//! The logger of the `log` crate can only be installed once, so this binary has a single test.
+
+mod common;
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::{create_secret, get_share, wait_until, TestCluster, API_KEY};
+use shared_secret_server::conf::settings::Settings;
+use shared_secret_server::logging;
+use sss_wrap::secret::secret::Share;
+
+const CLIENT_ID: u64 = 1;
+const SECRET: &[u8] = b"my-secret-test";
+
+/// Writer appending to a buffer the test reads.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the ways the bytes of the share could be printed.
+fn share_renderings(share: &Share) -> Vec<String> {
+    let bytes = Vec::<u8>::from(share.clone());
+    let ys = &bytes[1..];
+    vec![hex::encode(ys), format!("{:?}", ys)]
+}
+
+#[tokio::test]
+async fn test_logs_never_contain_shares_or_api_keys() {
+    let settings = Settings::from_toml(
+        r#"
+        raft_addr = "127.0.0.1:7070"
+        http_port = 8080
+        node_id = 1
+        interval_refresh_secs = 3600
+        bootstrap = true
+        [log]
+        format = "json"
+        level = "trace"
+        "#,
+    )
+    .unwrap();
+    let buffer = SharedBuffer::default();
+    let guard = logging::init(settings.log(), buffer.clone()).unwrap();
+
+    let cluster = TestCluster::start("logging", 3).await;
+    let mut shares = create_secret(&cluster, CLIENT_ID, SECRET, 2, 3).await;
+
+    // The request ID sent by the client is logged and returned
+    let response = reqwest::Client::new()
+        .get(format!(
+            "http://{}/api/{}/share",
+            cluster.http_addr(1),
+            CLIENT_ID
+        ))
+        .bearer_auth(API_KEY)
+        .header(logging::REQUEST_ID_HEADER, "test-request-1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        response.headers()[logging::REQUEST_ID_HEADER],
+        "test-request-1"
+    );
+    let response = reqwest::Client::new()
+        .get(format!(
+            "http://{}/api/{}/share",
+            cluster.http_addr(1),
+            CLIENT_ID
+        ))
+        .bearer_auth(format!("{}-wrong", API_KEY))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.headers().contains_key(logging::REQUEST_ID_HEADER));
+
+    // The nodes applying the refresh log it with the ID of the request that proposed it
+    let handler = cluster.node(1).consensus_handler();
+    logging::with_request_id("test-refresh-1".to_string(), async {
+        handler.start_refresh().await.unwrap();
+        handler.refresh_secrets().await.unwrap();
+        handler.finish_refresh().await.unwrap();
+    })
+    .await;
+    for id in 1..=3 {
+        let old = shares[id as usize - 1].clone();
+        let cluster = &cluster;
+        wait_until(
+            &format!("node {} to refresh its share", id),
+            Duration::from_secs(10),
+            || {
+                let old = old.clone();
+                async move {
+                    get_share(cluster, id, CLIENT_ID)
+                        .await
+                        .is_some_and(|s| s != old)
+                }
+            },
+        )
+        .await;
+        shares.push(get_share(cluster, id, CLIENT_ID).await.unwrap());
+    }
+    cluster.stop().await;
+    drop(guard);
+
+    let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let records = logs
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert!(records
+        .iter()
+        .any(|record| record["request_id"] == "test-request-1"));
+    let refresh_nodes = records
+        .iter()
+        .filter(|record| record["request_id"] == "test-refresh-1")
+        .filter(|record| {
+            record["msg"]
+                .as_str()
+                .is_some_and(|msg| msg.starts_with("Refresh"))
+        })
+        .count();
+    assert!(refresh_nodes >= 3, "{}", logs);
+
+    assert!(!logs.contains(API_KEY));
+    assert!(!logs.contains(std::str::from_utf8(SECRET).unwrap()));
+    for share in &shares {
+        for rendering in share_renderings(share) {
+            assert!(
+                !logs.contains(&rendering),
+                "share bytes {} logged",
+                rendering
+            );
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+use shared_secret_server::audit::AuditLog;
+use shared_secret_server::conf::settings::Settings;
+use shared_secret_server::consensus::backend::init_consensus;
+use shared_secret_server::consensus::handler::ConsensusHandler;
+use shared_secret_server::consensus::raft::HashStore;
+use shared_secret_server::consensus::signing::NodeKey;
+use shared_secret_server::domain::model::NodeId;
+use shared_secret_server::routes::http;
+use sss_wrap::secret::secret::{Metadata, Share, ShareMeta};
+
+// sha256("api-key-test")
+const API_KEY: &str = "api-key-test";
+const API_KEY_HASH: &str = "47cd528f4164b8ea10cb964d47ba75a5e9671564a625056c0b3d43ca8b66c64e";
+
+struct Pki {
+    ca: String,
+    server_cert: String,
+    server_key: String,
+    client_1: String,
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn generate_pki() -> Pki {
+    let mut ca_params = CertificateParams::new(vec![]);
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "shared-secrets-test-ca");
+    let ca = Certificate::from_params(ca_params).unwrap();
+
+    let server =
+        Certificate::from_params(CertificateParams::new(vec!["localhost".to_string()])).unwrap();
+
+    let mut client_params = CertificateParams::new(vec![]);
+    client_params
+        .distinguished_name
+        .push(DnType::CommonName, "1");
+    let client = Certificate::from_params(client_params).unwrap();
+
+    Pki {
+        ca: ca.serialize_pem().unwrap(),
+        server_cert: server.serialize_pem_with_signer(&ca).unwrap(),
+        server_key: server.serialize_private_key_pem(),
+        client_1: format!(
+            "{}{}",
+            client.serialize_pem_with_signer(&ca).unwrap(),
+            client.serialize_private_key_pem()
+        ),
+    }
+}
+
+fn write(dir: &Path, name: &str, content: &str) -> String {
+    let path = dir.join(name);
+    std::fs::write(&path, content).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+async fn start_server(dir: &Path, pki: &Pki, require_client_cert: bool) -> u16 {
+    let http_port = free_port();
+    let raft_port = free_port();
+    let settings = Settings::from_toml(&format!(
+        r#"
+        raft_addr = "127.0.0.1:{raft_port}"
+        http_port = {http_port}
+        node_id = 1
+        interval_refresh_secs = 3600
+        bootstrap = true
+
+        [tls]
+        cert_path = "{cert}"
+        key_path = "{key}"
+        client_ca_path = "{ca}"
+        require_client_cert = {require_client_cert}
+
+        [[api_keys]]
+        hash = "{API_KEY_HASH}"
+        namespace = {{ from = 1, to = 2 }}
+        scopes = ["create", "read"]
+        "#,
+        cert = write(dir, "server.pem", &pki.server_cert),
+        key = write(dir, "server.key", &pki.server_key),
+        ca = write(dir, "ca.pem", &pki.ca),
+    ))
+    .unwrap();
+
+    let store = HashStore::new(NodeId(settings.node_id()));
+    let node_key = Arc::new(NodeKey::generate());
+    let (_, backend) = init_consensus(
+        &settings,
+        store.clone(),
+        node_key.clone(),
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .await
+    .unwrap();
+    let audit_log = Arc::new(AuditLog::open(NodeId(settings.node_id()), None, &node_key).unwrap());
+    let consensus_handler = ConsensusHandler::new(store, backend, node_key);
+    let server = http::run(&settings, consensus_handler, audit_log)
+        .await
+        .unwrap();
+    tokio::spawn(server);
+    http_port
+}
+
+fn client(pki: &Pki, identity: Option<&str>) -> reqwest::Client {
+    let builder = reqwest::Client::builder()
+        .use_rustls_tls()
+        .add_root_certificate(reqwest::Certificate::from_pem(pki.ca.as_bytes()).unwrap());
+    let builder = match identity {
+        Some(identity) => {
+            builder.identity(reqwest::Identity::from_pem(identity.as_bytes()).unwrap())
+        }
+        None => builder,
+    };
+    builder.build().unwrap()
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn test_mtls_releases_share_only_to_matching_client() {
+    let dir = temp_dir("tls-matching-client");
+    let pki = generate_pki();
+    let port = start_server(&dir, &pki, false).await;
+    let client = client(&pki, Some(&pki.client_1));
+
+    let share = ShareMeta::new(Share::new(1, vec![1, 2, 3]), Metadata::new(2, 3, 3));
+    let created = client
+        .post(format!("https://localhost:{}/api/1/secret", port))
+        .bearer_auth(API_KEY)
+        .json(&share)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(created.status(), reqwest::StatusCode::OK);
+
+    let fetched = client
+        .get(format!("https://localhost:{}/api/1/share", port))
+        .bearer_auth(API_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(fetched.status(), reqwest::StatusCode::OK);
+    assert_eq!(fetched.json::<Share>().await.unwrap(), share.share);
+
+    // The API key is bound to clients 1 and 2 but the certificate only to client 1
+    let other_client = client
+        .get(format!("https://localhost:{}/api/2/share", port))
+        .bearer_auth(API_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(other_client.status(), reqwest::StatusCode::FORBIDDEN);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_mtls_without_client_certificate() {
+    let dir = temp_dir("tls-no-client-cert");
+    let pki = generate_pki();
+    let optional_port = start_server(&dir, &pki, false).await;
+    let required_port = start_server(&dir, &pki, true).await;
+    let client = client(&pki, None);
+
+    let health = client
+        .get(format!("https://localhost:{}/healthz", optional_port))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(health.status(), reqwest::StatusCode::OK);
+
+    // Without `require_client_cert` the bearer token alone authenticates the client
+    let share = ShareMeta::new(Share::new(1, vec![1, 2, 3]), Metadata::new(2, 3, 3));
+    let created = client
+        .post(format!("https://localhost:{}/api/1/secret", optional_port))
+        .bearer_auth(API_KEY)
+        .json(&share)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(created.status(), reqwest::StatusCode::OK);
+    let fetched = client
+        .get(format!("https://localhost:{}/api/1/share", optional_port))
+        .bearer_auth(API_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(fetched.status(), reqwest::StatusCode::OK);
+    assert_eq!(fetched.json::<Share>().await.unwrap(), share.share);
+
+    let rejected = client
+        .get(format!("https://localhost:{}/healthz", required_port))
+        .send()
+        .await;
+    assert!(rejected.is_err());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
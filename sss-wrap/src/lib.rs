@@ -1,5 +1,6 @@
 #![warn(rust_2018_idioms, missing_debug_implementations)]
 mod polynomial;
+pub mod sealed;
 pub mod secret;
 
 pub use sss_rs::basic_sharing::*;
@@ -0,0 +1,185 @@
+//! Seals shares to a client public key so only that client can read them.
+//!
+//! A share is encrypted with ChaCha20-Poly1305 under a key derived with HKDF-SHA256 from the
+//! X25519 agreement between a fresh ephemeral key of the node and the client public key.
+
+use std::fmt::{self, Debug, Formatter};
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::secret::secret::Share;
+
+const INFO: &[u8] = b"shared-secrets sealed share v1";
+const NONCE_LEN: usize = 12;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SealError {
+    #[error("Invalid public key")]
+    InvalidPublicKey,
+    #[error("Cannot encrypt share")]
+    Encrypt,
+    #[error("Cannot decrypt share")]
+    Decrypt,
+}
+
+/// A `Share` encrypted to a client public key.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Clone, Debug)]
+pub struct SealedShare {
+    #[serde(with = "hex::serde")]
+    ephemeral_public_key: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    nonce: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    ciphertext: Vec<u8>,
+}
+
+/// Key pair of the client receiving sealed shares.
+pub struct RecipientKey {
+    secret: StaticSecret,
+}
+
+impl Debug for RecipientKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "RecipientKey {{ secret: **** }}")
+    }
+}
+
+fn derive_key(shared_secret: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> Key {
+    let mut salt = Vec::with_capacity(64);
+    salt.extend_from_slice(ephemeral.as_bytes());
+    salt.extend_from_slice(recipient.as_bytes());
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared_secret);
+    let mut key = Key::default();
+    hkdf.expand(INFO, key.as_mut_slice())
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+/// Parses a hex encoded X25519 public key.
+pub fn parse_public_key(public_key: &str) -> Result<PublicKey, SealError> {
+    let bytes: [u8; 32] = hex::decode(public_key)
+        .map_err(|_| SealError::InvalidPublicKey)?
+        .try_into()
+        .map_err(|_| SealError::InvalidPublicKey)?;
+    Ok(PublicKey::from(bytes))
+}
+
+impl SealedShare {
+    /// Seals the share to the given recipient public key.
+    pub fn seal(share: &Share, recipient: &PublicKey) -> Result<Self, SealError> {
+        let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral = PublicKey::from(&ephemeral_secret);
+        let shared_secret = ephemeral_secret.diffie_hellman(recipient);
+        if !shared_secret.was_contributory() {
+            return Err(SealError::InvalidPublicKey);
+        }
+        let key = derive_key(shared_secret.as_bytes(), &ephemeral, recipient);
+
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let plaintext: Vec<u8> = share.clone().into();
+        let ciphertext = ChaCha20Poly1305::new(&key)
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|_| SealError::Encrypt)?;
+
+        Ok(Self {
+            ephemeral_public_key: ephemeral.as_bytes().to_vec(),
+            nonce: nonce.to_vec(),
+            ciphertext,
+        })
+    }
+}
+
+impl RecipientKey {
+    /// Generates a new random key pair.
+    pub fn generate() -> Self {
+        Self {
+            secret: StaticSecret::random_from_rng(OsRng),
+        }
+    }
+
+    /// Returns the hex encoded public key to send to the nodes.
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(PublicKey::from(&self.secret).as_bytes())
+    }
+
+    /// Opens a share sealed to this key.
+    pub fn open(&self, sealed: &SealedShare) -> Result<Share, SealError> {
+        let ephemeral_bytes: [u8; 32] = sealed
+            .ephemeral_public_key
+            .as_slice()
+            .try_into()
+            .map_err(|_| SealError::Decrypt)?;
+        if sealed.nonce.len() != NONCE_LEN {
+            return Err(SealError::Decrypt);
+        }
+        let ephemeral = PublicKey::from(ephemeral_bytes);
+        let recipient = PublicKey::from(&self.secret);
+        let shared_secret = self.secret.diffie_hellman(&ephemeral);
+        let key = derive_key(shared_secret.as_bytes(), &ephemeral, &recipient);
+
+        let plaintext = ChaCha20Poly1305::new(&key)
+            .decrypt(
+                Nonce::from_slice(&sealed.nonce),
+                sealed.ciphertext.as_slice(),
+            )
+            .map_err(|_| SealError::Decrypt)?;
+        match plaintext.split_first() {
+            Some((x, ys)) => Ok(Share::new(*x, ys.to_vec())),
+            None => Err(SealError::Decrypt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let share = Share::new(2, vec![7, 8, 9, 10]);
+        let recipient = RecipientKey::generate();
+        let public_key = parse_public_key(&recipient.public_key_hex()).unwrap();
+        let sealed = SealedShare::seal(&share, &public_key).unwrap();
+        assert_eq!(recipient.open(&sealed).unwrap(), share);
+    }
+
+    #[test]
+    fn test_open_with_other_key_or_tampered_ciphertext() {
+        let share = Share::new(2, vec![7, 8, 9, 10]);
+        let recipient = RecipientKey::generate();
+        let public_key = parse_public_key(&recipient.public_key_hex()).unwrap();
+        let mut sealed = SealedShare::seal(&share, &public_key).unwrap();
+        assert_eq!(
+            RecipientKey::generate().open(&sealed),
+            Err(SealError::Decrypt)
+        );
+        sealed.ciphertext[0] ^= 1;
+        assert_eq!(recipient.open(&sealed), Err(SealError::Decrypt));
+    }
+
+    #[test]
+    fn test_reject_invalid_public_key() {
+        assert_eq!(
+            parse_public_key("not-hex").unwrap_err(),
+            SealError::InvalidPublicKey
+        );
+        assert_eq!(
+            parse_public_key("0102").unwrap_err(),
+            SealError::InvalidPublicKey
+        );
+        let low_order = parse_public_key(&hex::encode([0u8; 32])).unwrap();
+        assert_eq!(
+            SealedShare::seal(&Share::new(1, vec![1]), &low_order),
+            Err(SealError::InvalidPublicKey)
+        );
+    }
+}
@@ -12,6 +12,9 @@ pub struct Metadata {
     pub shares_required: u8,
     pub shares_to_create: u8,
     pub sec_len: usize,
+    /// Interval between two refreshes of the shares, overriding the interval of the servers.
+    #[serde(default)]
+    pub refresh_interval_secs: Option<u64>,
 }
 impl Metadata {
     pub fn new(shares_required: u8, shares_to_create: u8, sec_len: usize) -> Metadata {
@@ -19,8 +22,14 @@ impl Metadata {
             shares_required,
             shares_to_create,
             sec_len,
+            refresh_interval_secs: None,
         }
     }
+
+    pub fn with_refresh_interval_secs(mut self, refresh_interval_secs: u64) -> Metadata {
+        self.refresh_interval_secs = Some(refresh_interval_secs);
+        self
+    }
 }
 
 #[derive(PartialEq, Eq, Serialize, Deserialize, Clone, Debug)]